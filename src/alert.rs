@@ -0,0 +1,164 @@
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Terminal bell control character
+pub const BELL: &str = "\x07";
+
+/// A daily span of hours (UTC) during which mention alerts stay silent.
+///
+/// Written as `START-END`, e.g. `22-7`; the span may wrap past midnight and
+/// includes `START` but not `END`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuietHours {
+    start: u8,
+    end: u8,
+}
+
+impl QuietHours {
+    /// Returns whether `hour` (0-23) falls within the quiet span.
+    pub fn contains(&self, hour: u8) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&hour)
+        } else {
+            hour >= self.start || hour < self.end
+        }
+    }
+}
+
+impl FromStr for QuietHours {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let usage = || format!("Invalid quiet hours '{}', expected START-END like 22-7", s);
+        let (start, end) = s.split_once('-').ok_or_else(usage)?;
+        let start: u8 = start.trim().parse().map_err(|_| usage())?;
+        let end: u8 = end.trim().parse().map_err(|_| usage())?;
+        if start > 23 || end > 23 {
+            return Err(usage());
+        }
+        Ok(Self { start, end })
+    }
+}
+
+/// Decides when an incoming message should ring the terminal bell.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MentionAlert {
+    /// Whether alerts are enabled at all
+    pub enabled: bool,
+    /// Hours during which alerts are suppressed
+    pub quiet_hours: Option<QuietHours>,
+}
+
+impl MentionAlert {
+    /// Returns whether the chat line `text`, mentioning the users the server
+    /// listed in `mentions`, should alert `own_name` right now.
+    pub fn should_alert(&self, text: &str, mentions: &[String], own_name: &str) -> bool {
+        self.should_alert_at(text, mentions, own_name, current_utc_hour())
+    }
+
+    fn should_alert_at(&self, text: &str, mentions: &[String], own_name: &str, hour: u8) -> bool {
+        if !self.enabled || self.quiet_hours.is_some_and(|quiet| quiet.contains(hour)) {
+            return false;
+        }
+        mentions_us(text, mentions, own_name)
+    }
+}
+
+/// Returns whether someone else's chat line mentions `own_name`: the server
+/// listed us in the message's `mentions`, or the text names us.
+///
+/// The list catches names the text search can't, such as ones with spaces;
+/// the search covers servers that don't send a list and names given
+/// without an `@`.
+pub fn mentions_us(text: &str, mentions: &[String], own_name: &str) -> bool {
+    let own = own_name.to_lowercase();
+    let from_us = text
+        .split_once(": ")
+        .is_some_and(|(sender, _)| sender.to_lowercase() == own);
+    let listed = mentions.iter().any(|name| name.to_lowercase() == own);
+    (listed && !from_us) || is_mention(text, own_name)
+}
+
+/// Returns whether someone else's chat line mentions `own_name`, with or
+/// without a leading `@`.
+///
+/// Chat lines look like `Sender: text`; our own messages never count.
+pub fn is_mention(text: &str, own_name: &str) -> bool {
+    let own_name = own_name.to_lowercase();
+    let body = match text.split_once(": ") {
+        Some((sender, _)) if sender.to_lowercase() == own_name => return false,
+        Some((_, body)) => body,
+        None => text,
+    };
+    body.to_lowercase()
+        .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+        .any(|word| word == own_name)
+}
+
+/// Whether this build can show desktop notifications
+pub const NOTIFICATIONS_SUPPORTED: bool = cfg!(feature = "notifications");
+
+/// Shows a desktop notification for a chat line that mentions us.
+///
+/// The notification is sent from a background thread so a slow notification
+/// daemon can't stall rendering. Does nothing in builds without the
+/// `notifications` feature.
+pub fn notify_mention(text: &str) {
+    #[cfg(feature = "notifications")]
+    {
+        let text = text.to_string();
+        std::thread::spawn(move || {
+            if let Err(e) = notify_rust::Notification::new()
+                .summary("You were mentioned in chat")
+                .body(&text)
+                .show()
+            {
+                eprintln!("Failed to show notification: {}", e);
+            }
+        });
+    }
+    #[cfg(not(feature = "notifications"))]
+    let _ = text;
+}
+
+fn current_utc_hour() -> u8 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    ((secs / 3600) % 24) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mention_alert_decision() {
+        let alert = MentionAlert {
+            enabled: true,
+            quiet_hours: Some("22-7".parse().unwrap()),
+        };
+
+        assert!(alert.should_alert_at("Bob: hey @alice, look", &[], "Alice", 12));
+        assert!(alert.should_alert_at("Bob: alice?", &[], "Alice", 12));
+        assert!(!alert.should_alert_at("Bob: talking to malice", &[], "Alice", 12));
+        // Our own messages don't ring
+        assert!(!alert.should_alert_at("Alice: I'm alice", &[], "Alice", 12));
+        // Quiet hours wrap past midnight
+        assert!(!alert.should_alert_at("Bob: @Alice", &[], "Alice", 23));
+        assert!(!alert.should_alert_at("Bob: @Alice", &[], "Alice", 3));
+        assert!(alert.should_alert_at("Bob: @Alice", &[], "Alice", 7));
+
+        let disabled = MentionAlert::default();
+        assert!(!disabled.should_alert_at("Bob: @Alice", &[], "Alice", 12));
+
+        // Names the text search can't find are taken from the server's list
+        let ann = vec!["Ann Lee".to_string()];
+        assert!(alert.should_alert_at("Bob: hi @ann lee", &ann, "Ann Lee", 12));
+        assert!(!alert.should_alert_at("Ann Lee: hi @Ann Lee", &ann, "Ann Lee", 12));
+
+        assert!("25-3".parse::<QuietHours>().is_err());
+        assert!("night".parse::<QuietHours>().is_err());
+    }
+}
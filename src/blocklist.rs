@@ -0,0 +1,112 @@
+use regex::{Captures, Regex};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::shared::{ChatError, ChatResult};
+
+/// What happens to a message containing a blocked term.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BlocklistMode {
+    /// Turn the message away with an error
+    #[default]
+    Reject,
+    /// Replace each blocked term with asterisks and let the message through
+    Mask,
+}
+
+impl FromStr for BlocklistMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "mask" => Ok(Self::Mask),
+            _ => Err(format!(
+                "Invalid blocklist mode '{}', expected reject or mask",
+                s
+            )),
+        }
+    }
+}
+
+/// Terms that may not appear in messages.
+///
+/// Each entry is a word or a regular expression, matched ignoring case and
+/// only as whole words, so blocking "ass" leaves "class" alone.
+#[derive(Debug, Clone)]
+pub struct Blocklist {
+    pattern: Regex,
+    mode: BlocklistMode,
+}
+
+impl Blocklist {
+    /// Reads a blocklist with one entry per line from `path`.
+    pub fn load(path: &Path, mode: BlocklistMode) -> ChatResult<Self> {
+        Self::parse(&fs::read_to_string(path)?, mode)
+    }
+
+    /// Parses a blocklist with one entry per line. Blank lines and lines
+    /// starting with `#` are skipped.
+    pub fn parse(contents: &str, mode: BlocklistMode) -> ChatResult<Self> {
+        let mut entries = Vec::new();
+        for (n, line) in contents.lines().enumerate() {
+            let entry = line.trim();
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+            Regex::new(entry)
+                .map_err(|e| ChatError::ConfigError(format!("Blocklist line {}: {}", n + 1, e)))?;
+            entries.push(format!("(?:{})", entry));
+        }
+        if entries.is_empty() {
+            return Err(ChatError::ConfigError("Blocklist is empty".to_string()));
+        }
+        let pattern = Regex::new(&format!(r"(?i)\b(?:{})\b", entries.join("|")))
+            .map_err(|e| ChatError::ConfigError(format!("Blocklist: {}", e)))?;
+        Ok(Self { pattern, mode })
+    }
+
+    /// Returns `text` as it may be sent, masked if need be, or `None` if it
+    /// must be rejected.
+    pub fn filter(&self, text: &str) -> Option<String> {
+        if !self.pattern.is_match(text) {
+            return Some(text.to_string());
+        }
+        match self.mode {
+            BlocklistMode::Reject => None,
+            BlocklistMode::Mask => Some(
+                self.pattern
+                    .replace_all(text, |caps: &Captures| "*".repeat(caps[0].chars().count()))
+                    .into_owned(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocklist_matches_whole_words_ignoring_case() {
+        let list = "# profanity\nheck\n\ndarn(it)?\n";
+        let reject = Blocklist::parse(list, BlocklistMode::Reject).unwrap();
+        assert_eq!(reject.filter("Oh HECK no"), None);
+        assert_eq!(reject.filter("darnit"), None);
+        // Blocked words inside longer ones are left alone
+        assert_eq!(
+            reject.filter("Checking the darning needle").as_deref(),
+            Some("Checking the darning needle")
+        );
+
+        let mask = Blocklist::parse(list, BlocklistMode::Mask).unwrap();
+        assert_eq!(
+            mask.filter("Heck, darn it. Darnit!").as_deref(),
+            Some("****, **** it. ******!")
+        );
+
+        assert!(Blocklist::parse("ok\n(unclosed", BlocklistMode::Mask).is_err());
+        assert!(Blocklist::parse("# nothing\n", BlocklistMode::Mask).is_err());
+    }
+}
@@ -1,12 +1,156 @@
-use futures::{sink::SinkExt, stream::StreamExt};
-use rustyline::Editor;
+use rustyline::ExternalPrinter;
 use rustyline::error::ReadlineError;
-use std::io::Write;
+use rustyline::{CompletionType, Editor};
+use std::fs::{File, OpenOptions};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use tokio::sync::mpsc;
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message as WsMessage};
+use tokio_tungstenite::connect_async;
 
-use crate::shared::{ClientMessage, ServerMessage};
+use crate::alert::{BELL, MentionAlert, mentions_us, notify_mention};
+use crate::client_tui;
+use crate::completion::ChatHelper;
+use crate::connection::{self, Backoff, Session};
+use crate::shared::{
+    ClientMessage, LastSeen, Message, RoomInfo, ServerMessage, UserList, UserStatus, action_line,
+    attachment_line, chat_line, default_user_name, reply_line, status_line, unix_time,
+};
+use crate::signing::MessageSigner;
+use crate::theme::{Theme, sender_color};
+use crate::transcript::Transcript;
+
+/// The most recent user list from the server and when it was received
+pub(crate) type Roster = Arc<Mutex<Option<(UserList, Instant)>>>;
+
+/// Readline editor used by the prompt front end
+type ChatEditor = Editor<ChatHelper, rustyline::history::DefaultHistory>;
+
+/// Number of unrendered incoming messages at which the prompt starts
+/// coalescing chat lines to catch up
+const BACKLOG_HIGH_WATER: usize = 256;
+
+/// Backlog size below which chat lines are rendered one by one again
+const BACKLOG_LOW_WATER: usize = 16;
+
+/// Number of messages `/history` fetches when no count is given
+const DEFAULT_HISTORY_PAGE: usize = 20;
+
+/// Question asked before `/purge` is sent; see [`is_confirmed`]
+pub(crate) const PURGE_PROMPT: &str = "Delete all your messages in this room? [y/N] ";
+
+/// Runtime configuration for the chat client.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// The IP address or hostname of the chat server
+    pub address: String,
+    /// The port number the server is listening on
+    pub port: u16,
+    /// Username for the client; a random name is generated when `None`
+    pub name: Option<String>,
+    /// The room to join
+    pub room: String,
+    /// File or FIFO that receives a plain-text copy of incoming messages
+    pub tee: Option<PathBuf>,
+    /// Use the full-screen interface instead of the readline prompt
+    pub tui: bool,
+    /// Auth token to present if the server requires one
+    pub token: Option<String>,
+    /// Password of `room`, if it has one
+    pub room_password: Option<String>,
+    /// When to ring the terminal bell for messages mentioning us
+    pub mention_alert: MentionAlert,
+    /// File the prompt's command history is loaded from and saved to;
+    /// history isn't kept between sessions when `None`
+    pub history_path: Option<PathBuf>,
+    /// Show a desktop notification for messages mentioning us
+    pub notifications: bool,
+    /// Colors of the readline prompt's output
+    pub theme: Theme,
+    /// Whether the readline prompt's output may be colored; it never is
+    /// when stdout isn't a terminal
+    pub color: bool,
+    /// Log every protocol frame sent and received to stderr
+    pub debug_protocol: bool,
+    /// File a transcript of the session is appended to, in either front end
+    pub log_file: Option<PathBuf>,
+    /// Key the server signs messages with; their signatures aren't checked
+    /// when `None`
+    pub hmac_key: Option<String>,
+    /// File holding the secret that keeps our messages ours to edit and
+    /// delete across reconnects and restarts; created on first use. Only
+    /// this connection's messages are ours when `None`
+    pub identity_path: Option<PathBuf>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1".to_string(),
+            port: 12345,
+            name: None,
+            room: crate::shared::DEFAULT_ROOM.to_string(),
+            tee: None,
+            tui: false,
+            token: None,
+            room_password: None,
+            mention_alert: MentionAlert::default(),
+            history_path: default_history_path(),
+            notifications: false,
+            theme: Theme::default(),
+            color: true,
+            debug_protocol: false,
+            log_file: None,
+            hmac_key: None,
+            identity_path: default_identity_path(),
+        }
+    }
+}
+
+/// Returns `~/.rust-chat-history`, or `None` if the home directory is unknown.
+pub fn default_history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".rust-chat-history"))
+}
+
+/// Returns `~/.rust-chat-identity`, or `None` if the home directory is unknown.
+pub fn default_identity_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".rust-chat-identity"))
+}
+
+/// Reads the identity secret kept in `path`, creating the file with a new
+/// random one if there isn't one yet.
+///
+/// The file is only readable by its owner where that can be set. Returns
+/// `None` with a warning if it can't be read or written.
+fn load_identity(path: &Path) -> Option<String> {
+    match std::fs::read_to_string(path) {
+        Ok(identity) if !identity.trim().is_empty() => return Some(identity.trim().to_string()),
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            eprintln!("Failed to read identity from {}: {}", path.display(), e);
+            return None;
+        }
+    }
+
+    let identity = uuid::Uuid::new_v4().simple().to_string();
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    match options
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", identity))
+    {
+        Ok(()) => Some(identity),
+        Err(e) => {
+            eprintln!("Failed to save identity to {}: {}", path.display(), e);
+            None
+        }
+    }
+}
 
 /// Runs the chat client and connects to the specified server.
 ///
@@ -15,150 +159,1222 @@ use crate::shared::{ClientMessage, ServerMessage};
 ///
 /// # Arguments
 ///
-/// * `server_address` - The IP address or hostname of the chat server
-/// * `server_port` - The port number the server is listening on
-/// * `name` - Optional username for the client. If None, a random name is generated.
+/// * `config` - Server to connect to, name, room and display settings
 ///
 /// # Examples
 ///
 /// ```rust
 /// // Connect with a specific name
-/// run_client("127.0.0.1", 12345, Some("Alice".to_string())).await;
+/// run_client(ClientConfig {
+///     name: Some("Alice".to_string()),
+///     ..ClientConfig::default()
+/// })
+/// .await;
 ///
 /// // Connect with a random name
-/// run_client("127.0.0.1", 12345, None).await;
+/// run_client(ClientConfig::default()).await;
 /// ```
-pub async fn run_client(server_address: &str, server_port: u16, name: Option<String>) {
-    let client_name = name.unwrap_or_else(generate_random_name);
-    let ws_url = format!("ws://{}:{}/room/1", server_address, server_port);
+pub async fn run_client(config: ClientConfig) {
+    let ClientConfig {
+        address,
+        port,
+        name,
+        room,
+        tee,
+        tui,
+        token,
+        room_password,
+        mention_alert,
+        history_path,
+        notifications,
+        theme,
+        color,
+        debug_protocol,
+        log_file,
+        hmac_key,
+        identity_path,
+    } = config;
+    // Named like the server names clients that don't give one
+    let client_name = name.unwrap_or_else(default_user_name);
+    let ws_url = format!("ws://{}:{}/room/{}", address, port, room);
 
     println!("Connecting to chat server as {}...", client_name);
 
-    let (ws_stream, _) = connect_async(&ws_url)
-        .await
-        .expect("Failed to connect to server");
-
-    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+    // Only the first connection has to succeed; later drops are retried
+    let ws_stream = match connect_async(&ws_url).await {
+        Ok((ws_stream, _)) => ws_stream,
+        Err(e) => {
+            eprintln!("Failed to connect to server: {}", e);
+            return;
+        }
+    };
 
-    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    let (tx, rx) = mpsc::unbounded_channel::<ClientMessage>();
+    let (events_tx, events_rx) = mpsc::unbounded_channel::<Incoming>();
 
-    // Send initial connection message with user name
-    let connect_msg = ClientMessage::Connect {
+    // Relay messages both ways, forwarding everything the server sends to
+    // whichever front end is running
+    let api = ServerApi {
+        base_url: format!("http://{}:{}", address, port),
+        token: token.clone(),
+        room_password: room_password.clone(),
+        room: Arc::new(Mutex::new(room)),
+    };
+    let session = Session {
+        url: ws_url,
         name: client_name.clone(),
+        token,
+        room_password,
+        debug_protocol,
+        signer: hmac_key.as_deref().map(MessageSigner::new),
+        identity: identity_path.as_deref().and_then(load_identity),
     };
-    let json = serde_json::to_string(&connect_msg).expect("Failed to serialize connect message");
-    ws_sender
-        .send(WsMessage::Text(json.into()))
-        .await
-        .expect("Failed to send connect message");
+    tokio::spawn(connection::run(
+        session,
+        ws_stream,
+        Backoff::default(),
+        rx,
+        events_tx,
+    ));
 
-    let _tx_clone = tx.clone();
+    let mut events_rx = match log_file.as_deref().map(Transcript::open) {
+        Some(Ok(transcript)) => log_events(events_rx, transcript),
+        Some(Err(e)) => {
+            eprintln!("Failed to open log file, not logging: {}", e);
+            events_rx
+        }
+        None => events_rx,
+    };
 
-    tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            let chat_msg = ClientMessage::Chat { text: msg };
-            let json = serde_json::to_string(&chat_msg).expect("Failed to serialize chat message");
-            ws_sender
-                .send(WsMessage::Text(json.into()))
-                .await
-                .expect("Failed to send message");
+    if tui {
+        let result = tokio::task::spawn_blocking(move || {
+            client_tui::run(
+                tx,
+                events_rx,
+                client_name,
+                api,
+                mention_alert,
+                notifications,
+            )
+        })
+        .await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => eprintln!("TUI error: {}", e),
+            Err(e) => eprintln!("TUI task failed: {}", e),
         }
-    });
+        return;
+    }
+
+    let mut output = match MessageOutput::new(tee.as_deref(), color) {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("Failed to open tee output: {}", e);
+            return;
+        }
+    };
+
+    let roster: Roster = Arc::new(Mutex::new(None));
 
-    let _tx_clone = tx.clone();
+    // Route incoming messages through rustyline so they appear above the
+    // prompt and whatever the user has typed so far is redrawn below them.
+    // Repeated Tab presses cycle through completions.
+    let editor_config = rustyline::Config::builder()
+        .completion_type(CompletionType::Circular)
+        .build();
+    let mut rl = ChatEditor::with_config(editor_config).unwrap();
+    rl.set_helper(Some(ChatHelper::new(roster.clone())));
+    if let Some(path) = &history_path {
+        load_history(&mut rl, path);
+    }
+    match rl.create_external_printer() {
+        Ok(printer) => output.printer = Some(Box::new(printer)),
+        Err(e) => eprintln!("Incoming messages may overwrite the prompt: {}", e),
+    }
+
+    let current_name = Arc::new(Mutex::new(client_name));
+    let name_clone = current_name.clone();
+    let roster_clone = roster.clone();
+    let api_clone = api.clone();
     tokio::spawn(async move {
-        while let Some(msg) = ws_receiver.next().await {
-            match msg {
-                Ok(WsMessage::Text(text)) => {
-                    // Try to parse as ServerMessage
-                    if let Ok(server_msg) = serde_json::from_str::<ServerMessage>(&text) {
-                        match server_msg {
-                            ServerMessage::Chat { text } => {
-                                let mut t = term::stdout().unwrap();
-                                t.fg(term::color::GREEN).unwrap();
-                                writeln!(t, "{}", text).unwrap();
-                                t.reset().unwrap();
-                            }
-                            ServerMessage::UserList(user_list) => {
-                                let mut t = term::stdout().unwrap();
-                                t.fg(term::color::BLUE).unwrap();
-                                writeln!(t, "=== Users online: {} ===", user_list.count).unwrap();
-                                for user in user_list.users {
-                                    writeln!(t, "  {}", user.name).unwrap();
-                                }
-                                writeln!(t, "========================").unwrap();
-                                t.reset().unwrap();
-                            }
-                            ServerMessage::UserJoined { name } => {
-                                let mut t = term::stdout().unwrap();
-                                t.fg(term::color::YELLOW).unwrap();
-                                writeln!(t, "*** {} joined the chat ***", name).unwrap();
-                                t.reset().unwrap();
-                            }
-                            ServerMessage::UserLeft { name } => {
-                                let mut t = term::stdout().unwrap();
-                                t.fg(term::color::YELLOW).unwrap();
-                                writeln!(t, "*** {} left the chat ***", name).unwrap();
-                                t.reset().unwrap();
+        let mut backlog = BacklogDetector::new(BACKLOG_HIGH_WATER, BACKLOG_LOW_WATER);
+        while let Some(event) = events_rx.recv().await {
+            match backlog.observe(events_rx.len()) {
+                Some(BacklogChange::FellBehind) => output.print(
+                    theme.system,
+                    "Falling behind, coalescing incoming messages...",
+                ),
+                Some(BacklogChange::CaughtUp { skipped }) => output.print(
+                    theme.system,
+                    &format!("Caught up; skipped {} messages", skipped),
+                ),
+                None => {}
+            }
+            match event {
+                // Skip rendering chat while behind; the tee still gets everything
+                Incoming::Server(ServerMessage::Chat { text, sender, .. })
+                    if backlog.is_behind() =>
+                {
+                    backlog.record_skip();
+                    output.tee_only(&chat_line(sender.as_deref(), &text));
+                }
+                Incoming::Text(text) if backlog.is_behind() => {
+                    backlog.record_skip();
+                    output.tee_only(&text);
+                }
+                Incoming::Server(server_msg) => {
+                    match &server_msg {
+                        ServerMessage::UserList(user_list) => {
+                            *roster_clone.lock().unwrap() =
+                                Some((user_list.clone(), Instant::now()));
+                        }
+                        ServerMessage::UserRenamed { old, new } => {
+                            let mut current = name_clone.lock().unwrap();
+                            if *current == *old {
+                                *current = new.clone();
                             }
                         }
-                    } else {
-                        // Fallback for old message format
-                        let mut t = term::stdout().unwrap();
-                        t.fg(term::color::GREEN).unwrap();
-                        writeln!(t, "{}", text).unwrap();
-                        t.reset().unwrap();
+                        // The server may have picked another name if ours was taken
+                        ServerMessage::Welcome { name, .. } if !name.is_empty() => {
+                            *name_clone.lock().unwrap() = name.clone();
+                        }
+                        _ => {}
+                    }
+                    api_clone.observe(&server_msg);
+                    let own_name = name_clone.lock().unwrap().clone();
+                    let (color, line) = render_server_message(&server_msg, &theme, &own_name);
+                    output.print(color, &line);
+                    if let ServerMessage::Chat {
+                        text,
+                        sender,
+                        mentions,
+                        ..
+                    } = &server_msg
+                    {
+                        // Mentions are judged on the whole line, so our own
+                        // messages can be told apart
+                        let text = &chat_line(sender.as_deref(), text);
+                        if mention_alert.should_alert(text, mentions, &own_name) {
+                            // The bell doesn't move the cursor, so it can bypass the printer
+                            eprint!("{}", BELL);
+                        }
+                        // The prompt can't tell whether its terminal has focus,
+                        // so every mention notifies
+                        if notifications && mentions_us(text, mentions, &own_name) {
+                            notify_mention(text);
+                        }
                     }
                 }
-                Ok(WsMessage::Close(_)) => {
-                    println!("Server closed connection");
-                    break;
-                }
-                Err(e) => {
-                    eprintln!("WebSocket error: {}", e);
+                Incoming::Text(text) => output.print(theme.message, &text),
+                Incoming::Status(status) => output.print(theme.system, &status),
+                Incoming::Latency(rtt) => output.print(term::color::CYAN, &latency_line(rtt)),
+                Incoming::Closed(reason) => {
+                    output.print(theme.error, &reason);
                     break;
                 }
-                _ => {}
             }
         }
     });
 
-    run_chat_tui(tx, &client_name).await;
+    run_chat_tui(rl, tx, current_name, roster, api, history_path).await;
+}
+
+/// Talks to the server over HTTP, for `/upload` and `/rooms`.
+#[derive(Debug, Clone)]
+pub(crate) struct ServerApi {
+    /// Scheme, host and port of the server
+    base_url: String,
+    /// Auth token to present if the server requires one
+    token: Option<String>,
+    /// Password of the room we started in, sent with every upload
+    room_password: Option<String>,
+    /// The room we're in, following `/join` and `/leave`
+    room: Arc<Mutex<String>>,
+}
+
+impl ServerApi {
+    /// Follows the room we're in; the server greets us again on every switch.
+    pub(crate) fn observe(&self, server_msg: &ServerMessage) {
+        if let ServerMessage::Welcome { room, .. } = server_msg {
+            *self.room.lock().unwrap() = room.clone();
+        }
+    }
+
+    /// Uploads the file at `path` to the current room, returning the URL it
+    /// can be downloaded from, or a description of what went wrong.
+    pub(crate) async fn upload(&self, path: &Path) -> Result<String, String> {
+        let data = tokio::fs::read(path)
+            .await
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let url = format!(
+            "{}/room/{}/upload",
+            self.base_url,
+            self.room.lock().unwrap()
+        );
+        let file = reqwest::multipart::Part::bytes(data)
+            .file_name(name)
+            .mime_str(content_type_for(path))
+            .map_err(|e| format!("Upload failed: {}", e))?;
+        let mut request = reqwest::Client::new()
+            .post(url)
+            .multipart(reqwest::multipart::Form::new().part("file", file));
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(password) = &self.room_password {
+            request = request.header("x-room-password", password);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| format!("Upload failed: {}", e))?;
+        match response.status() {
+            status if status.is_success() => {
+                let body: serde_json::Value = response
+                    .json()
+                    .await
+                    .map_err(|e| format!("Upload failed: {}", e))?;
+                // Links relative to the server are made absolute
+                body["url"]
+                    .as_str()
+                    .map(|url| {
+                        if url.starts_with('/') {
+                            format!("{}{}", self.base_url, url)
+                        } else {
+                            url.to_string()
+                        }
+                    })
+                    .ok_or_else(|| "Upload failed: no URL in the response".to_string())
+            }
+            reqwest::StatusCode::NOT_FOUND => {
+                Err("Upload failed: the server doesn't accept uploads here".to_string())
+            }
+            reqwest::StatusCode::PAYLOAD_TOO_LARGE => {
+                Err("Upload failed: the file is too large for the server".to_string())
+            }
+            reqwest::StatusCode::UNSUPPORTED_MEDIA_TYPE => Err(format!(
+                "Upload failed: the server doesn't accept {} files",
+                content_type_for(path)
+            )),
+            status => Err(format!("Upload failed: {}", status)),
+        }
+    }
+
+    /// Fetches the rooms open on the server, formatted for display with the
+    /// room we're in marked.
+    pub(crate) async fn rooms(&self) -> Result<String, String> {
+        let response = reqwest::get(format!("{}/rooms", self.base_url))
+            .await
+            .map_err(|e| format!("Failed to list rooms: {}", e))?;
+        if !response.status().is_success() {
+            return Err(format!("Failed to list rooms: {}", response.status()));
+        }
+        let rooms: Vec<RoomInfo> = response
+            .json()
+            .await
+            .map_err(|e| format!("Failed to list rooms: {}", e))?;
+        Ok(format_rooms(&rooms, &self.room.lock().unwrap()))
+    }
+
+    /// Asks the server when `name` was last active, formatted for display.
+    pub(crate) async fn last_seen(&self, name: &str) -> Result<String, String> {
+        let fail = |e: String| format!("Failed to look up {}: {}", name, e);
+        // Built segment by segment so names are escaped
+        let mut url = reqwest::Url::parse(&self.base_url).map_err(|e| fail(e.to_string()))?;
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.extend(["users", name, "lastseen"]);
+        }
+        let response = reqwest::get(url).await.map_err(|e| fail(e.to_string()))?;
+        let seen = match response.status() {
+            status if status.is_success() => {
+                response.json().await.map_err(|e| fail(e.to_string()))?
+            }
+            reqwest::StatusCode::NOT_FOUND => LastSeen {
+                name: name.to_string(),
+                online: false,
+                last_seen: None,
+            },
+            status => return Err(fail(status.to_string())),
+        };
+        Ok(seen_line(&seen, unix_time()))
+    }
+}
+
+/// Guesses a file's content type from its extension, for the types servers
+/// accept by default.
+fn content_type_for(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "pdf" => "application/pdf",
+        "txt" | "md" | "log" => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Loads saved prompt history from `path`; a missing file (e.g. on first
+/// run) just means there's no history yet.
+fn load_history(rl: &mut ChatEditor, path: &Path) {
+    match rl.load_history(path) {
+        Ok(()) => {}
+        Err(ReadlineError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => eprintln!("Failed to load history from {}: {}", path.display(), e),
+    }
+}
+
+/// Saves the prompt history to `path`.
+fn save_history(rl: &mut ChatEditor, path: &Path) {
+    if let Err(e) = rl.save_history(path) {
+        eprintln!("Failed to save history to {}: {}", path.display(), e);
+    }
+}
+
+/// Something received from the server, as handed to the active front end.
+pub enum Incoming {
+    /// A protocol message
+    Server(ServerMessage),
+    /// A frame that isn't a protocol message (old plain-text format)
+    Text(String),
+    /// The connection dropped or came back; it is being retried meanwhile
+    Status(String),
+    /// A `/ping` came back after this long, or `None` if it timed out
+    Latency(Option<Duration>),
+    /// The connection ended, with a description of why
+    Closed(String),
+}
+
+/// Formats a server message for display, returning the color to show it in
+/// and the plain text.
+///
+/// Chat from others is colored by sender (see [`sender_color`]); our own,
+/// from `own_name`, keeps the theme's message color.
+fn render_server_message(
+    server_msg: &ServerMessage,
+    theme: &Theme,
+    own_name: &str,
+) -> (term::color::Color, String) {
+    match server_msg {
+        ServerMessage::Welcome {
+            server_name,
+            version,
+            motd,
+            name,
+            role,
+            ..
+        } => (
+            term::color::CYAN,
+            format!(
+                "*** {} (v{}) ***\n{}\nYou are signed in as {} ({})",
+                server_name, version, motd, name, role
+            ),
+        ),
+        ServerMessage::Chat {
+            text,
+            sender,
+            reply_to,
+            quote,
+            ..
+        } => {
+            let line = chat_line(sender.as_deref(), text);
+            let color = match sender {
+                Some(sender) if sender != own_name => sender_color(sender),
+                _ => theme.message,
+            };
+            match reply_to {
+                Some(reply_to) => (
+                    color,
+                    format!("{}\n{}", reply_line(*reply_to, quote.as_deref()), line),
+                ),
+                None => (color, line),
+            }
+        }
+        ServerMessage::Action { name, text } => {
+            (term::color::BRIGHT_MAGENTA, action_line(name, text))
+        }
+        ServerMessage::Attachment {
+            name,
+            url,
+            content_type,
+            size,
+        } => (
+            term::color::CYAN,
+            attachment_line(name, url, content_type, *size),
+        ),
+        ServerMessage::History { messages } => (
+            term::color::BRIGHT_BLACK,
+            history_lines(messages).join("\n"),
+        ),
+        ServerMessage::MessageEdited { id, text, sender } => (
+            theme.message,
+            format!("{} (edited #{})", chat_line(sender.as_deref(), text), id),
+        ),
+        ServerMessage::MessageDeleted { id } => {
+            (term::color::BRIGHT_BLACK, format!("[deleted] (#{})", id))
+        }
+        ServerMessage::MessagePinned { id, by } => (
+            theme.system,
+            format!("*** {} pinned message #{} ***", by, id),
+        ),
+        ServerMessage::MessageUnpinned { id } => (
+            theme.system,
+            format!("*** Message #{} was unpinned ***", id),
+        ),
+        ServerMessage::Reaction {
+            message_id,
+            emoji,
+            name,
+            added,
+        } => {
+            let action = if *added { "reacted" } else { "withdrew" };
+            (
+                term::color::BRIGHT_BLACK,
+                format!("  ↳ {} {} {} on #{}", name, action, emoji, message_id),
+            )
+        }
+        ServerMessage::RoomEvent { room, event } => {
+            let (color, text) = render_server_message(event, theme, own_name);
+            (color, format!("[{}] {}", room, text))
+        }
+        ServerMessage::Ack { id, .. } => {
+            (term::color::BRIGHT_BLACK, format!("  ✓ delivered #{}", id))
+        }
+        ServerMessage::HistoryGap { from, to } => (
+            theme.system,
+            format!(
+                "*** Some messages were not recovered (#{} to #{}) ***",
+                from, to
+            ),
+        ),
+        // The connection reports pongs as `Incoming::Latency` instead
+        ServerMessage::Pong { nonce } => (term::color::BRIGHT_BLACK, format!("Pong {}", nonce)),
+        ServerMessage::Stats {
+            uptime_secs,
+            total_messages,
+            connected_users,
+            rooms,
+        } => (
+            term::color::CYAN,
+            stats_line(*uptime_secs, *total_messages, *connected_users, *rooms),
+        ),
+        ServerMessage::HistoryTrimmed { dropped } => (
+            term::color::BRIGHT_BLACK,
+            format!(
+                "*** {} older messages were dropped from the room's history ***",
+                dropped
+            ),
+        ),
+        ServerMessage::UserList(user_list) => {
+            (term::color::BLUE, format_roster(user_list, Duration::ZERO))
+        }
+        ServerMessage::UserJoined { name } => {
+            (theme.system, format!("*** {} joined the chat ***", name))
+        }
+        ServerMessage::UserLeft { name } => {
+            (theme.system, format!("*** {} left the chat ***", name))
+        }
+        ServerMessage::UserRenamed { old, new } => (
+            theme.system,
+            format!("*** {} is now known as {} ***", old, new),
+        ),
+        ServerMessage::StatusChanged { name, status } => (
+            theme.system,
+            format!("*** {} ***", status_line(name, *status)),
+        ),
+        ServerMessage::DirectMessage { from, to, text } => {
+            (theme.dm, format!("[DM] {} -> {}: {}", from, to, text))
+        }
+        ServerMessage::RoomClosed { room, reason } => (
+            theme.system,
+            format!("*** Room {} was closed: {} ***", room, reason),
+        ),
+        ServerMessage::ServerShutdown => {
+            (theme.system, "*** Server is shutting down ***".to_string())
+        }
+        ServerMessage::Announcement { text } => (
+            term::color::BRIGHT_YELLOW,
+            format!("!!! ANNOUNCEMENT: {} !!!", text),
+        ),
+        ServerMessage::IdleWarning { disconnect_in_secs } => (
+            theme.system,
+            format!(
+                "*** Idle for too long; disconnecting in {}s unless you send something ***",
+                disconnect_in_secs
+            ),
+        ),
+        ServerMessage::MessagesPurged { name, count } => (
+            theme.system,
+            format!(
+                "*** {} messages from {} were removed by a moderator ***",
+                count, name
+            ),
+        ),
+        ServerMessage::OwnMessagesPurged { count } => (
+            theme.system,
+            format!("*** Deleted {} of your messages ***", count),
+        ),
+        ServerMessage::TopicChanged { topic, by } => (
+            theme.system,
+            format!("*** {} ***", topic_line(topic.as_deref(), by.as_deref())),
+        ),
+        ServerMessage::System { text } => (term::color::BRIGHT_CYAN, format!("» {}", text)),
+        ServerMessage::Error { code, message } => {
+            (theme.error, format!("Error {}: {}", code, message))
+        }
+    }
+}
+
+/// Writes every event from `events` to `transcript` on its way to the front
+/// end, returning the receiver the front end reads from instead.
+fn log_events(
+    mut events: mpsc::UnboundedReceiver<Incoming>,
+    mut transcript: Transcript,
+) -> mpsc::UnboundedReceiver<Incoming> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            transcript.record(&event);
+            if tx.send(event).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Where incoming messages are displayed.
+///
+/// Messages are printed in color on the terminal and, when a tee path is
+/// given, mirrored as plain text to that file or FIFO as they arrive. When a
+/// readline printer is attached, terminal output goes through it so the
+/// prompt and pending input are redrawn below each message. Colors are left
+/// out when they're turned off or stdout isn't a terminal, so piped output
+/// has no escape codes.
+struct MessageOutput {
+    tee: Option<File>,
+    printer: Option<Box<dyn ExternalPrinter + Send>>,
+    colors: bool,
 }
 
-fn generate_random_name() -> String {
-    let adjectives = [
-        "Happy", "Quick", "Silent", "Brave", "Clever", "Swift", "Bright", "Calm",
-    ];
-    let nouns = [
-        "Panda", "Eagle", "Tiger", "Wolf", "Fox", "Bear", "Lion", "Hawk",
-    ];
+impl MessageOutput {
+    /// Creates the output, opening `tee` for appending if given. Output is
+    /// colored if `color` allows it and stdout is a terminal.
+    fn new(tee: Option<&Path>, color: bool) -> std::io::Result<Self> {
+        let tee = match tee {
+            Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+            None => None,
+        };
+        Ok(Self {
+            tee,
+            printer: None,
+            colors: color && std::io::stdout().is_terminal(),
+        })
+    }
 
-    let adj = adjectives[rand::random::<usize>() % adjectives.len()];
-    let noun = nouns[rand::random::<usize>() % nouns.len()];
-    let number = rand::random::<u32>() % 1000;
+    /// Prints `text` in `color` and mirrors it uncolored to the tee, if any.
+    fn print(&mut self, color: term::color::Color, text: &str) {
+        self.print_terminal(color, text);
+        self.tee_only(text);
+    }
 
-    format!("{}{}{}", adj, noun, number)
+    fn print_terminal(&mut self, color: term::color::Color, text: &str) {
+        match &mut self.printer {
+            Some(printer) => {
+                let line = if self.colors {
+                    printer_line(color, text)
+                } else {
+                    format!("{}\n", text)
+                };
+                if let Err(e) = printer.print(line) {
+                    eprintln!("Failed to print message: {}", e);
+                }
+            }
+            None => match term::stdout().filter(|_| self.colors) {
+                Some(mut t) => {
+                    let _ = t.fg(color);
+                    let _ = writeln!(t, "{}", text);
+                    let _ = t.reset();
+                }
+                None => println!("{}", text),
+            },
+        }
+    }
+
+    /// Mirrors `text` to the tee, if any, without printing it.
+    fn tee_only(&mut self, text: &str) {
+        if let Some(tee) = &mut self.tee
+            && let Err(e) = writeln!(tee, "{}", text).and_then(|_| tee.flush())
+        {
+            eprintln!("Failed to write tee output: {}", e);
+            self.tee = None;
+        }
+    }
+}
+
+/// A change in whether the prompt is keeping up with incoming messages.
+#[derive(Debug, PartialEq, Eq)]
+enum BacklogChange {
+    /// The backlog reached the high-water mark
+    FellBehind,
+    /// The backlog drained below the low-water mark
+    CaughtUp {
+        /// Chat lines left unrendered while behind
+        skipped: usize,
+    },
+}
+
+/// Detects when incoming messages arrive faster than they can be rendered.
+///
+/// Two thresholds keep the state from flapping: it falls behind once the
+/// backlog reaches `high` and catches up once it drops below `low`.
+#[derive(Debug)]
+struct BacklogDetector {
+    high: usize,
+    low: usize,
+    behind: bool,
+    skipped: usize,
+}
+
+impl BacklogDetector {
+    fn new(high: usize, low: usize) -> Self {
+        Self {
+            high,
+            low,
+            behind: false,
+            skipped: 0,
+        }
+    }
+
+    /// Updates the state for the current backlog `depth`, returning the
+    /// change to announce, if any.
+    fn observe(&mut self, depth: usize) -> Option<BacklogChange> {
+        if !self.behind && depth >= self.high {
+            self.behind = true;
+            self.skipped = 0;
+            Some(BacklogChange::FellBehind)
+        } else if self.behind && depth < self.low {
+            self.behind = false;
+            Some(BacklogChange::CaughtUp {
+                skipped: std::mem::take(&mut self.skipped),
+            })
+        } else {
+            None
+        }
+    }
+
+    fn is_behind(&self) -> bool {
+        self.behind
+    }
+
+    fn record_skip(&mut self) {
+        self.skipped += 1;
+    }
 }
 
-async fn run_chat_tui(tx: mpsc::UnboundedSender<String>, client_name: &str) {
-    let mut rl = Editor::<(), rustyline::history::DefaultHistory>::new().unwrap();
+/// Builds the text handed to the readline printer for one incoming message.
+///
+/// The printer clears the prompt line, writes this, then redraws the prompt
+/// and pending input, so the text must carry its own color codes and end
+/// with a newline.
+fn printer_line(color: term::color::Color, text: &str) -> String {
+    // term colors 0-7 are the normal ANSI colors, 8-15 their bright variants
+    let code = if color < 8 {
+        30 + color
+    } else {
+        90 + (color - 8)
+    };
+    format!("\x1b[{}m{}\x1b[0m\n", code, text)
+}
 
+/// Returns the file to send if `line` is an `/upload <path>` command, or a
+/// usage hint as the error when the path is missing.
+pub(crate) fn parse_upload(line: &str) -> Option<Result<PathBuf, String>> {
+    let path = line.trim_end().strip_prefix("/upload")?;
+    if !path.is_empty() && !path.starts_with(' ') {
+        return None;
+    }
+    match path.trim() {
+        "" => Some(Err("Usage: /upload <path>".to_string())),
+        path => Some(Ok(PathBuf::from(path))),
+    }
+}
+
+/// Returns the user to look up if `line` is a `/seen <user>` command, or a
+/// usage hint as the error when the name is missing.
+pub(crate) fn parse_seen(line: &str) -> Option<Result<String, String>> {
+    let name = line.trim_end().strip_prefix("/seen")?;
+    if !name.is_empty() && !name.starts_with(' ') {
+        return None;
+    }
+    match name.trim() {
+        "" => Some(Err("Usage: /seen <user>".to_string())),
+        name => Some(Ok(name.to_string())),
+    }
+}
+
+/// Converts a line typed by the user into the message to send to the server.
+///
+/// Supported commands:
+///
+/// * `/nick <name>` - change your display name
+/// * `/msg <user> <text>` - send a private message
+/// * `/me <text>` - describe an action, shown as `* you text`
+/// * `/history [count] [before-id]` - show earlier messages of the room
+/// * `/ping` - measure the round trip to the server
+/// * `/stats` - show the server's uptime and load
+/// * `/reply <id> <text>` - reply to a message, quoting it
+/// * `/away`, `/busy`, `/back` - set your status
+/// * `/edit <id> <text>` - replace the text of one of your messages
+/// * `/delete <id>` - delete one of your messages
+/// * `/purge` - delete all of your messages in the current room, once
+///   confirmed (see [`PURGE_PROMPT`])
+/// * `/pin <id>` - pin a message in the current room (moderators only)
+/// * `/react <id> <emoji>` - toggle a reaction on a message
+/// * `/watch <room>` - also show messages from another room
+/// * `/unwatch <room>` - stop showing messages from a watched room
+/// * `/join <room> [password]` - switch to another room; `/switch` does the same
+/// * `/leave <room>` - leave the current room for the default room
+/// * `/kick <user>` - disconnect a user (moderators only)
+/// * `/topic [text]` - set the current room's topic, or clear it
+///
+/// `/users` is handled locally from the last received user list,
+/// `/clear` clears the local view only, `/upload <path>` (see
+/// [`parse_upload`]) sends the file over HTTP, and `/rooms` and
+/// `/seen <user>` (see [`parse_seen`]) ask the server over HTTP.
+///
+/// Everything else is sent as a regular chat message. Returns a usage hint
+/// as the error when a command is malformed.
+///
+/// # Examples
+///
+/// ```rust
+/// let msg = parse_input("/nick Bob");
+/// // msg == Ok(ClientMessage::Rename { new_name: "Bob".to_string() })
+/// ```
+pub(crate) fn parse_input(line: &str) -> Result<ClientMessage, String> {
+    if let Some(new_name) = line.strip_prefix("/nick ") {
+        return Ok(ClientMessage::Rename {
+            new_name: new_name.trim().to_string(),
+        });
+    }
+
+    if let Some(rest) = line.strip_prefix("/msg ") {
+        return match rest.trim_start().split_once(' ') {
+            Some((to, text)) if !text.trim().is_empty() => Ok(ClientMessage::DirectMessage {
+                to: to.to_string(),
+                text: text.trim().to_string(),
+            }),
+            _ => Err("Usage: /msg <user> <text>".to_string()),
+        };
+    }
+
+    let status = match line.trim_end() {
+        "/away" => Some(UserStatus::Away),
+        "/busy" => Some(UserStatus::Busy),
+        "/back" => Some(UserStatus::Online),
+        _ => None,
+    };
+    if let Some(status) = status {
+        return Ok(ClientMessage::SetStatus { status });
+    }
+
+    if let Some(args) = line.trim_end().strip_prefix("/history")
+        && (args.is_empty() || args.starts_with(' '))
+    {
+        let usage = || "Usage: /history [count] [before-id]".to_string();
+        let mut args = args.split_whitespace();
+        let limit = match args.next() {
+            Some(count) => count.parse().map_err(|_| usage())?,
+            None => DEFAULT_HISTORY_PAGE,
+        };
+        let before = match args.next() {
+            Some(id) => Some(id.trim_start_matches('#').parse().map_err(|_| usage())?),
+            None => None,
+        };
+        if args.next().is_some() {
+            return Err(usage());
+        }
+        return Ok(ClientMessage::FetchHistory { before, limit });
+    }
+
+    if line.trim_end() == "/stats" {
+        return Ok(ClientMessage::Stats);
+    }
+
+    if line.trim_end() == "/purge" {
+        return Ok(ClientMessage::PurgeMine);
+    }
+
+    if line.trim_end() == "/ping" {
+        return Ok(ClientMessage::Ping {
+            nonce: rand::random(),
+        });
+    }
+
+    if let Some(text) = line.strip_prefix("/me ") {
+        let text = text.trim();
+        if text.is_empty() {
+            return Err("Usage: /me <text>".to_string());
+        }
+        return Ok(ClientMessage::Action {
+            text: text.to_string(),
+        });
+    }
+
+    if let Some(rest) = line.strip_prefix("/reply ") {
+        return match rest.trim_start().split_once(' ') {
+            Some((id, text)) if !text.trim().is_empty() => match id.parse() {
+                Ok(to) => Ok(ClientMessage::Reply {
+                    to,
+                    text: text.trim().to_string(),
+                }),
+                Err(_) => Err("Usage: /reply <id> <text>".to_string()),
+            },
+            _ => Err("Usage: /reply <id> <text>".to_string()),
+        };
+    }
+
+    if let Some(rest) = line.strip_prefix("/edit ") {
+        return match rest.trim_start().split_once(' ') {
+            Some((id, text)) if !text.trim().is_empty() => match id.parse() {
+                Ok(id) => Ok(ClientMessage::Edit {
+                    id,
+                    text: text.trim().to_string(),
+                }),
+                Err(_) => Err("Usage: /edit <id> <text>".to_string()),
+            },
+            _ => Err("Usage: /edit <id> <text>".to_string()),
+        };
+    }
+
+    if let Some(id) = line.strip_prefix("/delete ") {
+        return match id.trim().parse() {
+            Ok(id) => Ok(ClientMessage::Delete { id }),
+            Err(_) => Err("Usage: /delete <id>".to_string()),
+        };
+    }
+
+    if let Some(id) = line.strip_prefix("/pin ") {
+        return match id.trim().parse() {
+            Ok(id) => Ok(ClientMessage::Pin { id }),
+            Err(_) => Err("Usage: /pin <id>".to_string()),
+        };
+    }
+
+    if let Some(rest) = line.strip_prefix("/react ") {
+        let usage = || "Usage: /react <id> <emoji>".to_string();
+        let (id, emoji) = rest.trim().split_once(' ').ok_or_else(usage)?;
+        let message_id = id.parse().map_err(|_| usage())?;
+        return Ok(ClientMessage::React {
+            message_id,
+            emoji: emoji.trim().to_string(),
+        });
+    }
+
+    if let Some(room) = line.strip_prefix("/watch ") {
+        let room = room.trim();
+        if room.is_empty() {
+            return Err("Usage: /watch <room>".to_string());
+        }
+        return Ok(ClientMessage::Subscribe {
+            room: room.to_string(),
+        });
+    }
+
+    if let Some(room) = line.strip_prefix("/unwatch ") {
+        let room = room.trim();
+        if room.is_empty() {
+            return Err("Usage: /unwatch <room>".to_string());
+        }
+        return Ok(ClientMessage::Unsubscribe {
+            room: room.to_string(),
+        });
+    }
+
+    // Sending always goes to the room we're in, so switching the target is
+    // the same as moving there
+    if let Some((command, args)) = line.split_once(' ')
+        && matches!(command, "/join" | "/switch")
+    {
+        let mut args = args.split_whitespace();
+        let (Some(room), password, None) = (args.next(), args.next(), args.next()) else {
+            return Err(format!("Usage: {} <room> [password]", command));
+        };
+        return Ok(ClientMessage::JoinRoom {
+            room: room.to_string(),
+            password: password.map(str::to_string),
+        });
+    }
+
+    if let Some(room) = line.strip_prefix("/leave ") {
+        let room = room.trim();
+        if room.is_empty() {
+            return Err("Usage: /leave <room>".to_string());
+        }
+        return Ok(ClientMessage::LeaveRoom {
+            room: room.to_string(),
+        });
+    }
+
+    if let Some(target) = line.strip_prefix("/kick ") {
+        let target = target.trim();
+        if target.is_empty() {
+            return Err("Usage: /kick <user>".to_string());
+        }
+        return Ok(ClientMessage::Kick {
+            target: target.to_string(),
+        });
+    }
+
+    if let Some(args) = line.strip_prefix("/topic")
+        && (args.is_empty() || args.starts_with(' '))
+    {
+        return Ok(ClientMessage::SetTopic {
+            text: args.trim().to_string(),
+        });
+    }
+
+    Ok(ClientMessage::Chat {
+        text: line.to_string(),
+        client_msg_id: Some(uuid::Uuid::new_v4().to_string()),
+    })
+}
+
+/// Describes the server's answer to `/stats`, e.g.
+/// `Up 1h 2m 3s: 42 messages, 3 users online, 2 rooms`.
+pub(crate) fn stats_line(
+    uptime_secs: u64,
+    total_messages: u64,
+    connected_users: usize,
+    rooms: usize,
+) -> String {
+    let (hours, minutes, secs) = (uptime_secs / 3600, uptime_secs / 60 % 60, uptime_secs % 60);
+    format!(
+        "Up {}h {}m {}s: {} messages, {} users online, {} rooms",
+        hours, minutes, secs, total_messages, connected_users, rooms
+    )
+}
+
+/// Whether `answer` to a yes/no question such as [`PURGE_PROMPT`] means
+/// yes. Anything but `y` or `yes` is taken as no.
+pub(crate) fn is_confirmed(answer: &str) -> bool {
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Describes a room's topic, e.g. `Alice set the topic: Release planning`.
+pub(crate) fn topic_line(topic: Option<&str>, by: Option<&str>) -> String {
+    match (topic, by) {
+        (Some(topic), Some(by)) => format!("{} set the topic: {}", by, topic),
+        (Some(topic), None) => format!("Topic: {}", topic),
+        (None, Some(by)) => format!("{} cleared the topic", by),
+        (None, None) => "No topic is set".to_string(),
+    }
+}
+
+/// Describes when a user was last active, e.g. `Alice was last seen 5m ago`.
+pub(crate) fn seen_line(seen: &LastSeen, now: u64) -> String {
+    if seen.online {
+        return format!("{} is currently online", seen.name);
+    }
+    let Some(last_seen) = seen.last_seen else {
+        return format!("{} hasn't been seen", seen.name);
+    };
+    let ago = now.saturating_sub(last_seen);
+    let ago = match ago {
+        0..60 => format!("{}s", ago),
+        60..3600 => format!("{}m", ago / 60),
+        3600..86400 => format!("{}h", ago / 3600),
+        _ => format!("{}d", ago / 86400),
+    };
+    format!("{} was last seen {} ago", seen.name, ago)
+}
+
+/// Describes the outcome of a `/ping`.
+pub(crate) fn latency_line(rtt: Option<Duration>) -> String {
+    match rtt {
+        Some(rtt) => format!("Round trip to server: {:.1} ms", rtt.as_secs_f64() * 1000.0),
+        None => format!(
+            "No reply to /ping within {}s",
+            connection::PING_TIMEOUT.as_secs()
+        ),
+    }
+}
+
+/// Formats a page of history, as sent on joining or fetched with
+/// `/history`, one message per line with its ID, ending with how to fetch
+/// the page before it.
+fn history_lines(messages: &[Message]) -> Vec<String> {
+    if messages.is_empty() {
+        return vec![history_footer(messages)];
+    }
+    let mut lines = vec![format!("=== History: {} messages ===", messages.len())];
+    for message in messages {
+        match message.id {
+            Some(id) => lines.push(format!("#{} {}", id, message.display_text())),
+            None => lines.push(message.display_text()),
+        }
+    }
+    lines.push(history_footer(messages));
+    lines
+}
+
+/// Describes how to fetch the history before `messages`, or that there's
+/// none when it's empty.
+pub(crate) fn history_footer(messages: &[Message]) -> String {
+    match messages.first().and_then(|msg| msg.id) {
+        Some(oldest) => format!(
+            "=== /history {} {} for earlier messages ===",
+            messages.len(),
+            oldest
+        ),
+        None => "=== No earlier messages ===".to_string(),
+    }
+}
+
+/// Formats the roster for the `/users` command.
+///
+/// `age` is how long ago the list was received and is added to each user's
+/// connection time so the durations are current.
+fn format_roster(user_list: &UserList, age: Duration) -> String {
+    let mut out = format!("=== Users online: {} ===\n", user_list.count);
+    for user in &user_list.users {
+        out.push_str(&format!(
+            "  {} ({}s)",
+            user.name,
+            user.connected_secs + age.as_secs()
+        ));
+        if user.status != UserStatus::Online {
+            out.push_str(&format!(" [{}]", user.status));
+        }
+        out.push('\n');
+    }
+    out.push_str("========================");
+    out
+}
+
+/// Formats the room list for `/rooms`, marking `current` with a `*`.
+fn format_rooms(rooms: &[RoomInfo], current: &str) -> String {
+    let mut out = format!("=== Rooms: {} ===\n", rooms.len());
+    for room in rooms {
+        let marker = if room.id == current { '*' } else { ' ' };
+        out.push_str(&format!(
+            "{} {} ({} users, {} messages)",
+            marker, room.id, room.users, room.messages
+        ));
+        if room.password_protected {
+            out.push_str(" [password]");
+        }
+        if let Some(topic) = &room.topic {
+            out.push_str(&format!(" - {}", topic));
+        }
+        out.push('\n');
+    }
+    out.push_str("==================");
+    out
+}
+
+async fn run_chat_tui(
+    mut rl: ChatEditor,
+    tx: mpsc::UnboundedSender<ClientMessage>,
+    client_name: Arc<Mutex<String>>,
+    roster: Roster,
+    api: ServerApi,
+    history_path: Option<PathBuf>,
+) {
     println!(
         "Chat started as {}. Type your messages and press Enter.",
-        client_name
+        client_name.lock().unwrap()
     );
     println!("Press Ctrl+C to exit.");
 
     loop {
-        let readline = rl.readline(&format!("{}: ", client_name));
+        let prompt = format!("{}: ", client_name.lock().unwrap());
+        let readline = rl.readline(&prompt);
         match readline {
             Ok(line) => {
                 if line.trim().is_empty() {
                     continue;
                 }
+                let _ = rl.add_history_entry(line.as_str());
+
+                if line.trim() == "/users" {
+                    match &*roster.lock().unwrap() {
+                        Some((user_list, received_at)) => {
+                            println!("{}", format_roster(user_list, received_at.elapsed()))
+                        }
+                        None => println!("No user list received yet"),
+                    }
+                    continue;
+                }
+
+                // Only our own screen; the server never hears about it
+                if line.trim() == "/clear" {
+                    if let Err(e) = rl.clear_screen() {
+                        eprintln!("Failed to clear the screen: {}", e);
+                    }
+                    continue;
+                }
+
+                if line.trim() == "/rooms" {
+                    match api.rooms().await {
+                        Ok(rooms) => println!("{}", rooms),
+                        Err(e) => eprintln!("{}", e),
+                    }
+                    continue;
+                }
+
+                if let Some(name) = parse_seen(&line) {
+                    match name {
+                        Ok(name) => match api.last_seen(&name).await {
+                            Ok(seen) => println!("{}", seen),
+                            Err(e) => eprintln!("{}", e),
+                        },
+                        Err(usage) => eprintln!("{}", usage),
+                    }
+                    continue;
+                }
 
-                if tx.send(line).is_err() {
+                if let Some(path) = parse_upload(&line) {
+                    // Everyone in the room, us included, is shown the file
+                    // when the server announces it
+                    match path {
+                        Ok(path) => match api.upload(&path).await {
+                            Ok(url) => println!("Uploaded {} to {}", path.display(), url),
+                            Err(e) => eprintln!("{}", e),
+                        },
+                        Err(usage) => eprintln!("{}", usage),
+                    }
+                    continue;
+                }
+
+                let client_msg = match parse_input(&line) {
+                    Ok(client_msg) => client_msg,
+                    Err(usage) => {
+                        eprintln!("{}", usage);
+                        continue;
+                    }
+                };
+
+                // Purging can't be undone, so ask first. Ctrl+C or Ctrl+D
+                // here counts as no.
+                if matches!(client_msg, ClientMessage::PurgeMine)
+                    && !rl.readline(PURGE_PROMPT).is_ok_and(|a| is_confirmed(&a))
+                {
+                    println!("Purge cancelled");
+                    continue;
+                }
+
+                if tx.send(client_msg).is_err() {
                     eprintln!("Failed to send message");
                     break;
                 }
@@ -177,6 +1393,11 @@ async fn run_chat_tui(tx: mpsc::UnboundedSender<String>, client_name: &str) {
             }
         }
     }
+
+    // Every way out of the loop (Ctrl+C, Ctrl+D, a failed send) ends up here
+    if let Some(path) = &history_path {
+        save_history(&mut rl, path);
+    }
 }
 
 #[cfg(test)]
@@ -184,13 +1405,12 @@ mod tests {
     use super::*;
 
     use crate::shared::Message;
+    use rustyline::history::History;
     use url::Url;
 
     #[tokio::test]
     async fn test_message_serialization() {
-        let message = Message {
-            text: "Hello, World!".to_string(),
-        };
+        let message = Message::new("Hello, World!".to_string());
 
         let json = serde_json::to_string(&message).unwrap();
         let deserialized: Message = serde_json::from_str(&json).unwrap();
@@ -209,7 +1429,7 @@ mod tests {
 
         assert_eq!(whom, "Alice: Hello everyone!");
 
-        let message = Message { text: whom.clone() };
+        let message = Message::new(whom.clone());
         assert_eq!(message.text, "Alice: Hello everyone!");
     }
 
@@ -230,9 +1450,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_empty_message_handling() {
-        let message = Message {
-            text: "".to_string(),
-        };
+        let message = Message::new("".to_string());
 
         assert_eq!(message.text, "");
 
@@ -245,9 +1463,7 @@ mod tests {
     #[tokio::test]
     async fn test_long_message_handling() {
         let long_text = "a".repeat(1000);
-        let message = Message {
-            text: long_text.clone(),
-        };
+        let message = Message::new(long_text.clone());
 
         assert_eq!(message.text.len(), 1000);
 
@@ -261,9 +1477,7 @@ mod tests {
     #[tokio::test]
     async fn test_special_characters_in_message() {
         let special_text = "Hello! @#$%^&*()_+{}|:\"<>?[]\\;',./`~";
-        let message = Message {
-            text: special_text.to_string(),
-        };
+        let message = Message::new(special_text.to_string());
 
         let json = serde_json::to_string(&message).unwrap();
         let deserialized: Message = serde_json::from_str(&json).unwrap();
@@ -274,9 +1488,7 @@ mod tests {
     #[tokio::test]
     async fn test_unicode_characters_in_message() {
         let unicode_text = "Hello 世界! 🚀";
-        let message = Message {
-            text: unicode_text.to_string(),
-        };
+        let message = Message::new(unicode_text.to_string());
 
         let json = serde_json::to_string(&message).unwrap();
         let deserialized: Message = serde_json::from_str(&json).unwrap();
@@ -313,20 +1525,22 @@ mod tests {
 
     #[tokio::test]
     async fn test_random_name_generation() {
-        let name1 = generate_random_name();
-        let name2 = generate_random_name();
-
-        // Names should be strings
-        assert!(!name1.is_empty());
-        assert!(!name2.is_empty());
+        let name1 = default_user_name();
+        let name2 = default_user_name();
 
-        // Names should likely be different (very high probability)
-        // We don't require them to be different since it's random, but they usually will be
-        println!("Generated names: {}, {}", name1, name2);
+        // Same scheme as the server: User_ and eight hex digits
+        for name in [&name1, &name2] {
+            let suffix = name.strip_prefix("User_").unwrap();
+            assert_eq!(suffix.len(), 8);
+            assert!(suffix.chars().all(|c| c.is_ascii_hexdigit()));
+        }
+        assert_ne!(name1, name2);
 
-        // Name should contain alphanumeric characters
-        assert!(name1.chars().all(|c| c.is_alphanumeric()));
-        assert!(name2.chars().all(|c| c.is_alphanumeric()));
+        // The fallback name is the one the server is told about
+        match ClientMessage::connect(name1.clone(), None, None) {
+            ClientMessage::Connect { name, .. } => assert_eq!(name, name1),
+            other => panic!("Expected connect, got {:?}", other),
+        }
     }
 
     #[tokio::test]
@@ -348,6 +1562,291 @@ mod tests {
         assert_eq!(parsed_url.path(), "/room/1");
     }
 
+    #[tokio::test]
+    async fn test_parse_nick_command() {
+        match parse_input("/nick  Bob ") {
+            Ok(ClientMessage::Rename { new_name }) => assert_eq!(new_name, "Bob"),
+            other => panic!("Expected rename, got {:?}", other),
+        }
+
+        match parse_input("hello /nick Bob") {
+            Ok(ClientMessage::Chat { text, .. }) => assert_eq!(text, "hello /nick Bob"),
+            other => panic!("Expected chat, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_parse_msg_command() {
+        match parse_input("/msg Bob see you at 5") {
+            Ok(ClientMessage::DirectMessage { to, text }) => {
+                assert_eq!(to, "Bob");
+                assert_eq!(text, "see you at 5");
+            }
+            other => panic!("Expected direct message, got {:?}", other),
+        }
+
+        assert!(parse_input("/msg Bob").is_err());
+        assert!(parse_input("/msg Bob   ").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_parse_kick_command() {
+        match parse_input("/kick  Mallory ") {
+            Ok(ClientMessage::Kick { target }) => assert_eq!(target, "Mallory"),
+            other => panic!("Expected kick, got {:?}", other),
+        }
+
+        assert!(parse_input("/kick   ").is_err());
+    }
+
+    #[test]
+    fn test_parse_history_command_and_render_page() {
+        let parse = |line| match parse_input(line) {
+            Ok(ClientMessage::FetchHistory { before, limit }) => (before, limit),
+            other => panic!("Expected history request, got {:?}", other),
+        };
+        assert_eq!(parse("/history"), (None, DEFAULT_HISTORY_PAGE));
+        assert_eq!(parse("/history 50"), (None, 50));
+        assert_eq!(parse("/history 10 #120 "), (Some(120), 10));
+        assert!(parse_input("/history ten").is_err());
+        assert!(parse_input("/history 10 120 more").is_err());
+        assert!(matches!(
+            parse_input("/historyx"),
+            Ok(ClientMessage::Chat { .. })
+        ));
+
+        let page: Vec<Message> = (7..=8)
+            .map(|id| Message {
+                id: Some(id),
+                ..Message::chat_message("Bob", &format!("message {}", id))
+            })
+            .collect();
+        assert_eq!(
+            history_lines(&page),
+            vec![
+                "=== History: 2 messages ===",
+                "#7 Bob: message 7",
+                "#8 Bob: message 8",
+                "=== /history 2 7 for earlier messages ===",
+            ]
+        );
+        assert_eq!(history_lines(&[]), vec!["=== No earlier messages ==="]);
+    }
+
+    #[tokio::test]
+    async fn test_parse_me_command() {
+        match parse_input("/me waves hello ") {
+            Ok(ClientMessage::Action { text }) => assert_eq!(text, "waves hello"),
+            other => panic!("Expected action, got {:?}", other),
+        }
+        assert!(parse_input("/me  ").is_err());
+
+        for (line, expected) in [
+            ("/away", UserStatus::Away),
+            ("/busy ", UserStatus::Busy),
+            ("/back", UserStatus::Online),
+        ] {
+            match parse_input(line) {
+                Ok(ClientMessage::SetStatus { status }) => assert_eq!(status, expected),
+                other => panic!("Expected status, got {:?}", other),
+            }
+        }
+
+        let (color, line) = render_server_message(
+            &ServerMessage::Action {
+                name: "Alice".to_string(),
+                text: "waves".to_string(),
+            },
+            &Theme::default(),
+            "Alice",
+        );
+        assert_eq!(color, term::color::BRIGHT_MAGENTA);
+        assert_eq!(line, "* Alice waves");
+    }
+
+    #[test]
+    fn test_parse_ping_command() {
+        let nonce = match parse_input("/ping ") {
+            Ok(ClientMessage::Ping { nonce }) => nonce,
+            other => panic!("Expected ping, got {:?}", other),
+        };
+        // Each ping gets its own nonce so late pongs can't be mistaken for it
+        assert!(
+            matches!(parse_input("/ping"), Ok(ClientMessage::Ping { nonce: other }) if other != nonce)
+        );
+        assert!(matches!(
+            parse_input("/pinged"),
+            Ok(ClientMessage::Chat { .. })
+        ));
+        assert!(matches!(parse_input("/stats"), Ok(ClientMessage::Stats)));
+        assert!(matches!(
+            parse_input("/purge "),
+            Ok(ClientMessage::PurgeMine)
+        ));
+        assert!(is_confirmed(" Yes"));
+        assert!(is_confirmed("y"));
+        assert!(!is_confirmed(""));
+        assert!(!is_confirmed("yep"));
+        assert!(
+            matches!(parse_input("/topic  Release planning "), Ok(ClientMessage::SetTopic { text }) if text == "Release planning")
+        );
+        assert!(
+            matches!(parse_input("/topic"), Ok(ClientMessage::SetTopic { text }) if text.is_empty())
+        );
+        assert_eq!(
+            topic_line(Some("Release planning"), Some("Alice")),
+            "Alice set the topic: Release planning"
+        );
+        assert_eq!(
+            stats_line(3723, 42, 3, 2),
+            "Up 1h 2m 3s: 42 messages, 3 users online, 2 rooms"
+        );
+
+        assert_eq!(
+            latency_line(Some(Duration::from_micros(12_345))),
+            "Round trip to server: 12.3 ms"
+        );
+        assert_eq!(latency_line(None), "No reply to /ping within 5s");
+    }
+
+    #[test]
+    fn test_parse_seen_command_and_describe_result() {
+        assert_eq!(parse_seen("/seen  Alice "), Some(Ok("Alice".to_string())));
+        assert!(matches!(parse_seen("/seen"), Some(Err(_))));
+        assert_eq!(parse_seen("/seenit"), None);
+        assert_eq!(parse_seen("seen Alice"), None);
+
+        let seen = |online, last_seen| LastSeen {
+            name: "Alice".to_string(),
+            online,
+            last_seen,
+        };
+        let now = 1_700_000_000;
+        assert_eq!(
+            seen_line(&seen(true, Some(now - 600)), now),
+            "Alice is currently online"
+        );
+        assert_eq!(
+            seen_line(&seen(false, Some(now - 42)), now),
+            "Alice was last seen 42s ago"
+        );
+        assert_eq!(
+            seen_line(&seen(false, Some(now - 300)), now),
+            "Alice was last seen 5m ago"
+        );
+        assert_eq!(
+            seen_line(&seen(false, Some(now - 3 * 86400)), now),
+            "Alice was last seen 3d ago"
+        );
+        assert_eq!(seen_line(&seen(false, None), now), "Alice hasn't been seen");
+    }
+
+    #[test]
+    fn test_parse_upload_command() {
+        assert_eq!(
+            parse_upload("/upload  ~/cat photo.PNG "),
+            Some(Ok(PathBuf::from("~/cat photo.PNG")))
+        );
+        assert!(matches!(parse_upload("/upload"), Some(Err(_))));
+        assert_eq!(parse_upload("/uploads are broken"), None);
+        assert_eq!(parse_upload("hello"), None);
+
+        assert_eq!(content_type_for(Path::new("cat photo.PNG")), "image/png");
+        assert_eq!(content_type_for(Path::new("notes.md")), "text/plain");
+        assert_eq!(
+            content_type_for(Path::new("archive.tar.gz")),
+            "application/octet-stream"
+        );
+
+        let (_, line) = render_server_message(
+            &ServerMessage::Attachment {
+                name: "cat.png".to_string(),
+                url: "http://localhost/files/abc".to_string(),
+                content_type: "image/png".to_string(),
+                size: 2048,
+            },
+            &Theme::default(),
+            "Alice",
+        );
+        assert_eq!(
+            line,
+            "[file] cat.png (image/png, 2048 bytes) http://localhost/files/abc"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_parse_edit_and_delete_commands() {
+        match parse_input("/edit 12 fixed typo") {
+            Ok(ClientMessage::Edit { id, text }) => {
+                assert_eq!(id, 12);
+                assert_eq!(text, "fixed typo");
+            }
+            other => panic!("Expected edit, got {:?}", other),
+        }
+        match parse_input("/delete 12") {
+            Ok(ClientMessage::Delete { id }) => assert_eq!(id, 12),
+            other => panic!("Expected delete, got {:?}", other),
+        }
+        assert!(parse_input("/edit twelve oops").is_err());
+        assert!(parse_input("/delete x").is_err());
+        match parse_input("/pin 7") {
+            Ok(ClientMessage::Pin { id }) => assert_eq!(id, 7),
+            other => panic!("Expected pin, got {:?}", other),
+        }
+        assert!(parse_input("/pin first").is_err());
+        match parse_input("/reply 3 sounds good") {
+            Ok(ClientMessage::Reply { to, text }) => {
+                assert_eq!(to, 3);
+                assert_eq!(text, "sounds good");
+            }
+            other => panic!("Expected reply, got {:?}", other),
+        }
+        assert!(parse_input("/reply 3").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_parse_room_commands() {
+        match parse_input("/join standup") {
+            Ok(ClientMessage::JoinRoom { room, password }) => {
+                assert_eq!(room, "standup");
+                assert_eq!(password, None);
+            }
+            other => panic!("Expected join, got {:?}", other),
+        }
+        match parse_input("/join vault s3cret") {
+            Ok(ClientMessage::JoinRoom { room, password }) => {
+                assert_eq!(room, "vault");
+                assert_eq!(password.as_deref(), Some("s3cret"));
+            }
+            other => panic!("Expected join, got {:?}", other),
+        }
+        assert!(parse_input("/join vault s3cret extra").is_err());
+        match parse_input("/leave standup") {
+            Ok(ClientMessage::LeaveRoom { room }) => assert_eq!(room, "standup"),
+            other => panic!("Expected leave, got {:?}", other),
+        }
+        assert!(parse_input("/join ").is_err());
+        match parse_input("/switch standup") {
+            Ok(ClientMessage::JoinRoom { room, password }) => {
+                assert_eq!(room, "standup");
+                assert_eq!(password, None);
+            }
+            other => panic!("Expected a room switch, got {:?}", other),
+        }
+        assert_eq!(
+            parse_input("/switch ").unwrap_err(),
+            "Usage: /switch <room> [password]"
+        );
+        match parse_input("/watch b") {
+            Ok(ClientMessage::Subscribe { room }) => assert_eq!(room, "b"),
+            other => panic!("Expected subscribe, got {:?}", other),
+        }
+        match parse_input("/unwatch b") {
+            Ok(ClientMessage::Unsubscribe { room }) => assert_eq!(room, "b"),
+            other => panic!("Expected unsubscribe, got {:?}", other),
+        }
+    }
+
     #[tokio::test]
     async fn test_client_message_formatting() {
         let client_name = "Alice";
@@ -356,10 +1855,245 @@ mod tests {
         let formatted_message = format!("{}: {}", client_name, message_text);
         assert_eq!(formatted_message, "Alice: Hello everyone!");
 
-        let message = Message {
-            text: formatted_message.clone(),
-        };
+        let message = Message::new(formatted_message.clone());
 
         assert_eq!(message.text, "Alice: Hello everyone!");
     }
+
+    #[test]
+    fn test_format_roster_includes_connection_time() {
+        let user_list = UserList {
+            users: vec![
+                crate::shared::SerializableUser {
+                    name: "Bob".to_string(),
+                    connected_secs: 100,
+                    status: UserStatus::Online,
+                },
+                crate::shared::SerializableUser {
+                    name: "Carol".to_string(),
+                    connected_secs: 5,
+                    status: UserStatus::Away,
+                },
+            ],
+            count: 2,
+        };
+
+        let roster = format_roster(&user_list, Duration::from_secs(20));
+        assert!(roster.contains("Users online: 2"));
+        assert!(roster.contains("Bob (120s)\n"));
+        assert!(roster.contains("Carol (25s) [away]\n"));
+    }
+
+    #[test]
+    fn test_format_rooms_marks_current_room() {
+        let rooms = vec![
+            RoomInfo {
+                id: "1".to_string(),
+                users: 2,
+                messages: 10,
+                password_protected: false,
+                topic: Some("General chat".to_string()),
+            },
+            RoomInfo {
+                id: "ops".to_string(),
+                users: 0,
+                messages: 0,
+                password_protected: true,
+                topic: None,
+            },
+        ];
+
+        let listing = format_rooms(&rooms, "1");
+        assert!(listing.contains("Rooms: 2"));
+        assert!(listing.contains("* 1 (2 users, 10 messages) - General chat\n"));
+        assert!(listing.contains("  ops (0 users, 0 messages) [password]\n"));
+    }
+
+    #[test]
+    fn test_history_round_trips_through_file() {
+        let path = std::env::temp_dir().join(format!("chat-history-{}.txt", uuid::Uuid::new_v4()));
+
+        // First run: no file yet
+        let mut rl = ChatEditor::new().unwrap();
+        load_history(&mut rl, &path);
+        assert_eq!(rl.history().len(), 0);
+
+        rl.add_history_entry("/nick Bob").unwrap();
+        rl.add_history_entry("hello").unwrap();
+        save_history(&mut rl, &path);
+
+        let mut next = ChatEditor::new().unwrap();
+        load_history(&mut next, &path);
+        std::fs::remove_file(&path).unwrap();
+        let entries: Vec<&String> = next.history().iter().collect();
+        assert_eq!(entries, vec!["/nick Bob", "hello"]);
+    }
+
+    #[test]
+    fn test_identity_is_created_once_and_kept() {
+        let path = std::env::temp_dir().join(format!("chat-identity-{}.txt", uuid::Uuid::new_v4()));
+
+        let identity = load_identity(&path).unwrap();
+        assert!(!identity.is_empty());
+        assert_eq!(load_identity(&path), Some(identity));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_backlog_detection_thresholds() {
+        let mut backlog = BacklogDetector::new(10, 3);
+        assert_eq!(backlog.observe(9), None);
+        assert!(!backlog.is_behind());
+
+        assert_eq!(backlog.observe(10), Some(BacklogChange::FellBehind));
+        assert!(backlog.is_behind());
+        backlog.record_skip();
+        backlog.record_skip();
+
+        // Stays behind between the two thresholds
+        assert_eq!(backlog.observe(12), None);
+        assert_eq!(backlog.observe(3), None);
+        assert_eq!(
+            backlog.observe(2),
+            Some(BacklogChange::CaughtUp { skipped: 2 })
+        );
+        assert!(!backlog.is_behind());
+        assert_eq!(backlog.observe(9), None);
+    }
+
+    #[test]
+    fn test_tee_receives_plain_text() {
+        let path = std::env::temp_dir().join(format!("chat-tee-{}.txt", uuid::Uuid::new_v4()));
+        let mut output = MessageOutput::new(Some(&path), true).unwrap();
+
+        let (color, line) = render_server_message(
+            &ServerMessage::Chat {
+                text: "hi".to_string(),
+                sender: Some("Alice".to_string()),
+                id: Some(1),
+                reply_to: None,
+                quote: None,
+                mentions: Vec::new(),
+                timestamp: None,
+                signature: None,
+            },
+            &Theme::default(),
+            "Alice",
+        );
+        // The terminal shows chat in green...
+        assert_eq!(color, term::color::GREEN);
+        output.print(color, &line);
+        output.print(term::color::RED, "Error 404: User 'Bob' not found");
+
+        // ...while the tee only ever gets the plain text
+        let mirrored = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(mirrored, "Alice: hi\nError 404: User 'Bob' not found\n");
+        assert!(!mirrored.contains('\x1b'));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Records what would be drawn above the prompt
+    struct CapturingPrinter(Arc<Mutex<Vec<String>>>);
+
+    impl ExternalPrinter for CapturingPrinter {
+        fn print(&mut self, msg: String) -> rustyline::Result<()> {
+            self.0.lock().unwrap().push(msg);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_incoming_messages_go_through_prompt_printer() {
+        let printed = Arc::new(Mutex::new(Vec::new()));
+        let mut output = MessageOutput::new(None, false).unwrap();
+        // --no-color wins even on a terminal
+        assert!(!output.colors);
+        output.printer = Some(Box::new(CapturingPrinter(printed.clone())));
+        output.colors = true;
+
+        // The user is mid-way through typing when a message arrives; the
+        // printer gets a complete colored line so it can redraw the prompt after it
+        output.print(term::color::GREEN, "Bob: hi");
+        output.print(term::color::BRIGHT_RED, "Error 429: Slow down");
+
+        // Without a terminal to color, lines go out plain
+        output.colors = false;
+        output.print(term::color::GREEN, "Bob: bye");
+
+        let printed = printed.lock().unwrap();
+        assert_eq!(printed[0], "\x1b[32mBob: hi\x1b[0m\n");
+        assert_eq!(printed[1], "\x1b[91mError 429: Slow down\x1b[0m\n");
+        assert_eq!(printed[2], "Bob: bye\n");
+    }
+
+    #[test]
+    fn test_theme_colors_rendered_lines() {
+        let theme: Theme = "message=white,system=cyan,dm=bright-blue,error=bright-red"
+            .parse()
+            .unwrap();
+        let color =
+            |server_msg: ServerMessage| render_server_message(&server_msg, &theme, "Alice").0;
+
+        assert_eq!(
+            color(ServerMessage::Chat {
+                text: "hi".to_string(),
+                sender: None,
+                id: None,
+                reply_to: None,
+                quote: None,
+                mentions: Vec::new(),
+                timestamp: None,
+                signature: None,
+            }),
+            term::color::WHITE
+        );
+        // Others' chat is colored by sender, leaving the theme color to us
+        let chat_from = |sender: &str| ServerMessage::Chat {
+            text: "hi".to_string(),
+            sender: Some(sender.to_string()),
+            id: None,
+            reply_to: None,
+            quote: None,
+            mentions: Vec::new(),
+            timestamp: None,
+            signature: None,
+        };
+        assert_eq!(color(chat_from("Bob")), sender_color("Bob"));
+        assert_eq!(color(chat_from("Alice")), term::color::WHITE);
+        assert_eq!(
+            color(ServerMessage::UserJoined {
+                name: "Bob".to_string()
+            }),
+            term::color::CYAN
+        );
+        assert_eq!(
+            color(ServerMessage::DirectMessage {
+                from: "Bob".to_string(),
+                to: "Alice".to_string(),
+                text: "psst".to_string(),
+            }),
+            term::color::BRIGHT_BLUE
+        );
+        assert_eq!(
+            color(ServerMessage::error(404, "not found")),
+            term::color::BRIGHT_RED
+        );
+        // Watched rooms keep the colors of the event inside
+        assert_eq!(
+            color(ServerMessage::RoomEvent {
+                room: "2".to_string(),
+                event: Box::new(ServerMessage::UserLeft {
+                    name: "Bob".to_string()
+                }),
+            }),
+            term::color::CYAN
+        );
+    }
 }
@@ -0,0 +1,883 @@
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use ratatui::crossterm::event::{
+    self, DisableFocusChange, EnableFocusChange, Event, KeyCode, KeyEvent, KeyEventKind,
+    KeyModifiers,
+};
+use ratatui::crossterm::execute;
+use std::collections::{BTreeMap, HashMap};
+
+use ratatui::{
+    Frame,
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, List, ListItem, Paragraph},
+};
+use tokio::sync::mpsc;
+
+use crate::alert::{BELL, MentionAlert, mentions_us, notify_mention};
+use crate::client::{
+    Incoming, PURGE_PROMPT, ServerApi, history_footer, is_confirmed, latency_line, parse_input,
+    parse_seen, parse_upload, stats_line, topic_line,
+};
+use crate::shared::{
+    ClientMessage, SerializableUser, ServerMessage, UserStatus, action_line, attachment_line,
+    chat_line, reply_line, status_line,
+};
+use crate::theme::sender_color;
+
+/// How long to wait for keyboard input before checking for server messages
+const INPUT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Width of the user list sidebar, including its border
+const SIDEBAR_WIDTH: u16 = 24;
+
+/// Runs the full-screen chat client until the user quits.
+///
+/// Incoming server events arrive on `events` from the WebSocket task and are
+/// drained between redraws; submitted lines are sent on `tx`. Messages that
+/// trigger `mention_alert` ring the bell and are highlighted. With
+/// `notifications`, mentions that arrive while the terminal isn't focused
+/// also raise a desktop notification. `/upload`, `/rooms` and `/seen` go
+/// through `api`. This blocks on terminal input, so it should run on a
+/// blocking thread inside the Tokio runtime.
+pub fn run(
+    tx: mpsc::UnboundedSender<ClientMessage>,
+    mut events: mpsc::UnboundedReceiver<Incoming>,
+    name: String,
+    api: ServerApi,
+    mention_alert: MentionAlert,
+    notifications: bool,
+) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    if notifications {
+        let _ = execute!(io::stdout(), EnableFocusChange);
+    }
+    let mut view = ChatView::new(name);
+    view.mention_alert = mention_alert;
+    view.notifications = notifications;
+
+    let result = loop {
+        while let Ok(incoming) = events.try_recv() {
+            if let Incoming::Server(server_msg) = &incoming {
+                api.observe(server_msg);
+            }
+            view.apply(incoming);
+        }
+
+        if let Err(e) = terminal.draw(|frame| view.render(frame)) {
+            break Err(e);
+        }
+        if std::mem::take(&mut view.bell_pending) {
+            print!("{}", BELL);
+            let _ = io::Write::flush(&mut io::stdout());
+        }
+        for text in view.notifications_pending.drain(..) {
+            notify_mention(&text);
+        }
+
+        match event::poll(INPUT_POLL_INTERVAL) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => break Err(e),
+        }
+        match event::read() {
+            Ok(Event::FocusGained) => view.focused = true,
+            Ok(Event::FocusLost) => view.focused = false,
+            Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => match view.handle_key(key) {
+                Action::Send(client_msg) => {
+                    if tx.send(client_msg).is_err() {
+                        break Ok(());
+                    }
+                }
+                Action::Upload(path) => {
+                    // The interface stays frozen until the upload finishes
+                    let result = tokio::runtime::Handle::current().block_on(api.upload(&path));
+                    match result {
+                        Ok(url) => view.push(
+                            presence_style(),
+                            format!("* Uploaded {} to {}", path.display(), url),
+                        ),
+                        Err(e) => view.push(error_style(), e),
+                    }
+                }
+                Action::ListRooms => {
+                    match tokio::runtime::Handle::current().block_on(api.rooms()) {
+                        Ok(rooms) => {
+                            for line in rooms.lines() {
+                                view.push(presence_style(), line.to_string());
+                            }
+                        }
+                        Err(e) => view.push(error_style(), e),
+                    }
+                }
+                Action::LastSeen(name) => {
+                    match tokio::runtime::Handle::current().block_on(api.last_seen(&name)) {
+                        Ok(seen) => view.push(presence_style(), format!("* {}", seen)),
+                        Err(e) => view.push(error_style(), e),
+                    }
+                }
+                Action::Quit => break Ok(()),
+                Action::None => {}
+            },
+            Ok(_) => {}
+            Err(e) => break Err(e),
+        }
+    };
+
+    if notifications {
+        let _ = execute!(io::stdout(), DisableFocusChange);
+    }
+    ratatui::restore();
+    result
+}
+
+/// What the UI loop should do after a key press.
+#[derive(Debug)]
+enum Action {
+    /// Send this message to the server
+    Send(ClientMessage),
+    /// Upload the file at this path to the current room
+    Upload(PathBuf),
+    /// Fetch and show the rooms open on the server
+    ListRooms,
+    /// Ask the server when this user was last active
+    LastSeen(String),
+    /// Leave the chat
+    Quit,
+    /// Nothing beyond redrawing
+    None,
+}
+
+/// State of the full-screen client: message history, roster and input line.
+struct ChatView {
+    /// Our current display name, kept up to date across renames
+    name: String,
+    /// Rendered message lines, oldest first
+    lines: Vec<Line<'static>>,
+    /// Index into `lines` of each chat message, by message ID
+    line_ids: HashMap<u64, usize>,
+    /// Reaction counts by emoji, keyed by index into `lines`
+    reactions: HashMap<usize, BTreeMap<String, usize>>,
+    /// Name of the server we're connected to, once it has greeted us
+    server_name: Option<String>,
+    /// Topic of the current room, shown in the title bar
+    topic: Option<String>,
+    /// Users from the most recent `UserList`
+    users: Vec<SerializableUser>,
+    /// Text typed so far
+    input: String,
+    /// How many lines the message pane is scrolled up from the bottom
+    scroll: usize,
+    /// When messages mentioning us should alert
+    mention_alert: MentionAlert,
+    /// Whether a mention arrived since the bell was last rung
+    bell_pending: bool,
+    /// Whether mentions raise desktop notifications while unfocused
+    notifications: bool,
+    /// Whether the terminal has focus; assumed not until it reports
+    /// otherwise or a key is pressed
+    focused: bool,
+    /// Mentions waiting to be shown as desktop notifications
+    notifications_pending: Vec<String>,
+    /// Whether the next submitted line answers [`PURGE_PROMPT`]
+    confirming_purge: bool,
+}
+
+impl ChatView {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            lines: Vec::new(),
+            line_ids: HashMap::new(),
+            reactions: HashMap::new(),
+            server_name: None,
+            topic: None,
+            users: Vec::new(),
+            input: String::new(),
+            scroll: 0,
+            mention_alert: MentionAlert::default(),
+            bell_pending: false,
+            notifications: false,
+            focused: false,
+            notifications_pending: Vec::new(),
+            confirming_purge: false,
+        }
+    }
+
+    /// Updates the view with an event from the server.
+    fn apply(&mut self, incoming: Incoming) {
+        match incoming {
+            Incoming::Server(server_msg) => self.apply_server_message(server_msg),
+            Incoming::Text(text) => self.push(Style::default(), text),
+            Incoming::Status(status) => self.push(presence_style(), format!("*** {} ***", status)),
+            Incoming::Latency(rtt) => {
+                self.push(presence_style(), format!("* {}", latency_line(rtt)))
+            }
+            Incoming::Closed(reason) => self.push(error_style(), format!("*** {} ***", reason)),
+        }
+    }
+
+    fn apply_server_message(&mut self, server_msg: ServerMessage) {
+        match server_msg {
+            ServerMessage::Welcome {
+                server_name,
+                motd,
+                name,
+                role,
+                ..
+            } => {
+                self.server_name = Some(server_name);
+                // The server may have picked another name if ours was taken
+                if !name.is_empty() {
+                    self.name = name;
+                }
+                // Message IDs and topics are per room, so forget those from
+                // the last one; the new room's topic follows if it has one
+                self.line_ids.clear();
+                self.topic = None;
+                self.push(presence_style(), motd);
+                self.push(
+                    presence_style(),
+                    format!("You are signed in as {} ({})", self.name, role),
+                );
+            }
+            ServerMessage::Chat {
+                text,
+                sender,
+                id,
+                reply_to,
+                quote,
+                mentions,
+                ..
+            } => {
+                if let Some(reply_to) = reply_to {
+                    self.push(
+                        Style::default().add_modifier(Modifier::DIM),
+                        reply_line(reply_to, quote.as_deref()),
+                    );
+                }
+                let text = chat_line(sender.as_deref(), &text);
+                if self.notifications && !self.focused && mentions_us(&text, &mentions, &self.name)
+                {
+                    self.notifications_pending.push(text.clone());
+                }
+                if self
+                    .mention_alert
+                    .should_alert(&text, &mentions, &self.name)
+                {
+                    self.bell_pending = true;
+                    self.push(Style::default().add_modifier(Modifier::REVERSED), text);
+                } else {
+                    // Same colors as the prompt; the terminal palette indexes
+                    // match term's
+                    let style = match &sender {
+                        Some(sender) if *sender != self.name => {
+                            Style::default().fg(Color::Indexed(sender_color(sender) as u8))
+                        }
+                        _ => Style::default(),
+                    };
+                    self.push(style, text);
+                }
+                if let Some(id) = id {
+                    self.line_ids.insert(id, self.lines.len() - 1);
+                }
+            }
+            ServerMessage::Action { name, text } => {
+                self.push(action_style(), action_line(&name, &text))
+            }
+            ServerMessage::Attachment {
+                name,
+                url,
+                content_type,
+                size,
+            } => self.push(
+                Style::default().fg(Color::Cyan),
+                attachment_line(&name, &url, &content_type, size),
+            ),
+            ServerMessage::MessageEdited { id, text, sender } => {
+                if let Some(line) = self.line_ids.get(&id).and_then(|&i| self.lines.get_mut(i)) {
+                    *line = Line::from(vec![
+                        Span::raw(chat_line(sender.as_deref(), &text)),
+                        Span::styled(" (edited)", Style::default().fg(Color::DarkGray)),
+                    ]);
+                }
+            }
+            ServerMessage::MessageDeleted { id } => {
+                if let Some(line) = self.line_ids.get(&id).and_then(|&i| self.lines.get_mut(i)) {
+                    *line = Line::from(Span::styled(
+                        "[deleted]",
+                        Style::default().fg(Color::DarkGray),
+                    ));
+                }
+            }
+            ServerMessage::MessagePinned { id, by } => {
+                self.push(
+                    presence_style(),
+                    format!("*** {} pinned message #{} ***", by, id),
+                );
+            }
+            ServerMessage::MessageUnpinned { id } => {
+                self.push(
+                    presence_style(),
+                    format!("*** Message #{} was unpinned ***", id),
+                );
+            }
+            ServerMessage::Reaction {
+                message_id,
+                emoji,
+                added,
+                ..
+            } => {
+                if let Some(&line) = self.line_ids.get(&message_id) {
+                    let counts = self.reactions.entry(line).or_default();
+                    let count = counts.entry(emoji.clone()).or_default();
+                    if added {
+                        *count += 1;
+                    } else {
+                        *count = count.saturating_sub(1);
+                    }
+                    if *count == 0 {
+                        counts.remove(&emoji);
+                    }
+                }
+            }
+            ServerMessage::RoomEvent { room, event } => {
+                // IDs and rosters belong to our own room, so only show the
+                // conversation from watched rooms
+                match *event {
+                    ServerMessage::Chat { text, sender, .. } => self.push(
+                        Style::default(),
+                        format!("[{}] {}", room, chat_line(sender.as_deref(), &text)),
+                    ),
+                    ServerMessage::Action { name, text } => self.push(
+                        action_style(),
+                        format!("[{}] {}", room, action_line(&name, &text)),
+                    ),
+                    ServerMessage::UserJoined { name } => {
+                        self.push(presence_style(), format!("[{}] → {} joined", room, name))
+                    }
+                    ServerMessage::UserLeft { name } => {
+                        self.push(presence_style(), format!("[{}] ← {} left", room, name))
+                    }
+                    _ => {}
+                }
+            }
+            ServerMessage::Ack { id, .. } => {
+                // Mark our own message as delivered
+                if let Some(line) = self.line_ids.get(&id).and_then(|&i| self.lines.get_mut(i)) {
+                    line.spans
+                        .push(Span::styled(" ✓", Style::default().fg(Color::DarkGray)));
+                }
+            }
+            ServerMessage::HistoryGap { from, to } => self.push(
+                presence_style(),
+                format!("* Some messages were not recovered (#{} to #{})", from, to),
+            ),
+            ServerMessage::History { messages } => {
+                let footer = history_footer(&messages);
+                for message in messages {
+                    // Old messages never ring, but keep their ID and reactions
+                    // so later edits and reactions land on the right line
+                    self.push(Style::default(), message.display_text());
+                    let line = self.lines.len() - 1;
+                    if let Some(id) = message.id {
+                        self.line_ids.insert(id, line);
+                    }
+                    if !message.reactions.is_empty() {
+                        let counts = message
+                            .reactions
+                            .into_iter()
+                            .map(|(emoji, names)| (emoji, names.len()))
+                            .collect();
+                        self.reactions.insert(line, counts);
+                    }
+                }
+                self.push(Style::default().fg(Color::DarkGray), footer);
+            }
+            // The connection reports pongs as `Incoming::Latency` instead
+            ServerMessage::Pong { .. } => {}
+            ServerMessage::Stats {
+                uptime_secs,
+                total_messages,
+                connected_users,
+                rooms,
+            } => self.push(
+                presence_style(),
+                format!(
+                    "* {}",
+                    stats_line(uptime_secs, total_messages, connected_users, rooms)
+                ),
+            ),
+            ServerMessage::HistoryTrimmed { dropped } => self.push(
+                presence_style(),
+                format!(
+                    "* {} older messages were dropped from the room's history",
+                    dropped
+                ),
+            ),
+            ServerMessage::UserList(user_list) => self.users = user_list.users,
+            ServerMessage::UserJoined { name } => {
+                self.push(presence_style(), format!("→ {} joined", name))
+            }
+            ServerMessage::UserLeft { name } => {
+                self.push(presence_style(), format!("← {} left", name))
+            }
+            ServerMessage::UserRenamed { old, new } => {
+                if self.name == old {
+                    self.name = new.clone();
+                }
+                self.push(
+                    presence_style(),
+                    format!("* {} is now known as {}", old, new),
+                );
+            }
+            ServerMessage::StatusChanged { name, status } => self.push(
+                presence_style(),
+                format!("* {}", status_line(&name, status)),
+            ),
+            ServerMessage::DirectMessage { from, to, text } => self.push(
+                Style::default().fg(Color::Magenta),
+                format!("[DM] {} -> {}: {}", from, to, text),
+            ),
+            ServerMessage::RoomClosed { room, reason } => self.push(
+                error_style(),
+                format!("*** Room {} was closed: {} ***", room, reason),
+            ),
+            ServerMessage::ServerShutdown => {
+                self.push(error_style(), "*** Server is shutting down ***".to_string())
+            }
+            ServerMessage::Announcement { text } => self.push(
+                Style::default()
+                    .fg(Color::Yellow)
+                    .add_modifier(Modifier::BOLD | Modifier::REVERSED),
+                format!("!!! ANNOUNCEMENT: {} !!!", text),
+            ),
+            ServerMessage::IdleWarning { disconnect_in_secs } => self.push(
+                presence_style(),
+                format!(
+                    "* Idle for too long; disconnecting in {}s unless you send something",
+                    disconnect_in_secs
+                ),
+            ),
+            ServerMessage::MessagesPurged { name, count } => self.push(
+                presence_style(),
+                format!(
+                    "* {} messages from {} were removed by a moderator",
+                    count, name
+                ),
+            ),
+            ServerMessage::OwnMessagesPurged { count } => self.push(
+                presence_style(),
+                format!("* Deleted {} of your messages", count),
+            ),
+            ServerMessage::TopicChanged { topic, by } => {
+                if by.is_some() {
+                    self.push(
+                        presence_style(),
+                        format!("* {}", topic_line(topic.as_deref(), by.as_deref())),
+                    );
+                }
+                self.topic = topic;
+            }
+            ServerMessage::System { text } => self.push(
+                Style::default()
+                    .fg(Color::Cyan)
+                    .add_modifier(Modifier::ITALIC),
+                format!("» {}", text),
+            ),
+            ServerMessage::Error { code, message } => {
+                self.push(error_style(), format!("Error {}: {}", code, message))
+            }
+        }
+    }
+
+    fn push(&mut self, style: Style, text: String) {
+        self.lines.push(Line::from(Span::styled(text, style)));
+        // Keep the same lines on screen while the user is scrolled back
+        if self.scroll > 0 {
+            self.scroll += 1;
+        }
+    }
+
+    /// Handles a key press, returning what the UI loop should do next.
+    fn handle_key(&mut self, key: KeyEvent) -> Action {
+        // Typing means the terminal has focus, even if it doesn't report it
+        self.focused = true;
+        match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => Action::Quit,
+            KeyCode::Esc => Action::Quit,
+            KeyCode::Enter => self.submit(),
+            KeyCode::Backspace => {
+                self.input.pop();
+                Action::None
+            }
+            KeyCode::Char(c) => {
+                self.input.push(c);
+                Action::None
+            }
+            KeyCode::Up => self.scroll_by(1),
+            KeyCode::Down => self.scroll_by(-1),
+            KeyCode::PageUp => self.scroll_by(10),
+            KeyCode::PageDown => self.scroll_by(-10),
+            _ => Action::None,
+        }
+    }
+
+    fn scroll_by(&mut self, delta: isize) -> Action {
+        let max = self.lines.len().saturating_sub(1);
+        self.scroll = self.scroll.saturating_add_signed(delta).min(max);
+        Action::None
+    }
+
+    fn submit(&mut self) -> Action {
+        let line = std::mem::take(&mut self.input);
+        if std::mem::take(&mut self.confirming_purge) {
+            if is_confirmed(&line) {
+                return Action::Send(ClientMessage::PurgeMine);
+            }
+            self.push(presence_style(), "Purge cancelled".to_string());
+            return Action::None;
+        }
+        if line.trim().is_empty() {
+            return Action::None;
+        }
+        // The roster is always visible in the sidebar
+        if line.trim() == "/users" {
+            return Action::None;
+        }
+        if line.trim() == "/rooms" {
+            return Action::ListRooms;
+        }
+        // Only our own view; the server never hears about it
+        if line.trim() == "/clear" {
+            self.lines.clear();
+            self.line_ids.clear();
+            self.reactions.clear();
+            self.scroll = 0;
+            return Action::None;
+        }
+        if let Some(name) = parse_seen(&line) {
+            return match name {
+                Ok(name) => Action::LastSeen(name),
+                Err(usage) => {
+                    self.push(error_style(), usage);
+                    Action::None
+                }
+            };
+        }
+        if let Some(path) = parse_upload(&line) {
+            return match path {
+                Ok(path) => Action::Upload(path),
+                Err(usage) => {
+                    self.push(error_style(), usage);
+                    Action::None
+                }
+            };
+        }
+
+        match parse_input(&line) {
+            // Purging can't be undone, so ask first
+            Ok(ClientMessage::PurgeMine) => {
+                self.confirming_purge = true;
+                self.push(presence_style(), PURGE_PROMPT.trim_end().to_string());
+                Action::None
+            }
+            Ok(client_msg) => {
+                self.scroll = 0;
+                Action::Send(client_msg)
+            }
+            Err(usage) => {
+                self.push(error_style(), usage);
+                Action::None
+            }
+        }
+    }
+
+    /// Message lines as displayed, with a row of reaction counts under each
+    /// message that has any.
+    fn display_lines(&self) -> Vec<Line<'static>> {
+        let mut display = Vec::with_capacity(self.lines.len());
+        for (i, line) in self.lines.iter().enumerate() {
+            display.push(line.clone());
+            if let Some(counts) = self.reactions.get(&i).filter(|c| !c.is_empty()) {
+                let summary: Vec<String> = counts
+                    .iter()
+                    .map(|(emoji, count)| format!("{} {}", emoji, count))
+                    .collect();
+                display.push(Line::from(Span::styled(
+                    format!("  {}", summary.join("  ")),
+                    Style::default().fg(Color::DarkGray),
+                )));
+            }
+        }
+        display
+    }
+
+    fn render(&self, frame: &mut Frame) {
+        let [main, input_area] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(3)]).areas(frame.area());
+        let [messages_area, users_area] =
+            Layout::horizontal([Constraint::Min(20), Constraint::Length(SIDEBAR_WIDTH)])
+                .areas(main);
+
+        // Show the window of lines that ends `scroll` lines above the newest one
+        let lines = self.display_lines();
+        let height = messages_area.height.saturating_sub(2) as usize;
+        let end = lines.len().saturating_sub(self.scroll);
+        let start = end.saturating_sub(height);
+        let mut title = match &self.server_name {
+            Some(server_name) => format!("Messages — {}", server_name),
+            None => "Messages".to_string(),
+        };
+        if let Some(topic) = &self.topic {
+            title.push_str(&format!(" — {}", topic));
+        }
+        if self.scroll > 0 {
+            title.push_str(&format!(" (scrolled back {})", self.scroll));
+        }
+        let messages = Paragraph::new(lines[start..end].to_vec())
+            .block(Block::default().borders(Borders::ALL).title(title));
+        frame.render_widget(messages, messages_area);
+
+        let users: Vec<ListItem> = self
+            .users
+            .iter()
+            .map(|user| {
+                let mut style = if user.name == self.name {
+                    Style::default().add_modifier(Modifier::BOLD)
+                } else {
+                    Style::default()
+                };
+                let mut item = format!("{} ({}s)", user.name, user.connected_secs);
+                if user.status != UserStatus::Online {
+                    style = style.fg(Color::DarkGray);
+                    item.push_str(&format!(" [{}]", user.status));
+                }
+                ListItem::new(item).style(style)
+            })
+            .collect();
+        let users = List::new(users).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!("Users ({})", self.users.len())),
+        );
+        frame.render_widget(users, users_area);
+
+        let input = Paragraph::new(self.input.as_str()).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!("{} (Enter to send, Esc to quit)", self.name)),
+        );
+        frame.render_widget(input, input_area);
+        frame.set_cursor_position((
+            input_area.x + 1 + self.input.chars().count() as u16,
+            input_area.y + 1,
+        ));
+    }
+}
+
+fn presence_style() -> Style {
+    Style::default().fg(Color::Yellow)
+}
+
+fn action_style() -> Style {
+    Style::default()
+        .fg(Color::Magenta)
+        .add_modifier(Modifier::ITALIC)
+}
+
+fn error_style() -> Style {
+    Style::default().fg(Color::Red)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::UserList;
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    #[test]
+    fn test_view_applies_server_messages() {
+        let mut view = ChatView::new("Alice".to_string());
+
+        view.apply(Incoming::Server(ServerMessage::UserList(UserList {
+            users: vec![SerializableUser {
+                name: "Bob".to_string(),
+                connected_secs: 3,
+                status: UserStatus::Online,
+            }],
+            count: 1,
+        })));
+        view.apply(Incoming::Server(ServerMessage::Chat {
+            text: "hi".to_string(),
+            sender: Some("Bob".to_string()),
+            id: Some(1),
+            reply_to: None,
+            quote: None,
+            mentions: Vec::new(),
+            timestamp: None,
+            signature: None,
+        }));
+        view.apply(Incoming::Server(ServerMessage::Ack {
+            id: 1,
+            client_msg_id: None,
+        }));
+        view.apply(Incoming::Server(ServerMessage::DirectMessage {
+            from: "Bob".to_string(),
+            to: "Alice".to_string(),
+            text: "psst".to_string(),
+        }));
+        view.apply(Incoming::Server(ServerMessage::UserRenamed {
+            old: "Alice".to_string(),
+            new: "Al".to_string(),
+        }));
+
+        assert_eq!(view.users.len(), 1);
+        assert_eq!(view.name, "Al");
+        assert_eq!(view.lines.len(), 3);
+        assert_eq!(view.lines[0].spans[0].content, "Bob: hi");
+        // Acknowledged messages get a delivered mark
+        assert_eq!(view.lines[0].spans[1].content, " ✓");
+        // DMs stand out from regular chat
+        assert_eq!(view.lines[1].spans[0].style.fg, Some(Color::Magenta));
+        assert_ne!(view.lines[0].spans[0].style, view.lines[1].spans[0].style);
+    }
+
+    #[test]
+    fn test_view_shows_reaction_counts_under_message() {
+        let mut view = ChatView::new("Alice".to_string());
+        view.apply(Incoming::Server(ServerMessage::Chat {
+            text: "ship it".to_string(),
+            sender: Some("Alice".to_string()),
+            id: Some(7),
+            reply_to: None,
+            quote: None,
+            mentions: Vec::new(),
+            timestamp: None,
+            signature: None,
+        }));
+        for (name, added) in [("Bob", true), ("Carol", true), ("Carol", false)] {
+            view.apply(Incoming::Server(ServerMessage::Reaction {
+                message_id: 7,
+                emoji: "👍".to_string(),
+                name: name.to_string(),
+                added,
+            }));
+        }
+        // Reactions to messages we never saw are ignored
+        view.apply(Incoming::Server(ServerMessage::Reaction {
+            message_id: 99,
+            emoji: "🎉".to_string(),
+            name: "Bob".to_string(),
+            added: true,
+        }));
+
+        let display = view.display_lines();
+        assert_eq!(display.len(), 2);
+        assert_eq!(display[1].spans[0].content, "  👍 1");
+    }
+
+    #[test]
+    fn test_view_notifies_mentions_only_while_unfocused() {
+        let mut view = ChatView::new("Alice".to_string());
+        view.notifications = true;
+        let chat = |text: &str| {
+            let (sender, text) = text.split_once(": ").unwrap();
+            Incoming::Server(ServerMessage::Chat {
+                text: text.to_string(),
+                sender: Some(sender.to_string()),
+                id: None,
+                reply_to: None,
+                quote: None,
+                mentions: Vec::new(),
+                timestamp: None,
+                signature: None,
+            })
+        };
+
+        view.apply(chat("Bob: hi @alice"));
+        view.apply(chat("Bob: nothing to see"));
+        view.apply(chat("Alice: talking about alice"));
+        assert_eq!(view.notifications_pending, vec!["Bob: hi @alice"]);
+
+        // Once the user is typing, the terminal evidently has focus
+        view.notifications_pending.clear();
+        view.handle_key(key(KeyCode::Char('x')));
+        view.apply(chat("Bob: alice?"));
+        assert!(view.notifications_pending.is_empty());
+    }
+
+    #[test]
+    fn test_view_submits_typed_input() {
+        let mut view = ChatView::new("Alice".to_string());
+        for c in "/msg Bob hey".chars() {
+            view.handle_key(key(KeyCode::Char(c)));
+        }
+
+        match view.handle_key(key(KeyCode::Enter)) {
+            Action::Send(ClientMessage::DirectMessage { to, text }) => {
+                assert_eq!(to, "Bob");
+                assert_eq!(text, "hey");
+            }
+            other => panic!("Expected a DM, got {:?}", other),
+        }
+        assert!(view.input.is_empty());
+
+        // Malformed commands show their usage instead of being sent
+        for c in "/msg".chars() {
+            view.handle_key(key(KeyCode::Char(c)));
+        }
+        view.handle_key(key(KeyCode::Char(' ')));
+        assert!(matches!(view.handle_key(key(KeyCode::Enter)), Action::None));
+        assert_eq!(view.lines.len(), 1);
+
+        assert!(matches!(view.handle_key(key(KeyCode::Esc)), Action::Quit));
+    }
+
+    #[test]
+    fn test_view_clears_locally() {
+        let mut view = ChatView::new("Alice".to_string());
+        view.apply(Incoming::Text("Bob: hi".to_string()));
+        view.apply(Incoming::Text("Bob: anyone?".to_string()));
+        view.scroll = 1;
+
+        for c in "/clear ".chars() {
+            view.handle_key(key(KeyCode::Char(c)));
+        }
+        assert!(matches!(view.handle_key(key(KeyCode::Enter)), Action::None));
+        assert!(view.lines.is_empty());
+        assert_eq!(view.scroll, 0);
+
+        // New messages show up as usual afterwards
+        view.apply(Incoming::Text("Bob: back".to_string()));
+        assert_eq!(view.lines.len(), 1);
+    }
+
+    #[test]
+    fn test_view_confirms_purge() {
+        let mut view = ChatView::new("Alice".to_string());
+        let submit = |view: &mut ChatView, line: &str| {
+            for c in line.chars() {
+                view.handle_key(key(KeyCode::Char(c)));
+            }
+            view.handle_key(key(KeyCode::Enter))
+        };
+
+        assert!(matches!(submit(&mut view, "/purge"), Action::None));
+        assert!(matches!(submit(&mut view, "no"), Action::None));
+        assert_eq!(view.lines.len(), 2);
+
+        assert!(matches!(submit(&mut view, "/purge"), Action::None));
+        assert!(matches!(
+            submit(&mut view, "y"),
+            Action::Send(ClientMessage::PurgeMine)
+        ));
+    }
+}
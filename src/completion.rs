@@ -0,0 +1,128 @@
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+
+use crate::client::Roster;
+
+/// Slash-commands understood by the prompt
+pub const COMMANDS: &[&str] = &[
+    "/away", "/back", "/busy", "/clear", "/delete", "/edit", "/history", "/join", "/kick",
+    "/leave", "/me", "/msg", "/nick", "/pin", "/ping", "/purge", "/react", "/reply", "/rooms",
+    "/seen", "/stats", "/switch", "/topic", "/unwatch", "/upload", "/users", "/watch",
+];
+
+/// Commands whose first argument is a connected user's name
+const NAME_COMMANDS: &[&str] = &["/msg", "/kick"];
+
+/// Tab-completion for the readline prompt.
+///
+/// Completes slash-commands, and user names after `/msg` and `/kick` from
+/// the most recent user list the server sent.
+pub struct ChatHelper {
+    roster: Roster,
+}
+
+impl ChatHelper {
+    /// Creates a helper completing names from `roster`.
+    pub fn new(roster: Roster) -> Self {
+        Self { roster }
+    }
+}
+
+impl Completer for ChatHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let names: Vec<String> = match &*self.roster.lock().unwrap() {
+            Some((user_list, _)) => user_list.users.iter().map(|u| u.name.clone()).collect(),
+            None => Vec::new(),
+        };
+        Ok(complete(&line[..pos], &names))
+    }
+}
+
+impl Hinter for ChatHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ChatHelper {}
+
+impl Validator for ChatHelper {}
+
+impl Helper for ChatHelper {}
+
+/// Returns where the word being completed starts in `line` (the text before
+/// the cursor) and the candidates to replace it with.
+///
+/// Commands match by prefix; names match by prefix ignoring case. Each
+/// candidate ends with a space so the next argument can be typed straight away.
+pub fn complete(line: &str, names: &[String]) -> (usize, Vec<String>) {
+    let start = line.rfind(' ').map_or(0, |i| i + 1);
+    let word = &line[start..];
+    let previous: Vec<&str> = line[..start].split_whitespace().collect();
+
+    let candidates = match previous.as_slice() {
+        [] if word.starts_with('/') => COMMANDS
+            .iter()
+            .filter(|command| command.starts_with(word))
+            .map(|command| format!("{} ", command))
+            .collect(),
+        [command] if NAME_COMMANDS.contains(command) => {
+            let word = word.to_lowercase();
+            let mut matches: Vec<String> = names
+                .iter()
+                .filter(|name| name.to_lowercase().starts_with(&word))
+                .map(|name| format!("{} ", name))
+                .collect();
+            matches.sort_by_key(|name| name.to_lowercase());
+            matches
+        }
+        _ => Vec::new(),
+    };
+    (start, candidates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_completes_commands_and_names() {
+        let names = vec!["alice".to_string(), "Albert".to_string(), "Bob".to_string()];
+
+        assert_eq!(
+            complete("/m", &names),
+            (0, vec!["/me ".to_string(), "/msg ".to_string()])
+        );
+        assert_eq!(
+            complete("/u", &names),
+            (
+                0,
+                vec![
+                    "/unwatch ".to_string(),
+                    "/upload ".to_string(),
+                    "/users ".to_string()
+                ]
+            )
+        );
+
+        // Names ignore case and are offered in a stable order to cycle through
+        assert_eq!(
+            complete("/msg AL", &names),
+            (5, vec!["Albert ".to_string(), "alice ".to_string()])
+        );
+        assert_eq!(complete("/kick b", &names), (6, vec!["Bob ".to_string()]));
+
+        // Plain chat and later arguments aren't completed
+        assert_eq!(complete("hello al", &names).1, Vec::<String>::new());
+        assert_eq!(complete("/msg alice hi", &names).1, Vec::<String>::new());
+        assert_eq!(complete("/nick al", &names).1, Vec::<String>::new());
+    }
+}
@@ -0,0 +1,195 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use crate::server::ServerConfig;
+use crate::shared::{ChatError, ChatResult};
+
+/// Server settings read from a TOML file given with `--config`.
+///
+/// Every key is optional. Settings are layered: the built-in defaults of
+/// [`ServerConfig`] come first, values from the file replace them, and flags
+/// given on the command line replace both.
+///
+/// The file covers the settings a deployment keeps, each matching the flag
+/// of the same name: `--address`, `--port`, `--max-messages`,
+/// `--rate-limit-per-sec`, `--auth-token`, `--admins`, `--welcome`,
+/// `--room-welcome` (as the `room_welcomes` table), `--tls-cert` and
+/// `--tls-key`. Everything else, the admin and moderator tokens included,
+/// can only be given as a flag; unknown keys are refused.
+///
+/// ```toml
+/// address = "0.0.0.0"
+/// port = 8080
+/// max_messages = 500
+/// rate_limit_per_sec = 10
+/// auth_token = "s3cret"
+/// admins = ["alice", "bob"]
+/// welcome = "Be nice. Topic: release planning"
+/// tls_cert = "/etc/chat/cert.pem"
+/// tls_key = "/etc/chat/key.pem"
+///
+/// [room_welcomes]
+/// standup = "Keep it short!"
+/// ```
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    pub max_messages: Option<usize>,
+    pub rate_limit_per_sec: Option<u32>,
+    pub auth_token: Option<String>,
+    pub admins: Option<Vec<String>>,
+    pub welcome: Option<String>,
+    pub room_welcomes: Option<HashMap<String, String>>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+}
+
+impl ConfigFile {
+    /// Reads and parses the config file at `path`.
+    pub fn load(path: &Path) -> ChatResult<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        toml::from_str(&contents)
+            .map_err(|e| ChatError::ConfigError(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Overwrites the settings in `config` that this file sets.
+    pub fn apply(self, config: &mut ServerConfig) {
+        if let Some(address) = self.address {
+            config.address = address;
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(max_messages) = self.max_messages {
+            config.max_messages = max_messages;
+        }
+        if let Some(rate) = self.rate_limit_per_sec {
+            config.rate_limit_per_sec = rate;
+        }
+        if self.auth_token.is_some() {
+            config.auth_token = self.auth_token;
+        }
+        if let Some(admins) = self.admins {
+            config.admins = admins;
+        }
+        if self.welcome.is_some() {
+            config.welcome = self.welcome;
+        }
+        if let Some(room_welcomes) = self.room_welcomes {
+            config.room_welcomes = room_welcomes;
+        }
+        if self.tls_cert.is_some() {
+            config.tls_cert = self.tls_cert;
+        }
+        if self.tls_key.is_some() {
+            config.tls_key = self.tls_key;
+        }
+    }
+}
+
+/// Checks a fully assembled server config for values the server can't run with.
+pub fn validate(config: &ServerConfig) -> ChatResult<()> {
+    let invalid = |msg: String| Err(ChatError::ConfigError(msg));
+
+    if config.address.parse::<IpAddr>().is_err() {
+        return invalid(format!("Invalid listen address '{}'", config.address));
+    }
+    if config.rate_limit_per_sec == 0 {
+        return invalid("rate_limit_per_sec must be at least 1".to_string());
+    }
+    if config.auth_token.as_deref().is_some_and(str::is_empty) {
+        return invalid("auth_token must not be empty".to_string());
+    }
+    if config.hmac_key.as_deref().is_some_and(str::is_empty) {
+        return invalid("hmac_key must not be empty".to_string());
+    }
+    if let Some(url) = &config.public_url
+        && !(url.starts_with("http://") || url.starts_with("https://"))
+    {
+        return invalid(format!(
+            "public_url '{}' must start with http:// or https://",
+            url
+        ));
+    }
+    if config.admins.iter().any(|name| name.trim().is_empty()) {
+        return invalid("admins must not contain empty names".to_string());
+    }
+    if config.tls_cert.is_some() != config.tls_key.is_some() {
+        return invalid("tls_cert and tls_key must be given together".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_sample_config_file() {
+        let path = std::env::temp_dir().join(format!("chat-config-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            r#"
+address = "0.0.0.0"
+port = 8080
+max_messages = 500
+rate_limit_per_sec = 10
+auth_token = "s3cret"
+admins = ["alice", "bob"]
+welcome = "Be nice"
+tls_cert = "cert.pem"
+tls_key = "key.pem"
+
+[room_welcomes]
+standup = "Keep it short!"
+"#,
+        )
+        .unwrap();
+        let file = ConfigFile::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // File values replace defaults; unset keys keep them
+        let mut config = ServerConfig::default();
+        file.apply(&mut config);
+        assert_eq!(config.address, "0.0.0.0");
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.max_messages, 500);
+        assert_eq!(config.rate_limit_per_sec, 10);
+        assert_eq!(config.auth_token.as_deref(), Some("s3cret"));
+        assert_eq!(config.admins, vec!["alice", "bob"]);
+        assert_eq!(config.welcome.as_deref(), Some("Be nice"));
+        assert_eq!(config.room_welcomes["standup"], "Keep it short!");
+        assert_eq!(config.tls_cert, Some(PathBuf::from("cert.pem")));
+        assert_eq!(config.tls_key, Some(PathBuf::from("key.pem")));
+        assert_eq!(
+            config.keepalive_interval,
+            ServerConfig::default().keepalive_interval
+        );
+        assert!(validate(&config).is_ok());
+
+        let unknown = toml::from_str::<ConfigFile>("prot = 8080");
+        assert!(unknown.is_err());
+        // Flags the file doesn't cover are refused like any unknown key
+        let flag_only = toml::from_str::<ConfigFile>(r#"admin_token = "secret""#);
+        assert!(flag_only.is_err());
+
+        let mut bad = ServerConfig {
+            rate_limit_per_sec: 0,
+            ..ServerConfig::default()
+        };
+        assert!(matches!(validate(&bad), Err(ChatError::ConfigError(_))));
+        bad.rate_limit_per_sec = 5;
+        bad.public_url = Some("chat.example.com".to_string());
+        assert!(matches!(validate(&bad), Err(ChatError::ConfigError(_))));
+        bad.public_url = None;
+        bad.tls_cert = Some(PathBuf::from("cert.pem"));
+        assert!(matches!(validate(&bad), Err(ChatError::ConfigError(_))));
+        bad.tls_cert = None;
+        bad.address = "localhost:80".to_string();
+        assert!(matches!(validate(&bad), Err(ChatError::ConfigError(_))));
+    }
+}
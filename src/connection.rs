@@ -0,0 +1,977 @@
+use futures::{sink::SinkExt, stream::StreamExt};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::protocol::Message as WsMessage;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async};
+
+use crate::client::Incoming;
+use crate::shared::{ClientMessage, Message, ServerMessage};
+use crate::signing::MessageSigner;
+
+/// Wait before the first attempt to reconnect after the connection drops
+pub const RECONNECT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest wait between two attempts to reconnect
+pub const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// How long a `/ping` waits for its pong before it's reported as lost
+pub const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most chats kept to resend until the server acknowledges them
+const MAX_UNACKED: usize = 256;
+
+/// An open WebSocket connection to the server
+pub type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Exponential backoff between reconnection attempts.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    /// Creates a backoff starting at `initial` and doubling up to `max`.
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            next: initial,
+        }
+    }
+
+    /// Returns how long to wait before the next attempt, and doubles the
+    /// wait after that one.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    /// Starts over from the initial delay, e.g. once a connection succeeds.
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(RECONNECT_INITIAL_DELAY, RECONNECT_MAX_DELAY)
+    }
+}
+
+/// Who to connect as, kept up to date so a reconnect picks up where the
+/// last connection left off.
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// WebSocket URL of the room, following moves to other rooms
+    pub url: String,
+    /// Our current name, following renames and server-assigned names
+    pub name: String,
+    /// Auth token sent with every `Connect`
+    pub token: Option<String>,
+    /// Password of the room in `url`, if it has one
+    pub room_password: Option<String>,
+    /// Log every frame sent and received to stderr
+    pub debug_protocol: bool,
+    /// Checks the signatures of chat messages; nothing is checked when `None`
+    pub signer: Option<MessageSigner>,
+    /// Secret sent with every `Connect` so our messages stay ours after a
+    /// reconnect
+    pub identity: Option<String>,
+}
+
+impl Session {
+    /// Follows name and room changes the server tells us about.
+    ///
+    /// `joining` is the room and password of the last `JoinRoom` sent, so
+    /// the password is kept once the server welcomes us there.
+    fn observe(&mut self, server_msg: &ServerMessage, joining: Option<&(String, Option<String>)>) {
+        match server_msg {
+            ServerMessage::Welcome { name, room, .. } => {
+                if !name.is_empty() {
+                    self.name = name.clone();
+                }
+                if !room.is_empty() {
+                    self.follow_room(room, joining);
+                }
+            }
+            ServerMessage::UserRenamed { old, new } if *old == self.name => self.name = new.clone(),
+            _ => {}
+        }
+    }
+
+    /// Points `url` at `room`, so a reconnect rejoins the room we're in
+    /// rather than the one we started in.
+    fn follow_room(&mut self, room: &str, joining: Option<&(String, Option<String>)>) {
+        let Ok(mut url) = reqwest::Url::parse(&self.url) else {
+            return;
+        };
+        if url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            == Some(room)
+        {
+            return;
+        }
+        let Ok(mut segments) = url.path_segments_mut() else {
+            return;
+        };
+        segments.pop().push(room);
+        drop(segments);
+        self.url = url.into();
+        self.room_password = joining
+            .filter(|(joined, _)| joined == room)
+            .and_then(|(_, password)| password.clone());
+    }
+}
+
+/// How a single connection came to an end.
+enum Disconnect {
+    /// The front end quit; there's nobody left to reconnect for
+    FrontEndGone,
+    /// The server turned us away for good, e.g. a kick, ban or bad token
+    Refused(String),
+    /// The connection dropped and is worth retrying
+    Dropped(String),
+}
+
+/// Returns whether the server closing the connection right after this
+/// message means it will refuse us again: a failed auth, kick or ban, a
+/// taken name, or the room closing. Being dropped for idling also ends it,
+/// since reconnecting would only keep an unused connection open.
+fn ends_session(server_msg: &ServerMessage) -> bool {
+    match server_msg {
+        ServerMessage::Error { code, .. } => matches!(code, 401 | 403 | 408 | 409),
+        ServerMessage::RoomClosed { .. } => true,
+        _ => false,
+    }
+}
+
+/// Describes the chat messages in `server_msg` whose signature doesn't
+/// match, or returns `None` if there are none.
+fn signature_warning(signer: &MessageSigner, server_msg: &ServerMessage) -> Option<String> {
+    match server_msg {
+        ServerMessage::Chat {
+            text,
+            sender,
+            id,
+            timestamp,
+            signature,
+            ..
+        } => {
+            if signer.verify(sender.as_deref(), text, *timestamp, signature.as_deref()) {
+                return None;
+            }
+            Some(match id {
+                Some(id) => format!("Warning: message #{} failed its signature check", id),
+                None => "Warning: the next message failed its signature check".to_string(),
+            })
+        }
+        ServerMessage::History { messages } => {
+            let failed = messages
+                .iter()
+                .filter(|message| !signer.verify_message(message))
+                .count();
+            (failed > 0).then(|| {
+                format!(
+                    "Warning: {} of these messages failed their signature check",
+                    failed
+                )
+            })
+        }
+        ServerMessage::RoomEvent { room, event } => {
+            signature_warning(signer, event).map(|warning| format!("{} (in {})", warning, room))
+        }
+        _ => None,
+    }
+}
+
+/// Describes why a frame that isn't a protocol message can't be trusted,
+/// or returns `None` if it's a chat message in the old format with a
+/// matching signature. Anything else could have come from whoever is
+/// between us and the server.
+fn text_signature_warning(signer: &MessageSigner, text: &str) -> Option<String> {
+    match serde_json::from_str::<Message>(text) {
+        Ok(message) if signer.verify_message(&message) => None,
+        Ok(_) => Some("Warning: the next message failed its signature check".to_string()),
+        Err(_) => Some("Warning: the next message isn't signed".to_string()),
+    }
+}
+
+/// Makes the link in an `Attachment` absolute, resolving one relative to the
+/// server against `ws_url`, the address we're connected to.
+fn resolve_attachment_url(ws_url: &str, server_msg: &mut ServerMessage) {
+    match server_msg {
+        ServerMessage::Attachment { url, .. } if url.starts_with('/') => {
+            if let Some(absolute) = http_url(ws_url, url) {
+                *url = absolute;
+            }
+        }
+        ServerMessage::RoomEvent { event, .. } => resolve_attachment_url(ws_url, event),
+        _ => {}
+    }
+}
+
+/// Resolves `path` against the WebSocket URL `ws_url`, switching to the
+/// matching HTTP scheme.
+fn http_url(ws_url: &str, path: &str) -> Option<String> {
+    let mut url = reqwest::Url::parse(ws_url).ok()?.join(path).ok()?;
+    let scheme = if url.scheme() == "wss" {
+        "https"
+    } else {
+        "http"
+    };
+    url.set_scheme(scheme).ok()?;
+    Some(url.into())
+}
+
+/// Runs the client's side of the connection, starting from the already open
+/// `stream`.
+///
+/// Messages from the front end arrive on `outgoing` and everything the server
+/// sends is forwarded on `events`. When the connection drops, it reconnects
+/// with `backoff`, reporting progress as `Incoming::Status`, sends `Connect`
+/// again, then any chats left unacknowledged and anything typed in the
+/// meantime. Returns once the front end
+/// quits (e.g. on Ctrl-C), or after an `Incoming::Closed` when the server
+/// ends the session.
+pub async fn run(
+    mut session: Session,
+    stream: WsStream,
+    mut backoff: Backoff,
+    mut outgoing: mpsc::UnboundedReceiver<ClientMessage>,
+    events: mpsc::UnboundedSender<Incoming>,
+) {
+    let mut pending = VecDeque::new();
+    let mut unacked = Unacked::default();
+    let mut stream = Some(stream);
+    loop {
+        let ws = match stream.take() {
+            Some(ws) => ws,
+            None => {
+                let delay = backoff.next_delay();
+                let status = format!("Reconnecting in {}s...", delay.as_secs_f64().ceil());
+                if events.send(Incoming::Status(status)).is_err()
+                    || !wait(delay, &mut outgoing, &mut pending).await
+                {
+                    return;
+                }
+                match connect_async(&session.url).await {
+                    Ok((ws, _)) => {
+                        backoff.reset();
+                        let _ = events.send(Incoming::Status("Reconnected".to_string()));
+                        ws
+                    }
+                    Err(_) => continue,
+                }
+            }
+        };
+
+        match pump(
+            &mut session,
+            ws,
+            &mut outgoing,
+            &mut pending,
+            &mut unacked,
+            &events,
+        )
+        .await
+        {
+            Disconnect::FrontEndGone => return,
+            Disconnect::Refused(reason) => {
+                let _ = events.send(Incoming::Closed(reason));
+                return;
+            }
+            Disconnect::Dropped(reason) => {
+                if events.send(Incoming::Status(reason)).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Waits out `delay`, queueing whatever the front end sends meanwhile.
+///
+/// Returns `false` if the front end quit while waiting.
+async fn wait(
+    delay: Duration,
+    outgoing: &mut mpsc::UnboundedReceiver<ClientMessage>,
+    pending: &mut VecDeque<ClientMessage>,
+) -> bool {
+    let sleep = tokio::time::sleep(delay);
+    tokio::pin!(sleep);
+    loop {
+        tokio::select! {
+            _ = &mut sleep => return true,
+            client_msg = outgoing.recv() => match client_msg {
+                Some(client_msg) => pending.push_back(client_msg),
+                None => return false,
+            },
+        }
+    }
+}
+
+/// Introduces us on `ws` and relays messages both ways until it ends.
+///
+/// Pings are timed from when they go out on this connection, so one queued
+/// while disconnected isn't charged for the outage. Their pongs are
+/// reported as `Incoming::Latency` rather than passed on.
+///
+/// With an identity set, chats the last connection never saw acknowledged
+/// are sent again first; the server recognises their keys and acknowledges
+/// any it already stored without posting them twice.
+async fn pump(
+    session: &mut Session,
+    ws: WsStream,
+    outgoing: &mut mpsc::UnboundedReceiver<ClientMessage>,
+    pending: &mut VecDeque<ClientMessage>,
+    unacked: &mut Unacked,
+    events: &mpsc::UnboundedSender<Incoming>,
+) -> Disconnect {
+    let (mut ws_sender, mut ws_receiver) = ws.split();
+    let lost = || Disconnect::Dropped("Lost connection to server".to_string());
+
+    let mut pings = Pings::default();
+    // Room and password of the last `JoinRoom`, until we're welcomed there
+    let mut joining = None;
+
+    let connect = ClientMessage::connect(
+        session.name.clone(),
+        session.token.clone(),
+        session.room_password.clone(),
+    )
+    .with_identity(session.identity.clone());
+    let debug = session.debug_protocol;
+    if send(&mut ws_sender, &connect, debug).await.is_err() {
+        return lost();
+    }
+    // Without an identity the server can't tell a resend is ours
+    if session.identity.is_some() {
+        for client_msg in unacked.take().into_iter().rev() {
+            pending.push_front(client_msg);
+        }
+    }
+    while let Some(client_msg) = pending.pop_front() {
+        if send(&mut ws_sender, &client_msg, debug).await.is_err() {
+            pending.push_front(client_msg);
+            return lost();
+        }
+        pings.sent(&client_msg);
+        unacked.sent(&client_msg);
+        joining = joined_room(&client_msg).or(joining);
+    }
+
+    let mut refused = false;
+    loop {
+        let next_expiry = pings.next_expiry();
+        tokio::select! {
+            _ = tokio::time::sleep_until(next_expiry.unwrap_or_else(Instant::now).into()),
+                if next_expiry.is_some() =>
+            {
+                for _ in 0..pings.expire(Instant::now()) {
+                    if events.send(Incoming::Latency(None)).is_err() {
+                        return Disconnect::FrontEndGone;
+                    }
+                }
+            }
+            frame = ws_receiver.next() => {
+                let event = match frame {
+                    Some(Ok(WsMessage::Text(text))) => {
+                        if debug {
+                            eprintln!("{}", frame_log("<-", &text));
+                        }
+                        match serde_json::from_str::<ServerMessage>(&text) {
+                            Ok(ServerMessage::Pong { nonce }) => match pings.answered(nonce) {
+                                Some(rtt) => Incoming::Latency(Some(rtt)),
+                                // Already reported as lost
+                                None => continue,
+                            },
+                            Ok(mut server_msg) => {
+                                resolve_attachment_url(&session.url, &mut server_msg);
+                                refused = ends_session(&server_msg);
+                                session.observe(&server_msg, joining.as_ref());
+                                if let ServerMessage::Ack {
+                                    client_msg_id: Some(key),
+                                    ..
+                                } = &server_msg
+                                {
+                                    unacked.acked(key);
+                                }
+                                // Flagged just ahead of the message itself
+                                let warning = session
+                                    .signer
+                                    .as_ref()
+                                    .and_then(|signer| signature_warning(signer, &server_msg));
+                                if let Some(warning) = warning
+                                    && events.send(Incoming::Status(warning)).is_err()
+                                {
+                                    return Disconnect::FrontEndGone;
+                                }
+                                Incoming::Server(server_msg)
+                            }
+                            // Fallback for old message format, which is
+                            // only trusted when signed like any other
+                            Err(_) => {
+                                let warning = session
+                                    .signer
+                                    .as_ref()
+                                    .and_then(|signer| text_signature_warning(signer, &text));
+                                if let Some(warning) = warning
+                                    && events.send(Incoming::Status(warning)).is_err()
+                                {
+                                    return Disconnect::FrontEndGone;
+                                }
+                                Incoming::Text(text.to_string())
+                            }
+                        }
+                    }
+                    Some(Ok(WsMessage::Close(_))) if refused => {
+                        return Disconnect::Refused("Server closed connection".to_string());
+                    }
+                    Some(Ok(WsMessage::Close(_))) | None => {
+                        return Disconnect::Dropped("Server closed connection".to_string());
+                    }
+                    Some(Err(e)) => return Disconnect::Dropped(format!("WebSocket error: {}", e)),
+                    Some(Ok(_)) => continue,
+                };
+                if events.send(event).is_err() {
+                    return Disconnect::FrontEndGone;
+                }
+            }
+            client_msg = outgoing.recv() => {
+                let Some(client_msg) = client_msg else {
+                    return Disconnect::FrontEndGone;
+                };
+                if send(&mut ws_sender, &client_msg, debug).await.is_err() {
+                    pending.push_back(client_msg);
+                    return lost();
+                }
+                pings.sent(&client_msg);
+                unacked.sent(&client_msg);
+                joining = joined_room(&client_msg).or(joining);
+            }
+        }
+    }
+}
+
+/// Returns the room and password `client_msg` asks to join, if it's a
+/// `JoinRoom`.
+fn joined_room(client_msg: &ClientMessage) -> Option<(String, Option<String>)> {
+    match client_msg {
+        ClientMessage::JoinRoom { room, password } => Some((room.clone(), password.clone())),
+        _ => None,
+    }
+}
+
+/// Keyed chats sent but not yet acknowledged, oldest first.
+#[derive(Debug, Default)]
+struct Unacked {
+    chats: VecDeque<ClientMessage>,
+}
+
+impl Unacked {
+    /// Holds on to `client_msg` if it's a chat with a key, dropping the
+    /// oldest beyond `MAX_UNACKED`.
+    fn sent(&mut self, client_msg: &ClientMessage) {
+        if let ClientMessage::Chat {
+            client_msg_id: Some(_),
+            ..
+        } = client_msg
+        {
+            self.chats.push_back(client_msg.clone());
+            if self.chats.len() > MAX_UNACKED {
+                self.chats.pop_front();
+            }
+        }
+    }
+
+    /// Lets go of the chat with `key`, and of any sent before it, since the
+    /// server acknowledges in order.
+    fn acked(&mut self, key: &str) {
+        if let Some(pos) = self.chats.iter().position(
+            |chat| matches!(chat, ClientMessage::Chat { client_msg_id: Some(k), .. } if k == key),
+        ) {
+            self.chats.drain(..=pos);
+        }
+    }
+
+    /// Returns every chat still waiting, oldest first, forgetting them.
+    fn take(&mut self) -> VecDeque<ClientMessage> {
+        std::mem::take(&mut self.chats)
+    }
+}
+
+/// Pings sent on one connection that are still waiting for their pong.
+#[derive(Debug, Default)]
+struct Pings {
+    waiting: HashMap<u64, Instant>,
+}
+
+impl Pings {
+    /// Starts timing `client_msg` if it's a ping.
+    fn sent(&mut self, client_msg: &ClientMessage) {
+        if let ClientMessage::Ping { nonce } = client_msg {
+            self.waiting.insert(*nonce, Instant::now());
+        }
+    }
+
+    /// Returns the round trip of the ping with `nonce`, or `None` if it
+    /// isn't one we're waiting for.
+    fn answered(&mut self, nonce: u64) -> Option<Duration> {
+        self.waiting.remove(&nonce).map(|sent| sent.elapsed())
+    }
+
+    /// Returns when the oldest ping runs out of time, if any are waiting.
+    fn next_expiry(&self) -> Option<Instant> {
+        self.waiting.values().min().map(|sent| *sent + PING_TIMEOUT)
+    }
+
+    /// Gives up on pings that have waited `PING_TIMEOUT` by `now`, returning
+    /// how many there were.
+    fn expire(&mut self, now: Instant) -> usize {
+        let before = self.waiting.len();
+        self.waiting
+            .retain(|_, sent| now.duration_since(*sent) < PING_TIMEOUT);
+        before - self.waiting.len()
+    }
+}
+
+/// Sends `client_msg`, logging it to stderr first when `debug` is set.
+async fn send(
+    ws_sender: &mut futures::stream::SplitSink<WsStream, WsMessage>,
+    client_msg: &ClientMessage,
+    debug: bool,
+) -> Result<(), tokio_tungstenite::tungstenite::Error> {
+    let json = serde_json::to_string(client_msg).expect("Failed to serialize client message");
+    if debug {
+        eprintln!("{}", frame_log("->", &json));
+    }
+    ws_sender.send(WsMessage::Text(json.into())).await
+}
+
+/// Fields of a frame that hold secrets, blanked out of `--debug-protocol` logs
+const SECRET_FIELDS: [&str; 3] = ["token", "room_password", "password"];
+
+/// Formats a frame for `--debug-protocol`: the direction arrow, `->` for
+/// sent and `<-` for received, then the frame, pretty-printed if it's JSON.
+/// Tokens and passwords are replaced with `<redacted>`, since the log is
+/// often kept in a file.
+fn frame_log(arrow: &str, text: &str) -> String {
+    let pretty = serde_json::from_str::<serde_json::Value>(text)
+        .and_then(|mut value| {
+            redact_secrets(&mut value);
+            serde_json::to_string_pretty(&value)
+        })
+        .unwrap_or_else(|_| text.to_string());
+    format!("{} {}", arrow, pretty)
+}
+
+/// Replaces every non-null `SECRET_FIELDS` value in `value`, at any depth.
+fn redact_secrets(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (key, field) in fields.iter_mut() {
+                if SECRET_FIELDS.contains(&key.as_str()) && !field.is_null() {
+                    *field = serde_json::Value::from("<redacted>");
+                } else {
+                    redact_secrets(field);
+                }
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(redact_secrets),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::Message;
+
+    #[test]
+    fn test_frame_log_pretty_prints_json() {
+        assert_eq!(
+            frame_log("->", r#"{"type":"Chat","text":"hi"}"#),
+            "-> {\n  \"text\": \"hi\",\n  \"type\": \"Chat\"\n}"
+        );
+        // Frames in the old plain-text format are shown as they are
+        assert_eq!(frame_log("<-", "Alice: hi"), "<- Alice: hi");
+    }
+
+    #[test]
+    fn test_relative_attachment_links_point_at_the_server() {
+        let attachment = |url: &str| ServerMessage::Attachment {
+            name: "cat.png".to_string(),
+            url: url.to_string(),
+            content_type: "image/png".to_string(),
+            size: 1,
+        };
+        let resolved = |ws_url: &str, mut server_msg: ServerMessage| {
+            resolve_attachment_url(ws_url, &mut server_msg);
+            match server_msg {
+                ServerMessage::Attachment { url, .. } => url,
+                ServerMessage::RoomEvent { event, .. } => match *event {
+                    ServerMessage::Attachment { url, .. } => url,
+                    other => panic!("Expected an attachment, got {:?}", other),
+                },
+                other => panic!("Expected an attachment, got {:?}", other),
+            }
+        };
+
+        assert_eq!(
+            resolved("ws://127.0.0.1:12345/room/1", attachment("/files/abc")),
+            "http://127.0.0.1:12345/files/abc"
+        );
+        assert_eq!(
+            resolved("wss://chat.example.com/room/1", attachment("/files/abc")),
+            "https://chat.example.com/files/abc"
+        );
+        let watched = ServerMessage::RoomEvent {
+            room: "2".to_string(),
+            event: Box::new(attachment("/files/abc")),
+        };
+        assert_eq!(
+            resolved("ws://127.0.0.1:12345/room/1", watched),
+            "http://127.0.0.1:12345/files/abc"
+        );
+        // Links the server made absolute are left alone
+        assert_eq!(
+            resolved(
+                "ws://127.0.0.1:12345/room/1",
+                attachment("https://cdn.example.com/files/abc")
+            ),
+            "https://cdn.example.com/files/abc"
+        );
+    }
+
+    #[test]
+    fn test_frame_log_redacts_secrets() {
+        let connect = serde_json::to_string(&ClientMessage::connect(
+            "Alice".to_string(),
+            Some("letmein".to_string()),
+            Some("open sesame".to_string()),
+        ))
+        .unwrap();
+        let join = serde_json::to_string(&ClientMessage::JoinRoom {
+            room: "vault".to_string(),
+            password: Some("open sesame".to_string()),
+        })
+        .unwrap();
+        for frame in [connect, join] {
+            let logged = frame_log("->", &frame);
+            assert!(!logged.contains("letmein"), "{}", logged);
+            assert!(!logged.contains("open sesame"), "{}", logged);
+            assert!(logged.contains("<redacted>"), "{}", logged);
+        }
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_cap_and_resets() {
+        let mut backoff = Backoff::default();
+        let delays: Vec<u64> = (0..7).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 30, 30]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), RECONNECT_INITIAL_DELAY);
+
+        assert!(ends_session(&ServerMessage::error(403, "You were kicked")));
+        assert!(ends_session(&ServerMessage::error(
+            408,
+            "Disconnected: idle for too long"
+        )));
+        assert!(!ends_session(&ServerMessage::error(
+            503,
+            "Disconnected: too slow"
+        )));
+        assert!(!ends_session(&ServerMessage::ServerShutdown));
+    }
+
+    #[test]
+    fn test_chats_are_held_until_acknowledged() {
+        let chat = |key: Option<&str>| ClientMessage::Chat {
+            text: "hi".to_string(),
+            client_msg_id: key.map(str::to_string),
+        };
+        let mut unacked = Unacked::default();
+        unacked.sent(&chat(Some("a")));
+        unacked.sent(&chat(None));
+        unacked.sent(&ClientMessage::Ping { nonce: 1 });
+        unacked.sent(&chat(Some("b")));
+        unacked.sent(&chat(Some("c")));
+
+        // Acknowledging "b" implies "a" was stored too
+        unacked.acked("b");
+        let left: Vec<_> = unacked
+            .take()
+            .into_iter()
+            .map(|chat| match chat {
+                ClientMessage::Chat { client_msg_id, .. } => client_msg_id,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(left, vec![Some("c".to_string())]);
+        assert!(unacked.take().is_empty());
+    }
+
+    #[test]
+    fn test_pings_are_matched_by_nonce_and_expire() {
+        let mut pings = Pings::default();
+        assert_eq!(pings.next_expiry(), None);
+        pings.sent(&ClientMessage::Chat {
+            text: "not a ping".to_string(),
+            client_msg_id: None,
+        });
+        assert_eq!(pings.next_expiry(), None);
+
+        pings.sent(&ClientMessage::Ping { nonce: 1 });
+        pings.sent(&ClientMessage::Ping { nonce: 2 });
+        assert!(pings.answered(1).is_some());
+        assert_eq!(pings.answered(1), None);
+        assert_eq!(pings.answered(99), None);
+
+        let expiry = pings.next_expiry().unwrap();
+        assert_eq!(pings.expire(expiry - Duration::from_millis(1)), 0);
+        assert_eq!(pings.expire(expiry), 1);
+        assert_eq!(pings.answered(2), None);
+    }
+
+    #[test]
+    fn test_tampered_messages_are_flagged() {
+        let signer = MessageSigner::new("s3cret");
+        let mut message = Message::chat_message("Alice", "pay Bob 5");
+        message.id = Some(7);
+        message.timestamp = Some(1_700_000_000);
+        signer.sign_message(&mut message);
+        assert_eq!(
+            signature_warning(&signer, &ServerMessage::chat(&message)),
+            None
+        );
+
+        message.text = "pay Mallory 500".to_string();
+        assert_eq!(
+            signature_warning(&signer, &ServerMessage::chat(&message)).as_deref(),
+            Some("Warning: message #7 failed its signature check")
+        );
+        let history = ServerMessage::History {
+            messages: vec![message.clone(), Message::new("unsigned".to_string())],
+        };
+        assert_eq!(
+            signature_warning(&signer, &history).as_deref(),
+            Some("Warning: 2 of these messages failed their signature check")
+        );
+        assert_eq!(
+            signature_warning(
+                &signer,
+                &ServerMessage::UserJoined {
+                    name: "Bob".to_string()
+                }
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_unsigned_text_frames_are_flagged() {
+        let signer = MessageSigner::new("s3cret");
+        let mut message = Message::chat_message("Alice", "hi");
+        message.timestamp = Some(1_700_000_000);
+        signer.sign_message(&mut message);
+        let signed = serde_json::to_string(&message).unwrap();
+        assert_eq!(text_signature_warning(&signer, &signed), None);
+
+        message.text = "bye".to_string();
+        let tampered = serde_json::to_string(&message).unwrap();
+        assert_eq!(
+            text_signature_warning(&signer, &tampered).as_deref(),
+            Some("Warning: the next message failed its signature check")
+        );
+        assert_eq!(
+            text_signature_warning(&signer, "Mallory: trust me").as_deref(),
+            Some("Warning: the next message isn't signed")
+        );
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_reconnects_after_connection_drops() {
+        use crate::server::{self, AppState, ServerConfig};
+        use crate::shared::DEFAULT_ROOM;
+
+        let state = AppState::with_config(ServerConfig {
+            admin_token: Some("secret".to_string()),
+            ..ServerConfig::default()
+        });
+        let addr = server::spawn_local(state.clone()).await.unwrap();
+        let session = Session {
+            url: format!("ws://{}/room/{}", addr, DEFAULT_ROOM),
+            name: "Alice".to_string(),
+            token: None,
+            room_password: None,
+            debug_protocol: false,
+            signer: None,
+            identity: Some("alice-secret".to_string()),
+        };
+        let (stream, _) = connect_async(&session.url).await.unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        let backoff = Backoff::new(Duration::from_millis(50), Duration::from_millis(200));
+        let task = tokio::spawn(run(session, stream, backoff, rx, events_tx));
+
+        let mut next_event = async || {
+            tokio::time::timeout(Duration::from_secs(2), events.recv())
+                .await
+                .unwrap()
+                .unwrap()
+        };
+        while !matches!(
+            next_event().await,
+            Incoming::Server(ServerMessage::Welcome { .. })
+        ) {}
+        tx.send(ClientMessage::Chat {
+            text: "before the drop".to_string(),
+            client_msg_id: None,
+        })
+        .unwrap();
+        let first_id = loop {
+            if let Incoming::Server(ServerMessage::Ack { id, .. }) = next_event().await {
+                break id;
+            }
+        };
+
+        // Drop the connection from the server side
+        let id = state.users.lock().unwrap().keys().next().unwrap().clone();
+        let response = reqwest::Client::new()
+            .delete(format!("http://{}/admin/connections/{}", addr, id))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NO_CONTENT);
+
+        // Typed while disconnected; sent once the connection is back
+        while !matches!(next_event().await, Incoming::Status(status) if status.starts_with("Reconnecting"))
+        {
+        }
+        tx.send(ClientMessage::Chat {
+            text: "still here".to_string(),
+            client_msg_id: None,
+        })
+        .unwrap();
+        while !matches!(next_event().await, Incoming::Status(status) if status == "Reconnected") {}
+        while !matches!(
+            next_event().await,
+            Incoming::Server(ServerMessage::Ack { .. })
+        ) {}
+        let names: Vec<String> = state
+            .users
+            .lock()
+            .unwrap()
+            .values()
+            .map(|user| user.name.clone())
+            .collect();
+        assert_eq!(names, vec!["Alice".to_string()]);
+
+        // The identity keeps what we said before the drop ours to delete
+        tx.send(ClientMessage::Delete { id: first_id }).unwrap();
+        while !matches!(
+            next_event().await,
+            Incoming::Server(ServerMessage::MessageDeleted { id }) if id == first_id
+        ) {}
+
+        // Quitting the front end stops the connection
+        drop(tx);
+        tokio::time::timeout(Duration::from_secs(2), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_reconnects_to_the_room_it_moved_to() {
+        use crate::password::PasswordHash;
+        use crate::server::{self, AppState, RoomState, ServerConfig};
+        use crate::shared::DEFAULT_ROOM;
+
+        let state = AppState::with_config(ServerConfig {
+            admin_token: Some("secret".to_string()),
+            ..ServerConfig::default()
+        });
+        let mut vault = RoomState::default();
+        vault.password = Some(PasswordHash::new("hunter2"));
+        state
+            .rooms
+            .lock()
+            .unwrap()
+            .insert("vault".to_string(), vault);
+        let addr = server::spawn_local(state.clone()).await.unwrap();
+        let session = Session {
+            url: format!("ws://{}/room/{}", addr, DEFAULT_ROOM),
+            name: "Alice".to_string(),
+            token: None,
+            room_password: None,
+            debug_protocol: false,
+            signer: None,
+            identity: None,
+        };
+        let (stream, _) = connect_async(&session.url).await.unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        let backoff = Backoff::new(Duration::from_millis(50), Duration::from_millis(200));
+        let task = tokio::spawn(run(session, stream, backoff, rx, events_tx));
+
+        let mut next_event = async || {
+            tokio::time::timeout(Duration::from_secs(2), events.recv())
+                .await
+                .unwrap()
+                .unwrap()
+        };
+        tx.send(ClientMessage::JoinRoom {
+            room: "vault".to_string(),
+            password: Some("hunter2".to_string()),
+        })
+        .unwrap();
+        while !matches!(
+            next_event().await,
+            Incoming::Server(ServerMessage::Welcome { room, .. }) if room == "vault"
+        ) {}
+
+        let id = state.users.lock().unwrap().keys().next().unwrap().clone();
+        let response = reqwest::Client::new()
+            .delete(format!("http://{}/admin/connections/{}", addr, id))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NO_CONTENT);
+
+        // Back in the room we moved to, with its password, not the one we
+        // started in
+        while !matches!(next_event().await, Incoming::Status(status) if status == "Reconnected") {}
+        let room = loop {
+            if let Incoming::Server(ServerMessage::Welcome { room, .. }) = next_event().await {
+                break room;
+            }
+        };
+        assert_eq!(room, "vault");
+        let rooms: Vec<String> = state
+            .users
+            .lock()
+            .unwrap()
+            .values()
+            .map(|user| user.room.clone())
+            .collect();
+        assert_eq!(rooms, vec!["vault".to_string()]);
+
+        drop(tx);
+        tokio::time::timeout(Duration::from_secs(2), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
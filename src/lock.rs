@@ -0,0 +1,49 @@
+use std::sync::{Mutex, MutexGuard};
+
+/// Locking that survives a panic in another task.
+///
+/// A `std::sync::Mutex` is poisoned when a thread panics while holding it,
+/// and every later `lock().unwrap()` panics in turn, so one failing handler
+/// would take the rest of the server down with it. The shared state behind
+/// these locks stays usable after such a panic, so the server carries on.
+pub trait LockExt<T> {
+    /// Locks the mutex, recovering the guard and clearing the poison if a
+    /// previous holder panicked.
+    fn lock_or_recover(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> LockExt<T> for Mutex<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(|poisoned| {
+            eprintln!("Warning: recovering a lock poisoned by a panicked handler");
+            let guard = poisoned.into_inner();
+            self.clear_poison();
+            guard
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_lock_recovers_after_holder_panics() {
+        let users = Arc::new(Mutex::new(vec!["Alice".to_string()]));
+
+        let holder = users.clone();
+        let result = std::thread::spawn(move || {
+            let mut guard = holder.lock().unwrap();
+            guard.push("Bob".to_string());
+            panic!("handler failed while holding the lock");
+        })
+        .join();
+        assert!(result.is_err());
+        assert!(users.is_poisoned());
+
+        // The data written before the panic is kept and the poison cleared
+        assert_eq!(*users.lock_or_recover(), vec!["Alice", "Bob"]);
+        assert!(!users.is_poisoned());
+    }
+}
@@ -1,12 +1,83 @@
+// Shared state lives behind std mutexes; a guard held across an `.await`
+// blocks the executor thread and can deadlock against other tasks, so
+// every guard must be dropped before the next `.await`
+#![deny(clippy::await_holding_lock)]
+// Server helpers fail with the `ServerMessage` to send back; it's large, but
+// only built on the error path
+#![allow(clippy::result_large_err)]
+
+#[cfg(feature = "client")]
 extern crate reqwest;
+#[cfg(feature = "client")]
 extern crate rustyline;
+#[cfg(feature = "client")]
 extern crate term;
 
 use clap::{Parser, Subcommand};
+#[cfg(any(feature = "server", feature = "client"))]
+use std::path::PathBuf;
+#[cfg(feature = "server")]
+use std::time::Duration;
+
+#[cfg(feature = "client")]
+use crate::alert::{MentionAlert, QuietHours};
+#[cfg(feature = "server")]
+use crate::blocklist::{Blocklist, BlocklistMode};
+#[cfg(feature = "server")]
+use crate::config::ConfigFile;
+#[cfg(feature = "server")]
+use crate::outbound::OverflowPolicy;
+#[cfg(feature = "server")]
+use crate::server::{DuplicateNamePolicy, PinOverflowPolicy, TopicPolicy};
+#[cfg(feature = "server")]
+use crate::storage::FlushPolicy;
 
+#[cfg(feature = "client")]
+mod alert;
+#[cfg(feature = "server")]
+mod blocklist;
+#[cfg(feature = "client")]
 mod client;
+#[cfg(feature = "client")]
+mod client_tui;
+#[cfg(feature = "client")]
+mod completion;
+#[cfg(feature = "server")]
+mod config;
+#[cfg(feature = "client")]
+mod connection;
+#[cfg(feature = "server")]
+mod lock;
+#[cfg(feature = "server")]
+mod metrics;
+#[cfg(feature = "server")]
+mod outbound;
+#[cfg(feature = "server")]
+mod password;
+// Shared protocol types include the server's per-user state, which a
+// client-only build doesn't use
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+mod rate_limit;
+#[cfg(all(feature = "server", feature = "client"))]
+mod selftest;
+#[cfg(feature = "server")]
 mod server;
+#[cfg(feature = "server")]
+mod server_tui;
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
 mod shared;
+// The server only signs and the client only verifies
+#[cfg(any(feature = "server", feature = "client"))]
+#[cfg_attr(not(all(feature = "server", feature = "client")), allow(dead_code))]
+mod signing;
+#[cfg(feature = "server")]
+mod storage;
+#[cfg(feature = "client")]
+mod theme;
+#[cfg(feature = "client")]
+mod transcript;
+#[cfg(feature = "server")]
+mod uploads;
 
 #[derive(Parser)]
 #[command(name = "chat")]
@@ -17,35 +88,372 @@ struct Cli {
 }
 
 #[derive(Subcommand)]
+// Parsed once at startup, so the size of the stubs for disabled commands doesn't matter
+#[allow(clippy::large_enum_variant)]
 enum Commands {
     /// Start chat server
+    #[cfg(feature = "server")]
     Server {
-        /// Listen address (default: 127.0.0.1)
-        #[arg(short, long, default_value = "127.0.0.1")]
-        address: String,
+        /// Read settings from this TOML file; flags given here take precedence
+        #[arg(long)]
+        config: Option<PathBuf>,
+
+        /// Listen address (default: $CHAT_BIND_ADDR or 127.0.0.1)
+        #[arg(short, long)]
+        address: Option<String>,
+
+        /// Listen port (default: $CHAT_PORT or 12345)
+        #[arg(short, long)]
+        port: Option<u16>,
 
-        /// Listen port (default: 12345)
-        #[arg(short, long, default_value_t = 12345)]
-        port: u16,
+        /// Serve HTTPS and WSS with this PEM certificate chain (needs --tls-key)
+        #[arg(long)]
+        tls_cert: Option<PathBuf>,
+
+        /// PEM private key for --tls-cert
+        #[arg(long)]
+        tls_key: Option<PathBuf>,
 
         /// Enable TUI interface
         #[arg(long, default_value_t = false)]
         tui: bool,
+
+        /// Persist chat history to this file (JSON Lines)
+        #[arg(long)]
+        persist: Option<PathBuf>,
+
+        /// Flush persisted messages after this many are pending (default: 10)
+        #[arg(long, default_value_t = 10)]
+        flush_every: usize,
+
+        /// Flush persisted messages at least this often, in seconds (default: 5)
+        #[arg(long, default_value_t = 5)]
+        flush_interval_secs: u64,
+
+        /// Bearer token for /admin endpoints (admin endpoints disabled if unset)
+        #[arg(long)]
+        admin_token: Option<String>,
+
+        /// Maximum message length in characters (default: 4096)
+        #[arg(long, default_value_t = 4096)]
+        max_message_len: usize,
+
+        /// Record client IP addresses in the admin user list
+        #[arg(long, default_value_t = false)]
+        record_ips: bool,
+
+        /// Serve Prometheus metrics at /metrics
+        #[arg(long, default_value_t = false)]
+        metrics: bool,
+
+        /// Messages kept per room; 0 keeps up to a hard cap of one million (default: 1000)
+        #[arg(long)]
+        max_messages: Option<usize>,
+
+        /// Recent messages replayed to a client joining a room; 0 replays none (default: 50)
+        #[arg(long, default_value_t = 50)]
+        join_backlog: usize,
+
+        /// Maximum messages per second from a single client (default: 5)
+        #[arg(long)]
+        rate_limit_per_sec: Option<u32>,
+
+        /// Ping idle clients this often, in seconds; unresponsive ones are dropped (default: 30)
+        #[arg(long, default_value_t = 30)]
+        keepalive_secs: u64,
+
+        /// Require clients to present this token to join rooms or post messages
+        #[arg(long)]
+        auth_token: Option<String>,
+
+        /// Clients presenting this token join as moderators and may kick users and pin messages
+        #[arg(long)]
+        moderator_token: Option<String>,
+
+        /// File used by the /admin/snapshot and /admin/restore endpoints
+        #[arg(long)]
+        snapshot_path: Option<PathBuf>,
+
+        /// Pass control characters (e.g. ANSI escapes) in messages through unchanged
+        #[arg(long, default_value_t = false)]
+        allow_control_chars: bool,
+
+        /// Accept messages that are empty or only whitespace instead of rejecting them
+        #[arg(long, default_value_t = false)]
+        allow_empty: bool,
+
+        /// Comma-separated names kept for admins; joining as one requires --admin-token
+        #[arg(long, value_delimiter = ',')]
+        admins: Vec<String>,
+
+        /// Seconds a kicked name must wait before rejoining; 0 disables bans (default: 300)
+        #[arg(long, default_value_t = 300)]
+        ban_secs: u64,
+
+        /// Seconds without sending a message before a user is marked away; 0 disables (default: 300)
+        #[arg(long, visible_alias = "auto-away-secs", default_value_t = 300)]
+        away_secs: u64,
+
+        /// Seconds a client may send nothing before it is disconnected, with a warning first; 0 disables (default: 0)
+        #[arg(long, default_value_t = 0)]
+        idle_timeout_secs: u64,
+
+        /// Name shown to clients and reported by /version and /healthz (default: rust-chat)
+        #[arg(long, default_value = crate::shared::DEFAULT_SERVER_NAME)]
+        server_name: String,
+
+        /// Messages queued for a single client before it counts as too slow (default: 256)
+        #[arg(long, default_value_t = crate::outbound::DEFAULT_OUTBOUND_CAPACITY)]
+        outbound_capacity: usize,
+
+        /// What to do with a client whose queue is full: disconnect or drop-oldest
+        #[arg(long, default_value = "disconnect")]
+        overflow_policy: OverflowPolicy,
+
+        /// When a name is taken: suffix (register as e.g. Alice_2) or reject
+        #[arg(long, default_value = "suffix")]
+        dedupe_names: DuplicateNamePolicy,
+
+        /// Who may change a room's topic: anyone or moderators
+        #[arg(long, default_value = "anyone")]
+        topic_policy: TopicPolicy,
+
+        /// Keep files uploaded to rooms in this directory (uploads disabled if unset)
+        #[arg(long)]
+        upload_dir: Option<PathBuf>,
+
+        /// Largest file accepted for upload, in bytes (default: 10485760)
+        #[arg(long, default_value_t = crate::uploads::DEFAULT_MAX_UPLOAD_SIZE)]
+        max_upload_size: usize,
+
+        /// URL the server is reached at from outside, e.g. https://chat.example.com, used in links to uploaded files (default: links relative to the server)
+        #[arg(long)]
+        public_url: Option<String>,
+
+        /// Comma-separated content types accepted for upload (default: common images, PDF and plain text)
+        #[arg(long, value_delimiter = ',')]
+        upload_types: Vec<String>,
+
+        /// Require clients to give this password to join the default room
+        #[arg(long)]
+        room_password: Option<String>,
+
+        /// Filter messages against the words or regexes in this file, one per line
+        #[arg(long)]
+        blocklist: Option<PathBuf>,
+
+        /// What to do with messages matching the blocklist: reject or mask
+        #[arg(long, default_value = "reject")]
+        blocklist_mode: BlocklistMode,
+
+        /// Enable POST /hooks/ROOM for a room, given as ROOM=TOKEN; repeat for more rooms
+        #[arg(long = "hook-token", value_parser = parse_hook_token)]
+        hook_tokens: Vec<(String, String)>,
+
+        /// Greeting shown only to each client joining a room, e.g. the rules or topic
+        #[arg(long)]
+        welcome: Option<String>,
+
+        /// Greeting for one room instead of --welcome, given as ROOM=TEXT; repeat for more rooms
+        #[arg(long = "room-welcome", value_parser = parse_room_welcome)]
+        room_welcomes: Vec<(String, String)>,
+
+        /// Sign stored messages with an HMAC-SHA256 under this key, so clients given the same key can detect tampering
+        #[arg(long)]
+        hmac_key: Option<String>,
+
+        /// Most messages pinned in a room at once; 0 allows any (default: 10)
+        #[arg(long, default_value_t = crate::server::DEFAULT_MAX_PINS)]
+        max_pins: usize,
+
+        /// What pinning does in a room already at --max-pins: reject or evict-oldest
+        #[arg(long, default_value = "reject")]
+        pin_overflow: PinOverflowPolicy,
+
+        /// Most different emoji one message may carry; 0 allows any (default: 20)
+        #[arg(long, default_value_t = crate::server::DEFAULT_MAX_DISTINCT_REACTIONS)]
+        max_distinct_reactions: usize,
+
+        /// Most reactions one message may carry, counting every user's; 0 allows any (default: 200)
+        #[arg(long, default_value_t = crate::server::DEFAULT_MAX_REACTIONS)]
+        max_reactions: usize,
+
+        /// Most WebSocket connections open at once; further ones are refused. 0 allows any (default: 0)
+        #[arg(long, default_value_t = 0)]
+        max_connections: usize,
+
+        /// Most WebSocket connections open at once from one IP address; 0 allows any (default: 0)
+        #[arg(long, default_value_t = 0)]
+        max_connections_per_ip: usize,
     },
+    /// Start chat server (not included in this build)
+    #[cfg(not(feature = "server"))]
+    #[command(disable_help_flag = true)]
+    Server {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, hide = true)]
+        args: Vec<String>,
+    },
+    /// Start a server and client in-process and check a message round-trip
+    Selftest,
     /// Connect to chat server
+    #[cfg(feature = "client")]
     Client {
-        /// Server address (default: 127.0.0.1)
-        #[arg(short, long, default_value = "127.0.0.1")]
-        address: String,
+        /// Server address (default: $CHAT_SERVER_ADDR or 127.0.0.1)
+        #[arg(short, long)]
+        address: Option<String>,
 
-        /// Server port (default: 12345)
-        #[arg(short, long, default_value_t = 12345)]
-        port: u16,
+        /// Server port (default: $CHAT_SERVER_PORT or 12345)
+        #[arg(short, long)]
+        port: Option<u16>,
 
-        /// Your chat name (optional, random if not provided)
+        /// Your chat name (default: $CHAT_NAME, or random if unset)
         #[arg(long)]
         name: Option<String>,
+
+        /// Room to join (default: 1)
+        #[arg(long, default_value = crate::shared::DEFAULT_ROOM)]
+        room: String,
+
+        /// Mirror incoming messages as plain text to this file or FIFO (readline mode only)
+        #[arg(long)]
+        tee: Option<PathBuf>,
+
+        /// Use the full-screen terminal interface
+        #[arg(long, default_value_t = false)]
+        tui: bool,
+
+        /// Auth token for servers started with --auth-token
+        #[arg(long)]
+        token: Option<String>,
+
+        /// Password of the room, if it has one
+        #[arg(long)]
+        room_password: Option<String>,
+
+        /// Ring the terminal bell when someone mentions your name
+        #[arg(long, default_value_t = false)]
+        bell_on_mention: bool,
+
+        /// Don't ring the bell during these UTC hours, e.g. 22-7
+        #[arg(long)]
+        quiet_hours: Option<QuietHours>,
+
+        /// Show a desktop notification when someone mentions your name
+        /// (needs a build with the `notifications` feature)
+        #[arg(long, default_value_t = false)]
+        notifications: bool,
+
+        /// File to keep prompt history in (default: ~/.rust-chat-history)
+        #[arg(long)]
+        history_path: Option<PathBuf>,
+
+        /// Output colors as KIND=COLOR pairs, e.g. message=white,dm=bright-cyan;
+        /// kinds are message, error, system and dm (readline mode only)
+        #[arg(long)]
+        theme: Option<theme::Theme>,
+
+        /// Never color output; it isn't colored when stdout isn't a terminal either
+        #[arg(long, default_value_t = false)]
+        no_color: bool,
+
+        /// Log every protocol frame sent (->) and received (<-) to stderr as JSON
+        #[arg(long, default_value_t = false)]
+        debug_protocol: bool,
+
+        /// Append a timestamped transcript of the session (chat, DMs, joins and leaves) to this file
+        #[arg(long)]
+        log_file: Option<PathBuf>,
+
+        /// Check chat messages against the server's --hmac-key, warning about any that don't match
+        #[arg(long)]
+        hmac_key: Option<String>,
+
+        /// File keeping the secret that lets you edit and delete your messages
+        /// after reconnecting (default: ~/.rust-chat-identity)
+        #[arg(long)]
+        identity_file: Option<PathBuf>,
     },
+    /// Connect to chat server (not included in this build)
+    #[cfg(not(feature = "client"))]
+    #[command(disable_help_flag = true)]
+    Client {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, hide = true)]
+        args: Vec<String>,
+    },
+}
+
+/// Returns the value of environment variable `var`, or `None` if it's unset
+/// or blank.
+#[cfg(any(feature = "server", feature = "client"))]
+fn env_value(var: &str) -> Option<String> {
+    let value = std::env::var(var).ok()?;
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// Returns the host name or IP address in environment variable `var`,
+/// ignoring values with whitespace inside with a warning.
+#[cfg(any(feature = "server", feature = "client"))]
+fn env_address(var: &str) -> Option<String> {
+    let value = env_value(var)?;
+    if value.contains(char::is_whitespace) {
+        eprintln!("Ignoring {}={:?}: not an address", var, value);
+        return None;
+    }
+    Some(value)
+}
+
+/// Returns the port in environment variable `var`, ignoring values that
+/// aren't a valid port with a warning.
+#[cfg(any(feature = "server", feature = "client"))]
+fn env_port(var: &str) -> Option<u16> {
+    let value = env_value(var)?;
+    let port = parse_port(&value);
+    if port.is_none() {
+        eprintln!("Ignoring {}={:?}: not a port number", var, value);
+    }
+    port
+}
+
+/// Parses a `--hook-token` value of the form `ROOM=TOKEN`.
+#[cfg(feature = "server")]
+fn parse_hook_token(value: &str) -> Result<(String, String), String> {
+    split_room_value(value)
+        .ok_or_else(|| format!("Invalid hook token '{}', expected ROOM=TOKEN", value))
+}
+
+/// Parses a `--room-welcome` value of the form `ROOM=TEXT`.
+#[cfg(feature = "server")]
+fn parse_room_welcome(value: &str) -> Result<(String, String), String> {
+    split_room_value(value)
+        .ok_or_else(|| format!("Invalid room welcome '{}', expected ROOM=TEXT", value))
+}
+
+/// Splits `ROOM=VALUE` into its parts, neither of which may be empty.
+#[cfg(feature = "server")]
+fn split_room_value(value: &str) -> Option<(String, String)> {
+    match value.split_once('=') {
+        Some((room, rest)) if !room.is_empty() && !rest.is_empty() => {
+            Some((room.to_string(), rest.to_string()))
+        }
+        _ => None,
+    }
+}
+
+/// Parses a port number between 1 and 65535.
+#[cfg(any(feature = "server", feature = "client"))]
+fn parse_port(value: &str) -> Option<u16> {
+    value.parse().ok().filter(|&port| port != 0)
+}
+
+/// Exits with an error for a subcommand this binary was built without.
+#[cfg(not(all(feature = "server", feature = "client")))]
+fn missing_feature(command: &str, features: &str) -> ! {
+    eprintln!(
+        "`chat {}` is not included in this build; rebuild with `--features {}`",
+        command, features
+    );
+    std::process::exit(2);
 }
 
 #[tokio::main]
@@ -53,18 +461,289 @@ async fn main() {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Server { address, port, tui } => {
-            if let Err(e) = server::run_server(&address, port, tui).await {
+        #[cfg(feature = "server")]
+        Commands::Server {
+            config: config_path,
+            address,
+            port,
+            tls_cert,
+            tls_key,
+            tui,
+            persist,
+            flush_every,
+            flush_interval_secs,
+            admin_token,
+            max_message_len,
+            record_ips,
+            metrics,
+            max_messages,
+            join_backlog,
+            rate_limit_per_sec,
+            keepalive_secs,
+            server_name,
+            auth_token,
+            moderator_token,
+            admins,
+            ban_secs,
+            away_secs,
+            idle_timeout_secs,
+            snapshot_path,
+            allow_control_chars,
+            allow_empty,
+            outbound_capacity,
+            overflow_policy,
+            dedupe_names,
+            topic_policy,
+            upload_dir,
+            max_upload_size,
+            public_url,
+            upload_types,
+            room_password,
+            blocklist,
+            blocklist_mode,
+            hook_tokens,
+            welcome,
+            room_welcomes,
+            hmac_key,
+            max_pins,
+            pin_overflow,
+            max_distinct_reactions,
+            max_reactions,
+            max_connections,
+            max_connections_per_ip,
+        } => {
+            // Defaults, then the config file, then flags given on the command line
+            let mut config = server::ServerConfig::default();
+            if let Some(path) = config_path {
+                match ConfigFile::load(&path) {
+                    Ok(file) => file.apply(&mut config),
+                    Err(e) => {
+                        eprintln!("Failed to load config: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            // The environment sits between the config file and the flags
+            if let Some(address) = address.or_else(|| env_address("CHAT_BIND_ADDR")) {
+                config.address = address;
+            }
+            if let Some(port) = port.or_else(|| env_port("CHAT_PORT")) {
+                config.port = port;
+            }
+            if tls_cert.is_some() {
+                config.tls_cert = tls_cert;
+            }
+            if tls_key.is_some() {
+                config.tls_key = tls_key;
+            }
+            if let Some(max_messages) = max_messages {
+                config.max_messages = max_messages;
+            }
+            if let Some(rate) = rate_limit_per_sec {
+                config.rate_limit_per_sec = rate;
+            }
+            if auth_token.is_some() {
+                config.auth_token = auth_token;
+            }
+            if !admins.is_empty() {
+                config.admins = admins;
+            }
+            if !upload_types.is_empty() {
+                config.upload_types = upload_types;
+            }
+            if welcome.is_some() {
+                config.welcome = welcome;
+            }
+            config.room_welcomes.extend(room_welcomes);
+            if let Some(path) = blocklist {
+                match Blocklist::load(&path, blocklist_mode) {
+                    Ok(blocklist) => config.blocklist = Some(blocklist),
+                    Err(e) => {
+                        eprintln!("Failed to load blocklist: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            let config = server::ServerConfig {
+                tui,
+                persist_path: persist,
+                flush_policy: FlushPolicy {
+                    max_pending: flush_every.max(1),
+                    interval: Duration::from_secs(flush_interval_secs.max(1)),
+                },
+                admin_token,
+                moderator_token,
+                max_message_len,
+                record_ips,
+                metrics_endpoint: metrics,
+                keepalive_interval: Duration::from_secs(keepalive_secs.max(1)),
+                server_name,
+                snapshot_path,
+                allow_control_chars,
+                allow_empty,
+                ban_cooldown: Duration::from_secs(ban_secs),
+                away_after: Duration::from_secs(away_secs),
+                idle_timeout: Duration::from_secs(idle_timeout_secs),
+                outbound_capacity: outbound_capacity.max(1),
+                overflow_policy,
+                duplicate_names: dedupe_names,
+                topic_policy,
+                join_backlog,
+                upload_dir,
+                max_upload_size,
+                public_url,
+                room_password,
+                hook_tokens: hook_tokens.into_iter().collect(),
+                hmac_key,
+                max_pins,
+                pin_overflow,
+                max_distinct_reactions,
+                max_reactions,
+                max_connections,
+                max_connections_per_ip,
+                ..config
+            };
+            if let Err(e) = config::validate(&config) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            if let Err(e) = server::run_server(config).await {
                 eprintln!("Server error: {}", e);
                 std::process::exit(1);
             }
         }
+        #[cfg(not(feature = "server"))]
+        Commands::Server { .. } => missing_feature("server", "server"),
+        #[cfg(all(feature = "server", feature = "client"))]
+        Commands::Selftest => {
+            let report = selftest::run().await;
+            for step in &report.steps {
+                match &step.error {
+                    None => println!("PASS {} ({:?})", step.name, step.elapsed),
+                    Some(e) => println!("FAIL {} ({:?}): {}", step.name, step.elapsed, e),
+                }
+            }
+            if report.passed() {
+                println!("Self-test passed in {:?}", report.elapsed());
+            } else {
+                println!("Self-test failed");
+                std::process::exit(1);
+            }
+        }
+        #[cfg(not(all(feature = "server", feature = "client")))]
+        Commands::Selftest => missing_feature("selftest", "server,client"),
+        #[cfg(feature = "client")]
         Commands::Client {
             address,
             port,
             name,
+            room,
+            tee,
+            tui,
+            token,
+            room_password,
+            bell_on_mention,
+            quiet_hours,
+            history_path,
+            notifications,
+            theme,
+            no_color,
+            debug_protocol,
+            log_file,
+            hmac_key,
+            identity_file,
         } => {
-            client::run_client(&address, port, name).await;
+            if notifications && !alert::NOTIFICATIONS_SUPPORTED {
+                eprintln!(
+                    "This build has no desktop notification support; ignoring --notifications"
+                );
+            }
+            let config = client::ClientConfig {
+                address: address
+                    .or_else(|| env_address("CHAT_SERVER_ADDR"))
+                    .unwrap_or_else(|| "127.0.0.1".to_string()),
+                port: port
+                    .or_else(|| env_port("CHAT_SERVER_PORT"))
+                    .unwrap_or(12345),
+                name: name.or_else(|| env_value("CHAT_NAME")),
+                room,
+                tee,
+                tui,
+                token,
+                room_password,
+                mention_alert: MentionAlert {
+                    enabled: bell_on_mention,
+                    quiet_hours,
+                },
+                history_path: history_path.or_else(client::default_history_path),
+                notifications: notifications && alert::NOTIFICATIONS_SUPPORTED,
+                theme: theme.unwrap_or_default(),
+                color: !no_color,
+                debug_protocol,
+                log_file,
+                hmac_key,
+                identity_path: identity_file.or_else(client::default_identity_path),
+            };
+            client::run_client(config).await;
+        }
+        #[cfg(not(feature = "client"))]
+        Commands::Client { .. } => missing_feature("client", "client"),
+    }
+}
+
+#[cfg(all(test, any(feature = "server", feature = "client")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_port_rejects_invalid_values() {
+        assert_eq!(parse_port("8080"), Some(8080));
+        assert_eq!(parse_port("0"), None);
+        assert_eq!(parse_port("65536"), None);
+        assert_eq!(parse_port("http"), None);
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn test_client_args_keep_name_and_server_apart() {
+        let cli = Cli::try_parse_from([
+            "chat", "client", "--name", "Alice", "-a", "10.0.0.5", "-p", "8080",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Client {
+                name,
+                address,
+                port,
+                ..
+            } => {
+                assert_eq!(name.as_deref(), Some("Alice"));
+                assert_eq!(address.as_deref(), Some("10.0.0.5"));
+                assert_eq!(port, Some(8080));
+            }
+            _ => panic!("Expected the client command"),
+        }
+
+        // Without a name one is picked later, not taken from the address
+        let cli = Cli::try_parse_from(["chat", "client", "-a", "example.com"]).unwrap();
+        match cli.command {
+            Commands::Client { name, address, .. } => {
+                assert_eq!(name, None);
+                assert_eq!(address.as_deref(), Some("example.com"));
+            }
+            _ => panic!("Expected the client command"),
+        }
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_auto_away_secs_is_an_alias_for_away_secs() {
+        for flag in ["--away-secs", "--auto-away-secs"] {
+            let cli = Cli::try_parse_from(["chat", "server", flag, "60"]).unwrap();
+            match cli.command {
+                Commands::Server { away_secs, .. } => assert_eq!(away_secs, 60),
+                _ => panic!("Expected the server command"),
+            }
         }
     }
 }
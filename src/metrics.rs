@@ -0,0 +1,327 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::lock::LockExt;
+
+/// Upper bounds of the fan-out histogram buckets, in microseconds
+pub const FANOUT_BUCKETS_MICROS: [u64; 8] = [10, 50, 100, 500, 1_000, 5_000, 10_000, 50_000];
+
+/// Fixed-bucket histogram of durations.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    bounds: &'static [u64],
+    /// Per-bucket counts, with one extra bucket for samples above every bound
+    counts: Vec<u64>,
+    sum_micros: u64,
+}
+
+impl Histogram {
+    /// Creates an empty histogram with the given bucket bounds in microseconds.
+    pub fn new(bounds: &'static [u64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum_micros: 0,
+        }
+    }
+
+    /// Records one sample.
+    pub fn record(&mut self, elapsed: Duration) {
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&bound| micros <= bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum_micros = self.sum_micros.saturating_add(micros);
+    }
+
+    /// Returns the current counts with cumulative buckets.
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let mut cumulative = 0;
+        let buckets = self
+            .bounds
+            .iter()
+            .zip(&self.counts)
+            .map(|(&le_micros, &count)| {
+                cumulative += count;
+                HistogramBucket {
+                    le_micros,
+                    count: cumulative,
+                }
+            })
+            .collect();
+        HistogramSnapshot {
+            buckets,
+            count: self.counts.iter().sum(),
+            sum_micros: self.sum_micros,
+        }
+    }
+}
+
+/// One cumulative histogram bucket.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistogramBucket {
+    /// Upper bound of the bucket, in microseconds
+    pub le_micros: u64,
+    /// Number of samples at or below the bound
+    pub count: u64,
+}
+
+/// Point-in-time copy of a [`Histogram`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistogramSnapshot {
+    /// Cumulative buckets in increasing order; samples above the last bound
+    /// only appear in `count`
+    pub buckets: Vec<HistogramBucket>,
+    /// Total number of samples
+    pub count: u64,
+    /// Sum of all samples, in microseconds
+    pub sum_micros: u64,
+}
+
+/// Summary returned by `GET /stats`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerStats {
+    /// Number of connected users
+    pub connected_users: usize,
+    /// Number of open rooms
+    pub rooms: usize,
+    /// Time taken to fan messages out to their recipients
+    pub fanout: HistogramSnapshot,
+}
+
+/// Uptime and current levels, as reported by `GET /healthz`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Health {
+    /// Always `"ok"` while the server is accepting requests
+    pub status: String,
+    /// Seconds since the server started
+    pub uptime_secs: u64,
+    /// Number of open rooms
+    pub rooms: usize,
+    /// Number of connected users
+    pub users: usize,
+}
+
+/// Event counts and current levels tracked alongside the timings.
+#[derive(Debug, Default)]
+struct Counters {
+    messages_received: AtomicU64,
+    messages_broadcast: AtomicU64,
+    connected_users: AtomicUsize,
+    rooms: AtomicUsize,
+}
+
+/// Server-wide performance metrics, shared by all handlers.
+///
+/// Kept by hand rather than through the `metrics` facade and its Prometheus
+/// exporter: those record into a process-wide recorder and only hand values
+/// back as rendered text, while `/healthz`, `/stats` and the `Stats` reply
+/// read these numbers back, and every [`crate::server::AppState`] (one per
+/// server, several per test run) needs counts of its own.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    fanout: Arc<Mutex<Histogram>>,
+    counters: Arc<Counters>,
+    started_at: Instant,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            fanout: Arc::new(Mutex::new(Histogram::new(&FANOUT_BUCKETS_MICROS))),
+            counters: Arc::new(Counters::default()),
+            started_at: Instant::now(),
+        }
+    }
+}
+
+impl Metrics {
+    /// Counts a chat message received from a client, before it is validated.
+    pub fn record_received(&self) {
+        self.counters
+            .messages_received
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a chat message stored and sent to its room.
+    pub fn record_broadcast(&self) {
+        self.counters
+            .messages_broadcast
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a client that finished joining.
+    pub fn user_connected(&self) {
+        self.counters
+            .connected_users
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a joined client going away.
+    pub fn user_disconnected(&self) {
+        let _ = self.counters.connected_users.fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |users| users.checked_sub(1),
+        );
+    }
+
+    /// Records the number of open rooms after one was created or closed.
+    pub fn set_rooms(&self, rooms: usize) {
+        self.counters.rooms.store(rooms, Ordering::Relaxed);
+    }
+
+    /// Records how long it took to queue one message for all its recipients.
+    pub fn record_fanout(&self, elapsed: Duration) {
+        self.fanout.lock_or_recover().record(elapsed);
+    }
+
+    /// Returns the fan-out timings recorded so far.
+    pub fn fanout(&self) -> HistogramSnapshot {
+        self.fanout.lock_or_recover().snapshot()
+    }
+
+    /// Returns uptime and current levels without taking any locks, so
+    /// frequent probes never contend with chat traffic.
+    pub fn health(&self) -> Health {
+        Health {
+            status: "ok".to_string(),
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            rooms: self.counters.rooms.load(Ordering::Relaxed),
+            users: self.counters.connected_users.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the number of chat messages stored and sent so far.
+    pub fn messages_broadcast(&self) -> u64 {
+        self.counters.messages_broadcast.load(Ordering::Relaxed)
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render_prometheus(&self) -> String {
+        let fanout = self.fanout();
+        let mut out = String::new();
+        let counters = &self.counters;
+        write_metric(
+            &mut out,
+            "chat_messages_received_total",
+            "counter",
+            "Chat messages received from clients",
+            counters.messages_received.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "chat_messages_broadcast_total",
+            "counter",
+            "Chat messages stored and sent to their room",
+            counters.messages_broadcast.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "chat_connected_users",
+            "gauge",
+            "Clients currently connected",
+            counters.connected_users.load(Ordering::Relaxed) as u64,
+        );
+        write_metric(
+            &mut out,
+            "chat_rooms",
+            "gauge",
+            "Rooms currently open",
+            counters.rooms.load(Ordering::Relaxed) as u64,
+        );
+        let _ = writeln!(
+            out,
+            "# HELP chat_fanout_seconds Time taken to queue a message for every recipient"
+        );
+        let _ = writeln!(out, "# TYPE chat_fanout_seconds histogram");
+        for bucket in &fanout.buckets {
+            let _ = writeln!(
+                out,
+                "chat_fanout_seconds_bucket{{le=\"{}\"}} {}",
+                micros_to_secs(bucket.le_micros),
+                bucket.count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "chat_fanout_seconds_bucket{{le=\"+Inf\"}} {}",
+            fanout.count
+        );
+        let _ = writeln!(
+            out,
+            "chat_fanout_seconds_sum {}",
+            micros_to_secs(fanout.sum_micros)
+        );
+        let _ = writeln!(out, "chat_fanout_seconds_count {}", fanout.count);
+        out
+    }
+}
+
+/// Writes a single-valued metric with its help and type lines.
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn micros_to_secs(micros: u64) -> f64 {
+    micros as f64 / 1_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let metrics = Metrics::default();
+        metrics.record_fanout(Duration::from_micros(5));
+        metrics.record_fanout(Duration::from_micros(10));
+        metrics.record_fanout(Duration::from_micros(700));
+        metrics.record_fanout(Duration::from_secs(1));
+
+        let snapshot = metrics.fanout();
+        assert_eq!(snapshot.count, 4);
+        assert_eq!(snapshot.sum_micros, 1_000_715);
+        let counts: Vec<u64> = snapshot.buckets.iter().map(|b| b.count).collect();
+        assert_eq!(counts, vec![2, 2, 2, 2, 3, 3, 3, 3]);
+
+        let text = metrics.render_prometheus();
+        assert!(text.contains("# TYPE chat_fanout_seconds histogram"));
+        assert!(text.contains("chat_fanout_seconds_bucket{le=\"0.00001\"} 2"));
+        assert!(text.contains("chat_fanout_seconds_bucket{le=\"+Inf\"} 4"));
+        assert!(text.contains("chat_fanout_seconds_count 4"));
+    }
+
+    #[test]
+    fn test_connected_users_gauge_goes_down() {
+        let metrics = Metrics::default();
+        metrics.user_connected();
+        metrics.user_connected();
+        metrics.user_disconnected();
+        metrics.record_received();
+        metrics.set_rooms(3);
+
+        let text = metrics.render_prometheus();
+        assert!(text.contains("# TYPE chat_connected_users gauge\nchat_connected_users 1\n"));
+        assert!(text.contains("chat_messages_received_total 1\n"));
+        assert!(text.contains("chat_messages_broadcast_total 0\n"));
+        assert!(text.contains("chat_rooms 3\n"));
+
+        // A stray disconnect never wraps the gauge around
+        metrics.user_disconnected();
+        metrics.user_disconnected();
+        assert!(
+            metrics
+                .render_prometheus()
+                .contains("chat_connected_users 0\n")
+        );
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+use crate::lock::LockExt;
+use crate::shared::Message;
+
+/// Default number of messages queued for a single client before its
+/// overflow policy kicks in
+pub const DEFAULT_OUTBOUND_CAPACITY: usize = 256;
+
+/// What to do when a client's outbound queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Disconnect the client as too slow
+    #[default]
+    Disconnect,
+    /// Discard the oldest queued message to make room for the new one
+    DropOldest,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disconnect" => Ok(Self::Disconnect),
+            "drop-oldest" => Ok(Self::DropOldest),
+            _ => Err(format!(
+                "Invalid overflow policy '{}', expected disconnect or drop-oldest",
+                s
+            )),
+        }
+    }
+}
+
+/// Returned by [`OutboundSender::send`] when the message was not queued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError;
+
+struct Queue {
+    messages: VecDeque<Message>,
+    /// Set once the queue overflowed under [`OverflowPolicy::Disconnect`]
+    overflowed: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    notify: Notify,
+    /// Woken once the queue overflows under [`OverflowPolicy::Disconnect`]
+    overflow: Notify,
+    senders: AtomicUsize,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+/// Creates a bounded outbound queue holding up to `capacity` messages.
+///
+/// Hand-rolled rather than a bounded `tokio::sync::mpsc` channel: when that
+/// is full its sender can only wait or fail, with no way to reach messages
+/// already queued, so [`OverflowPolicy::DropOldest`] couldn't evict the
+/// oldest one to make room for the newest.
+pub fn channel(capacity: usize, policy: OverflowPolicy) -> (OutboundSender, OutboundReceiver) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue {
+            messages: VecDeque::new(),
+            overflowed: false,
+        }),
+        notify: Notify::new(),
+        overflow: Notify::new(),
+        senders: AtomicUsize::new(1),
+        capacity: capacity.max(1),
+        policy,
+    });
+    (
+        OutboundSender {
+            shared: shared.clone(),
+        },
+        OutboundReceiver { shared },
+    )
+}
+
+/// Sending half of a client's outbound queue.
+pub struct OutboundSender {
+    shared: Arc<Shared>,
+}
+
+impl OutboundSender {
+    /// Queues a message for the client, applying the overflow policy when the
+    /// queue is full.
+    ///
+    /// Fails once the queue has overflowed under
+    /// [`OverflowPolicy::Disconnect`]; the receiver then yields nothing more.
+    pub fn send(&self, message: Message) -> Result<(), SendError> {
+        let mut queue = self.shared.queue.lock_or_recover();
+        if queue.overflowed {
+            return Err(SendError);
+        }
+        if queue.messages.len() >= self.shared.capacity {
+            match self.shared.policy {
+                OverflowPolicy::Disconnect => {
+                    queue.overflowed = true;
+                    queue.messages.clear();
+                    drop(queue);
+                    self.shared.notify.notify_one();
+                    self.shared.overflow.notify_one();
+                    return Err(SendError);
+                }
+                OverflowPolicy::DropOldest => {
+                    queue.messages.pop_front();
+                }
+            }
+        }
+        queue.messages.push_back(message);
+        drop(queue);
+        self.shared.notify.notify_one();
+        Ok(())
+    }
+}
+
+impl Clone for OutboundSender {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for OutboundSender {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.notify.notify_one();
+        }
+    }
+}
+
+/// Receiving half of a client's outbound queue, drained by its send task.
+pub struct OutboundReceiver {
+    shared: Arc<Shared>,
+}
+
+impl OutboundReceiver {
+    /// Waits for the next queued message.
+    ///
+    /// Returns `None` once the queue has overflowed, or once it is empty and
+    /// every sender is gone.
+    pub async fn recv(&mut self) -> Option<Message> {
+        loop {
+            let notified = self.shared.notify.notified();
+            {
+                let mut queue = self.shared.queue.lock_or_recover();
+                if queue.overflowed {
+                    return None;
+                }
+                if let Some(message) = queue.messages.pop_front() {
+                    return Some(message);
+                }
+                if self.shared.senders.load(Ordering::Acquire) == 0 {
+                    return None;
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Takes the next queued message without waiting.
+    pub fn try_recv(&mut self) -> Option<Message> {
+        let mut queue = self.shared.queue.lock_or_recover();
+        if queue.overflowed {
+            return None;
+        }
+        queue.messages.pop_front()
+    }
+
+    /// Returns whether the queue overflowed under
+    /// [`OverflowPolicy::Disconnect`].
+    pub fn is_overflowed(&self) -> bool {
+        self.shared.queue.lock_or_recover().overflowed
+    }
+
+    /// Returns a handle that can wait for the queue to overflow while the
+    /// receiver itself is busy, e.g. stuck writing to a client that stopped
+    /// reading.
+    pub fn overflow_signal(&self) -> OverflowSignal {
+        OverflowSignal {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+/// Waits for an outbound queue to overflow, see
+/// [`OutboundReceiver::overflow_signal`].
+pub struct OverflowSignal {
+    shared: Arc<Shared>,
+}
+
+impl OverflowSignal {
+    /// Waits until the queue has overflowed under
+    /// [`OverflowPolicy::Disconnect`]; never returns under any other policy.
+    pub async fn overflowed(&self) {
+        loop {
+            let notified = self.shared.overflow.notified();
+            if self.shared.queue.lock_or_recover().overflowed {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_stalled_consumer_hits_bound() {
+        // Nobody drains the queue until it is already past capacity
+        let (tx, mut rx) = channel(3, OverflowPolicy::Disconnect);
+        for i in 0..3 {
+            assert!(tx.send(Message::new(i.to_string())).is_ok());
+        }
+        assert!(!rx.is_overflowed());
+        assert_eq!(tx.send(Message::new("3".to_string())), Err(SendError));
+        assert!(rx.is_overflowed());
+        rx.overflow_signal().overflowed().await;
+        assert!(rx.recv().await.is_none());
+        assert_eq!(tx.send(Message::new("4".to_string())), Err(SendError));
+
+        let (tx, mut rx) = channel(3, OverflowPolicy::DropOldest);
+        for i in 0..5 {
+            assert!(tx.send(Message::new(i.to_string())).is_ok());
+        }
+        assert!(!rx.is_overflowed());
+        let mut received = Vec::new();
+        while let Some(message) = rx.try_recv() {
+            received.push(message.text);
+        }
+        assert_eq!(received, vec!["2", "3", "4"]);
+
+        // The receiver wakes for later messages and ends when senders are gone
+        let waiter = tokio::spawn(async move {
+            let first = rx.recv().await.map(|message| message.text);
+            (first, rx.recv().await.is_none())
+        });
+        tx.send(Message::new("5".to_string())).unwrap();
+        drop(tx);
+        assert_eq!(waiter.await.unwrap(), (Some("5".to_string()), true));
+
+        assert_eq!(
+            "drop-oldest".parse::<OverflowPolicy>(),
+            Ok(OverflowPolicy::DropOldest)
+        );
+        assert!("block".parse::<OverflowPolicy>().is_err());
+    }
+}
@@ -0,0 +1,100 @@
+use std::time::{Duration, Instant};
+
+/// Default number of messages a client may send per second
+pub const DEFAULT_RATE_LIMIT_PER_SEC: u32 = 5;
+
+/// Token bucket limiting how fast a single client may send messages.
+///
+/// The bucket holds up to `rate` tokens and refills at `rate` tokens per
+/// second, so a client can burst up to one second's worth of messages and is
+/// then held to the steady rate.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket allowing `rate` messages per second.
+    pub fn new(rate: u32) -> Self {
+        let rate = f64::from(rate.max(1));
+        Self {
+            rate,
+            tokens: rate,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes a token if one is available, returning whether the message may be sent.
+    pub fn try_acquire(&mut self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    /// Number of whole tokens left as of the last acquire attempt.
+    pub fn remaining(&self) -> u32 {
+        self.tokens.floor() as u32
+    }
+
+    /// How long after the last acquire attempt until a token is available.
+    pub fn retry_after(&self) -> Duration {
+        Duration::from_secs_f64((1.0 - self.tokens).max(0.0) / self.rate)
+    }
+
+    /// Returns whether the bucket has refilled completely, i.e. the client
+    /// has been quiet for at least a second.
+    pub fn is_full(&self) -> bool {
+        self.tokens + self.last_refill.elapsed().as_secs_f64() * self.rate >= self.rate
+    }
+
+    fn try_acquire_at(&mut self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Default for TokenBucket {
+    fn default() -> Self {
+        Self::new(DEFAULT_RATE_LIMIT_PER_SEC)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_bucket_allows_burst_then_refills() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2);
+
+        assert!(bucket.try_acquire_at(start));
+        assert_eq!(bucket.remaining(), 1);
+        assert!(bucket.try_acquire_at(start));
+        assert!(!bucket.try_acquire_at(start));
+        assert_eq!(bucket.remaining(), 0);
+        assert_eq!(bucket.retry_after(), Duration::from_millis(500));
+
+        // Half a second at 2/s refills exactly one token
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.try_acquire_at(later));
+        assert!(!bucket.try_acquire_at(later));
+
+        // Long idle periods don't accumulate more than a full bucket
+        let much_later = later + Duration::from_secs(60);
+        assert!(bucket.try_acquire_at(much_later));
+        assert!(bucket.try_acquire_at(much_later));
+        assert!(!bucket.try_acquire_at(much_later));
+    }
+}
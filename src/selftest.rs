@@ -0,0 +1,193 @@
+use futures::{sink::SinkExt, stream::StreamExt};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio_tungstenite::{connect_async, tungstenite::protocol::Message as WsMessage};
+
+use crate::server::{self, AppState};
+use crate::shared::{ClientMessage, DEFAULT_ROOM, Message, ServerMessage};
+
+/// How long any single step may take before it counts as failed
+const STEP_TIMEOUT: Duration = Duration::from_secs(5);
+
+type TestSocket =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Outcome of one self-test step.
+#[derive(Debug)]
+pub struct StepResult {
+    /// What the step checked
+    pub name: &'static str,
+    /// How long the step took
+    pub elapsed: Duration,
+    /// Why the step failed, if it did
+    pub error: Option<String>,
+}
+
+/// Outcome of a whole self-test run.
+#[derive(Debug, Default)]
+pub struct SelfTestReport {
+    /// Steps in the order they ran; the run stops at the first failure
+    pub steps: Vec<StepResult>,
+}
+
+impl SelfTestReport {
+    /// Returns whether every step ran and passed.
+    pub fn passed(&self) -> bool {
+        !self.steps.is_empty() && self.steps.iter().all(|step| step.error.is_none())
+    }
+
+    /// Returns the total time spent across all steps.
+    pub fn elapsed(&self) -> Duration {
+        self.steps.iter().map(|step| step.elapsed).sum()
+    }
+
+    /// Times `step` and records its outcome, returning its value on success.
+    async fn run<T>(
+        &mut self,
+        name: &'static str,
+        step: impl Future<Output = Result<T, String>>,
+    ) -> Option<T> {
+        let started = Instant::now();
+        let result = match tokio::time::timeout(STEP_TIMEOUT, step).await {
+            Ok(result) => result,
+            Err(_) => Err(format!("timed out after {:?}", STEP_TIMEOUT)),
+        };
+        let (value, error) = match result {
+            Ok(value) => (Some(value), None),
+            Err(e) => (None, Some(e)),
+        };
+        self.steps.push(StepResult {
+            name,
+            elapsed: started.elapsed(),
+            error,
+        });
+        value
+    }
+}
+
+/// Starts a server on an ephemeral port, connects a client to it and checks
+/// that a message makes the round trip over WebSocket and HTTP.
+pub async fn run() -> SelfTestReport {
+    let mut report = SelfTestReport::default();
+    let token = format!("selftest {}", uuid::Uuid::new_v4());
+
+    let Some(addr) = report
+        .run("start server", async {
+            server::spawn_local(AppState::new())
+                .await
+                .map_err(|e| e.to_string())
+        })
+        .await
+    else {
+        return report;
+    };
+
+    let Some(mut ws) = report.run("connect client", connect(addr)).await else {
+        return report;
+    };
+
+    if report
+        .run("message round-trip", round_trip(&mut ws, &token))
+        .await
+        .is_none()
+    {
+        return report;
+    }
+
+    report
+        .run("history over HTTP", fetch_history(addr, &token))
+        .await;
+    report
+}
+
+async fn connect(addr: SocketAddr) -> Result<TestSocket, String> {
+    let url = format!("ws://{}/room/{}", addr, DEFAULT_ROOM);
+    let (mut ws, _) = connect_async(&url).await.map_err(|e| e.to_string())?;
+    send(
+        &mut ws,
+        &ClientMessage::connect("selftest".to_string(), None, None),
+    )
+    .await?;
+    expect(&mut ws, |msg| matches!(msg, ServerMessage::Welcome { .. })).await?;
+    Ok(ws)
+}
+
+async fn round_trip(ws: &mut TestSocket, token: &str) -> Result<(), String> {
+    send(
+        ws,
+        &ClientMessage::Chat {
+            text: token.to_string(),
+            client_msg_id: None,
+        },
+    )
+    .await?;
+    expect(
+        ws,
+        |msg| matches!(msg, ServerMessage::Chat { text, .. } if text.ends_with(token)),
+    )
+    .await?;
+    expect(ws, |msg| matches!(msg, ServerMessage::Ack { .. })).await?;
+    Ok(())
+}
+
+async fn fetch_history(addr: SocketAddr, token: &str) -> Result<(), String> {
+    let messages: Vec<Message> = reqwest::get(format!("http://{}/messages/json", addr))
+        .await
+        .map_err(|e| e.to_string())?
+        .json()
+        .await
+        .map_err(|e| e.to_string())?;
+    if messages.iter().any(|msg| msg.text.ends_with(token)) {
+        Ok(())
+    } else {
+        Err("sent message missing from history".to_string())
+    }
+}
+
+async fn send(ws: &mut TestSocket, msg: &ClientMessage) -> Result<(), String> {
+    let json = serde_json::to_string(msg).map_err(|e| e.to_string())?;
+    ws.send(WsMessage::Text(json.into()))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Reads frames until a server message matches `predicate`.
+async fn expect(
+    ws: &mut TestSocket,
+    predicate: impl Fn(&ServerMessage) -> bool,
+) -> Result<(), String> {
+    while let Some(frame) = ws.next().await {
+        if let WsMessage::Text(text) = frame.map_err(|e| e.to_string())?
+            && let Ok(msg) = serde_json::from_str::<ServerMessage>(&text)
+        {
+            if let ServerMessage::Error { message, .. } = &msg {
+                return Err(message.clone());
+            }
+            if predicate(&msg) {
+                return Ok(());
+            }
+        }
+    }
+    Err("connection closed".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_selftest_passes() {
+        let report = run().await;
+        for step in &report.steps {
+            assert!(
+                step.error.is_none(),
+                "{} failed: {:?}",
+                step.name,
+                step.error
+            );
+        }
+        assert!(report.passed());
+        assert_eq!(report.steps.len(), 4);
+    }
+}
@@ -1,816 +1,9521 @@
 use axum::{
     Json, Router,
     extract::{
-        State,
+        ConnectInfo, DefaultBodyLimit, Multipart, Path, Query, State,
         ws::{WebSocket, WebSocketUpgrade},
     },
-    http::StatusCode,
-    response::IntoResponse,
+    http::{
+        HeaderMap, StatusCode,
+        header::{AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER, X_CONTENT_TYPE_OPTIONS},
+    },
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
     routing::{get, post},
 };
+use axum_server::tls_rustls::RustlsConfig;
 use futures::{sink::SinkExt, stream::StreamExt};
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
 
-use crate::shared::{ChatError, ChatResult, ClientMessage, Message, ServerMessage, User, UserList};
+use crate::blocklist::Blocklist;
+use crate::lock::LockExt;
+use crate::metrics::{Metrics, ServerStats};
+use crate::outbound::{self, DEFAULT_OUTBOUND_CAPACITY, OutboundSender, OverflowPolicy};
+use crate::password::PasswordHash;
+use crate::rate_limit::{DEFAULT_RATE_LIMIT_PER_SEC, TokenBucket};
+use crate::server_tui;
+use crate::shared::{
+    AdminUserList, ChatError, ChatResult, ClientMessage, ConnectionInfo, DEFAULT_ROOM,
+    DEFAULT_SERVER_NAME, HealthStatus, LastSeen, MIN_SUPPORTED_PROTOCOL_VERSION, Message,
+    Permission, ReplayFormat, Role, RoomInfo, ServerInfo, ServerMessage, User, UserList,
+    UserStatus, default_user_name, rfc3339, unix_time,
+};
+use crate::signing::MessageSigner;
+use crate::storage::{self, FlushPolicy, MessageStore, RoomSnapshot, ServerSnapshot};
+use crate::uploads::{self, DEFAULT_MAX_UPLOAD_SIZE, DEFAULT_UPLOAD_TYPES, UploadInfo};
 
-/// Maximum number of messages to keep in memory
-const MAX_MESSAGES: usize = 1000;
+/// Maximum length of a reaction, in Unicode scalar values
+const MAX_REACTION_LEN: usize = 8;
 
-/// Represents the shared application state for the chat server.
-///
-/// This struct contains all the data that needs to be shared across
-/// different async tasks and WebSocket connections.
-#[derive(Clone)]
-pub struct AppState {
-    /// Stores the chat message history
-    pub messages: Arc<Mutex<Vec<Message>>>,
-    /// List of active WebSocket client connections
-    pub clients: Arc<Mutex<Vec<tokio::sync::mpsc::UnboundedSender<Message>>>>,
-    /// Mapping of user IDs to user information
-    pub users: Arc<Mutex<HashMap<String, User>>>,
-}
+/// Messages a room may have pinned at once when `max_pins` isn't configured
+pub(crate) const DEFAULT_MAX_PINS: usize = 10;
 
-/// Starts the chat server with the specified configuration.
-///
-/// # Arguments
-///
-/// * `address` - The IP address to bind the server to (e.g., "127.0.0.1")
-/// * `port` - The port number to listen on (e.g., 12345)
-/// * `tui` - Whether to enable the terminal user interface
-///
-/// # Returns
-///
-/// Returns `Ok(())` if the server starts successfully, or an error if binding fails.
-///
-/// # Examples
-///
-/// ```rust
-/// // Start server on localhost:12345 without TUI
-/// run_server("127.0.0.1", 12345, false).await?;
-/// ```
-pub async fn run_server(address: &str, port: u16, tui: bool) -> ChatResult<()> {
-    let messages = Arc::new(Mutex::new(Vec::new()));
-    let clients = Arc::new(Mutex::new(Vec::new()));
-    let users = Arc::new(Mutex::new(HashMap::new()));
-    let app_state = AppState {
-        messages,
-        clients,
-        users,
-    };
+/// Different emoji a single message may carry when
+/// `max_distinct_reactions` isn't configured
+pub(crate) const DEFAULT_MAX_DISTINCT_REACTIONS: usize = 20;
 
-    let addr = format!("{}:{}", address, port);
-    let socket_addr: SocketAddr = addr.parse().expect("Invalid address");
+/// Reactions a single message may carry, counting every user's, when
+/// `max_reactions` isn't configured
+pub(crate) const DEFAULT_MAX_REACTIONS: usize = 200;
 
-    if tui {
-        println!("Chat server running on http://{} with TUI", socket_addr);
-        run_tui_server(app_state.clone(), socket_addr).await?;
-    } else {
-        println!("Chat server running on http://{}", socket_addr);
-        let app = Router::new()
-            .route("/room/1", get(handle_websocket))
-            .route("/room/1", post(handle_post))
-            .route("/messages", get(handle_get))
-            .with_state(app_state);
+/// Maximum length of the sender name given with `POST /room/{room}`, in
+/// Unicode scalar values
+const MAX_POSTED_SENDER_LEN: usize = 64;
 
-        let listener = tokio::net::TcpListener::bind(socket_addr)
-            .await
-            .map_err(|e| {
-                ChatError::NetworkError(format!("Failed to bind to {}: {}", socket_addr, e))
-            })?;
+/// Room left in an upload's body limit for the multipart headers and
+/// boundaries around the file, which itself may be `max_upload_size`
+const UPLOAD_FRAMING_ALLOWANCE: usize = 16 * 1024;
 
-        if let Err(e) = axum::serve(listener, app).await {
-            eprintln!("Server error: {}", e);
-            return Err(ChatError::NetworkError(format!(
-                "Server runtime error: {}",
-                e
-            )));
-        }
-    }
+/// Longest name a client may go by, in Unicode scalar values
+const MAX_NAME_LEN: usize = 64;
 
-    Ok(())
-}
+/// Longest identity a client may present in its `Connect`
+const MAX_IDENTITY_LEN: usize = 256;
 
-/// Handles WebSocket upgrade requests for the chat endpoint.
-///
-/// This function is called when a client attempts to upgrade their HTTP
-/// connection to a WebSocket connection for real-time chat.
-///
-/// # Arguments
-///
-/// * `ws` - The WebSocket upgrade request from axum
-/// * `state` - The shared application state
-///
-/// # Returns
-///
-/// Returns a response that upgrades the connection to WebSocket.
-async fn handle_websocket(
-    ws: WebSocketUpgrade,
-    State(state): State<AppState>,
-) -> impl IntoResponse {
-    ws.on_upgrade(|socket| handle_socket(socket, state))
-}
+/// Text left in place of a deleted message
+const DELETED_PLACEHOLDER: &str = "[deleted]";
 
-/// Handles the actual WebSocket connection after upgrade.
-///
-/// This function manages the bidirectional communication with a connected
-/// client, processing incoming messages and broadcasting outgoing messages.
-///
-/// # Arguments
-///
-/// * `socket` - The upgraded WebSocket connection
-/// * `state` - The shared application state
-async fn handle_socket(socket: WebSocket, state: AppState) {
-    let (mut sender, mut receiver) = socket.split();
-    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+/// Characters of a message quoted above replies to it
+const QUOTE_LEN: usize = 60;
 
-    // First, wait for a connection message with the user's name
-    let user_name = match receiver.next().await {
-        Some(Ok(axum::extract::ws::Message::Text(text))) => {
-            if let Ok(client_msg) = serde_json::from_str::<ClientMessage>(&text) {
-                match client_msg {
-                    ClientMessage::Connect { name } => name,
-                    _ => format!(
-                        "User_{}",
-                        uuid::Uuid::new_v4().to_string().split('-').next().unwrap()
-                    ),
-                }
-            } else {
-                // Fallback for old format - extract name from "Name: message" format
-                if let Ok(msg) = serde_json::from_str::<Message>(&text) {
-                    if let Some(colon_pos) = msg.text.find(':') {
-                        msg.text[..colon_pos].to_string()
-                    } else {
-                        msg.text
-                    }
-                } else {
-                    format!(
-                        "User_{}",
-                        uuid::Uuid::new_v4().to_string().split('-').next().unwrap()
-                    )
-                }
-            }
-        }
-        _ => format!(
-            "User_{}",
-            uuid::Uuid::new_v4().to_string().split('-').next().unwrap()
-        ),
-    };
+/// Number of messages kept per room when `max_messages` isn't configured
+const DEFAULT_MAX_MESSAGES: usize = 1000;
 
-    // Generate a unique user ID
-    let user_id = uuid::Uuid::new_v4().to_string();
-    let user = User::new(user_name.clone());
+/// How long a user can go without sending a message before they're marked
+/// away, when `away_after` isn't configured
+const DEFAULT_AWAY_AFTER: Duration = Duration::from_secs(300);
 
-    // Add this client to list
-    if let Err(e) = state.clients.lock() {
-        eprintln!("Failed to acquire clients lock: {}", e);
-        return;
-    }
-    state.clients.lock().unwrap().push(tx);
+/// How long before an idle connection is closed its client is warned, at
+/// most; short timeouts warn halfway through instead
+const IDLE_WARNING_LEAD: Duration = Duration::from_secs(30);
 
-    // Add user to tracking
-    {
-        let mut users = state.users.lock().unwrap();
-        users.insert(user_id.clone(), user.clone());
-    }
+/// Number of recent messages replayed to a client joining a room when
+/// `join_backlog` isn't configured
+const DEFAULT_JOIN_BACKLOG: usize = 50;
 
-    // Send existing messages to new client
-    let messages_to_send = {
-        let messages = state.messages.lock().unwrap();
-        messages
-            .iter()
-            .map(|msg| msg.text.clone())
-            .collect::<Vec<String>>()
-    };
+/// Number of client message keys remembered per author to catch resends
+const RECENT_CLIENT_IDS: usize = 256;
 
-    for msg_text in messages_to_send {
-        if sender
-            .send(axum::extract::ws::Message::Text(msg_text.into()))
-            .await
-            .is_err()
-        {
-            return;
-        }
-    }
+/// How long a client message key is remembered, enough to cover a client
+/// reconnecting and resending what wasn't acknowledged
+const RECENT_CLIENT_ID_TTL: Duration = Duration::from_secs(600);
 
-    // Send user list to all clients
-    broadcast_user_list(&state).await;
+/// Number of authors whose message keys are tracked before those with only
+/// expired keys are pruned
+const MAX_TRACKED_AUTHORS: usize = 1024;
 
-    // Broadcast user joined notification
-    broadcast_user_joined(&state, &user_name).await;
+/// Most messages returned for a single `FetchHistory` request
+const MAX_HISTORY_PAGE: usize = 100;
 
-    // Handle incoming messages from this client
-    let state_clone = state.clone();
-    let user_name_clone = user_name.clone();
-    let recv_task = async {
-        while let Some(msg) = receiver.next().await {
-            if let Ok(axum::extract::ws::Message::Text(text)) = msg {
-                // Try to parse as ClientMessage
-                if let Ok(client_msg) = serde_json::from_str::<ClientMessage>(&text) {
-                    match client_msg {
-                        ClientMessage::Chat { text: chat_text } => {
-                            let message = Message::chat_message(&user_name_clone, &chat_text);
-
-                            // Store message with limit
-                            {
-                                let mut messages = state_clone.messages.lock().unwrap();
-
-                                // First check if we need to remove old messages
-                                let needs_trimming = messages.len() >= MAX_MESSAGES;
-
-                                // Add the new message
-                                messages.push(message.clone());
-
-                                // Remove oldest message if we exceeded the limit
-                                if needs_trimming {
-                                    messages.remove(0);
-                                }
-                            }
+/// Shortest time between two `HistoryTrimmed` notices in one room, so a
+/// busy room at its cap doesn't announce every message it drops
+const HISTORY_TRIMMED_INTERVAL: Duration = Duration::from_secs(60);
 
-                            // Broadcast to all clients
-                            let server_msg = ServerMessage::Chat { text: message.text };
-                            broadcast_server_message(&state_clone, &server_msg).await;
-                        }
-                        ClientMessage::Disconnect => {
-                            break;
-                        }
-                        ClientMessage::Connect { .. } => {
-                            // Ignore duplicate connect messages
-                        }
-                    }
-                } else {
-                    // Fallback for old message format
-                    let message = Message {
-                        text: text.to_string(),
-                    };
+/// Line shown at the top of `GET /messages` once older messages were dropped
+const HISTORY_OMITTED_SENTINEL: &str = "[earlier messages omitted]";
 
-                    // Store message with limit
-                    {
-                        let mut messages = state_clone.messages.lock().unwrap();
-                        messages.push(message.clone());
+/// Ceiling on a room's history when `max_messages` is 0 ("unlimited"), so a
+/// busy room still can't grow without bound and exhaust memory
+const UNLIMITED_MAX_MESSAGES: usize = 1_000_000;
 
-                        // Remove oldest messages if we exceed the limit
-                        while messages.len() > MAX_MESSAGES {
-                            messages.remove(0);
-                        }
-                    }
+/// Number of remote addresses whose POST rate limits are tracked before idle
+/// ones are pruned
+const MAX_TRACKED_POSTERS: usize = 1024;
 
-                    // Broadcast to all clients
-                    let clients = state_clone.clients.lock().unwrap();
-                    for client_tx in clients.iter() {
-                        let _ = client_tx.send(message.clone());
-                    }
-                }
-            }
-        }
-    };
+/// Number of user names whose last-seen time is remembered; past this the
+/// longest-unseen tenth are forgotten
+const MAX_LAST_SEEN: usize = 10_000;
 
-    // Handle outgoing messages to this client
-    let send_task = async {
-        while let Some(msg) = rx.recv().await {
-            if sender
-                .send(axum::extract::ws::Message::Text(msg.text.into()))
-                .await
-                .is_err()
-            {
-                break;
-            }
-        }
-    };
+/// Request header carrying the password of a protected room
+const ROOM_PASSWORD_HEADER: &str = "x-room-password";
 
-    // Wait for either task to complete
-    tokio::select! {
-        _ = recv_task => {},
-        _ = send_task => {},
-    }
+/// Request header carrying the token of a room's webhook
+const HOOK_TOKEN_HEADER: &str = "x-hook-token";
 
-    // Clean up user when disconnected
-    {
-        let mut users = state.users.lock().unwrap();
-        users.remove(&user_id);
-    }
+/// Response header telling HTTP clients how many more posts they may burst
+const RATE_LIMIT_REMAINING_HEADER: &str = "x-ratelimit-remaining";
 
-    // Broadcast user left notification
-    broadcast_user_left(&state, &user_name).await;
-}
+/// Room left in a WebSocket frame beyond `max_message_len` for the JSON
+/// envelope around the text
+const FRAME_OVERHEAD: usize = 16 * 1024;
 
-/// Handles GET requests to retrieve all chat messages.
-///
-/// This endpoint returns the complete message history as plain text,
-/// with each message on a new line.
-///
-/// # Arguments
-///
-/// * `state` - The shared application state containing the messages
-///
-/// # Returns
-///
-/// Returns a response with status 200 OK containing the message history.
-async fn handle_get(State(state): State<AppState>) -> impl IntoResponse {
-    let messages = state.messages.lock().unwrap();
-    let response: String = messages
-        .iter()
-        .map(|msg| format!("{}\n", msg.text))
-        .collect();
+/// How long shutdown waits for clients to receive their final messages
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
 
-    (StatusCode::OK, response)
-}
+/// How long a client dropped as too slow gets to take its close reason
+const SLOW_CLIENT_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
 
-/// Handles POST requests to add new chat messages.
-///
-/// This endpoint accepts JSON messages, stores them in the message history,
-/// enforces the message limit, and broadcasts them to all connected WebSocket clients.
-///
-/// # Arguments
-///
-/// * `state` - The shared application state
-/// * `message` - The message to add, extracted from the JSON request body
+/// What happens when a client connects with a name someone already has.
 ///
-/// # Returns
-///
-/// Returns status 201 CREATED if the message is successfully processed.
-async fn handle_post(
-    State(state): State<AppState>,
-    Json(message): Json<Message>,
-) -> impl IntoResponse {
-    let mut messages = state.messages.lock().unwrap();
-    messages.push(message.clone());
+/// Names are compared ignoring case, since "alice" and "Alice" are just as
+/// ambiguous in DMs and mentions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateNamePolicy {
+    /// Register the client with the first free suffix, e.g. "Alice_2"
+    #[default]
+    Suffix,
+    /// Turn the client away with a 409 error
+    Reject,
+}
 
-    // Remove oldest messages if we exceed the limit
-    if messages.len() > MAX_MESSAGES {
-        let drain_end = messages.len() - MAX_MESSAGES;
-        messages.drain(0..drain_end);
-    }
+impl FromStr for DuplicateNamePolicy {
+    type Err = String;
 
-    // Broadcast to all WebSocket clients
-    let clients = state.clients.lock().unwrap();
-    for client_tx in clients.iter() {
-        let _ = client_tx.send(message.clone());
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "suffix" => Ok(Self::Suffix),
+            "reject" => Ok(Self::Reject),
+            _ => Err(format!(
+                "Invalid duplicate name policy '{}', expected suffix or reject",
+                s
+            )),
+        }
     }
-
-    StatusCode::CREATED
 }
 
-async fn run_tui_server(state: AppState, socket_addr: SocketAddr) -> ChatResult<()> {
-    let listener = tokio::net::TcpListener::bind(socket_addr)
-        .await
-        .map_err(|e| {
-            ChatError::NetworkError(format!("Failed to bind to {}: {}", socket_addr, e))
-        })?;
-    let state_clone = state.clone();
+/// Who may change a room's topic.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TopicPolicy {
+    /// Anyone in the room
+    #[default]
+    Anyone,
+    /// Moderators and admins only
+    Moderators,
+}
 
-    // Start the server in a separate task
-    let server_handle = tokio::spawn(async move {
-        axum::serve(listener, {
-            Router::new()
-                .route("/room/1", get(handle_websocket))
-                .route("/room/1", post(handle_post))
-                .route("/messages", get(handle_get))
-                .with_state(state_clone.clone())
-        })
-        .await
-        .unwrap();
-    });
+impl FromStr for TopicPolicy {
+    type Err = String;
 
-    // Run TUI
-    if let Err(e) = run_tui(state).await {
-        eprintln!("TUI error: {}", e);
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "anyone" => Ok(Self::Anyone),
+            "moderators" => Ok(Self::Moderators),
+            _ => Err(format!(
+                "Invalid topic policy '{}', expected anyone or moderators",
+                s
+            )),
+        }
     }
+}
 
-    // Cancel server task
-    server_handle.abort();
-
-    Ok(())
+/// What happens when a moderator pins a message in a room that already has
+/// `max_pins` pinned.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PinOverflowPolicy {
+    /// Refuse the new pin
+    #[default]
+    Reject,
+    /// Unpin the message that was pinned first to make room
+    EvictOldest,
 }
 
-async fn broadcast_user_list(state: &AppState) {
-    let user_list = {
-        let users = state.users.lock().unwrap();
-        UserList::from_users(&users.values().cloned().collect::<Vec<_>>())
-    };
+impl FromStr for PinOverflowPolicy {
+    type Err = String;
 
-    let server_msg = ServerMessage::UserList(user_list);
-    broadcast_server_message(state, &server_msg).await;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "evict-oldest" => Ok(Self::EvictOldest),
+            _ => Err(format!(
+                "Invalid pin overflow policy '{}', expected reject or evict-oldest",
+                s
+            )),
+        }
+    }
 }
 
-async fn broadcast_user_joined(state: &AppState, user_name: &str) {
-    let server_msg = ServerMessage::UserJoined {
-        name: user_name.to_string(),
-    };
-    broadcast_server_message(state, &server_msg).await;
+/// Runtime configuration for the chat server.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// The IP address to bind the server to (e.g., "127.0.0.1")
+    pub address: String,
+    /// The port number to listen on (e.g., 12345)
+    pub port: u16,
+    /// PEM certificate chain to serve HTTPS and WSS with; the server speaks
+    /// plain HTTP and WebSocket when `None`
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for `tls_cert`
+    pub tls_key: Option<PathBuf>,
+    /// Whether to enable the terminal user interface
+    pub tui: bool,
+    /// File to persist chat history to; persistence is disabled when `None`
+    pub persist_path: Option<PathBuf>,
+    /// How often persisted messages are flushed to disk
+    pub flush_policy: FlushPolicy,
+    /// Bearer token required by `/admin` endpoints; they are disabled when `None`
+    pub admin_token: Option<String>,
+    /// Maximum length of a single message, in Unicode scalar values
+    pub max_message_len: usize,
+    /// Whether to record each client's remote address for the admin user list
+    pub record_ips: bool,
+    /// Whether to serve Prometheus metrics at `/metrics`
+    pub metrics_endpoint: bool,
+    /// Maximum number of messages per second accepted from a single client
+    pub rate_limit_per_sec: u32,
+    /// How often idle connections are pinged; a connection that sends nothing
+    /// (not even a pong) for two intervals is considered dead and closed
+    pub keepalive_interval: Duration,
+    /// Token clients must present to join a room or post; anyone may when `None`
+    pub auth_token: Option<String>,
+    /// Token that makes a connecting client a moderator
+    pub moderator_token: Option<String>,
+    /// File written by `/admin/snapshot` and read by `/admin/restore`;
+    /// both are disabled when `None`
+    pub snapshot_path: Option<PathBuf>,
+    /// Display names kept for admins; connecting as one requires `admin_token`
+    pub admins: Vec<String>,
+    /// How long a kicked name is kept from rejoining; zero disables bans
+    pub ban_cooldown: Duration,
+    /// How long a user can go without sending a message before they're
+    /// marked away, checked every `keepalive_interval`; zero disables it
+    pub away_after: Duration,
+    /// How long a connection may go without the client sending anything
+    /// (pongs aside) before it is closed; zero disables it
+    pub idle_timeout: Duration,
+    /// Whether control characters in messages are passed through verbatim
+    /// instead of being stripped
+    pub allow_control_chars: bool,
+    /// Whether messages that are empty or only whitespace are accepted
+    /// instead of being rejected
+    pub allow_empty: bool,
+    /// Name reported by `/version` and `/healthz` and greeted with on connect
+    pub server_name: String,
+    /// Maximum number of messages queued for a single client
+    pub outbound_capacity: usize,
+    /// What happens when a client falls `outbound_capacity` messages behind
+    pub overflow_policy: OverflowPolicy,
+    /// What happens when a client connects with a name that is already taken
+    pub duplicate_names: DuplicateNamePolicy,
+    /// Maximum number of messages kept in each room's history; 0 keeps up to
+    /// `UNLIMITED_MAX_MESSAGES`
+    pub max_messages: usize,
+    /// Number of recent messages replayed to a client joining a room; 0
+    /// replays none
+    pub join_backlog: usize,
+    /// Directory files uploaded to rooms are kept in; uploads are disabled
+    /// when `None`
+    pub upload_dir: Option<PathBuf>,
+    /// Largest file accepted for upload, in bytes
+    pub max_upload_size: usize,
+    /// Base URL the server is reached at from outside, e.g. behind a reverse
+    /// proxy; uploaded files are announced with links relative to the server
+    /// when `None`
+    pub public_url: Option<String>,
+    /// Content types accepted for upload, without parameters
+    pub upload_types: Vec<String>,
+    /// Password clients must give to join the default room; other rooms get
+    /// theirs when created
+    pub room_password: Option<String>,
+    /// Terms filtered out of messages; nothing is filtered when `None`
+    pub blocklist: Option<Blocklist>,
+    /// Token for `POST /hooks/{room}`, keyed by room; rooms without one
+    /// have no webhook
+    pub hook_tokens: HashMap<String, String>,
+    /// Greeting sent only to a client joining a room, after its history;
+    /// nobody is greeted when `None`
+    pub welcome: Option<String>,
+    /// Greetings replacing `welcome` in particular rooms, keyed by room
+    pub room_welcomes: HashMap<String, String>,
+    /// Who may change a room's topic
+    pub topic_policy: TopicPolicy,
+    /// Key stored messages are signed with, so clients holding the same key
+    /// can tell if they were tampered with; nothing is signed when `None`
+    pub hmac_key: Option<String>,
+    /// Maximum number of messages pinned in a room at once; zero allows any
+    /// number
+    pub max_pins: usize,
+    /// What happens when a room already has `max_pins` pinned messages
+    pub pin_overflow: PinOverflowPolicy,
+    /// Maximum number of different emoji on a single message; zero allows
+    /// any number
+    pub max_distinct_reactions: usize,
+    /// Maximum number of reactions on a single message, counting every
+    /// user's; zero allows any number
+    pub max_reactions: usize,
+    /// Maximum number of WebSocket connections open at once; zero allows
+    /// any number
+    pub max_connections: usize,
+    /// Maximum number of WebSocket connections open at once from a single
+    /// IP address; zero allows any number
+    pub max_connections_per_ip: usize,
 }
 
-async fn broadcast_user_left(state: &AppState, user_name: &str) {
-    let server_msg = ServerMessage::UserLeft {
-        name: user_name.to_string(),
-    };
-    broadcast_server_message(state, &server_msg).await;
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1".to_string(),
+            port: 12345,
+            tls_cert: None,
+            tls_key: None,
+            tui: false,
+            persist_path: None,
+            flush_policy: FlushPolicy::default(),
+            admin_token: None,
+            max_message_len: 4096,
+            record_ips: false,
+            metrics_endpoint: false,
+            rate_limit_per_sec: DEFAULT_RATE_LIMIT_PER_SEC,
+            keepalive_interval: Duration::from_secs(30),
+            server_name: DEFAULT_SERVER_NAME.to_string(),
+            auth_token: None,
+            moderator_token: None,
+            snapshot_path: None,
+            allow_control_chars: false,
+            allow_empty: false,
+            admins: Vec::new(),
+            ban_cooldown: Duration::from_secs(300),
+            away_after: DEFAULT_AWAY_AFTER,
+            idle_timeout: Duration::ZERO,
+            outbound_capacity: DEFAULT_OUTBOUND_CAPACITY,
+            overflow_policy: OverflowPolicy::default(),
+            duplicate_names: DuplicateNamePolicy::default(),
+            max_messages: DEFAULT_MAX_MESSAGES,
+            join_backlog: DEFAULT_JOIN_BACKLOG,
+            upload_dir: None,
+            max_upload_size: DEFAULT_MAX_UPLOAD_SIZE,
+            public_url: None,
+            upload_types: DEFAULT_UPLOAD_TYPES.iter().map(|t| t.to_string()).collect(),
+            room_password: None,
+            blocklist: None,
+            hook_tokens: HashMap::new(),
+            welcome: None,
+            room_welcomes: HashMap::new(),
+            topic_policy: TopicPolicy::default(),
+            hmac_key: None,
+            max_pins: DEFAULT_MAX_PINS,
+            pin_overflow: PinOverflowPolicy::default(),
+            max_distinct_reactions: DEFAULT_MAX_DISTINCT_REACTIONS,
+            max_reactions: DEFAULT_MAX_REACTIONS,
+            max_connections: 0,
+            max_connections_per_ip: 0,
+        }
+    }
 }
 
-async fn broadcast_server_message(state: &AppState, server_msg: &ServerMessage) {
-    let json = serde_json::to_string(server_msg).expect("Failed to serialize server message");
-    let message = Message { text: json };
+/// Channel used to push messages to one connected client
+pub type ClientSender = OutboundSender;
+
+/// Handle to a single WebSocket connection.
+///
+/// Used to deliver messages to the client and to make the connection close
+/// itself from elsewhere on the server (e.g. when its room expires).
+#[derive(Clone)]
+pub struct ClientHandle {
+    /// Outbound queue drained by the connection's send task
+    pub tx: ClientSender,
+    /// Notified to make the connection shut down and run its cleanup
+    pub close: Arc<Notify>,
+}
 
-    let clients = state.clients.lock().unwrap();
-    for client_tx in clients.iter() {
-        let _ = client_tx.send(message.clone());
+impl ClientHandle {
+    /// Creates a handle around the given outbound channel.
+    pub fn new(tx: ClientSender) -> Self {
+        Self {
+            tx,
+            close: Arc::new(Notify::new()),
+        }
     }
 }
 
-async fn run_tui(state: AppState) -> Result<(), Box<dyn std::error::Error>> {
-    println!("TUI Mode - Press Ctrl+C to exit");
+/// An open `GET /room/{room}/stream` response, fed like a member of `room`.
+#[derive(Clone)]
+pub struct StreamHandle {
+    /// The room whose events are streamed
+    pub room: String,
+    /// Outbound queue drained into the response
+    pub tx: ClientSender,
+}
 
-    loop {
-        // Clear screen and print status
-        print!("\x1B[2J\x1B[H"); // Clear screen and move cursor to top
+/// Unregisters a stream when its response is dropped, e.g. because the
+/// client went away.
+struct StreamGuard {
+    state: AppState,
+    id: String,
+}
 
-        let users_count = {
-            let users_guard = state.users.lock().unwrap();
-            users_guard.len()
-        };
-        let users_list = {
-            let users_guard = state.users.lock().unwrap();
-            users_guard.values().cloned().collect::<Vec<_>>()
-        };
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.state.streams.lock_or_recover().remove(&self.id);
+    }
+}
 
-        println!("┌─────────────────────────────────────────┐");
-        println!("│ Chat Server TUI                          │");
-        println!("├─────────────────────────────────────────┤");
-        println!("│ Connected Users: {}                     │", users_count);
-        println!("├─────────────────────────────────────────┤");
+/// A WebSocket connection's place in `AppState::connections`, given back
+/// when the connection is dropped.
+struct ConnectionSlot {
+    state: AppState,
+    ip: IpAddr,
+}
 
-        if users_list.is_empty() {
-            println!("│ No users connected                      │");
-        } else {
-            for (i, user) in users_list.iter().enumerate() {
-                let duration = user.connected_at.elapsed();
-                if i < 10 {
-                    // Limit display to 10 users
-                    println!(
-                        "│ {} ({}s ago)                       │",
-                        user.name.chars().take(30).collect::<String>(),
-                        duration.as_secs()
-                    );
-                }
-            }
-            if users_list.len() > 10 {
-                println!(
-                    "│ ... and {} more users                  │",
-                    users_list.len() - 10
-                );
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut connections = self.state.connections.lock_or_recover();
+        if let Some(count) = connections.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.ip);
             }
         }
-
-        println!("├─────────────────────────────────────────┤");
-        println!("│ Press Ctrl+C to quit                    │");
-        println!("└─────────────────────────────────────────┘");
-
-        // Sleep for 1 second before refreshing
-        tokio::time::sleep(Duration::from_secs(1)).await;
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::time::{Duration, Instant};
-    use tokio::time::sleep;
-
-    #[tokio::test]
-    async fn test_message_serialization() {
-        let message = Message {
-            text: "Hello, World!".to_string(),
-        };
+/// The most recent client message keys seen from one author, with the IDs
+/// their messages were stored under, oldest dropped first.
+///
+/// Keys are forgotten after [`RECENT_CLIENT_ID_TTL`]; callers pass the
+/// current time in.
+#[derive(Debug, Default)]
+struct RecentClientIds {
+    order: VecDeque<(String, Instant)>,
+    ids: HashMap<String, u64>,
+}
 
-        let json = serde_json::to_string(&message).unwrap();
-        let deserialized: Message = serde_json::from_str(&json).unwrap();
+impl RecentClientIds {
+    /// Returns the ID the message with `key` was stored under, if it was
+    /// seen recently.
+    fn get(&mut self, key: &str, now: Instant) -> Option<u64> {
+        self.expire(now);
+        self.ids.get(key).copied()
+    }
 
-        assert_eq!(message.text, deserialized.text);
+    /// Remembers that the message with `key` was stored as `id`.
+    fn insert(&mut self, key: String, id: u64, now: Instant) {
+        self.expire(now);
+        if self.ids.insert(key.clone(), id).is_some() {
+            return;
+        }
+        self.order.push_back((key, now));
+        if self.order.len() > RECENT_CLIENT_IDS
+            && let Some((oldest, _)) = self.order.pop_front()
+        {
+            self.ids.remove(&oldest);
+        }
     }
 
-    #[tokio::test]
-    async fn test_user_creation() {
-        let user_id = uuid::Uuid::new_v4().to_string();
-        let user = User {
-            id: user_id.clone(),
-            name: format!("User_{}", user_id.split('-').next().unwrap()),
-            connected_at: Instant::now(),
-        };
+    /// Forgets keys seen longer than [`RECENT_CLIENT_ID_TTL`] before `now`.
+    fn expire(&mut self, now: Instant) {
+        while let Some((key, seen)) = self.order.front()
+            && now.saturating_duration_since(*seen) >= RECENT_CLIENT_ID_TTL
+        {
+            self.ids.remove(key);
+            self.order.pop_front();
+        }
+    }
 
-        assert!(!user.id.is_empty());
-        assert!(!user.name.is_empty());
-        assert!(user.name.starts_with("User_"));
+    /// Returns whether no keys are remembered.
+    fn is_empty(&self) -> bool {
+        self.order.is_empty()
     }
+}
 
-    #[tokio::test]
-    async fn test_app_state_creation() {
-        let messages = Arc::new(Mutex::new(Vec::new()));
-        let clients = Arc::new(Mutex::new(Vec::new()));
-        let users = Arc::new(Mutex::new(HashMap::new()));
+/// State of a single chat room.
+#[derive(Debug, Default)]
+pub struct RoomState {
+    /// Chat history of the room, oldest first
+    pub messages: VecDeque<Message>,
+    /// ID given to the most recently stored message
+    pub last_id: u64,
+    /// IDs of pinned messages, in the order they were pinned
+    pub pinned: Vec<u64>,
+    /// Number of messages dropped from the front of the history by the cap
+    pub dropped: usize,
+    /// Dropped messages not yet announced with `HistoryTrimmed`
+    unannounced: usize,
+    /// When the last `HistoryTrimmed` notice went out
+    announced_at: Option<Instant>,
+    /// Hash of the password needed to join, or `None` if anyone may
+    pub password: Option<PasswordHash>,
+    /// Line describing what the room is about, shown to everyone in it
+    pub topic: Option<String>,
+    /// When an ephemeral room closes. Also tells its expiry timer that the
+    /// room by that name is still the one it was started for
+    expires_at: Option<Instant>,
+}
 
-        let app_state = AppState {
-            messages: messages.clone(),
-            clients: clients.clone(),
-            users: users.clone(),
-        };
+impl RoomState {
+    /// Creates a room holding previously saved history.
+    ///
+    /// Messages saved before message IDs existed are numbered after the
+    /// highest one already present.
+    fn with_history(mut messages: Vec<Message>) -> Self {
+        let mut last_id = messages.iter().filter_map(|msg| msg.id).max().unwrap_or(0);
+        for msg in messages.iter_mut().filter(|msg| msg.id.is_none()) {
+            last_id += 1;
+            msg.id = Some(last_id);
+        }
+        Self {
+            messages: messages.into(),
+            last_id,
+            ..Self::default()
+        }
+    }
+
+    /// Drops the oldest messages beyond `max_messages` (see [`trim_history`]),
+    /// returning how many were dropped.
+    fn trim(&mut self, max_messages: usize) -> usize {
+        let dropped = trim_history(&mut self.messages, max_messages);
+        self.dropped += dropped;
+        dropped
+    }
+
+    /// Counts `dropped` messages towards the next `HistoryTrimmed` notice.
+    ///
+    /// Returns the number to announce if no notice went out within
+    /// `HISTORY_TRIMMED_INTERVAL`; otherwise they wait for a later trim.
+    fn announce_trim(&mut self, dropped: usize) -> Option<usize> {
+        self.unannounced += dropped;
+        if self.unannounced == 0
+            || self
+                .announced_at
+                .is_some_and(|at| at.elapsed() < HISTORY_TRIMMED_INTERVAL)
+        {
+            return None;
+        }
+        self.announced_at = Some(Instant::now());
+        Some(std::mem::take(&mut self.unannounced))
+    }
+}
+
+/// Represents the shared application state for the chat server.
+///
+/// This struct contains all the data that needs to be shared across
+/// different async tasks and WebSocket connections.
+///
+/// The maps sit behind `std::sync::Mutex`es, which are cheap because every
+/// guard is short-lived: take it in a block or a single expression, copy out
+/// what is needed and drop it before the next `.await` (enforced with
+/// `clippy::await_holding_lock`). Sending to a client only queues the
+/// message, so broadcasting never waits on a socket while holding a lock.
+#[derive(Clone)]
+pub struct AppState {
+    /// Rooms keyed by room ID; the default room always exists
+    pub rooms: Arc<Mutex<HashMap<String, RoomState>>>,
+    /// Active WebSocket client connections, keyed by user ID
+    pub clients: Arc<Mutex<HashMap<String, ClientHandle>>>,
+    /// Mapping of user IDs to user information
+    pub users: Arc<Mutex<HashMap<String, User>>>,
+    /// On-disk history store, present when persistence is enabled
+    pub storage: Option<Arc<Mutex<MessageStore>>>,
+    /// Kicked names and when they may rejoin
+    pub bans: Arc<Mutex<HashMap<String, Instant>>>,
+    /// Rate limiters for `POST /room/{room}`, keyed by remote address
+    pub post_limits: Arc<Mutex<HashMap<IpAddr, TokenBucket>>>,
+    /// Server configuration shared by all handlers
+    pub config: Arc<ServerConfig>,
+    /// Counters and timings exposed by `/metrics` and `/stats`
+    pub metrics: Metrics,
+    /// Signs stored messages, built from `config.hmac_key`
+    pub signer: Option<MessageSigner>,
+    /// When each user name last sent a message or disconnected, in seconds
+    /// since the Unix epoch
+    pub last_seen: Arc<Mutex<HashMap<String, u64>>>,
+    /// Open event streams, keyed by a random ID
+    pub streams: Arc<Mutex<HashMap<String, StreamHandle>>>,
+    /// Open WebSocket connections, counted per remote address
+    pub connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    /// Recent client message keys, keyed by author, so a resend after a
+    /// reconnect isn't posted twice
+    client_ids: Arc<Mutex<HashMap<String, RecentClientIds>>>,
+}
+
+impl AppState {
+    /// Creates an empty state with the default configuration.
+    #[cfg_attr(not(feature = "client"), allow(dead_code))]
+    pub fn new() -> Self {
+        Self::with_config(ServerConfig::default())
+    }
+
+    /// Creates an empty state using the given configuration.
+    ///
+    /// Persistence is not set up here; `run_server` attaches the store.
+    pub fn with_config(config: ServerConfig) -> Self {
+        let mut rooms = HashMap::new();
+        let default_room = RoomState {
+            password: config.room_password.as_deref().map(PasswordHash::new),
+            ..RoomState::default()
+        };
+        rooms.insert(DEFAULT_ROOM.to_string(), default_room);
+        let metrics = Metrics::default();
+        metrics.set_rooms(rooms.len());
+
+        Self {
+            rooms: Arc::new(Mutex::new(rooms)),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            users: Arc::new(Mutex::new(HashMap::new())),
+            storage: None,
+            bans: Arc::new(Mutex::new(HashMap::new())),
+            post_limits: Arc::new(Mutex::new(HashMap::new())),
+            signer: config.hmac_key.as_deref().map(MessageSigner::new),
+            last_seen: Arc::new(Mutex::new(HashMap::new())),
+            streams: Arc::new(Mutex::new(HashMap::new())),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            client_ids: Arc::new(Mutex::new(HashMap::new())),
+            config: Arc::new(config),
+            metrics,
+        }
+    }
+}
+
+/// Starts the chat server with the specified configuration.
+///
+/// # Arguments
+///
+/// * `config` - Listen address, port, TUI and persistence settings
+///
+/// # Returns
+///
+/// The server runs until Ctrl+C, then notifies clients, waits for their
+/// queued messages to be sent and flushes persisted history.
+///
+/// Returns `Ok(())` once the server has shut down cleanly, or an error if binding
+/// fails or persisted history can't be loaded.
+///
+/// # Examples
+///
+/// ```rust
+/// // Start server on localhost:12345 without TUI
+/// run_server(ServerConfig::default()).await?;
+/// ```
+pub async fn run_server(config: ServerConfig) -> ChatResult<()> {
+    let mut app_state = AppState::with_config(config.clone());
+
+    if let Some(path) = &config.persist_path {
+        let mut store = MessageStore::new(path, config.flush_policy);
+        let mut room = RoomState::with_history(store.load()?);
+        room.trim(config.max_messages);
+        println!(
+            "Loaded {} messages from {}",
+            room.messages.len(),
+            path.display()
+        );
+        let mut rooms = app_state.rooms.lock_or_recover();
+        room.password = rooms
+            .get_mut(DEFAULT_ROOM)
+            .and_then(|default_room| default_room.password.take());
+        rooms.insert(DEFAULT_ROOM.to_string(), room);
+        // Only the default room outlives a restart, so its topic is the only
+        // one that can apply
+        for (id, topic) in store.load_topics()? {
+            if let Some(room) = rooms.get_mut(&id) {
+                room.topic = Some(topic);
+            }
+        }
+        drop(rooms);
+        let mut last_seen: HashMap<String, u64> = store.load_last_seen()?.into_iter().collect();
+        forget_oldest_seen(&mut last_seen, MAX_LAST_SEEN);
+        // Start from a compacted file so the log only holds this run's
+        // disconnects
+        store.save_last_seen(&last_seen.iter().map(|(k, &v)| (k.clone(), v)).collect())?;
+        app_state.last_seen = Arc::new(Mutex::new(last_seen));
+
+        let storage = Arc::new(Mutex::new(store));
+        spawn_flush_task(storage.clone());
+        app_state.storage = Some(storage);
+    }
+
+    let addr = format!("{}:{}", config.address, config.port);
+    let socket_addr: SocketAddr = addr.parse().expect("Invalid address");
+    let scheme = if config.tls_cert.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+
+    if config.tui {
+        println!(
+            "Chat server running on {}://{} with TUI",
+            scheme, socket_addr
+        );
+        run_tui_server(app_state.clone(), socket_addr).await?;
+    } else {
+        println!("Chat server running on {}://{}", scheme, socket_addr);
+        let listener = tokio::net::TcpListener::bind(socket_addr)
+            .await
+            .map_err(|e| {
+                ChatError::NetworkError(format!("Failed to bind to {}: {}", socket_addr, e))
+            })?;
+        serve(app_state.clone(), listener, std::future::pending()).await?;
+    }
+
+    finish_shutdown(&app_state).await;
+    println!("Server stopped");
+    Ok(())
+}
+
+/// Serves the chat endpoints on `listener` until Ctrl+C or `quit`, then
+/// shuts down gracefully.
+///
+/// Speaks HTTPS and WSS when the config names a TLS certificate and key,
+/// failing if they can't be loaded, and plain HTTP and WebSocket otherwise.
+async fn serve(
+    state: AppState,
+    listener: tokio::net::TcpListener,
+    quit: impl Future<Output = ()> + Send + 'static,
+) -> ChatResult<()> {
+    let app = app_router(state.clone()).into_make_service_with_connect_info::<SocketAddr>();
+    let shutdown = shutdown_signal(state.clone(), quit);
+    let served = match (&state.config.tls_cert, &state.config.tls_key) {
+        (Some(cert), Some(key)) => {
+            let tls = RustlsConfig::from_pem_file(cert, key).await.map_err(|e| {
+                ChatError::ConfigError(format!(
+                    "Failed to load TLS certificate {} and key {}: {}",
+                    cert.display(),
+                    key.display(),
+                    e
+                ))
+            })?;
+            let handle = axum_server::Handle::new();
+            let shutdown_handle = handle.clone();
+            tokio::spawn(async move {
+                shutdown.await;
+                shutdown_handle.graceful_shutdown(Some(SHUTDOWN_DRAIN_TIMEOUT));
+            });
+            axum_server::from_tcp_rustls(listener.into_std()?, tls)
+                .handle(handle)
+                .serve(app)
+                .await
+        }
+        _ => {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown)
+                .await
+        }
+    };
+    served.map_err(|e| ChatError::NetworkError(format!("Server runtime error: {}", e)))
+}
+
+/// Waits for Ctrl+C or for `quit` to resolve, then tells every client the
+/// server is going away.
+async fn shutdown_signal(state: AppState, quit: impl Future<Output = ()>) {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            eprintln!("Failed to listen for shutdown signal: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = quit => {}
+    }
+    println!("Shutting down...");
+    begin_shutdown(&state);
+}
+
+/// Sends `ServerShutdown` to every client and asks their connections to close.
+///
+/// Each connection delivers whatever is still queued for it before closing.
+fn begin_shutdown(state: &AppState) {
+    let json = serde_json::to_string(&ServerMessage::ServerShutdown)
+        .expect("Failed to serialize server message");
+    let message = Message::new(json);
+
+    let clients = state.clients.lock_or_recover();
+    for handle in clients.values() {
+        let _ = handle.tx.send(message.clone());
+        handle.close.notify_one();
+    }
+}
+
+/// Waits (up to `SHUTDOWN_DRAIN_TIMEOUT`) for connections to finish closing,
+/// then writes any pending history to disk.
+async fn finish_shutdown(state: &AppState) {
+    let drained = tokio::time::timeout(SHUTDOWN_DRAIN_TIMEOUT, async {
+        while !state.clients.lock_or_recover().is_empty() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await;
+    if drained.is_err() {
+        eprintln!("Timed out waiting for clients to disconnect");
+    }
+
+    if let Some(storage) = &state.storage
+        && let Err(e) = storage.lock_or_recover().flush()
+    {
+        eprintln!("Failed to persist messages: {}", e);
+    }
+    save_last_seen(state);
+}
+
+/// Periodically flushes pending messages so none wait longer than the flush interval.
+fn spawn_flush_task(storage: Arc<Mutex<MessageStore>>) {
+    let interval = storage.lock_or_recover().policy().interval;
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = storage.lock_or_recover().flush_if_due() {
+                eprintln!("Failed to persist messages: {}", e);
+            }
+        }
+    });
+}
+
+/// Drops the oldest messages beyond `max_messages`, where 0 means unlimited
+/// up to `UNLIMITED_MAX_MESSAGES`. Returns how many were dropped.
+fn trim_history(messages: &mut VecDeque<Message>, max_messages: usize) -> usize {
+    let limit = if max_messages == 0 {
+        UNLIMITED_MAX_MESSAGES
+    } else {
+        max_messages
+    };
+    let dropped = messages.len().saturating_sub(limit);
+    messages.drain(..dropped);
+    dropped
+}
+
+/// Appends a message to a room's history, trimming the oldest entries beyond
+/// the configured `max_messages` and queueing it for persistence when enabled.
+///
+/// The message is given the room's next message ID and the current time,
+/// and signed when a key is configured. Only the default room is persisted. When trimming drops messages, the room's members are
+/// sent a `HistoryTrimmed` notice, at most once per
+/// `HISTORY_TRIMMED_INTERVAL`. Returns the stored message, or `None` if the
+/// room no longer exists.
+fn store_message(state: &AppState, room: &str, mut message: Message) -> Option<Message> {
+    let notice = {
+        let mut rooms = state.rooms.lock_or_recover();
+        let room_state = rooms.get_mut(room)?;
+        room_state.last_id += 1;
+        message.id = Some(room_state.last_id);
+        message.timestamp = Some(unix_time());
+        // Whatever signature the poster sent is never passed on
+        message.signature = None;
+        if let Some(signer) = &state.signer {
+            signer.sign_message(&mut message);
+        }
+        room_state.messages.push_back(message.clone());
+
+        // Remove oldest messages if we exceed the limit
+        let dropped = room_state.trim(state.config.max_messages);
+
+        // Persist while still holding the history lock so rewrites can't interleave
+        if room == DEFAULT_ROOM
+            && let Some(storage) = &state.storage
+            && let Err(e) = storage.lock_or_recover().append(message.clone())
+        {
+            eprintln!("Failed to persist message: {}", e);
+        }
+
+        if dropped > 0 {
+            room_state.announce_trim(dropped)
+        } else {
+            None
+        }
+    };
+
+    if let Some(dropped) = notice {
+        let json = serde_json::to_string(&ServerMessage::HistoryTrimmed { dropped })
+            .expect("Failed to serialize server message");
+        send_to_members(state, room, &Message::new(json));
+    }
+    Some(message)
+}
+
+/// Looks up message `id` in `room` for a reply to quote.
+///
+/// Returns a snippet of the message, `None` if it has since been trimmed
+/// from the history (the reply then goes out as a plain message), or a 404
+/// error if no message with that ID was ever stored.
+fn reply_quote(state: &AppState, room: &str, id: u64) -> Result<Option<String>, ServerMessage> {
+    let rooms = state.rooms.lock_or_recover();
+    let room_state = rooms
+        .get(room)
+        .filter(|room_state| id != 0 && id <= room_state.last_id)
+        .ok_or_else(|| ServerMessage::error(404, format!("Message #{} not found", id)))?;
+    Ok(room_state
+        .messages
+        .iter()
+        .find(|msg| msg.id == Some(id))
+        .map(|parent| {
+            let text = parent.display_text();
+            match text.char_indices().nth(QUOTE_LEN) {
+                Some((end, _)) => format!("{}…", &text[..end]),
+                None => text,
+            }
+        }))
+}
+
+/// Returns the author key for the identity a client presented, or `None` if
+/// it presented none (or one that's blank or too long to be a real secret).
+///
+/// Only a hash of the identity is kept, so the history file doesn't hold
+/// the secret that would let someone take over its messages.
+fn author_key(identity: Option<&str>) -> Option<String> {
+    let identity = identity?.trim();
+    if identity.is_empty() || identity.len() > MAX_IDENTITY_LEN {
+        return None;
+    }
+    let hash = ring::digest::digest(&ring::digest::SHA256, identity.as_bytes());
+    Some(hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Applies `change` to message `id` in `room` if the author with key
+/// `author_id` wrote it.
+///
+/// The change is appended to the persisted history. Returns the updated message,
+/// or the error to send back: 404 if there's no such message (or it was
+/// deleted) and 403 if it belongs to someone else.
+fn modify_own_message(
+    state: &AppState,
+    room: &str,
+    id: u64,
+    author_id: &str,
+    change: impl FnOnce(&mut Message),
+) -> Result<Message, ServerMessage> {
+    let mut rooms = state.rooms.lock_or_recover();
+    let not_found = || ServerMessage::error(404, format!("Message #{} not found", id));
+    let room_state = rooms.get_mut(room).ok_or_else(not_found)?;
+    let message = room_state
+        .messages
+        .iter_mut()
+        .find(|msg| msg.id == Some(id) && !msg.deleted)
+        .ok_or_else(not_found)?;
+    if message.author_id.as_deref() != Some(author_id) {
+        return Err(ServerMessage::error(
+            403,
+            "You can only change your own messages",
+        ));
+    }
+    change(message);
+    if let Some(signer) = &state.signer {
+        signer.sign_message(message);
+    }
+    let message = message.clone();
+    persist_changes(state, room, room_state, [&message]);
+
+    Ok(message)
+}
+
+/// Messages taken out of a room's history at once.
+#[derive(Debug, Default, PartialEq)]
+struct Purged {
+    /// IDs of the messages tombstoned, in order
+    deleted: Vec<u64>,
+    /// Those of them that were pinned
+    unpinned: Vec<u64>,
+}
+
+/// Tombstones every message the author with key `author_id` wrote in
+/// `room`, like a `Delete` of each.
+fn purge_own_messages(state: &AppState, room: &str, author_id: &str) -> Purged {
+    tombstone_messages(state, room, |msg| {
+        msg.author_id.as_deref() == Some(author_id)
+    })
+}
+
+/// Tombstones every message `name` sent, in all rooms.
+///
+/// Returns what was removed per room, omitting rooms where nothing changed.
+fn purge_user_messages(state: &AppState, name: &str) -> Vec<(String, Purged)> {
+    let rooms: Vec<String> = state.rooms.lock_or_recover().keys().cloned().collect();
+    rooms
+        .into_iter()
+        .map(|room| {
+            let purged =
+                tombstone_messages(state, &room, |msg| msg.sender.as_deref() == Some(name));
+            (room, purged)
+        })
+        .filter(|(_, purged)| !purged.deleted.is_empty())
+        .collect()
+}
+
+/// Tombstones every live message in `room` that `matches`, the way a
+/// `Delete` does, and unpins them.
+fn tombstone_messages(state: &AppState, room: &str, matches: impl Fn(&Message) -> bool) -> Purged {
+    let mut rooms = state.rooms.lock_or_recover();
+    let Some(room_state) = rooms.get_mut(room) else {
+        return Purged::default();
+    };
+    let mut purged = Vec::new();
+    for message in room_state
+        .messages
+        .iter_mut()
+        .filter(|msg| !msg.deleted && matches(msg))
+    {
+        message.text = DELETED_PLACEHOLDER.to_string();
+        message.deleted = true;
+        if let Some(signer) = &state.signer {
+            signer.sign_message(message);
+        }
+        purged.push(message.clone());
+    }
+
+    if !purged.is_empty() {
+        persist_changes(state, room, room_state, &purged);
+    }
+
+    let deleted: Vec<u64> = purged.iter().filter_map(|message| message.id).collect();
+    let unpinned = room_state
+        .pinned
+        .iter()
+        .copied()
+        .filter(|id| deleted.contains(id))
+        .collect();
+    room_state.pinned.retain(|id| !deleted.contains(id));
+    Purged { deleted, unpinned }
+}
+
+/// Tells everyone in `room` about messages taken out of its history, as
+/// for a `Delete` and `Unpin` of each.
+async fn announce_purged(state: &AppState, room: &str, purged: &Purged) {
+    for &id in &purged.unpinned {
+        broadcast_server_message(state, room, &ServerMessage::MessageUnpinned { id }).await;
+    }
+    for &id in &purged.deleted {
+        broadcast_server_message(state, room, &ServerMessage::MessageDeleted { id }).await;
+    }
+}
+
+/// Adds or removes `name`'s `emoji` reaction on message `id` in `room`.
+///
+/// Returns whether the reaction was added, a 404 error if the message isn't
+/// in the room's history (e.g. it was trimmed or deleted), or a 422 error if
+/// adding it would take the message past `max_distinct_reactions` or
+/// `max_reactions`. Removing a reaction is always allowed.
+fn toggle_reaction(
+    state: &AppState,
+    room: &str,
+    id: u64,
+    emoji: &str,
+    name: &str,
+) -> Result<bool, ServerMessage> {
+    let mut rooms = state.rooms.lock_or_recover();
+    let room_state = rooms
+        .get_mut(room)
+        .ok_or_else(|| ServerMessage::error(404, format!("Message #{} not found", id)))?;
+    let message = room_state
+        .messages
+        .iter_mut()
+        .find(|msg| msg.id == Some(id) && !msg.deleted)
+        .ok_or_else(|| ServerMessage::error(404, format!("Message #{} not found", id)))?;
+
+    let adding = !message
+        .reactions
+        .get(emoji)
+        .is_some_and(|names| names.iter().any(|n| n == name));
+    if adding {
+        let config = &state.config;
+        let new_kind = !message.reactions.contains_key(emoji);
+        if new_kind
+            && config.max_distinct_reactions > 0
+            && message.reactions.len() >= config.max_distinct_reactions
+        {
+            return Err(ServerMessage::error(
+                422,
+                format!(
+                    "Messages can carry at most {} different reactions",
+                    config.max_distinct_reactions
+                ),
+            ));
+        }
+        let total: usize = message.reactions.values().map(Vec::len).sum();
+        if config.max_reactions > 0 && total >= config.max_reactions {
+            return Err(ServerMessage::error(
+                422,
+                format!(
+                    "Messages can carry at most {} reactions",
+                    config.max_reactions
+                ),
+            ));
+        }
+    }
+
+    let names = message.reactions.entry(emoji.to_string()).or_default();
+    let added = match names.iter().position(|n| n == name) {
+        Some(i) => {
+            names.remove(i);
+            false
+        }
+        None => {
+            names.push(name.to_string());
+            true
+        }
+    };
+    if names.is_empty() {
+        message.reactions.remove(emoji);
+    }
+    let message = message.clone();
+    persist_changes(state, room, room_state, [&message]);
+
+    Ok(added)
+}
+
+/// Appends `changed` messages of `room` to the persisted history, if it's
+/// the room being persisted.
+///
+/// Once more changes than messages have piled up, the history is
+/// rewritten from `room_state` instead, so the file stays in proportion.
+fn persist_changes<'a>(
+    state: &AppState,
+    room: &str,
+    room_state: &RoomState,
+    changed: impl IntoIterator<Item = &'a Message>,
+) {
+    if room != DEFAULT_ROOM {
+        return;
+    }
+    let Some(storage) = &state.storage else {
+        return;
+    };
+    let mut storage = storage.lock_or_recover();
+    match storage.update(changed) {
+        Ok(appended) if appended > room_state.messages.len() => {
+            if let Err(e) = storage.rewrite(&room_state.messages) {
+                eprintln!("Failed to rewrite persisted history: {}", e);
+            }
+        }
+        Ok(_) => {}
+        Err(e) => eprintln!("Failed to save changed messages: {}", e),
+    }
+}
+
+/// Returns whether `emoji` is acceptable as a reaction.
+fn is_valid_reaction(emoji: &str) -> bool {
+    let len = emoji.chars().count();
+    (1..=MAX_REACTION_LEN).contains(&len) && !emoji.chars().any(char::is_whitespace)
+}
+
+/// Resends the messages of `room` with IDs in `from..=to` to a
+/// single client.
+///
+/// The range is clamped to what the room still retains. If part of it has
+/// already been trimmed from history, the client is told with a
+/// `HistoryGap` before the remaining messages are sent.
+fn replay_history(state: &AppState, room: &str, from: u64, to: u64, client_tx: &ClientSender) {
+    let (gap, messages) = {
+        let rooms = state.rooms.lock_or_recover();
+        let Some(room_state) = rooms.get(room) else {
+            return;
+        };
+        let to = to.min(room_state.last_id);
+        let oldest = room_state
+            .messages
+            .front()
+            .and_then(|msg| msg.id)
+            .unwrap_or(room_state.last_id + 1);
+        let gap = (from < oldest && from <= to).then(|| (from, to.min(oldest - 1)));
+        let messages: Vec<Message> = room_state
+            .messages
+            .iter()
+            .filter(|msg| msg.id.is_some_and(|id| (from..=to).contains(&id)))
+            .cloned()
+            .collect();
+        (gap, messages)
+    };
+
+    if let Some((from, to)) = gap {
+        send_server_message(client_tx, &ServerMessage::HistoryGap { from, to });
+    }
+    for message in &messages {
+        send_server_message(client_tx, &ServerMessage::chat(message));
+    }
+}
+
+/// Returns up to `limit` of the newest messages in `room` with IDs below
+/// `before`, or the newest overall when `before` is `None`, oldest first.
+fn history_page(state: &AppState, room: &str, before: Option<u64>, limit: usize) -> Vec<Message> {
+    let rooms = state.rooms.lock_or_recover();
+    let Some(room_state) = rooms.get(room) else {
+        return Vec::new();
+    };
+    let mut page: Vec<Message> = room_state
+        .messages
+        .iter()
+        .rev()
+        .filter(|msg| before.is_none_or(|before| msg.id.is_some_and(|id| id < before)))
+        .take(limit)
+        .cloned()
+        .collect();
+    page.reverse();
+    page
+}
+
+/// Logs the versions a user connected with, warning about outdated clients.
+fn log_client_version(user: &User) {
+    let client = user.client_version.as_deref().unwrap_or("unknown");
+    match user.protocol_version {
+        Some(version) if version >= MIN_SUPPORTED_PROTOCOL_VERSION => println!(
+            "{} connected to room {} (client {}, protocol {})",
+            user.name, user.room, client, version
+        ),
+        Some(version) => eprintln!(
+            "Warning: {} connected with outdated protocol {} (client {}); minimum supported is {}",
+            user.name, version, client, MIN_SUPPORTED_PROTOCOL_VERSION
+        ),
+        None => eprintln!(
+            "Warning: {} connected to room {} without reporting a version",
+            user.name, user.room
+        ),
+    }
+}
+
+/// Takes a token from the user's rate limiter.
+///
+/// Returns `false` and tells the client to slow down if they've exceeded the
+/// configured rate; the message should then be dropped.
+fn check_rate_limit(state: &AppState, user_id: &str, client_tx: &ClientSender) -> bool {
+    let allowed = state
+        .users
+        .lock_or_recover()
+        .get_mut(user_id)
+        .is_none_or(|user| user.rate_limiter.try_acquire());
+
+    if !allowed {
+        send_server_message(
+            client_tx,
+            &ServerMessage::error(
+                429,
+                format!(
+                    "Slow down: at most {} messages per second",
+                    state.config.rate_limit_per_sec
+                ),
+            ),
+        );
+    }
+    allowed
+}
+
+/// Returns the ID of the message the author with key `author_id` recently
+/// sent with client message key `key`, if there was one.
+fn recent_client_id(state: &AppState, author_id: &str, key: &str) -> Option<u64> {
+    state
+        .client_ids
+        .lock_or_recover()
+        .get_mut(author_id)
+        .and_then(|ids| ids.get(key, Instant::now()))
+}
+
+/// Remembers that the author with key `author_id` sent the message stored
+/// as `id` with client message key `key`.
+fn remember_client_id(state: &AppState, author_id: &str, key: &str, id: u64) {
+    let now = Instant::now();
+    let mut client_ids = state.client_ids.lock_or_recover();
+    // Forget authors who have gone quiet so the map doesn't grow forever
+    if client_ids.len() >= MAX_TRACKED_AUTHORS {
+        client_ids.retain(|_, ids| {
+            ids.expire(now);
+            !ids.is_empty()
+        });
+    }
+    client_ids
+        .entry(author_id.to_string())
+        .or_default()
+        .insert(key.to_string(), id, now);
+}
+
+/// Takes a token from the POST rate limiter for `ip`.
+///
+/// Returns the number of posts left in the current burst, or how long the
+/// caller should wait before retrying if the limit was exceeded.
+fn check_post_rate_limit(state: &AppState, ip: IpAddr) -> Result<u32, Duration> {
+    let mut limits = state.post_limits.lock_or_recover();
+    // Forget addresses that have gone quiet so the map doesn't grow forever
+    if limits.len() >= MAX_TRACKED_POSTERS {
+        limits.retain(|_, bucket| !bucket.is_full());
+    }
+
+    let bucket = limits
+        .entry(ip)
+        .or_insert_with(|| TokenBucket::new(state.config.rate_limit_per_sec));
+    if bucket.try_acquire() {
+        Ok(bucket.remaining())
+    } else {
+        Err(bucket.retry_after())
+    }
+}
+
+/// Bumps the message count and activity time of the given user, bringing
+/// them back if the idle timeout marked them away.
+async fn record_user_message(state: &AppState, user_id: &str) {
+    let mut name = None;
+    let was_idle = state
+        .users
+        .lock_or_recover()
+        .get_mut(user_id)
+        .is_some_and(|user| {
+            user.record_message();
+            name = Some(user.name.clone());
+            user.auto_away
+        });
+    if let Some(name) = name {
+        mark_seen(state, name);
+    }
+    if was_idle {
+        set_status(state, user_id, UserStatus::Online, false).await;
+    }
+}
+
+/// Returns whether the given user is online but hasn't sent a message for
+/// `away_after`.
+fn is_idle(state: &AppState, user_id: &str, away_after: Duration) -> bool {
+    state
+        .users
+        .lock_or_recover()
+        .get(user_id)
+        .is_some_and(|user| {
+            user.status == UserStatus::Online && user.last_active_at.elapsed() >= away_after
+        })
+}
+
+/// Sets the presence status of the given user and tells their room.
+///
+/// `auto` records that the idle timeout set it, so the user's next message
+/// brings them back. Returns whether the status changed.
+async fn set_status(state: &AppState, user_id: &str, status: UserStatus, auto: bool) -> bool {
+    let (name, room) = {
+        let mut users = state.users.lock_or_recover();
+        let Some(user) = users.get_mut(user_id) else {
+            return false;
+        };
+        user.auto_away = auto;
+        if user.status == status {
+            return false;
+        }
+        user.status = status;
+        (user.name.clone(), user.room.clone())
+    };
+
+    let server_msg = ServerMessage::StatusChanged { name, status };
+    broadcast_server_message(state, &room, &server_msg).await;
+    broadcast_user_list(state, &room).await;
+    true
+}
+
+/// Names of connected users mentioned as `@name` in `text`, in the order
+/// they first appear.
+///
+/// Case is ignored, and where several names fit (e.g. "Ann" and "Ann Lee")
+/// the longest wins, so names with spaces can be mentioned too. A name only
+/// counts when it isn't followed by more of a word.
+fn extract_mentions(state: &AppState, text: &str) -> Vec<String> {
+    if !text.contains('@') {
+        return Vec::new();
+    }
+    let mut names: Vec<String> = state
+        .users
+        .lock_or_recover()
+        .values()
+        .map(|user| user.name.clone())
+        .collect();
+    names.sort_by_key(|name| std::cmp::Reverse(name.len()));
+
+    let mut mentions = Vec::new();
+    for (at, _) in text.match_indices('@') {
+        let rest = &text[at + 1..];
+        let found = names.iter().find(|name| {
+            rest.get(..name.len())
+                .is_some_and(|prefix| prefix.to_lowercase() == name.to_lowercase())
+                && !rest[name.len()..]
+                    .starts_with(|c: char| c.is_alphanumeric() || c == '_' || c == '-')
+        });
+        if let Some(name) = found
+            && !mentions.contains(name)
+        {
+            mentions.push(name.clone());
+        }
+    }
+    mentions
+}
+
+/// Returns the user IDs of everyone currently in `room`.
+fn room_member_ids(state: &AppState, room: &str) -> Vec<String> {
+    let users = state.users.lock_or_recover();
+    users
+        .iter()
+        .filter(|(_, user)| user.room == room)
+        .map(|(id, _)| id.clone())
+        .collect()
+}
+
+/// Returns the IDs of connections watching `room` through a subscription.
+fn room_watcher_ids(state: &AppState, room: &str) -> Vec<String> {
+    let users = state.users.lock_or_recover();
+    users
+        .iter()
+        .filter(|(_, user)| user.subscriptions.contains(room))
+        .map(|(id, _)| id.clone())
+        .collect()
+}
+
+/// Closes a room, telling its members why and disconnecting them.
+///
+/// Only closes the room if `is_instance` holds for it, so a room closed and
+/// opened again under the same name in the meantime is left alone. The
+/// room's history is discarded. Returns `false` if no room was closed.
+fn close_room(
+    state: &AppState,
+    room: &str,
+    reason: &str,
+    is_instance: impl Fn(&RoomState) -> bool,
+) -> bool {
+    {
+        let mut rooms = state.rooms.lock_or_recover();
+        if !rooms.get(room).is_some_and(is_instance) {
+            return false;
+        }
+        rooms.remove(room);
+        state.metrics.set_rooms(rooms.len());
+    }
+    for user in state.users.lock_or_recover().values_mut() {
+        user.subscriptions.remove(room);
+    }
+
+    let notice = ServerMessage::RoomClosed {
+        room: room.to_string(),
+        reason: reason.to_string(),
+    };
+    let member_ids = room_member_ids(state, room);
+    let clients = state.clients.lock_or_recover();
+    for id in member_ids {
+        if let Some(handle) = clients.get(&id) {
+            send_server_message(&handle.tx, &notice);
+            handle.close.notify_one();
+        }
+    }
+    // Dropping their queues ends the streams once the notice is out
+    send_to_streams(state, room, &notice);
+    state
+        .streams
+        .lock_or_recover()
+        .retain(|_, stream| stream.room != room);
+
+    true
+}
+
+/// Checks the `Authorization: Bearer` header against the configured admin token.
+///
+/// Returns `403 Forbidden` when no admin token is configured and
+/// `401 Unauthorized` when the header is missing or doesn't match.
+fn check_admin(state: &AppState, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let Some(expected) = &state.config.admin_token else {
+        return Err(StatusCode::FORBIDDEN);
+    };
+
+    if token_matches(bearer_token(headers), expected) {
+        Ok(())
+    } else {
+        Err(StatusCode::UNAUTHORIZED)
+    }
+}
+
+/// Checks the `Authorization: Bearer` header against the configured auth token.
+///
+/// Always succeeds when the server doesn't require authentication.
+fn check_auth(state: &AppState, headers: &HeaderMap) -> Result<(), StatusCode> {
+    match &state.config.auth_token {
+        Some(expected) if !token_matches(bearer_token(headers), expected) => {
+            Err(StatusCode::UNAUTHORIZED)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks `password` against the one needed to join `room`.
+///
+/// Always succeeds for rooms without a password. Fails with 401
+/// UNAUTHORIZED when the room has one and none was given, or 403 FORBIDDEN
+/// when it doesn't match.
+fn check_room_password(
+    state: &AppState,
+    room: &str,
+    password: Option<&str>,
+) -> Result<(), StatusCode> {
+    // Hashing is deliberately slow, so check outside the rooms lock
+    let hash = state
+        .rooms
+        .lock_or_recover()
+        .get(room)
+        .and_then(|room_state| room_state.password.clone());
+    match (hash, password) {
+        (None, _) => Ok(()),
+        (Some(_), None) => Err(StatusCode::UNAUTHORIZED),
+        (Some(hash), Some(password)) if hash.verify(password) => Ok(()),
+        (Some(_), Some(_)) => Err(StatusCode::FORBIDDEN),
+    }
+}
+
+/// Like [`check_room_password`], for HTTP requests carrying the password
+/// in an `X-Room-Password` header.
+fn check_room_password_header(
+    state: &AppState,
+    room: &str,
+    headers: &HeaderMap,
+) -> Result<(), StatusCode> {
+    let password = headers
+        .get(ROOM_PASSWORD_HEADER)
+        .and_then(|value| value.to_str().ok());
+    check_room_password(state, room, password)
+}
+
+/// Describes a failed [`check_room_password`] to a WebSocket client.
+fn room_password_error(room: &str, status: StatusCode) -> ServerMessage {
+    let message = if status == StatusCode::UNAUTHORIZED {
+        format!("Room '{}' requires a password", room)
+    } else {
+        format!("Wrong password for room '{}'", room)
+    };
+    ServerMessage::error(status.as_u16(), message)
+}
+
+/// Returns whether `presented` is the secret token `expected`, comparing in
+/// constant time so response timings don't give the token away.
+///
+/// Goes through an HMAC under a throwaway key, as ring's plain slice
+/// comparison is deprecated; this also hides the token's length.
+fn token_matches(presented: Option<&str>, expected: &str) -> bool {
+    let Some(presented) = presented else {
+        return false;
+    };
+    let Ok(key) =
+        ring::hmac::Key::generate(ring::hmac::HMAC_SHA256, &ring::rand::SystemRandom::new())
+    else {
+        return false;
+    };
+    let tag = ring::hmac::sign(&key, expected.as_bytes());
+    ring::hmac::verify(&key, presented.as_bytes(), tag.as_ref()).is_ok()
+}
+
+/// Extracts the token from an `Authorization: Bearer` header, if present.
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Serves `state` on an ephemeral localhost port in the background and
+/// returns the bound address.
+///
+/// Unlike [`run_server`] there is no persistence, TUI or graceful shutdown;
+/// the server lives until the runtime stops. Used by `chat selftest`.
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+pub async fn spawn_local(state: AppState) -> ChatResult<SocketAddr> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        let app = app_router(state).into_make_service_with_connect_info::<SocketAddr>();
+        if let Err(e) = axum::serve(listener, app).await {
+            eprintln!("Server error: {}", e);
+        }
+    });
+    Ok(addr)
+}
+
+/// Builds the axum router with all chat endpoints bound to the given state.
+fn app_router(state: AppState) -> Router {
+    let mut router = Router::new();
+    if state.config.metrics_endpoint {
+        router = router.route("/metrics", get(handle_metrics));
+    }
+    router
+        .route("/room/{room}", get(handle_websocket).post(handle_post))
+        .route("/room/{room}/users", get(handle_room_users))
+        .route("/room/{room}/pins", get(handle_room_pins))
+        .route("/room/{room}/stream", get(handle_stream))
+        .route("/users/{name}/lastseen", get(handle_last_seen))
+        .route(
+            "/room/{room}/upload",
+            post(handle_upload).layer(DefaultBodyLimit::max(
+                state.config.max_upload_size + UPLOAD_FRAMING_ALLOWANCE,
+            )),
+        )
+        .route("/files/{id}", get(handle_file))
+        .route("/hooks/{room}", post(handle_hook))
+        .route("/messages", get(handle_get))
+        .route("/messages/json", get(handle_get_json))
+        .route("/version", get(handle_version))
+        .route("/healthz", get(handle_healthz))
+        .route("/health", get(handle_healthz))
+        .route("/stats", get(handle_stats))
+        .route("/rooms", get(handle_list_rooms).post(handle_create_room))
+        .route("/rooms/ephemeral", post(handle_create_ephemeral_room))
+        .route("/admin/announce", post(handle_announce))
+        .route("/admin/purge", post(handle_purge))
+        .route("/admin/users", get(handle_admin_users))
+        .route("/admin/snapshot", post(handle_snapshot))
+        .route("/admin/restore", post(handle_restore))
+        .route("/admin/export", get(handle_export))
+        .route("/admin/import", post(handle_import))
+        .route("/admin/connections", get(handle_list_connections))
+        .route(
+            "/admin/connections/{id}",
+            axum::routing::delete(handle_terminate_connection),
+        )
+        .with_state(state)
+}
+
+/// Handles WebSocket upgrade requests for the chat endpoint.
+///
+/// This function is called when a client attempts to upgrade their HTTP
+/// connection to a WebSocket connection for real-time chat.
+///
+/// # Arguments
+///
+/// * `ws` - The WebSocket upgrade request from axum
+/// * `room` - The room to join, taken from the URL path
+/// * `remote` - The client's address, counted against `max_connections_per_ip`
+///   and recorded only if `record_ips` is enabled
+/// * `state` - The shared application state
+///
+/// # Returns
+///
+/// Returns a response that upgrades the connection to WebSocket, or
+/// 404 NOT FOUND if the room doesn't exist. A connection over the server's
+/// or its address's limit is upgraded only to be sent an `Error` and closed.
+async fn handle_websocket(
+    ws: WebSocketUpgrade,
+    Path(room): Path<String>,
+    ConnectInfo(remote): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+) -> Response {
+    if !state.rooms.lock_or_recover().contains_key(&room) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let ip = state.config.record_ips.then(|| remote.ip().to_string());
+    let max_frame = max_frame_size(&state.config);
+    let ws = ws.max_frame_size(max_frame).max_message_size(max_frame);
+    match reserve_connection(&state, remote.ip()) {
+        Ok(slot) => ws.on_upgrade(|socket| async move {
+            handle_socket(socket, state, room, ip).await;
+            drop(slot);
+        }),
+        Err(reply) => ws.on_upgrade(|mut socket| async move {
+            let json = serde_json::to_string(&reply).expect("Failed to serialize server message");
+            let _ = socket
+                .send(axum::extract::ws::Message::Text(json.into()))
+                .await;
+            let _ = socket.send(axum::extract::ws::Message::Close(None)).await;
+        }),
+    }
+}
+
+/// Counts a new connection from `ip`, unless the server or that address
+/// already has as many open as `max_connections` or
+/// `max_connections_per_ip` allow.
+///
+/// # Returns
+///
+/// Returns the slot to hold for as long as the connection is open, or the
+/// error to send the client: 503 when the server is full, 429 when its
+/// address is.
+fn reserve_connection(state: &AppState, ip: IpAddr) -> Result<ConnectionSlot, ServerMessage> {
+    let config = &state.config;
+    let mut connections = state.connections.lock_or_recover();
+    let total: usize = connections.values().sum();
+    if config.max_connections > 0 && total >= config.max_connections {
+        return Err(ServerMessage::error(
+            503,
+            "The server has too many connections, try again later",
+        ));
+    }
+    let from_ip = connections.entry(ip).or_default();
+    if config.max_connections_per_ip > 0 && *from_ip >= config.max_connections_per_ip {
+        return Err(ServerMessage::error(
+            429,
+            "Too many connections from your address",
+        ));
+    }
+    *from_ip += 1;
+    Ok(ConnectionSlot {
+        state: state.clone(),
+        ip,
+    })
+}
+
+/// Largest WebSocket frame accepted from a client, in bytes.
+///
+/// Sized so that a message of `max_message_len` characters fits even when
+/// every character takes four bytes; anything bigger is refused by the
+/// socket before it is buffered.
+fn max_frame_size(config: &ServerConfig) -> usize {
+    config
+        .max_message_len
+        .saturating_mul(4)
+        .saturating_add(FRAME_OVERHEAD)
+}
+
+/// Handles the actual WebSocket connection after upgrade.
+///
+/// This function manages the bidirectional communication with a connected
+/// client, processing incoming messages and broadcasting outgoing messages.
+///
+/// # Arguments
+///
+/// * `socket` - The upgraded WebSocket connection
+/// * `state` - The shared application state
+/// * `room` - The room the client joined
+/// * `ip` - The client's remote address, if it is being recorded
+async fn handle_socket(socket: WebSocket, state: AppState, room: String, ip: Option<String>) {
+    let (mut sender, mut receiver) = socket.split();
+    let (tx, mut rx) =
+        outbound::channel(state.config.outbound_capacity, state.config.overflow_policy);
+
+    // First, wait for a connection message with the user's name, versions and token
+    let mut client_version = None;
+    let mut protocol_version = None;
+    let mut token = None;
+    let mut replay = None;
+    let mut room_password = None;
+    let mut identity = None;
+    let user_name = match receiver.next().await {
+        Some(Ok(axum::extract::ws::Message::Text(text))) => {
+            if let Ok(client_msg) = serde_json::from_str::<ClientMessage>(&text) {
+                match client_msg {
+                    ClientMessage::Connect {
+                        name,
+                        client_version: reported_client,
+                        protocol_version: reported_protocol,
+                        token: provided_token,
+                        replay: requested_replay,
+                        room_password: provided_password,
+                        identity: presented_identity,
+                    } => {
+                        client_version = reported_client;
+                        protocol_version = reported_protocol;
+                        token = provided_token;
+                        replay = requested_replay;
+                        room_password = provided_password;
+                        identity = presented_identity;
+                        name
+                    }
+                    _ => default_user_name(),
+                }
+            } else {
+                // Fallback for old format - take the name from `sender`, or
+                // from the text when it still has the "Name: message" form
+                if let Ok(msg) = serde_json::from_str::<Message>(&text) {
+                    match (msg.sender, msg.text.split_once(':')) {
+                        (Some(sender), _) => sender,
+                        (None, Some((name, _))) => name.to_string(),
+                        (None, None) => msg.text,
+                    }
+                } else {
+                    default_user_name()
+                }
+            }
+        }
+        _ => default_user_name(),
+    };
+
+    // Credentials are checked up front, then the name against the role they
+    // give, the same way a rename is
+    let user_name = if user_name.trim().is_empty() {
+        default_user_name()
+    } else {
+        user_name
+    };
+    let checked = authenticate(&state.config, token.as_deref())
+        .and_then(|role| Ok((role, check_name(&state, &user_name, role)?)));
+    let (role, user_name) = match checked {
+        Ok(checked) => checked,
+        Err(reply) => {
+            let json = serde_json::to_string(&reply).expect("Failed to serialize server message");
+            let _ = sender
+                .send(axum::extract::ws::Message::Text(json.into()))
+                .await;
+            let _ = sender.send(axum::extract::ws::Message::Close(None)).await;
+            return;
+        }
+    };
+
+    if let Err(status) = check_room_password(&state, &room, room_password.as_deref()) {
+        let reply = room_password_error(&room, status);
+        let json = serde_json::to_string(&reply).expect("Failed to serialize server message");
+        let _ = sender
+            .send(axum::extract::ws::Message::Text(json.into()))
+            .await;
+        let _ = sender.send(axum::extract::ws::Message::Close(None)).await;
+        return;
+    }
+
+    // The generated user ID doubles as the connection ID
+    let mut user = User::new(user_name.clone(), &room);
+    let user_id = user.id.clone();
+    let author_id = author_key(identity.as_deref()).unwrap_or_else(|| user_id.clone());
+    user.ip = ip;
+    user.rate_limiter = TokenBucket::new(state.config.rate_limit_per_sec);
+    user.client_version = client_version;
+    user.protocol_version = protocol_version;
+    user.replay_format = ReplayFormat::negotiate(replay, protocol_version);
+
+    // Add user to tracking under a name nobody else has; checking and
+    // inserting under one lock keeps simultaneous connects from both
+    // getting the same name. The name actually given may carry a suffix
+    // the requested one didn't, so it's checked against the role again
+    user.role = role;
+    let assigned = {
+        let mut users = state.users.lock_or_recover();
+        assign_name(&users, &user_name, state.config.duplicate_names).and_then(|name| {
+            check_reserved_name(&state.config, &name, role)?;
+            user.name = name.clone();
+            users.insert(user_id.clone(), user.clone());
+            Ok(name)
+        })
+    };
+    let user_name = match assigned {
+        Ok(name) => name,
+        Err(reply) => {
+            let json = serde_json::to_string(&reply).expect("Failed to serialize server message");
+            let _ = sender
+                .send(axum::extract::ws::Message::Text(json.into()))
+                .await;
+            let _ = sender.send(axum::extract::ws::Message::Close(None)).await;
+            return;
+        }
+    };
+    state.metrics.user_connected();
+    log_client_version(&user);
+
+    // Keep a handle to this client's own channel for direct replies
+    let self_tx = tx.clone();
+    let handle = ClientHandle::new(tx);
+    let close = handle.close.clone();
+
+    // Add this client to list
+    state
+        .clients
+        .lock_or_recover()
+        .insert(user_id.clone(), handle);
+
+    // Greet the client, replay history, then send the room's topic and
+    // greeting, to this client alone
+    let welcome = serde_json::to_string(&welcome_message(&state, &room, &user_name, role))
+        .expect("Failed to serialize server message");
+    let history = history_frames(&state, &room, user.replay_format)
+        .into_iter()
+        .map(|frame| frame.text);
+    let notices = [
+        topic_for_joiner(&state, &room),
+        room_greeting(&state, &room),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|notice| serde_json::to_string(&notice).expect("Failed to serialize server message"));
+    for text in std::iter::once(welcome).chain(history).chain(notices) {
+        if sender
+            .send(axum::extract::ws::Message::Text(text.into()))
+            .await
+            .is_err()
+        {
+            // Nobody was told they joined, so there's no leave to announce
+            unregister_user(&state, &user_id);
+            return;
+        }
+    }
+
+    // Send user list to all clients
+    broadcast_user_list(&state, &room).await;
+
+    // Broadcast user joined notification
+    broadcast_user_joined(&state, &room, &user_name).await;
+
+    // Any frame from the client, pongs included, shows the connection is alive
+    let last_seen = Mutex::new(Instant::now());
+    let keepalive = state.config.keepalive_interval;
+    let away_after = state.config.away_after;
+    // Pongs only show the client is alive, not that anyone is using it
+    let last_activity = Mutex::new(Instant::now());
+    let idle_timeout = state.config.idle_timeout;
+
+    // Handle incoming messages from this client
+    let state_clone = state.clone();
+    let mut user_name_clone = user_name.clone();
+    let mut current_room = room;
+    // Ends with whether the client has to be told why it's being closed
+    let recv_task = async {
+        while let Some(msg) = receiver.next().await {
+            let frame = match msg {
+                Ok(frame) => frame,
+                Err(err) => {
+                    // An oversized frame is refused unread, which leaves the
+                    // stream unusable, so the connection has to end here
+                    println!("Closing connection to {}: {}", user_name, err);
+                    send_server_message(
+                        &self_tx,
+                        &ServerMessage::error(
+                            413,
+                            format!(
+                                "Disconnected: frames may be at most {} bytes",
+                                max_frame_size(&state_clone.config)
+                            ),
+                        ),
+                    );
+                    return true;
+                }
+            };
+            *last_seen.lock_or_recover() = Instant::now();
+            if !matches!(frame, axum::extract::ws::Message::Pong(_)) {
+                *last_activity.lock_or_recover() = Instant::now();
+            }
+            let text = match frame {
+                axum::extract::ws::Message::Text(text) => text,
+                axum::extract::ws::Message::Binary(_) => {
+                    send_server_message(
+                        &self_tx,
+                        &ServerMessage::error(415, "Binary frames are not supported"),
+                    );
+                    continue;
+                }
+                axum::extract::ws::Message::Close(_) => break,
+                _ => continue,
+            };
+            // Try to parse as ClientMessage
+            if let Ok(client_msg) = serde_json::from_str::<ClientMessage>(&text) {
+                match client_msg {
+                    ClientMessage::Chat {
+                        text: chat_text,
+                        client_msg_id,
+                    } => {
+                        state_clone.metrics.record_received();
+                        // A resend of something already posted only needs
+                        // its acknowledgement again
+                        if let Some(id) = client_msg_id
+                            .as_deref()
+                            .and_then(|key| recent_client_id(&state_clone, &author_id, key))
+                        {
+                            let ack = ServerMessage::Ack { id, client_msg_id };
+                            send_server_message(&self_tx, &ack);
+                            continue;
+                        }
+                        let Some(chat_text) =
+                            validate_outgoing_text(&state_clone, &user_id, &chat_text, &self_tx)
+                        else {
+                            continue;
+                        };
+
+                        let mut message = Message::chat_message(&user_name_clone, &chat_text);
+                        message.author_id = Some(author_id.clone());
+                        message.mentions = extract_mentions(&state_clone, &message.text);
+                        record_user_message(&state_clone, &user_id).await;
+
+                        // Store message with limit, broadcast it, then confirm to the sender
+                        if let Some(message) = store_message(&state_clone, &current_room, message) {
+                            let server_msg = ServerMessage::chat(&message);
+                            broadcast_server_message(&state_clone, &current_room, &server_msg)
+                                .await;
+                            state_clone.metrics.record_broadcast();
+                            if let Some(id) = message.id {
+                                if let Some(key) = &client_msg_id {
+                                    remember_client_id(&state_clone, &author_id, key, id);
+                                }
+                                let ack = ServerMessage::Ack { id, client_msg_id };
+                                send_server_message(&self_tx, &ack);
+                            }
+                        }
+                    }
+                    ClientMessage::Reply {
+                        to,
+                        text: reply_text,
+                    } => {
+                        state_clone.metrics.record_received();
+                        let Some(reply_text) =
+                            validate_outgoing_text(&state_clone, &user_id, &reply_text, &self_tx)
+                        else {
+                            continue;
+                        };
+                        let quote = match reply_quote(&state_clone, &current_room, to) {
+                            Ok(quote) => quote,
+                            Err(error) => {
+                                send_server_message(&self_tx, &error);
+                                continue;
+                            }
+                        };
+
+                        let mut message = Message::chat_message(&user_name_clone, &reply_text);
+                        message.author_id = Some(author_id.clone());
+                        message.mentions = extract_mentions(&state_clone, &message.text);
+                        // A parent trimmed from the history can't be shown,
+                        // so the reply goes out as a plain message
+                        message.reply_to = quote.is_some().then_some(to);
+                        record_user_message(&state_clone, &user_id).await;
+
+                        if let Some(message) = store_message(&state_clone, &current_room, message) {
+                            let server_msg = ServerMessage::Chat {
+                                text: message.text.clone(),
+                                sender: message.sender.clone(),
+                                id: message.id,
+                                reply_to: message.reply_to,
+                                quote,
+                                mentions: message.mentions.clone(),
+                                timestamp: message.timestamp,
+                                signature: message.signature.clone(),
+                            };
+                            broadcast_server_message(&state_clone, &current_room, &server_msg)
+                                .await;
+                            state_clone.metrics.record_broadcast();
+                            if let Some(id) = message.id {
+                                let ack = ServerMessage::Ack {
+                                    id,
+                                    client_msg_id: None,
+                                };
+                                send_server_message(&self_tx, &ack);
+                            }
+                        }
+                    }
+                    ClientMessage::Action { text: action_text } => {
+                        state_clone.metrics.record_received();
+                        let Some(action_text) =
+                            validate_outgoing_text(&state_clone, &user_id, &action_text, &self_tx)
+                        else {
+                            continue;
+                        };
+
+                        let mut message = Message::action(&user_name_clone, &action_text);
+                        message.author_id = Some(author_id.clone());
+                        record_user_message(&state_clone, &user_id).await;
+
+                        if let Some(message) = store_message(&state_clone, &current_room, message) {
+                            let server_msg = ServerMessage::Action {
+                                name: user_name_clone.clone(),
+                                text: action_text,
+                            };
+                            broadcast_server_message(&state_clone, &current_room, &server_msg)
+                                .await;
+                            state_clone.metrics.record_broadcast();
+                            if let Some(id) = message.id {
+                                let ack = ServerMessage::Ack {
+                                    id,
+                                    client_msg_id: None,
+                                };
+                                send_server_message(&self_tx, &ack);
+                            }
+                        }
+                    }
+                    ClientMessage::History { from, to } => {
+                        replay_history(&state_clone, &current_room, from, to, &self_tx);
+                    }
+                    ClientMessage::FetchHistory { before, limit } => {
+                        let limit = limit.min(MAX_HISTORY_PAGE);
+                        let messages = history_page(&state_clone, &current_room, before, limit);
+                        send_server_message(&self_tx, &ServerMessage::History { messages });
+                    }
+                    ClientMessage::SetStatus { status } => {
+                        // A change is broadcast with a fresh user list, so
+                        // it costs as much as a message
+                        if !check_rate_limit(&state_clone, &user_id, &self_tx) {
+                            continue;
+                        }
+                        set_status(&state_clone, &user_id, status, false).await;
+                    }
+                    ClientMessage::Ping { nonce } => {
+                        send_server_message(&self_tx, &ServerMessage::Pong { nonce });
+                    }
+                    ClientMessage::Stats => {
+                        let health = state_clone.metrics.health();
+                        let stats = ServerMessage::Stats {
+                            uptime_secs: health.uptime_secs,
+                            total_messages: state_clone.metrics.messages_broadcast(),
+                            connected_users: health.users,
+                            rooms: health.rooms,
+                        };
+                        send_server_message(&self_tx, &stats);
+                    }
+                    ClientMessage::Rename { new_name } => {
+                        let role = user_role(&state_clone, &user_id);
+                        let new_name = match check_name(&state_clone, &new_name, role) {
+                            Ok(name) => name,
+                            Err(reply) => {
+                                send_server_message(&self_tx, &reply);
+                                continue;
+                            }
+                        };
+                        // A rename is broadcast with a fresh user list, so
+                        // it costs as much as a message
+                        if !check_rate_limit(&state_clone, &user_id, &self_tx) {
+                            continue;
+                        }
+
+                        let old_name = {
+                            let mut users = state_clone.users.lock_or_recover();
+                            if name_taken(&users, &new_name, Some(&user_id)) {
+                                send_server_message(
+                                    &self_tx,
+                                    &ServerMessage::error(
+                                        409,
+                                        format!("The name '{}' is already in use", new_name),
+                                    ),
+                                );
+                                continue;
+                            }
+                            match users.get_mut(&user_id) {
+                                Some(user) => std::mem::replace(&mut user.name, new_name.clone()),
+                                None => continue,
+                            }
+                        };
+                        user_name_clone = new_name.clone();
+
+                        let server_msg = ServerMessage::UserRenamed {
+                            old: old_name,
+                            new: new_name,
+                        };
+                        broadcast_server_message(&state_clone, &current_room, &server_msg).await;
+                        broadcast_user_list(&state_clone, &current_room).await;
+                    }
+                    ClientMessage::DirectMessage { to, text } => {
+                        let Some(text) =
+                            validate_outgoing_text(&state_clone, &user_id, &text, &self_tx)
+                        else {
+                            continue;
+                        };
+
+                        let server_msg = ServerMessage::DirectMessage {
+                            from: user_name_clone.clone(),
+                            to: to.clone(),
+                            text,
+                        };
+                        if !send_direct_message(&state_clone, &to, &server_msg) {
+                            send_server_message(
+                                &self_tx,
+                                &ServerMessage::error(404, format!("User '{}' not found", to)),
+                            );
+                            continue;
+                        }
+
+                        // Echo back so the sender sees their own DM
+                        send_server_message(&self_tx, &server_msg);
+                    }
+                    ClientMessage::Edit { id, text } => {
+                        // Every change is saved and broadcast, so it costs
+                        // as much as a message
+                        let Some(text) =
+                            validate_outgoing_text(&state_clone, &user_id, &text, &self_tx)
+                        else {
+                            continue;
+                        };
+                        let edited = modify_own_message(
+                            &state_clone,
+                            &current_room,
+                            id,
+                            &author_id,
+                            |msg| msg.text = text.clone(),
+                        );
+                        match edited {
+                            Ok(message) => {
+                                let server_msg = ServerMessage::MessageEdited {
+                                    id,
+                                    text: message.text,
+                                    sender: message.sender,
+                                };
+                                broadcast_server_message(&state_clone, &current_room, &server_msg)
+                                    .await;
+                            }
+                            Err(error) => send_server_message(&self_tx, &error),
+                        }
+                    }
+                    ClientMessage::Delete { id } => {
+                        if !check_rate_limit(&state_clone, &user_id, &self_tx) {
+                            continue;
+                        }
+                        let deleted = modify_own_message(
+                            &state_clone,
+                            &current_room,
+                            id,
+                            &author_id,
+                            |msg| {
+                                msg.text = DELETED_PLACEHOLDER.to_string();
+                                msg.deleted = true;
+                            },
+                        );
+                        match deleted {
+                            Ok(_) => {
+                                let server_msg = ServerMessage::MessageDeleted { id };
+                                broadcast_server_message(&state_clone, &current_room, &server_msg)
+                                    .await;
+                            }
+                            Err(error) => send_server_message(&self_tx, &error),
+                        }
+                    }
+                    ClientMessage::PurgeMine => {
+                        if !check_rate_limit(&state_clone, &user_id, &self_tx) {
+                            continue;
+                        }
+                        let purged = purge_own_messages(&state_clone, &current_room, &author_id);
+                        let count = purged.deleted.len();
+                        announce_purged(&state_clone, &current_room, &purged).await;
+                        send_server_message(&self_tx, &ServerMessage::OwnMessagesPurged { count });
+                    }
+                    ClientMessage::React { message_id, emoji } => {
+                        let emoji = sanitize_text(&state_clone, emoji.trim());
+                        if !is_valid_reaction(&emoji) {
+                            send_server_message(
+                                &self_tx,
+                                &ServerMessage::error(
+                                    422,
+                                    format!(
+                                        "Reactions must be 1 to {} characters without spaces",
+                                        MAX_REACTION_LEN
+                                    ),
+                                ),
+                            );
+                            continue;
+                        }
+                        if !check_rate_limit(&state_clone, &user_id, &self_tx) {
+                            continue;
+                        }
+                        match toggle_reaction(
+                            &state_clone,
+                            &current_room,
+                            message_id,
+                            &emoji,
+                            &user_name_clone,
+                        ) {
+                            Ok(added) => {
+                                let server_msg = ServerMessage::Reaction {
+                                    message_id,
+                                    emoji,
+                                    name: user_name_clone.clone(),
+                                    added,
+                                };
+                                broadcast_server_message(&state_clone, &current_room, &server_msg)
+                                    .await;
+                            }
+                            Err(reply) => send_server_message(&self_tx, &reply),
+                        }
+                    }
+                    ClientMessage::Subscribe { room: target } => {
+                        if !state_clone.rooms.lock_or_recover().contains_key(&target) {
+                            send_server_message(
+                                &self_tx,
+                                &ServerMessage::error(404, format!("Room '{}' not found", target)),
+                            );
+                            continue;
+                        }
+                        // Watching carries no password, so protected rooms
+                        // can only be followed by joining them
+                        if check_room_password(&state_clone, &target, None).is_err() {
+                            send_server_message(
+                                &self_tx,
+                                &ServerMessage::error(
+                                    403,
+                                    format!(
+                                        "Room '{}' requires a password; /join it instead",
+                                        target
+                                    ),
+                                ),
+                            );
+                            continue;
+                        }
+                        if let Some(user) = state_clone.users.lock_or_recover().get_mut(&user_id)
+                            && target != user.room
+                        {
+                            user.subscriptions.insert(target);
+                        }
+                    }
+                    ClientMessage::Unsubscribe { room: target } => {
+                        if let Some(user) = state_clone.users.lock_or_recover().get_mut(&user_id) {
+                            user.subscriptions.remove(&target);
+                        }
+                    }
+                    ClientMessage::JoinRoom {
+                        room: target,
+                        password,
+                    } => {
+                        if target == current_room {
+                            continue;
+                        }
+                        if !state_clone.rooms.lock_or_recover().contains_key(&target) {
+                            send_server_message(
+                                &self_tx,
+                                &ServerMessage::error(404, format!("Room '{}' not found", target)),
+                            );
+                            continue;
+                        }
+                        if let Err(status) =
+                            check_room_password(&state_clone, &target, password.as_deref())
+                        {
+                            send_server_message(&self_tx, &room_password_error(&target, status));
+                            continue;
+                        }
+                        switch_room(
+                            &state_clone,
+                            &user_id,
+                            &user_name_clone,
+                            &current_room,
+                            &target,
+                            &self_tx,
+                        )
+                        .await;
+                        current_room = target;
+                    }
+                    ClientMessage::LeaveRoom { room: target } => {
+                        if target != current_room {
+                            send_server_message(
+                                &self_tx,
+                                &ServerMessage::error(
+                                    400,
+                                    format!("You are not in room '{}'", target),
+                                ),
+                            );
+                            continue;
+                        }
+                        if target == DEFAULT_ROOM {
+                            send_server_message(
+                                &self_tx,
+                                &ServerMessage::error(400, "Can't leave the default room"),
+                            );
+                            continue;
+                        }
+                        switch_room(
+                            &state_clone,
+                            &user_id,
+                            &user_name_clone,
+                            &current_room,
+                            DEFAULT_ROOM,
+                            &self_tx,
+                        )
+                        .await;
+                        current_room = DEFAULT_ROOM.to_string();
+                    }
+                    ClientMessage::Pin { id } => {
+                        if !user_role(&state_clone, &user_id).can(Permission::Pin) {
+                            send_server_message(
+                                &self_tx,
+                                &ServerMessage::error(403, "Only moderators can pin messages"),
+                            );
+                            continue;
+                        }
+                        match pin_message(&state_clone, &current_room, id) {
+                            Ok(PinOutcome::Pinned { evicted }) => {
+                                for id in evicted {
+                                    let server_msg = ServerMessage::MessageUnpinned { id };
+                                    broadcast_server_message(
+                                        &state_clone,
+                                        &current_room,
+                                        &server_msg,
+                                    )
+                                    .await;
+                                }
+                                let server_msg = ServerMessage::MessagePinned {
+                                    id,
+                                    by: user_name_clone.clone(),
+                                };
+                                broadcast_server_message(&state_clone, &current_room, &server_msg)
+                                    .await;
+                            }
+                            Ok(PinOutcome::AlreadyPinned) => {}
+                            Err(reply) => send_server_message(&self_tx, &reply),
+                        }
+                    }
+                    ClientMessage::SetTopic { text } => {
+                        if state_clone.config.topic_policy == TopicPolicy::Moderators
+                            && !user_role(&state_clone, &user_id).can(Permission::SetTopic)
+                        {
+                            send_server_message(
+                                &self_tx,
+                                &ServerMessage::error(403, "Only moderators can change the topic"),
+                            );
+                            continue;
+                        }
+                        // Blank text clears the topic rather than being refused
+                        let topic = match validate_text(&state_clone, &text) {
+                            Ok(text) if text.trim().is_empty() => None,
+                            Ok(text) => Some(text.trim().to_string()),
+                            Err(TextRejection::Blank) => None,
+                            Err(rejection) => {
+                                send_server_message(&self_tx, &rejection.error(&state_clone));
+                                continue;
+                            }
+                        };
+                        // Every change is broadcast and saved, so it costs
+                        // as much as a message
+                        if !check_rate_limit(&state_clone, &user_id, &self_tx) {
+                            continue;
+                        }
+                        if set_topic(&state_clone, &current_room, topic.clone()) {
+                            let server_msg = ServerMessage::TopicChanged {
+                                topic,
+                                by: Some(user_name_clone.clone()),
+                            };
+                            broadcast_server_message(&state_clone, &current_room, &server_msg)
+                                .await;
+                        }
+                    }
+                    ClientMessage::Kick { target } => {
+                        let role = user_role(&state_clone, &user_id);
+                        if !role.can(Permission::Kick) {
+                            send_server_message(
+                                &self_tx,
+                                &ServerMessage::error(
+                                    403,
+                                    "Only moderators and admins can kick users",
+                                ),
+                            );
+                            continue;
+                        }
+                        if let Err(error) = kick_user(&state_clone, &target, &user_name_clone, role)
+                        {
+                            send_server_message(&self_tx, &error);
+                        }
+                    }
+                    ClientMessage::Disconnect => {
+                        break;
+                    }
+                    ClientMessage::Connect { .. } => {
+                        // Ignore duplicate connect messages
+                    }
+                }
+            } else if let Ok(legacy) = serde_json::from_str::<Message>(&text) {
+                // Fallback for old message format
+                let Some(text) =
+                    validate_outgoing_text(&state_clone, &user_id, &legacy.text, &self_tx)
+                else {
+                    continue;
+                };
+                let message = Message::new(text);
+                record_user_message(&state_clone, &user_id).await;
+
+                // Store message with limit, then broadcast to all clients
+                if let Some(message) = store_message(&state_clone, &current_room, message) {
+                    broadcast_raw(&state_clone, &current_room, &message);
+                }
+            } else {
+                send_server_message(
+                    &self_tx,
+                    &ServerMessage::error(
+                        400,
+                        format!("Malformed message: {}", truncate_for_error(&text)),
+                    ),
+                );
+            }
+        }
+        false
+    };
+
+    // Noticed even while the send task is stuck on a client that stopped
+    // reading
+    let overflow = rx.overflow_signal();
+
+    // Handle outgoing messages to this client, pinging it while idle
+    let send_task = async {
+        let mut ticker = tokio::time::interval(keepalive);
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                msg = rx.recv() => {
+                    let Some(msg) = msg else { break };
+                    if sender
+                        .send(axum::extract::ws::Message::Text(msg.text.into()))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                _ = ticker.tick() => {
+                    if last_seen.lock_or_recover().elapsed() >= keepalive * 2 {
+                        println!("{} timed out; closing connection", user_name);
+                        break;
+                    }
+                    if sender
+                        .send(axum::extract::ws::Message::Ping(Default::default()))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                    if !away_after.is_zero() && is_idle(&state, &user_id, away_after) {
+                        set_status(&state, &user_id, UserStatus::Away, true).await;
+                    }
+                }
+            }
+        }
+    };
+
+    // Warn the client once its connection nears the idle timeout, then
+    // close it if nothing arrives in time
+    let idle_task = async {
+        if idle_timeout.is_zero() {
+            return std::future::pending().await;
+        }
+        let lead = IDLE_WARNING_LEAD.min(idle_timeout / 2);
+        let mut warned = false;
+        loop {
+            let deadline = *last_activity.lock_or_recover() + idle_timeout;
+            let warn_at = deadline - lead;
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            if now < warn_at {
+                warned = false;
+                tokio::time::sleep_until(warn_at.into()).await;
+                continue;
+            }
+            if !warned {
+                let warning = ServerMessage::IdleWarning {
+                    disconnect_in_secs: (deadline - now).as_secs_f64().ceil() as u64,
+                };
+                send_server_message(&self_tx, &warning);
+                warned = true;
+            }
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+        println!("{} was idle for too long; closing connection", user_name);
+        send_server_message(
+            &self_tx,
+            &ServerMessage::error(408, "Disconnected: idle for too long"),
+        );
+    };
+
+    // Wait for either task to complete, or for the server to close us
+    let closed_by_server = tokio::select! {
+        refused = recv_task => refused,
+        _ = send_task => false,
+        _ = overflow.overflowed() => false,
+        _ = idle_task => true,
+        _ = close.notified() => true,
+    };
+
+    if rx.is_overflowed() {
+        println!("{} fell too far behind; disconnecting", user_name);
+        let error = ServerMessage::error(503, "Disconnected: too slow");
+        // The client may never read again, so don't wait on it for long
+        let _ = tokio::time::timeout(SLOW_CLIENT_CLOSE_TIMEOUT, async {
+            if let Ok(json) = serde_json::to_string(&error) {
+                let _ = sender
+                    .send(axum::extract::ws::Message::Text(json.into()))
+                    .await;
+            }
+            let _ = sender.send(axum::extract::ws::Message::Close(None)).await;
+        })
+        .await;
+    } else if closed_by_server {
+        // Deliver anything already queued (e.g. the close reason) before closing
+        while let Some(msg) = rx.try_recv() {
+            if sender
+                .send(axum::extract::ws::Message::Text(msg.text.into()))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+        let _ = sender.send(axum::extract::ws::Message::Close(None)).await;
+    }
+
+    // Clean up user when disconnected, using the latest name and room in
+    // case they renamed or switched rooms
+    let Some(User {
+        name: user_name,
+        room,
+        ..
+    }) = unregister_user(&state, &user_id)
+    else {
+        return;
+    };
+    mark_seen(&state, user_name.clone());
+    append_last_seen(&state, &user_name);
+
+    // Broadcast user left notification
+    broadcast_user_left(&state, &room, &user_name).await;
+}
+
+/// Undoes a connection's registration: stops routing messages to it, frees
+/// its name and counts it as gone. Returns the user as last known, or `None`
+/// if it's already gone.
+fn unregister_user(state: &AppState, user_id: &str) -> Option<User> {
+    state.clients.lock_or_recover().remove(user_id);
+    let user = state.users.lock_or_recover().remove(user_id)?;
+    state.metrics.user_disconnected();
+    Some(user)
+}
+
+/// Records that `name` was active just now.
+///
+/// Only kept in memory; [`append_last_seen`] writes it out on disconnects
+/// and [`save_last_seen`] at shutdown, rather than on every message.
+fn mark_seen(state: &AppState, name: String) {
+    let mut last_seen = state.last_seen.lock_or_recover();
+    last_seen.insert(name, unix_time());
+    forget_oldest_seen(&mut last_seen, MAX_LAST_SEEN);
+}
+
+/// Forgets the longest-unseen names once more than `max` are remembered,
+/// leaving room for a tenth of `max` more before pruning again.
+fn forget_oldest_seen(last_seen: &mut HashMap<String, u64>, max: usize) {
+    if last_seen.len() <= max {
+        return;
+    }
+    let mut by_age: Vec<(u64, String)> = last_seen
+        .iter()
+        .map(|(name, &time)| (time, name.clone()))
+        .collect();
+    by_age.sort_unstable();
+    let excess = by_age.len() - (max - max / 10);
+    for (_, name) in by_age.drain(..excess) {
+        last_seen.remove(&name);
+    }
+}
+
+/// Appends `name`'s last-seen time to the persisted log, if any.
+///
+/// Once the log holds more records than there are names, every time is
+/// saved whole instead, so the files stay proportional to the names kept.
+fn append_last_seen(state: &AppState, name: &str) {
+    let Some(storage) = &state.storage else {
+        return;
+    };
+    let (time, known) = {
+        let last_seen = state.last_seen.lock_or_recover();
+        (last_seen.get(name).copied(), last_seen.len())
+    };
+    let Some(time) = time else {
+        return;
+    };
+    let appended = storage.lock_or_recover().append_last_seen(name, time);
+    match appended {
+        Ok(appended) if appended > known => save_last_seen(state),
+        Ok(_) => {}
+        Err(e) => eprintln!("Failed to save last-seen time: {}", e),
+    }
+}
+
+/// Writes every user's last-seen time beside the persisted history, if any.
+fn save_last_seen(state: &AppState) {
+    let Some(storage) = &state.storage else {
+        return;
+    };
+    let last_seen: BTreeMap<String, u64> = state
+        .last_seen
+        .lock_or_recover()
+        .iter()
+        .map(|(name, &time)| (name.clone(), time))
+        .collect();
+    if let Err(e) = storage.lock_or_recover().save_last_seen(&last_seen) {
+        eprintln!("Failed to save last-seen times: {}", e);
+    }
+}
+
+/// Sets or clears the topic of `room`, saving the topics of every room when
+/// persistence is enabled. Returns `false` if the room doesn't exist.
+fn set_topic(state: &AppState, room: &str, topic: Option<String>) -> bool {
+    match state.rooms.lock_or_recover().get_mut(room) {
+        Some(room_state) => room_state.topic = topic,
+        None => return false,
+    }
+    save_topics(state);
+    true
+}
+
+/// Writes the topic of every room beside the persisted history, if any.
+fn save_topics(state: &AppState) {
+    let Some(storage) = &state.storage else {
+        return;
+    };
+    let topics: BTreeMap<String, String> = state
+        .rooms
+        .lock_or_recover()
+        .iter()
+        .filter_map(|(id, room)| Some((id.clone(), room.topic.clone()?)))
+        .collect();
+    if let Err(e) = storage.lock_or_recover().save_topics(&topics) {
+        eprintln!("Failed to save room topics: {}", e);
+    }
+}
+
+/// The `TopicChanged` a client joining `room` is sent, if the room has a topic.
+fn topic_for_joiner(state: &AppState, room: &str) -> Option<ServerMessage> {
+    let topic = state.rooms.lock_or_recover().get(room)?.topic.clone()?;
+    Some(ServerMessage::TopicChanged {
+        topic: Some(topic),
+        by: None,
+    })
+}
+
+/// Decides which role a connecting client gets, or the error to reject it with.
+///
+/// When the server requires an auth token, the admin and moderator tokens
+/// are accepted in its place. The admin token makes the client an admin and
+/// the moderator token a moderator; anyone else is a member if they presented
+/// the auth token and a guest otherwise. Which names the client may use is
+/// up to [`check_name`].
+fn authenticate(config: &ServerConfig, token: Option<&str>) -> Result<Role, ServerMessage> {
+    let presented = |expected: &Option<String>| {
+        expected
+            .as_deref()
+            .is_some_and(|expected| token_matches(token, expected))
+    };
+    let privileged = presented(&config.admin_token) || presented(&config.moderator_token);
+
+    if config.auth_token.is_some() && !presented(&config.auth_token) && !privileged {
+        return Err(ServerMessage::error(401, "Invalid or missing auth token"));
+    }
+    if presented(&config.admin_token) {
+        Ok(Role::Admin)
+    } else if presented(&config.moderator_token) {
+        Ok(Role::Moderator)
+    } else if config.auth_token.is_some() {
+        Ok(Role::Member)
+    } else {
+        Ok(Role::Guest)
+    }
+}
+
+/// Checks a name a client asked to go by, on connecting or renaming, and
+/// returns it cleaned up: control characters stripped and trimmed.
+///
+/// Fails with 422 if it's empty or longer than [`MAX_NAME_LEN`], or 403 if
+/// it's kept for an admin and `role` isn't one, or is banned. Whether
+/// someone else has it is checked separately, under the users lock.
+fn check_name(state: &AppState, name: &str, role: Role) -> Result<String, ServerMessage> {
+    let name = sanitize_text(state, name).trim().to_string();
+    if name.is_empty() {
+        return Err(ServerMessage::error(422, "Name cannot be empty"));
+    }
+    if name.chars().count() > MAX_NAME_LEN {
+        return Err(ServerMessage::error(
+            422,
+            format!("Names may be at most {} characters", MAX_NAME_LEN),
+        ));
+    }
+    check_reserved_name(&state.config, &name, role)?;
+    if let Some(remaining) = ban_remaining(state, &name) {
+        return Err(ServerMessage::error(
+            403,
+            format!(
+                "You are banned for another {} seconds",
+                remaining.as_secs().max(1)
+            ),
+        ));
+    }
+    Ok(name)
+}
+
+/// Refuses names listed in `admins` to anyone but an admin, so nobody can
+/// pass for one by name alone. Names are compared ignoring case, as when
+/// checking whether a name is taken.
+fn check_reserved_name(config: &ServerConfig, name: &str, role: Role) -> Result<(), ServerMessage> {
+    let name_lower = name.to_lowercase();
+    if role != Role::Admin
+        && config
+            .admins
+            .iter()
+            .any(|admin| admin.to_lowercase() == name_lower)
+    {
+        return Err(ServerMessage::error(
+            403,
+            format!("The name {} is reserved for an admin", name),
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the role of a connected user; unknown users are guests.
+fn user_role(state: &AppState, user_id: &str) -> Role {
+    state
+        .users
+        .lock_or_recover()
+        .get(user_id)
+        .map_or(Role::Guest, |user| user.role)
+}
+
+/// Pins message `id` in `room`.
+///
+/// Pins are kept in the order they were made. When the room already has
+/// `max_pins`, the new pin is refused with a 409 error or the oldest pins
+/// make room for it, as `pin_overflow` says. Returns what changed, or a 404
+/// error if the room has no such message.
+fn pin_message(state: &AppState, room: &str, id: u64) -> Result<PinOutcome, ServerMessage> {
+    let mut rooms = state.rooms.lock_or_recover();
+    let room_state = rooms
+        .get_mut(room)
+        .filter(|room_state| room_state.messages.iter().any(|msg| msg.id == Some(id)))
+        .ok_or_else(|| ServerMessage::error(404, format!("Message #{} not found", id)))?;
+    if room_state.pinned.contains(&id) {
+        return Ok(PinOutcome::AlreadyPinned);
+    }
+
+    let max_pins = state.config.max_pins;
+    let mut evicted = Vec::new();
+    if max_pins > 0 && room_state.pinned.len() >= max_pins {
+        match state.config.pin_overflow {
+            PinOverflowPolicy::Reject => {
+                return Err(ServerMessage::error(
+                    409,
+                    format!("This room already has {} pinned messages", max_pins),
+                ));
+            }
+            PinOverflowPolicy::EvictOldest => {
+                let excess = room_state.pinned.len() + 1 - max_pins;
+                evicted = room_state.pinned.drain(..excess).collect();
+            }
+        }
+    }
+    room_state.pinned.push(id);
+    Ok(PinOutcome::Pinned { evicted })
+}
+
+/// What [`pin_message`] changed.
+#[derive(Debug, PartialEq, Eq)]
+enum PinOutcome {
+    /// The message was pinned already; nothing changed
+    AlreadyPinned,
+    /// The message was pinned, unpinning `evicted` to stay within `max_pins`
+    Pinned { evicted: Vec<u64> },
+}
+
+/// Returns whether a connected user, other than the one with ID `except`,
+/// goes by `name` ignoring case.
+fn name_taken(users: &HashMap<String, User>, name: &str, except: Option<&str>) -> bool {
+    let name = name.to_lowercase();
+    users
+        .iter()
+        .any(|(id, user)| Some(id.as_str()) != except && user.name.to_lowercase() == name)
+}
+
+/// Picks the name a connecting client is registered under, or the error to
+/// reject it with when `name` is taken and `policy` doesn't allow renaming.
+fn assign_name(
+    users: &HashMap<String, User>,
+    name: &str,
+    policy: DuplicateNamePolicy,
+) -> Result<String, ServerMessage> {
+    if !name_taken(users, name, None) {
+        return Ok(name.to_string());
+    }
+    match policy {
+        DuplicateNamePolicy::Reject => Err(ServerMessage::error(
+            409,
+            format!("The name '{}' is already in use", name),
+        )),
+        DuplicateNamePolicy::Suffix => Ok((2..)
+            .map(|n| format!("{}_{}", name, n))
+            .find(|candidate| !name_taken(users, candidate, None))
+            .expect("some suffix is always free")),
+    }
+}
+
+/// Builds the snapshot header the client registered as `name` with `role`
+/// receives on entering `room`.
+fn welcome_message(state: &AppState, room: &str, name: &str, role: Role) -> ServerMessage {
+    ServerMessage::Welcome {
+        name: name.to_string(),
+        role,
+        server_name: state.config.server_name.clone(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        room: room.to_string(),
+        motd: format!(
+            "Welcome to {}! You are in room {}.",
+            state.config.server_name, room
+        ),
+    }
+}
+
+/// Encodes the last `join_backlog` messages of `room` for replay to a
+/// client joining it: a text frame per message for legacy clients, or a
+/// single `History` block.
+fn history_frames(state: &AppState, room: &str, format: ReplayFormat) -> Vec<Message> {
+    let messages = history_page(state, room, None, state.config.join_backlog);
+    if messages.is_empty() {
+        return Vec::new();
+    }
+    match format {
+        ReplayFormat::Text => messages
+            .iter()
+            .map(|message| Message::new(message.display_text()))
+            .collect(),
+        ReplayFormat::Full => {
+            let history = ServerMessage::History { messages };
+            vec![Message::new(
+                serde_json::to_string(&history).expect("Failed to serialize server message"),
+            )]
+        }
+    }
+}
+
+/// The greeting for a client joining `room`, if the server has one: the
+/// room's own, or else the server-wide one.
+fn room_greeting(state: &AppState, room: &str) -> Option<ServerMessage> {
+    let text = state
+        .config
+        .room_welcomes
+        .get(room)
+        .or(state.config.welcome.as_ref())?;
+    Some(ServerMessage::System { text: text.clone() })
+}
+
+/// Moves a connected user from room `from` to room `to`.
+///
+/// The old room is told they left, the user receives a fresh snapshot of
+/// the new room (welcome and history) and the new room is told they joined.
+async fn switch_room(
+    state: &AppState,
+    user_id: &str,
+    user_name: &str,
+    from: &str,
+    to: &str,
+    client_tx: &ClientSender,
+) {
+    let mut replay_format = ReplayFormat::default();
+    let mut role = Role::default();
+    if let Some(user) = state.users.lock_or_recover().get_mut(user_id) {
+        user.room = to.to_string();
+        // Events from the new room now arrive untagged
+        user.subscriptions.remove(to);
+        replay_format = user.replay_format;
+        role = user.role;
+    }
+    broadcast_user_list(state, from).await;
+    broadcast_user_left(state, from, user_name).await;
+
+    send_server_message(client_tx, &welcome_message(state, to, user_name, role));
+    for frame in history_frames(state, to, replay_format) {
+        let _ = client_tx.send(frame);
+    }
+    for notice in [topic_for_joiner(state, to), room_greeting(state, to)]
+        .into_iter()
+        .flatten()
+    {
+        send_server_message(client_tx, &notice);
+    }
+
+    broadcast_user_list(state, to).await;
+    broadcast_user_joined(state, to, user_name).await;
+}
+
+/// Query parameters for `GET /messages`.
+#[derive(Debug, Default, Deserialize)]
+struct MessagesQuery {
+    /// Prefix each line with the RFC 3339 time the message was stored
+    #[serde(default)]
+    timestamps: bool,
+}
+
+/// Handles GET requests to retrieve all chat messages.
+///
+/// This endpoint returns the complete message history of the default room
+/// as plain text, with each message on a new line. With `?timestamps=true`
+/// each line starts with the time the message was stored, except for
+/// messages kept from before the server recorded times.
+///
+/// # Arguments
+///
+/// * `query` - Whether to prefix lines with timestamps
+/// * `state` - The shared application state containing the messages
+/// * `headers` - Request headers, carrying the room password if there is one
+///
+/// # Returns
+///
+/// Returns a response with status 200 OK containing the message history,
+/// 401 UNAUTHORIZED if the server requires a token and it's missing or
+/// wrong, or 401/403 if the default room has a password and the
+/// `X-Room-Password` header is missing or wrong.
+async fn handle_get(
+    Query(query): Query<MessagesQuery>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(status) = check_auth(&state, &headers) {
+        return status.into_response();
+    }
+    if let Err(status) = check_room_password_header(&state, DEFAULT_ROOM, &headers) {
+        return status.into_response();
+    }
+
+    let rooms = state.rooms.lock_or_recover();
+    let mut response = String::new();
+    if let Some(room) = rooms.get(DEFAULT_ROOM) {
+        if room.dropped > 0 {
+            response.push_str(HISTORY_OMITTED_SENTINEL);
+            response.push('\n');
+        }
+        for msg in room.messages.iter().filter(|msg| !msg.deleted) {
+            if query.timestamps
+                && let Some(timestamp) = msg.timestamp
+            {
+                response.push_str(&rfc3339(timestamp));
+                response.push(' ');
+            }
+            response.push_str(&msg.display_text());
+            response.push('\n');
+        }
+    }
+
+    (StatusCode::OK, response).into_response()
+}
+
+/// Handles GET requests for the users currently in a room.
+///
+/// # Returns
+///
+/// Returns status 200 OK with the room's `UserList` as JSON, 401
+/// UNAUTHORIZED if the server requires a token and it's missing or wrong,
+/// 404 NOT FOUND if the room doesn't exist, or 401/403 if the room has a
+/// password and the `X-Room-Password` header is missing or wrong.
+async fn handle_room_users(
+    Path(room): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(status) = check_auth(&state, &headers) {
+        return status.into_response();
+    }
+    if !state.rooms.lock_or_recover().contains_key(&room) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    if let Err(status) = check_room_password_header(&state, &room, &headers) {
+        return status.into_response();
+    }
+
+    (StatusCode::OK, Json(room_user_list(&state, &room))).into_response()
+}
+
+/// Handles GET requests for the messages pinned in a room.
+///
+/// # Returns
+///
+/// Returns status 200 OK with the pinned messages as a JSON array, oldest
+/// pin first and leaving out any since trimmed from the history, 401
+/// UNAUTHORIZED if the server requires a token and it's missing or wrong,
+/// 404 NOT FOUND if the room doesn't exist, or 401/403 if the room has a
+/// password and the `X-Room-Password` header is missing or wrong.
+async fn handle_room_pins(
+    Path(room): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(status) = check_auth(&state, &headers) {
+        return status.into_response();
+    }
+    if !state.rooms.lock_or_recover().contains_key(&room) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    if let Err(status) = check_room_password_header(&state, &room, &headers) {
+        return status.into_response();
+    }
+
+    let rooms = state.rooms.lock_or_recover();
+    let Some(room_state) = rooms.get(&room) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let pinned: Vec<Message> = room_state
+        .pinned
+        .iter()
+        .filter_map(|&id| room_state.messages.iter().find(|msg| msg.id == Some(id)))
+        .cloned()
+        .collect();
+    (StatusCode::OK, Json(pinned)).into_response()
+}
+
+/// Handles GET requests for a room's events as Server-Sent Events, for
+/// clients that can't use a WebSocket.
+///
+/// Each event's data is a `ServerMessage` as JSON, the same ones the
+/// room's WebSocket members receive. Nothing is replayed; the stream starts
+/// with whatever happens next. It ends when the room closes or the client
+/// falls `outbound_capacity` events behind under the disconnect policy.
+///
+/// # Returns
+///
+/// Returns status 200 OK with a `text/event-stream` body, 401 UNAUTHORIZED
+/// if the server requires a token and it's missing or wrong, 401/403 if the
+/// room has a password and the `X-Room-Password` header is missing or wrong,
+/// or 404 NOT FOUND if the room doesn't exist.
+async fn handle_stream(
+    Path(room): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(status) = check_auth(&state, &headers) {
+        return status.into_response();
+    }
+    if !state.rooms.lock_or_recover().contains_key(&room) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    if let Err(status) = check_room_password_header(&state, &room, &headers) {
+        return status.into_response();
+    }
+
+    let (tx, rx) = outbound::channel(state.config.outbound_capacity, state.config.overflow_policy);
+    let id = uuid::Uuid::new_v4().to_string();
+    state
+        .streams
+        .lock_or_recover()
+        .insert(id.clone(), StreamHandle { room, tx });
+    let guard = StreamGuard {
+        state: state.clone(),
+        id,
+    };
+    let events = futures::stream::unfold((rx, guard), |(mut rx, guard)| async move {
+        let message = rx.recv().await?;
+        let event = Event::default().data(message.text);
+        Some((Ok::<_, Infallible>(event), (rx, guard)))
+    });
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// Handles GET requests for when a user was last active.
+///
+/// # Returns
+///
+/// Returns status 200 OK with a [`LastSeen`] as JSON, or 404 NOT FOUND if
+/// nobody by that name is online or has been seen.
+async fn handle_last_seen(Path(name): Path<String>, State(state): State<AppState>) -> Response {
+    let online = state
+        .users
+        .lock_or_recover()
+        .values()
+        .any(|user| user.name == name);
+    let last_seen = state.last_seen.lock_or_recover().get(&name).copied();
+    if !online && last_seen.is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    Json(LastSeen {
+        name,
+        online,
+        last_seen,
+    })
+    .into_response()
+}
+
+/// Handles GET requests for the server's name and version.
+async fn handle_version(State(state): State<AppState>) -> Json<ServerInfo> {
+    Json(ServerInfo::new(&state.config.server_name))
+}
+
+/// Handles GET requests for the health check, served at both `/healthz`
+/// and `/health`.
+///
+/// Takes no locks, so frequent probes never contend with chat traffic.
+async fn handle_healthz(State(state): State<AppState>) -> Json<HealthStatus> {
+    let health = state.metrics.health();
+    Json(HealthStatus {
+        status: health.status,
+        server_name: state.config.server_name.clone(),
+        connected_users: health.users,
+        uptime_secs: health.uptime_secs,
+        rooms: health.rooms,
+    })
+}
+
+/// Handles GET requests for metrics in the Prometheus text format.
+async fn handle_metrics(State(state): State<AppState>) -> String {
+    state.metrics.render_prometheus()
+}
+
+/// Handles GET requests for a JSON summary of server load and timings.
+async fn handle_stats(State(state): State<AppState>) -> Json<ServerStats> {
+    Json(ServerStats {
+        connected_users: state.users.lock_or_recover().len(),
+        rooms: state.rooms.lock_or_recover().len(),
+        fanout: state.metrics.fanout(),
+    })
+}
+
+/// Response header carrying the history index of the first message returned
+/// by `GET /messages/json`; pass it as `before` to fetch the previous page.
+const HISTORY_START_HEADER: &str = "x-history-start";
+
+/// Query parameters for `GET /messages/json`.
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    /// Return at most this many messages (the newest ones before the cursor)
+    limit: Option<usize>,
+    /// Only return messages whose history index is lower than this
+    before: Option<usize>,
+}
+
+/// Handles GET requests for the message history as JSON.
+///
+/// Returns the default room's history as an array of `Message` objects,
+/// oldest first. `before` is an index into the current history and `limit`
+/// keeps only the newest messages below it, so a UI can page backwards using
+/// the index reported in the `x-history-start` header.
+///
+/// # Returns
+///
+/// Returns status 200 OK with the JSON array, 401 UNAUTHORIZED if the
+/// server requires a token and it's missing or wrong, or 401/403 if the
+/// default room has a password and the `X-Room-Password` header is missing
+/// or wrong.
+async fn handle_get_json(
+    State(state): State<AppState>,
+    Query(query): Query<HistoryQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(status) = check_auth(&state, &headers) {
+        return status.into_response();
+    }
+    if let Err(status) = check_room_password_header(&state, DEFAULT_ROOM, &headers) {
+        return status.into_response();
+    }
+
+    let rooms = state.rooms.lock_or_recover();
+    let (start, page) = match rooms.get(DEFAULT_ROOM) {
+        Some(room) => {
+            let len = room.messages.len();
+            let end = query.before.unwrap_or(len).min(len);
+            let start = query.limit.map_or(0, |limit| end.saturating_sub(limit));
+            let page: Vec<Message> = room.messages.range(start..end).cloned().collect();
+            (start, page)
+        }
+        None => (0, Vec::new()),
+    };
+
+    (
+        StatusCode::OK,
+        [(HISTORY_START_HEADER, start.to_string())],
+        Json(page),
+    )
+        .into_response()
+}
+
+/// Handles POST requests to add new chat messages.
+///
+/// This endpoint accepts JSON messages, stores them in the message history,
+/// enforces the message limit, and broadcasts them to the room's WebSocket clients.
+///
+/// # Arguments
+///
+/// * `room` - The room to post to, taken from the URL path
+/// * `state` - The shared application state
+/// * `remote` - The caller's address, used for rate limiting
+/// * `headers` - Request headers, carrying the bearer token when auth is enabled
+/// * `request` - The text to post and the name to show it from, extracted
+///   from the JSON request body; every other field of the message is set here
+///
+/// # Returns
+///
+/// Returns status 201 CREATED if the message is successfully processed,
+/// 400 BAD REQUEST if the text is blank or the sender name is too long,
+/// 403 FORBIDDEN if the sender name is "SERVER", kept for an admin or in use
+/// by a connected user,
+/// 401 UNAUTHORIZED if the server requires a token and it's missing or wrong,
+/// 401/403 if the room has a password and the `X-Room-Password` header is
+/// missing or wrong, 404 NOT FOUND if the room doesn't exist, 413 PAYLOAD TOO LARGE if it
+/// exceeds the configured maximum length, 422 UNPROCESSABLE ENTITY if the
+/// blocklist rejects it, or 429 TOO MANY REQUESTS with a
+/// `Retry-After` header if the caller's address is posting too fast. Every
+/// rate-limited response carries `X-RateLimit-Remaining`.
+async fn handle_post(
+    Path(room): Path<String>,
+    State(state): State<AppState>,
+    ConnectInfo(remote): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(request): Json<PostRequest>,
+) -> Response {
+    if let Err(status) = check_auth(&state, &headers) {
+        return status.into_response();
+    }
+    if let Err(status) = check_room_password_header(&state, &room, &headers) {
+        return status.into_response();
+    }
+
+    let remaining = match check_post_rate_limit(&state, remote.ip()) {
+        Ok(remaining) => remaining,
+        Err(retry_after) => {
+            let retry_secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [
+                    (RETRY_AFTER.as_str(), retry_secs.to_string()),
+                    (RATE_LIMIT_REMAINING_HEADER, "0".to_string()),
+                ],
+            )
+                .into_response();
+        }
+    };
+    let rate_limit_headers = [(RATE_LIMIT_REMAINING_HEADER, remaining.to_string())];
+    state.metrics.record_received();
+
+    let sender = match posted_sender(&state, request.sender.as_deref()) {
+        Ok(sender) => sender,
+        Err(status) => return (status, rate_limit_headers).into_response(),
+    };
+    let text = match validate_text(&state, &request.text) {
+        Ok(text) => text,
+        Err(rejection) => return (rejection.status(), rate_limit_headers).into_response(),
+    };
+    let mut message = Message {
+        sender,
+        ..Message::new(text)
+    };
+    message.mentions = extract_mentions(&state, &message.text);
+
+    let Some(message) = store_message(&state, &room, message) else {
+        return (StatusCode::NOT_FOUND, rate_limit_headers).into_response();
+    };
+
+    // Broadcast to all WebSocket clients in the room
+    broadcast_raw(&state, &room, &message);
+    state.metrics.record_broadcast();
+
+    (StatusCode::CREATED, rate_limit_headers).into_response()
+}
+
+/// Request body for `POST /room/{room}`.
+#[derive(Debug, Deserialize)]
+struct PostRequest {
+    /// Text of the message
+    text: String,
+    /// Name to show the message from; it has no sender when `None`
+    #[serde(default)]
+    sender: Option<String>,
+}
+
+/// Cleans up the sender name given with `POST /room/{room}`.
+///
+/// A blank name counts as none. Fails with 400 BAD REQUEST if the name is
+/// longer than `MAX_POSTED_SENDER_LEN`, or 403 FORBIDDEN if it would pass the
+/// message off as the server's, an admin's or a connected user's.
+fn posted_sender(state: &AppState, sender: Option<&str>) -> Result<Option<String>, StatusCode> {
+    let Some(sender) = sender.map(|sender| sanitize_text(state, sender.trim())) else {
+        return Ok(None);
+    };
+    if sender.is_empty() {
+        return Ok(None);
+    }
+    if sender.chars().count() > MAX_POSTED_SENDER_LEN {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let reserved = sender.eq_ignore_ascii_case("SERVER")
+        || state
+            .config
+            .admins
+            .iter()
+            .any(|admin| admin.eq_ignore_ascii_case(&sender))
+        || name_taken(&state.users.lock_or_recover(), &sender, None);
+    if reserved {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(Some(sender))
+}
+
+/// Query parameters for `POST /hooks/{room}`.
+#[derive(Debug, Deserialize)]
+struct HookQuery {
+    /// The room's hook token, if not sent in the `X-Hook-Token` header
+    token: Option<String>,
+}
+
+/// Request body for `POST /hooks/{room}`.
+#[derive(Debug, Deserialize)]
+struct HookRequest {
+    /// Name the message is shown from
+    username: String,
+    /// Text of the message
+    text: String,
+}
+
+/// Handles messages posted by external services through a room's webhook.
+///
+/// The caller names the sender, and the message goes out as a regular
+/// `Chat` to the room. The hook's token is taken from the `token` query
+/// parameter or the `X-Hook-Token` header.
+///
+/// # Returns
+///
+/// Returns status 201 CREATED with the message's `id`, 404 NOT FOUND if
+/// the room has no webhook, 401 UNAUTHORIZED if the token is missing or
+/// wrong, 400 BAD REQUEST for an empty name or text, 413 PAYLOAD TOO LARGE
+/// for overlong text, or 422 UNPROCESSABLE ENTITY if the blocklist rejects
+/// it.
+async fn handle_hook(
+    Path(room): Path<String>,
+    State(state): State<AppState>,
+    Query(query): Query<HookQuery>,
+    headers: HeaderMap,
+    Json(request): Json<HookRequest>,
+) -> Response {
+    let Some(expected) = state.config.hook_tokens.get(&room) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let token = query.token.as_deref().or_else(|| {
+        headers
+            .get(HOOK_TOKEN_HEADER)
+            .and_then(|value| value.to_str().ok())
+    });
+    if !token_matches(token, expected) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    let username = sanitize_text(&state, request.username.trim());
+    if username.is_empty() {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let text = match validate_text(&state, &request.text) {
+        Ok(text) => text,
+        Err(rejection) => return rejection.status().into_response(),
+    };
+    state.metrics.record_received();
+
+    let mut message = Message::chat_message(&username, &text);
+    message.mentions = extract_mentions(&state, &message.text);
+    let Some(message) = store_message(&state, &room, message) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    broadcast_server_message(&state, &room, &ServerMessage::chat(&message)).await;
+    state.metrics.record_broadcast();
+
+    (
+        StatusCode::CREATED,
+        Json(serde_json::json!({ "id": message.id })),
+    )
+        .into_response()
+}
+
+/// Handles uploads of a file to a room.
+///
+/// The request body is `multipart/form-data` with the file in a `file`
+/// part, its name and type taken from that part's headers. The file is saved
+/// under the configured upload directory, kept in the room's history and
+/// announced to the room with an `Attachment` message.
+///
+/// # Returns
+///
+/// Returns status 201 CREATED with `{"url": ...}` where the file can be
+/// downloaded, 400 BAD REQUEST if there's no `file` part, 401 UNAUTHORIZED
+/// if the server requires a token and it's
+/// missing or wrong, 401/403 if the room has a password and the
+/// `X-Room-Password` header is missing or wrong, 404 NOT FOUND if the room doesn't exist or uploads are
+/// disabled, 413 PAYLOAD TOO LARGE if the file exceeds `max_upload_size`,
+/// 415 UNSUPPORTED MEDIA TYPE if its type isn't allowed, or 429 TOO MANY
+/// REQUESTS if the caller's address is posting too fast.
+async fn handle_upload(
+    Path(room): Path<String>,
+    State(state): State<AppState>,
+    ConnectInfo(remote): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
+    if let Err(status) = check_auth(&state, &headers) {
+        return status.into_response();
+    }
+    let Some(dir) = &state.config.upload_dir else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if !state.rooms.lock_or_recover().contains_key(&room) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    if let Err(status) = check_room_password_header(&state, &room, &headers) {
+        return status.into_response();
+    }
+    if let Err(retry_after) = check_post_rate_limit(&state, remote.ip()) {
+        let retry_secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [
+                (RETRY_AFTER.as_str(), retry_secs.to_string()),
+                (RATE_LIMIT_REMAINING_HEADER, "0".to_string()),
+            ],
+        )
+            .into_response();
+    }
+
+    // Other parts are skipped; a body over the limit fails partway with 413
+    let field = loop {
+        match multipart.next_field().await {
+            Ok(Some(field)) if field.name() == Some("file") => break field,
+            Ok(Some(_)) => continue,
+            Ok(None) => return StatusCode::BAD_REQUEST.into_response(),
+            Err(e) => return e.status().into_response(),
+        }
+    };
+    let content_type = field
+        .content_type()
+        .map(uploads::media_type)
+        .unwrap_or_default();
+    if !state.config.upload_types.contains(&content_type) {
+        return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+    }
+    let name = uploads::clean_file_name(field.file_name());
+    let body = match field.bytes().await {
+        Ok(body) => body,
+        Err(e) => return e.status().into_response(),
+    };
+    if body.len() > state.config.max_upload_size {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    }
+
+    let info = UploadInfo {
+        name,
+        content_type,
+        size: body.len() as u64,
+    };
+    let id = match uploads::save(dir, &info, &body) {
+        Ok(id) => id,
+        Err(e) => {
+            eprintln!("Failed to save upload to {}: {}", dir.display(), e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let url = file_url(&state, &id);
+    let message = Message::attachment(&info.name, &url, &info.content_type, info.size);
+    if store_message(&state, &room, message).is_some() {
+        let server_msg = ServerMessage::Attachment {
+            name: info.name,
+            url: url.clone(),
+            content_type: info.content_type,
+            size: info.size,
+        };
+        broadcast_server_message(&state, &room, &server_msg).await;
+    }
+
+    (StatusCode::CREATED, Json(serde_json::json!({ "url": url }))).into_response()
+}
+
+/// Returns the URL the uploaded file `id` is served at: under `public_url`
+/// when it's configured, otherwise a path relative to the server, which
+/// clients resolve against the address they connected to. Nothing from the
+/// request goes into it, so an uploader can't point the announced link
+/// elsewhere.
+fn file_url(state: &AppState, id: &str) -> String {
+    match &state.config.public_url {
+        Some(base) => format!("{}/files/{}", base.trim_end_matches('/'), id),
+        None => format!("/files/{}", id),
+    }
+}
+
+/// Handles downloads of uploaded files.
+///
+/// # Returns
+///
+/// Returns status 200 OK with the file and the content type it was uploaded
+/// with, or 404 NOT FOUND if there's no such file or uploads are disabled.
+async fn handle_file(Path(id): Path<String>, State(state): State<AppState>) -> Response {
+    let Some(dir) = &state.config.upload_dir else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match uploads::load(dir, &id) {
+        Ok(Some((info, data))) => (
+            StatusCode::OK,
+            [
+                (CONTENT_TYPE, info.content_type),
+                // Never let a browser reinterpret e.g. a text file as HTML
+                (X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+            ],
+            data,
+        )
+            .into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            eprintln!("Failed to read upload {}: {}", id, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Handles GET requests for the list of open rooms, sorted by ID.
+async fn handle_list_rooms(State(state): State<AppState>) -> Json<Vec<RoomInfo>> {
+    let mut users_per_room: HashMap<String, usize> = HashMap::new();
+    for user in state.users.lock_or_recover().values() {
+        *users_per_room.entry(user.room.clone()).or_default() += 1;
+    }
+
+    let mut rooms: Vec<RoomInfo> = state
+        .rooms
+        .lock_or_recover()
+        .iter()
+        .map(|(id, room)| RoomInfo {
+            id: id.clone(),
+            users: users_per_room.get(id).copied().unwrap_or(0),
+            messages: room.messages.len(),
+            password_protected: room.password.is_some(),
+            topic: room.topic.clone(),
+        })
+        .collect();
+    rooms.sort_by(|a, b| a.id.cmp(&b.id));
+    Json(rooms)
+}
+
+/// Request body for `POST /rooms`.
+#[derive(Debug, Deserialize)]
+struct CreateRoomRequest {
+    /// Identifier of the room to create
+    name: String,
+    /// Password clients must give to join; anyone may when absent
+    #[serde(default)]
+    password: Option<String>,
+}
+
+/// Handles admin requests to create a room that stays open until the server
+/// stops.
+///
+/// # Returns
+///
+/// Returns status 201 CREATED, 409 CONFLICT if the room already exists,
+/// 400 BAD REQUEST for an empty name, or 401/403 if not authorized.
+async fn handle_create_room(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<CreateRoomRequest>,
+) -> StatusCode {
+    if let Err(status) = check_admin(&state, &headers) {
+        return status;
+    }
+
+    let name = request.name.trim().to_string();
+    if name.is_empty() {
+        return StatusCode::BAD_REQUEST;
+    }
+
+    let password = request.password.as_deref().map(PasswordHash::new);
+    let mut rooms = state.rooms.lock_or_recover();
+    if rooms.contains_key(&name) {
+        return StatusCode::CONFLICT;
+    }
+    let room = RoomState {
+        password,
+        ..RoomState::default()
+    };
+    rooms.insert(name, room);
+    state.metrics.set_rooms(rooms.len());
+    StatusCode::CREATED
+}
+
+/// Request body for `POST /rooms/ephemeral`.
+#[derive(Debug, Deserialize)]
+struct EphemeralRoomRequest {
+    /// Identifier of the room to create
+    name: String,
+    /// How long the room lives, counted from creation
+    lifetime_secs: u64,
+    /// Password clients must give to join; anyone may when absent
+    #[serde(default)]
+    password: Option<String>,
+}
+
+/// Handles admin requests to create a time-boxed room.
+///
+/// The room is closed once `lifetime_secs` have passed since creation,
+/// no matter how active it is. Members get a `RoomClosed` notice and are
+/// disconnected.
+///
+/// # Returns
+///
+/// Returns status 201 CREATED, 409 CONFLICT if the room already exists,
+/// 400 BAD REQUEST for an empty name or zero lifetime, or 401/403 if not authorized.
+async fn handle_create_ephemeral_room(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<EphemeralRoomRequest>,
+) -> StatusCode {
+    if let Err(status) = check_admin(&state, &headers) {
+        return status;
+    }
+
+    let name = request.name.trim().to_string();
+    if name.is_empty() || request.lifetime_secs == 0 {
+        return StatusCode::BAD_REQUEST;
+    }
+
+    let expires_at = Instant::now() + Duration::from_secs(request.lifetime_secs);
+    let password = request.password.as_deref().map(PasswordHash::new);
+    {
+        let mut rooms = state.rooms.lock_or_recover();
+        if rooms.contains_key(&name) {
+            return StatusCode::CONFLICT;
+        }
+        let room = RoomState {
+            password,
+            expires_at: Some(expires_at),
+            ..RoomState::default()
+        };
+        rooms.insert(name.clone(), room);
+        state.metrics.set_rooms(rooms.len());
+    }
+
+    tokio::spawn(async move {
+        tokio::time::sleep_until(expires_at.into()).await;
+        close_room(&state, &name, "Room lifetime expired", |room| {
+            room.expires_at == Some(expires_at)
+        });
+    });
+
+    StatusCode::CREATED
+}
+
+/// Request body for `POST /admin/announce`.
+#[derive(Debug, Deserialize)]
+struct AnnounceRequest {
+    /// Text of the announcement
+    text: String,
+}
+
+/// Handles admin requests to post a notice to every room.
+///
+/// # Returns
+///
+/// Returns status 204 NO CONTENT, 400 BAD REQUEST for empty or overlong
+/// text, or 401/403 if not authorized.
+async fn handle_announce(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<AnnounceRequest>,
+) -> StatusCode {
+    if let Err(status) = check_admin(&state, &headers) {
+        return status;
+    }
+
+    let text = sanitize_text(&state, request.text.trim());
+    if text.is_empty() || is_too_long(&state, &text) {
+        return StatusCode::BAD_REQUEST;
+    }
+    broadcast_announcement(&state, &text).await;
+    StatusCode::NO_CONTENT
+}
+
+/// Request body for `POST /admin/purge`.
+#[derive(Debug, Deserialize)]
+struct PurgeRequest {
+    /// Name of the user whose messages should be removed
+    name: String,
+}
+
+/// Handles moderator requests to remove all messages from one user.
+///
+/// Requires the admin bearer token. The messages are tombstoned as if
+/// deleted one by one, and a `MessagesPurged` notice follows the
+/// deletions so clients know why.
+///
+/// # Returns
+///
+/// Returns status 200 OK with `{"removed": n}`, or 401/403 if not authorized.
+async fn handle_purge(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<PurgeRequest>,
+) -> Response {
+    if let Err(status) = check_admin(&state, &headers) {
+        return status.into_response();
+    }
+
+    let purged = purge_user_messages(&state, &request.name);
+    let mut removed = 0;
+    for (room, purged) in purged {
+        announce_purged(&state, &room, &purged).await;
+        let count = purged.deleted.len();
+        let server_msg = ServerMessage::MessagesPurged {
+            name: request.name.clone(),
+            count,
+        };
+        broadcast_server_message(&state, &room, &server_msg).await;
+        removed += count;
+    }
+
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({ "removed": removed })),
+    )
+        .into_response()
+}
+
+/// Copies every room's history into a snapshot.
+fn take_snapshot(state: &AppState) -> ServerSnapshot {
+    let last_seen = state
+        .last_seen
+        .lock_or_recover()
+        .iter()
+        .map(|(name, &time)| (name.clone(), time))
+        .collect();
+    let rooms = state.rooms.lock_or_recover();
+    ServerSnapshot {
+        rooms: rooms
+            .iter()
+            .map(|(id, room)| {
+                let snapshot = RoomSnapshot {
+                    messages: room.messages.iter().cloned().collect(),
+                    last_id: room.last_id,
+                    topic: room.topic.clone(),
+                    pinned: room.pinned.clone(),
+                    dropped: room.dropped,
+                };
+                (id.clone(), snapshot)
+            })
+            .collect(),
+        last_seen,
+    }
+}
+
+/// Replaces every room's history with the contents of `snapshot` in one step.
+///
+/// Connected clients stay connected: rooms that still have members but
+/// aren't in the snapshot are kept, and the default room always exists.
+/// Message IDs never go backwards, so IDs clients have already seen aren't
+/// handed out again, and last-seen times keep whichever is later. The
+/// persisted history is rewritten to match.
+fn restore_snapshot(state: &AppState, snapshot: ServerSnapshot) {
+    let occupied: Vec<String> = state
+        .users
+        .lock_or_recover()
+        .values()
+        .map(|user| user.room.clone())
+        .collect();
+
+    let mut rooms = state.rooms.lock_or_recover();
+    let mut restored: HashMap<String, RoomState> = snapshot
+        .rooms
+        .into_iter()
+        .map(|(id, room)| {
+            let mut room = RoomState {
+                messages: room.messages.into(),
+                last_id: room.last_id,
+                topic: room.topic,
+                pinned: room.pinned,
+                dropped: room.dropped,
+                ..RoomState::default()
+            };
+            room.trim(state.config.max_messages);
+            (id, room)
+        })
+        .collect();
+    for (id, room) in rooms.drain() {
+        // Passwords aren't part of snapshots, so rooms keep the one they have
+        if let Some(restored_room) = restored.get_mut(&id) {
+            restored_room.password = room.password;
+            restored_room.last_id = restored_room.last_id.max(room.last_id);
+        } else if id == DEFAULT_ROOM || occupied.contains(&id) {
+            restored.insert(
+                id,
+                RoomState {
+                    last_id: room.last_id,
+                    password: room.password,
+                    ..RoomState::default()
+                },
+            );
+        }
+    }
+    *rooms = restored;
+    state.metrics.set_rooms(rooms.len());
+
+    if let Some(storage) = &state.storage
+        && let Err(e) = storage
+            .lock_or_recover()
+            .rewrite(&rooms[DEFAULT_ROOM].messages)
+    {
+        eprintln!("Failed to rewrite persisted history: {}", e);
+    }
+    drop(rooms);
+    save_topics(state);
+
+    {
+        let mut last_seen = state.last_seen.lock_or_recover();
+        for (name, time) in snapshot.last_seen {
+            let seen = last_seen.entry(name).or_default();
+            *seen = (*seen).max(time);
+        }
+        forget_oldest_seen(&mut last_seen, MAX_LAST_SEEN);
+    }
+    save_last_seen(state);
+}
+
+/// Summary of the rooms in a snapshot, returned by the snapshot endpoints.
+fn snapshot_summary(snapshot: &ServerSnapshot) -> serde_json::Value {
+    let messages: usize = snapshot.rooms.values().map(|r| r.messages.len()).sum();
+    serde_json::json!({ "rooms": snapshot.rooms.len(), "messages": messages })
+}
+
+/// Handles admin requests to save every room's history to the snapshot file.
+///
+/// # Returns
+///
+/// Returns status 200 OK with `{"rooms": n, "messages": m}`, 404 NOT FOUND if
+/// no snapshot path is configured, 500 if the file can't be written, or
+/// 401/403 if not authorized.
+async fn handle_snapshot(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Err(status) = check_admin(&state, &headers) {
+        return status.into_response();
+    }
+    let Some(path) = &state.config.snapshot_path else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let snapshot = take_snapshot(&state);
+    if let Err(e) = snapshot.save(path) {
+        eprintln!("Failed to write snapshot to {}: {}", path.display(), e);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    (StatusCode::OK, Json(snapshot_summary(&snapshot))).into_response()
+}
+
+/// Handles admin requests to replace every room's history from the snapshot file.
+///
+/// The file is read and parsed before anything changes, so a bad snapshot
+/// leaves the server untouched.
+///
+/// # Returns
+///
+/// Returns status 200 OK with `{"rooms": n, "messages": m}`, 404 NOT FOUND if
+/// no snapshot path is configured, 500 if the file can't be read, or 401/403
+/// if not authorized.
+async fn handle_restore(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Err(status) = check_admin(&state, &headers) {
+        return status.into_response();
+    }
+    let Some(path) = &state.config.snapshot_path else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let snapshot = match ServerSnapshot::load(path) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            eprintln!("Failed to read snapshot from {}: {}", path.display(), e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let summary = snapshot_summary(&snapshot);
+    restore_snapshot(&state, snapshot);
+
+    (StatusCode::OK, Json(summary)).into_response()
+}
+
+/// Query parameters for `/admin/export` and `/admin/import`.
+#[derive(Debug, Deserialize)]
+struct RoomQuery {
+    /// Room to export from or import into; the default room when absent
+    room: Option<String>,
+}
+
+/// Merges `imported` messages into the history of `room`.
+///
+/// Messages whose ID is already in the room are skipped, the rest are
+/// slotted in by ID, and messages without one are numbered after the
+/// newest. The history is then trimmed to `max_messages` and, for the
+/// default room, persisted. Returns how many messages were added and how
+/// many skipped, or `None` if the room doesn't exist.
+fn import_messages(state: &AppState, room: &str, imported: Vec<Message>) -> Option<(usize, usize)> {
+    let mut rooms = state.rooms.lock_or_recover();
+    let room_state = rooms.get_mut(room)?;
+
+    let mut known: HashSet<u64> = room_state.messages.iter().filter_map(|m| m.id).collect();
+    let (numbered, unnumbered): (Vec<Message>, Vec<Message>) =
+        imported.into_iter().partition(|m| m.id.is_some());
+    let mut added = 0;
+    let mut skipped = 0;
+    for message in numbered {
+        let id = message.id.unwrap_or_default();
+        if !known.insert(id) {
+            skipped += 1;
+            continue;
+        }
+        room_state.last_id = room_state.last_id.max(id);
+        room_state.messages.push_back(message);
+        added += 1;
+    }
+    room_state.messages.make_contiguous().sort_by_key(|m| m.id);
+    for mut message in unnumbered {
+        room_state.last_id += 1;
+        message.id = Some(room_state.last_id);
+        room_state.messages.push_back(message);
+        added += 1;
+    }
+    room_state.trim(state.config.max_messages);
+
+    if room == DEFAULT_ROOM
+        && let Some(storage) = &state.storage
+        && let Err(e) = storage.lock_or_recover().rewrite(&room_state.messages)
+    {
+        eprintln!("Failed to rewrite persisted history: {}", e);
+    }
+    Some((added, skipped))
+}
+
+/// Handles admin requests to download a room's history as JSON Lines.
+///
+/// # Returns
+///
+/// Returns status 200 OK with one `Message` per line, oldest first,
+/// 404 NOT FOUND if the room doesn't exist, or 401/403 if not authorized.
+async fn handle_export(
+    State(state): State<AppState>,
+    Query(query): Query<RoomQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(status) = check_admin(&state, &headers) {
+        return status.into_response();
+    }
+    let room = query.room.as_deref().unwrap_or(DEFAULT_ROOM);
+
+    let exported = {
+        let rooms = state.rooms.lock_or_recover();
+        match rooms.get(room) {
+            Some(room_state) => storage::to_json_lines(&room_state.messages),
+            None => return StatusCode::NOT_FOUND.into_response(),
+        }
+    };
+    match exported {
+        Ok(body) => (
+            StatusCode::OK,
+            [(CONTENT_TYPE, "application/x-ndjson")],
+            body,
+        )
+            .into_response(),
+        Err(e) => {
+            eprintln!("Failed to export history: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Handles admin requests to load messages exported by `/admin/export`
+/// into a room; see [`import_messages`] for how they are merged.
+///
+/// The body is parsed in full before anything changes, so a damaged
+/// export leaves the room untouched.
+///
+/// # Returns
+///
+/// Returns status 200 OK with `{"imported": n, "skipped": m}`, 400 BAD
+/// REQUEST naming the first unreadable line, 404 NOT FOUND if the room
+/// doesn't exist, or 401/403 if not authorized.
+async fn handle_import(
+    State(state): State<AppState>,
+    Query(query): Query<RoomQuery>,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    if let Err(status) = check_admin(&state, &headers) {
+        return status.into_response();
+    }
+    let room = query.room.as_deref().unwrap_or(DEFAULT_ROOM);
+
+    let messages = match storage::from_json_lines(&body) {
+        Ok(messages) => messages,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    match import_messages(&state, room, messages) {
+        Some((imported, skipped)) => (
+            StatusCode::OK,
+            Json(serde_json::json!({ "imported": imported, "skipped": skipped })),
+        )
+            .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Handles admin requests for the detailed list of connected users.
+///
+/// Unlike the `UserList` broadcast to clients, this includes connection
+/// duration, message count, remote address (if recorded) and activity status.
+///
+/// # Returns
+///
+/// Returns status 200 OK with an `AdminUserList`, or 401/403 if not authorized.
+async fn handle_admin_users(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Err(status) = check_admin(&state, &headers) {
+        return status.into_response();
+    }
+
+    let users: Vec<User> = state.users.lock_or_recover().values().cloned().collect();
+    (StatusCode::OK, Json(AdminUserList::from_users(&users))).into_response()
+}
+
+/// Handles admin requests to list active connections.
+///
+/// # Returns
+///
+/// Returns status 200 OK with a JSON array of `ConnectionInfo`, or 401/403
+/// if not authorized.
+async fn handle_list_connections(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Err(status) = check_admin(&state, &headers) {
+        return status.into_response();
+    }
+
+    let connections: Vec<ConnectionInfo> = state
+        .users
+        .lock_or_recover()
+        .values()
+        .map(ConnectionInfo::from)
+        .collect();
+    (StatusCode::OK, Json(connections)).into_response()
+}
+
+/// Handles admin requests to forcibly close one connection by ID.
+///
+/// The connection runs its normal cleanup, so the user leaves the roster and
+/// the rest of the room is told they left.
+///
+/// # Returns
+///
+/// Returns status 204 NO CONTENT, 404 NOT FOUND if there's no such connection,
+/// or 401/403 if not authorized.
+async fn handle_terminate_connection(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> StatusCode {
+    if let Err(status) = check_admin(&state, &headers) {
+        return status;
+    }
+
+    match state.clients.lock_or_recover().get(&id) {
+        Some(handle) => {
+            handle.close.notify_one();
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn run_tui_server(state: AppState, socket_addr: SocketAddr) -> ChatResult<()> {
+    let listener = tokio::net::TcpListener::bind(socket_addr)
+        .await
+        .map_err(|e| {
+            ChatError::NetworkError(format!("Failed to bind to {}: {}", socket_addr, e))
+        })?;
+    let state_clone = state.clone();
+    let quit = Arc::new(Notify::new());
+    let quit_signal = quit.clone();
+
+    // Start the server in a separate task; it stops when the console quits
+    let server_handle = tokio::spawn(serve(state_clone, listener, async move {
+        quit_signal.notified().await
+    }));
+
+    // The console reads the terminal in raw mode, so Ctrl+C arrives as a key
+    // press there rather than as a signal
+    let runtime = tokio::runtime::Handle::current();
+    match tokio::task::spawn_blocking(move || server_tui::run(state, runtime)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => eprintln!("TUI error: {}", e),
+        Err(e) => eprintln!("TUI task failed: {}", e),
+    }
+    quit.notify_one();
+    let served = server_handle.await;
+
+    match served {
+        Ok(served) => served,
+        Err(e) => Err(ChatError::NetworkError(format!(
+            "Server task failed: {}",
+            e
+        ))),
+    }
+}
+
+/// Posts `text` from "SERVER" to every room as an `Announcement`.
+///
+/// The announcement is stored in each room's history like a regular chat
+/// message so late joiners see it too. Announcements come from the operator,
+/// so no rate limit applies.
+pub(crate) async fn broadcast_announcement(state: &AppState, text: &str) {
+    let rooms: Vec<String> = state.rooms.lock_or_recover().keys().cloned().collect();
+    let server_msg = ServerMessage::Announcement {
+        text: text.to_string(),
+    };
+    for room in rooms {
+        let message = Message::chat_message("SERVER", text);
+        if store_message(state, &room, message).is_some() {
+            broadcast_server_message(state, &room, &server_msg).await;
+        }
+    }
+}
+
+/// Builds the user list for everyone in `room`.
+fn room_user_list(state: &AppState, room: &str) -> UserList {
+    let users = state.users.lock_or_recover();
+    UserList::from_users(
+        &users
+            .values()
+            .filter(|user| user.room == room)
+            .cloned()
+            .collect::<Vec<_>>(),
+    )
+}
+
+async fn broadcast_user_list(state: &AppState, room: &str) {
+    let server_msg = ServerMessage::UserList(room_user_list(state, room));
+    broadcast_server_message(state, room, &server_msg).await;
+}
+
+async fn broadcast_user_joined(state: &AppState, room: &str, user_name: &str) {
+    let server_msg = ServerMessage::UserJoined {
+        name: user_name.to_string(),
+    };
+    broadcast_server_message(state, room, &server_msg).await;
+}
+
+async fn broadcast_user_left(state: &AppState, room: &str, user_name: &str) {
+    let server_msg = ServerMessage::UserLeft {
+        name: user_name.to_string(),
+    };
+    broadcast_server_message(state, room, &server_msg).await;
+}
+
+/// Sends a server message to every client in `room`.
+///
+/// Returns the number of clients the message was queued for. When nobody is
+/// in the room the message isn't serialized at all. The time taken is
+/// recorded in the fan-out histogram.
+async fn broadcast_server_message(
+    state: &AppState,
+    room: &str,
+    server_msg: &ServerMessage,
+) -> usize {
+    let started = Instant::now();
+    let mut delivered = 0;
+    if !room_member_ids(state, room).is_empty() {
+        let json = serde_json::to_string(server_msg).expect("Failed to serialize server message");
+        delivered += send_to_members(state, room, &Message::new(json));
+    }
+    // Watchers have their own room's roster; they don't need this one
+    if !matches!(server_msg, ServerMessage::UserList(_)) {
+        delivered += send_to_watchers(state, room, server_msg);
+    }
+    delivered += send_to_streams(state, room, server_msg);
+    state.metrics.record_fanout(started.elapsed());
+    delivered
+}
+
+/// Queues an already-encoded message for every client in `room`.
+///
+/// Connections watching the room get it as a tagged `Chat` instead, since
+/// the raw format carries no room, and event streams as a plain `Chat`. The
+/// time taken is recorded in the fan-out histogram.
+fn broadcast_raw(state: &AppState, room: &str, message: &Message) -> usize {
+    let started = Instant::now();
+    let server_msg = ServerMessage::chat(message);
+    let delivered = send_to_members(state, room, message)
+        + send_to_watchers(state, room, &server_msg)
+        + send_to_streams(state, room, &server_msg);
+    state.metrics.record_fanout(started.elapsed());
+    delivered
+}
+
+/// Queues a message for every client whose current room is `room`.
+fn send_to_members(state: &AppState, room: &str, message: &Message) -> usize {
+    let member_ids = room_member_ids(state, room);
+    let clients = state.clients.lock_or_recover();
+    let mut delivered = 0;
+    for id in member_ids {
+        if let Some(handle) = clients.get(&id) {
+            let _ = handle.tx.send(message.clone());
+            delivered += 1;
+        }
+    }
+    delivered
+}
+
+/// Queues `server_msg`, tagged with `room`, for every client watching `room`.
+fn send_to_watchers(state: &AppState, room: &str, server_msg: &ServerMessage) -> usize {
+    let watcher_ids = room_watcher_ids(state, room);
+    if watcher_ids.is_empty() {
+        return 0;
+    }
+
+    let tagged = ServerMessage::RoomEvent {
+        room: room.to_string(),
+        event: Box::new(server_msg.clone()),
+    };
+    let clients = state.clients.lock_or_recover();
+    let mut delivered = 0;
+    for id in watcher_ids {
+        if let Some(handle) = clients.get(&id) {
+            send_server_message(&handle.tx, &tagged);
+            delivered += 1;
+        }
+    }
+    delivered
+}
+
+/// Queues `server_msg` for every event stream of `room`.
+fn send_to_streams(state: &AppState, room: &str, server_msg: &ServerMessage) -> usize {
+    let streams = state.streams.lock_or_recover();
+    let mut delivered = 0;
+    for stream in streams.values().filter(|stream| stream.room == room) {
+        send_server_message(&stream.tx, server_msg);
+        delivered += 1;
+    }
+    delivered
+}
+
+/// Returns whether `text` exceeds the configured maximum message length.
+///
+/// Length is counted in Unicode scalar values rather than bytes.
+fn is_too_long(state: &AppState, text: &str) -> bool {
+    text.chars().count() > state.config.max_message_len
+}
+
+/// Removes C0 and C1 control characters other than newline from `text`,
+/// unless the server allows them.
+///
+/// This keeps clients from sending escape sequences that would clear or
+/// recolor other users' terminals.
+fn sanitize_text(state: &AppState, text: &str) -> String {
+    if state.config.allow_control_chars {
+        return text.to_string();
+    }
+    text.chars()
+        .filter(|&c| c == '\n' || !c.is_control())
+        .collect()
+}
+
+/// Applies the blocklist to `text`, returning it masked as configured, or
+/// `None` if it must be rejected.
+fn filter_blocked(state: &AppState, text: String) -> Option<String> {
+    match &state.config.blocklist {
+        Some(blocklist) => blocklist.filter(&text),
+        None => Some(text),
+    }
+}
+
+/// Returns whether `text` should be turned away for having nothing but
+/// whitespace in it.
+fn is_blank(state: &AppState, text: &str) -> bool {
+    !state.config.allow_empty && text.trim().is_empty()
+}
+
+/// Why [`validate_text`] turned a message's text away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TextRejection {
+    /// Nothing but whitespace, on a server that doesn't allow that
+    Blank,
+    /// Longer than `max_message_len`
+    TooLong,
+    /// Refused by the blocklist
+    Blocked,
+}
+
+impl TextRejection {
+    /// Returns the status an HTTP endpoint answers with.
+    fn status(self) -> StatusCode {
+        match self {
+            TextRejection::Blank => StatusCode::BAD_REQUEST,
+            TextRejection::TooLong => StatusCode::PAYLOAD_TOO_LARGE,
+            TextRejection::Blocked => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+
+    /// Returns the error sent to a WebSocket client.
+    fn error(self, state: &AppState) -> ServerMessage {
+        match self {
+            TextRejection::Blank => ServerMessage::error(400, "Message must not be empty"),
+            TextRejection::TooLong => ServerMessage::error(
+                413,
+                format!(
+                    "Message too long (max {} characters)",
+                    state.config.max_message_len
+                ),
+            ),
+            TextRejection::Blocked => ServerMessage::error(400, "Message contains blocked words"),
+        }
+    }
+}
+
+/// Checks the text of a message before it goes out: strips control
+/// characters, then refuses it if blank or too long, then applies the
+/// blocklist. Returns the text as it should be sent.
+///
+/// Every path that posts text goes through here, so none can skip a step.
+fn validate_text(state: &AppState, text: &str) -> Result<String, TextRejection> {
+    let text = sanitize_text(state, text);
+    if is_blank(state, &text) {
+        return Err(TextRejection::Blank);
+    }
+    if is_too_long(state, &text) {
+        return Err(TextRejection::TooLong);
+    }
+    filter_blocked(state, text).ok_or(TextRejection::Blocked)
+}
+
+/// Runs a WebSocket client's text through [`validate_text`] and then its
+/// rate limiter, telling it why when the text is refused.
+fn validate_outgoing_text(
+    state: &AppState,
+    user_id: &str,
+    text: &str,
+    client_tx: &ClientSender,
+) -> Option<String> {
+    let text = match validate_text(state, text) {
+        Ok(text) => text,
+        Err(rejection) => {
+            send_server_message(client_tx, &rejection.error(state));
+            return None;
+        }
+    };
+    check_rate_limit(state, user_id, client_tx).then_some(text)
+}
+
+/// Shortens raw client input to at most 100 characters for error replies.
+fn truncate_for_error(text: &str) -> String {
+    const MAX_ERROR_SNIPPET: usize = 100;
+
+    if text.chars().count() > MAX_ERROR_SNIPPET {
+        let snippet: String = text.chars().take(MAX_ERROR_SNIPPET).collect();
+        format!("{}...", snippet)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Delivers a server message only to the user named `recipient`.
+///
+/// Returns `false` if no connected user has that name.
+fn send_direct_message(state: &AppState, recipient: &str, server_msg: &ServerMessage) -> bool {
+    let recipient_id = {
+        let users = state.users.lock_or_recover();
+        users
+            .iter()
+            .find(|(_, user)| user.name == recipient)
+            .map(|(id, _)| id.clone())
+    };
+
+    let Some(recipient_id) = recipient_id else {
+        return false;
+    };
+
+    let clients = state.clients.lock_or_recover();
+    match clients.get(&recipient_id) {
+        Some(handle) => {
+            send_server_message(&handle.tx, server_msg);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Disconnects the user named `target` on behalf of `by`, whose role is
+/// `by_role`, and bans the name for the configured cooldown.
+///
+/// Names are matched ignoring case, as when checking whether one is taken.
+/// The target is told why before its socket closes; its normal cleanup then
+/// tells the room it left. Fails with 404 if no such user is connected, or
+/// 403 if their role is as high as `by_role`.
+fn kick_user(state: &AppState, target: &str, by: &str, by_role: Role) -> Result<(), ServerMessage> {
+    let not_found = || ServerMessage::error(404, format!("User '{}' not found", target));
+    let target_lower = target.to_lowercase();
+    let (target_id, target) = {
+        let users = state.users.lock_or_recover();
+        users
+            .iter()
+            .find(|(_, user)| user.name.to_lowercase() == target_lower)
+            .map(|(id, user)| (id.clone(), user.clone()))
+            .ok_or_else(not_found)?
+    };
+    if target.role >= by_role {
+        return Err(ServerMessage::error(
+            403,
+            format!(
+                "You can't kick {}, whose role is as high as yours",
+                target.name
+            ),
+        ));
+    }
+
+    if !state.config.ban_cooldown.is_zero() {
+        state
+            .bans
+            .lock_or_recover()
+            .insert(target_lower, Instant::now() + state.config.ban_cooldown);
+    }
+
+    let clients = state.clients.lock_or_recover();
+    let handle = clients.get(&target_id).ok_or_else(not_found)?;
+    send_server_message(
+        &handle.tx,
+        &ServerMessage::error(403, format!("You were kicked by {}", by)),
+    );
+    handle.close.notify_one();
+    Ok(())
+}
+
+/// Returns how much longer `name` is banned for, clearing expired bans.
+///
+/// Bans are kept by lowercased name, so they hold whatever the case.
+fn ban_remaining(state: &AppState, name: &str) -> Option<Duration> {
+    let name = name.to_lowercase();
+    let mut bans = state.bans.lock_or_recover();
+    let until = *bans.get(&name)?;
+    let remaining = until.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        bans.remove(&name);
+        None
+    } else {
+        Some(remaining)
+    }
+}
+
+/// Sends a server message to a single client channel.
+fn send_server_message(client_tx: &ClientSender, server_msg: &ServerMessage) {
+    let json = serde_json::to_string(server_msg).expect("Failed to serialize server message");
+    let _ = client_tx.send(Message::new(json));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocklist::BlocklistMode;
+    use crate::shared::{SerializableUser, attachment_line};
+    use std::time::{Duration, Instant};
+    use tokio::time::sleep;
+    use tokio_tungstenite::tungstenite::protocol::Message as WsMessage;
+
+    type TestSocket = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+
+    /// Starts a server for the given state on an ephemeral port.
+    async fn spawn_test_server(state: AppState) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app_router(state).into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap();
+        });
+        addr
+    }
+
+    /// Opens a WebSocket to the default room and sends the connect frame.
+    async fn connect_test_client(addr: SocketAddr, name: &str) -> TestSocket {
+        connect_test_client_to_room(addr, DEFAULT_ROOM, name).await
+    }
+
+    /// Opens a WebSocket to `room` on the test server and sends the connect frame.
+    async fn connect_test_client_to_room(addr: SocketAddr, room: &str, name: &str) -> TestSocket {
+        let url = format!("ws://{}/room/{}", addr, room);
+        let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        send_client_message(
+            &mut ws,
+            &ClientMessage::connect(name.to_string(), None, None),
+        )
+        .await;
+        ws
+    }
+
+    async fn send_client_message(ws: &mut TestSocket, msg: &ClientMessage) {
+        let json = serde_json::to_string(msg).unwrap();
+        ws.send(WsMessage::Text(json.into())).await.unwrap();
+    }
+
+    /// Reads frames until one matches `predicate`, failing after a timeout.
+    async fn expect_server_message(
+        ws: &mut TestSocket,
+        predicate: impl Fn(&ServerMessage) -> bool,
+    ) -> ServerMessage {
+        tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                let frame = ws.next().await.unwrap().unwrap();
+                if let WsMessage::Text(text) = frame
+                    && let Ok(msg) = serde_json::from_str::<ServerMessage>(&text)
+                    && predicate(&msg)
+                {
+                    return msg;
+                }
+            }
+        })
+        .await
+        .expect("Timed out waiting for server message")
+    }
+
+    fn test_state() -> AppState {
+        AppState::new()
+    }
+
+    fn default_room_messages(state: &AppState) -> Vec<Message> {
+        state.rooms.lock().unwrap()[DEFAULT_ROOM]
+            .messages
+            .clone()
+            .into()
+    }
+
+    #[tokio::test]
+    async fn test_message_serialization() {
+        let message = Message::new("Hello, World!".to_string());
+
+        let json = serde_json::to_string(&message).unwrap();
+        let deserialized: Message = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(message.text, deserialized.text);
+    }
+
+    #[tokio::test]
+    async fn test_user_creation() {
+        let user_id = uuid::Uuid::new_v4().to_string();
+        let user = User {
+            id: user_id.clone(),
+            name: format!("User_{}", user_id.split('-').next().unwrap()),
+            connected_at: Instant::now(),
+            room: DEFAULT_ROOM.to_string(),
+            subscriptions: Default::default(),
+            message_count: 0,
+            last_active_at: Instant::now(),
+            ip: None,
+            rate_limiter: TokenBucket::default(),
+            client_version: None,
+            protocol_version: None,
+            replay_format: ReplayFormat::Text,
+            role: Role::Member,
+            status: UserStatus::Online,
+            auto_away: false,
+        };
+
+        assert!(!user.id.is_empty());
+        assert!(!user.name.is_empty());
+        assert!(user.name.starts_with("User_"));
+    }
+
+    #[tokio::test]
+    async fn test_app_state_creation() {
+        let clients = Arc::new(Mutex::new(HashMap::new()));
+        let users = Arc::new(Mutex::new(HashMap::new()));
+
+        let app_state = AppState {
+            clients: clients.clone(),
+            users: users.clone(),
+            ..AppState::new()
+        };
 
         // Test initial state
-        assert_eq!(app_state.messages.lock().unwrap().len(), 0);
+        assert_eq!(default_room_messages(&app_state).len(), 0);
         assert_eq!(app_state.clients.lock().unwrap().len(), 0);
         assert_eq!(app_state.users.lock().unwrap().len(), 0);
     }
 
     #[tokio::test]
-    async fn test_message_storage() {
-        let messages = Arc::new(Mutex::new(Vec::new()));
-        let clients = Arc::new(Mutex::new(Vec::new()));
-        let users = Arc::new(Mutex::new(HashMap::new()));
+    async fn test_message_storage() {
+        let clients = Arc::new(Mutex::new(HashMap::new()));
+        let users = Arc::new(Mutex::new(HashMap::new()));
+
+        let app_state = AppState {
+            clients: clients.clone(),
+            users: users.clone(),
+            ..AppState::new()
+        };
+
+        // Add a message
+        let test_message = Message::new("Test message".to_string());
+
+        {
+            let mut rooms_guard = app_state.rooms.lock().unwrap();
+            let room = rooms_guard.get_mut(DEFAULT_ROOM).unwrap();
+            room.messages.push_back(test_message.clone());
+        }
+
+        // Verify message was stored
+        assert_eq!(default_room_messages(&app_state).len(), 1);
+        assert_eq!(default_room_messages(&app_state)[0].text, "Test message");
+    }
+
+    #[tokio::test]
+    async fn test_user_management() {
+        let clients = Arc::new(Mutex::new(HashMap::new()));
+        let users = Arc::new(Mutex::new(HashMap::new()));
+
+        let app_state = AppState {
+            clients: clients.clone(),
+            users: users.clone(),
+            ..AppState::new()
+        };
+
+        // Add a user
+        let user_id = uuid::Uuid::new_v4().to_string();
+        let user = User {
+            id: user_id.clone(),
+            name: "TestUser".to_string(),
+            connected_at: Instant::now(),
+            room: DEFAULT_ROOM.to_string(),
+            subscriptions: Default::default(),
+            message_count: 0,
+            last_active_at: Instant::now(),
+            ip: None,
+            rate_limiter: TokenBucket::default(),
+            client_version: None,
+            protocol_version: None,
+            replay_format: ReplayFormat::Text,
+            role: Role::Member,
+            status: UserStatus::Online,
+            auto_away: false,
+        };
+
+        {
+            let mut users_guard = app_state.users.lock().unwrap();
+            users_guard.insert(user_id.clone(), user.clone());
+        }
+
+        // Verify user was added
+        assert_eq!(app_state.users.lock().unwrap().len(), 1);
+        assert!(app_state.users.lock().unwrap().contains_key(&user_id));
+
+        // Remove user
+        {
+            let mut users_guard = app_state.users.lock().unwrap();
+            users_guard.remove(&user_id);
+        }
+
+        // Verify user was removed
+        assert_eq!(app_state.users.lock().unwrap().len(), 0);
+        assert!(!app_state.users.lock().unwrap().contains_key(&user_id));
+    }
+
+    #[tokio::test]
+    async fn test_client_broadcast_simulation() {
+        let clients = Arc::new(Mutex::new(HashMap::new()));
+        let users = Arc::new(Mutex::new(HashMap::new()));
+
+        let app_state = AppState {
+            clients: clients.clone(),
+            users: users.clone(),
+            ..AppState::new()
+        };
+
+        // Create mock client channels
+        let (tx1, mut rx1) = outbound::channel(8, OverflowPolicy::Disconnect);
+        let (tx2, mut rx2) = outbound::channel(8, OverflowPolicy::Disconnect);
+
+        // Add clients to state
+        {
+            let mut clients_guard = app_state.clients.lock().unwrap();
+            clients_guard.insert("client1".to_string(), ClientHandle::new(tx1));
+            clients_guard.insert("client2".to_string(), ClientHandle::new(tx2));
+        }
+
+        // Broadcast a message
+        let broadcast_message = Message::new("Broadcast test".to_string());
+
+        {
+            let clients_guard = app_state.clients.lock().unwrap();
+            for handle in clients_guard.values() {
+                let _ = handle.tx.send(broadcast_message.clone());
+            }
+        }
+
+        // Verify both clients received the message
+        let received1 = rx1.recv().await;
+        let received2 = rx2.recv().await;
+
+        assert!(received1.is_some());
+        assert!(received2.is_some());
+        assert_eq!(received1.unwrap().text, "Broadcast test");
+        assert_eq!(received2.unwrap().text, "Broadcast test");
+    }
+
+    #[tokio::test]
+    async fn test_message_formatting() {
+        let name = "Alice";
+        let line = "Hello everyone!";
+
+        let mut whom = String::from(name);
+        whom.push_str(": ");
+        whom.push_str(line);
+
+        assert_eq!(whom, "Alice: Hello everyone!");
+
+        let message = Message::new(whom.clone());
+        assert_eq!(message.text, "Alice: Hello everyone!");
+    }
+
+    #[tokio::test]
+    async fn test_sender_travels_apart_from_text() {
+        // A colon in the body no longer confuses anyone about who sent it
+        let message = Message::chat_message("Alice", "note: bring snacks");
+        assert_eq!(message.text, "note: bring snacks");
+        assert_eq!(message.display_text(), "Alice: note: bring snacks");
+        match ServerMessage::chat(&message) {
+            ServerMessage::Chat { text, sender, .. } => {
+                assert_eq!(text, "note: bring snacks");
+                assert_eq!(sender.as_deref(), Some("Alice"));
+            }
+            other => panic!("Expected chat, got {:?}", other),
+        }
+
+        // Old-format clients introduce themselves with a bare message, whose
+        // sender now names them
+        let state = test_state();
+        let addr = spawn_test_server(state.clone()).await;
+        let url = format!("ws://{}/room/{}", addr, DEFAULT_ROOM);
+        let (mut legacy, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let hello = Message {
+            sender: Some("Dave".to_string()),
+            ..Message::new("hi: there".to_string())
+        };
+        legacy
+            .send(WsMessage::Text(
+                serde_json::to_string(&hello).unwrap().into(),
+            ))
+            .await
+            .unwrap();
+        expect_server_message(
+            &mut legacy,
+            |m| matches!(m, ServerMessage::UserJoined { name } if name == "Dave"),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_server_url_construction() {
+        let address = "127.0.0.1";
+        let port = 8080;
+        let server_url = format!("http://{}:{}", address, port);
+
+        assert_eq!(server_url, "http://127.0.0.1:8080");
+
+        let messages_url = format!("{}/messages", server_url);
+        assert_eq!(messages_url, "http://127.0.0.1:8080/messages");
+
+        let room_url = format!("{}/room/1", server_url);
+        assert_eq!(room_url, "http://127.0.0.1:8080/room/1");
+    }
+
+    #[tokio::test]
+    async fn test_user_id_generation() {
+        let user_id1 = uuid::Uuid::new_v4().to_string();
+        let user_id2 = uuid::Uuid::new_v4().to_string();
+
+        // Verify UUIDs are unique
+        assert_ne!(user_id1, user_id2);
+
+        // Verify UUID format (should contain hyphens)
+        assert!(user_id1.contains('-'));
+        assert!(user_id2.contains('-'));
+
+        // Verify user name generation
+        let name1 = format!("User_{}", user_id1.split('-').next().unwrap());
+        let name2 = format!("User_{}", user_id2.split('-').next().unwrap());
+
+        assert!(name1.starts_with("User_"));
+        assert!(name2.starts_with("User_"));
+        assert_ne!(name1, name2);
+    }
+
+    #[tokio::test]
+    async fn test_instant_timing() {
+        let start = Instant::now();
+        sleep(Duration::from_millis(10)).await;
+        let duration = start.elapsed();
+
+        // Verify that some time has passed
+        assert!(duration.as_millis() >= 5);
+        assert!(duration.as_millis() <= 50); // Allow some margin for timing variations
+    }
+
+    #[tokio::test]
+    async fn test_server_start_and_stop() {
+        let address = "127.0.0.1";
+        let port = 12346; // Use different port to avoid conflicts
+        let server_url = format!("http://{}:{}", address, port);
+
+        // Start server in background
+        let server_handle = tokio::spawn(async move {
+            let clients = Arc::new(Mutex::new(HashMap::new()));
+            let users = Arc::new(Mutex::new(HashMap::new()));
+            let app_state = AppState {
+                clients,
+                users,
+                ..AppState::new()
+            };
+
+            let socket_addr: SocketAddr = format!("{}:{}", address, port).parse().unwrap();
+            let listener = tokio::net::TcpListener::bind(socket_addr).await.unwrap();
+
+            let app = Router::new()
+                .route("/room/{room}", get(handle_websocket).post(handle_post))
+                .route("/messages", get(handle_get))
+                .with_state(app_state);
+
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap();
+        });
+
+        // Give server time to start
+        sleep(Duration::from_millis(100)).await;
+
+        // Test that server is responding
+        let client = reqwest::Client::new();
+        let response = client.get(format!("{}/messages", server_url)).send().await;
+
+        assert!(response.is_ok());
+        assert!(response.unwrap().status().is_success());
+
+        // Stop server by aborting the task
+        server_handle.abort();
+
+        // Give server time to stop
+        sleep(Duration::from_millis(100)).await;
+
+        // Test that server is no longer responding
+        let response = client.get(format!("{}/messages", server_url)).send().await;
+
+        assert!(response.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_server_with_multiple_clients() {
+        let address = "127.0.0.1";
+        let port = 12347; // Use different port
+        let server_url = format!("http://{}:{}", address, port);
+
+        // Start server in background
+        let server_handle = tokio::spawn(async move {
+            let clients = Arc::new(Mutex::new(HashMap::new()));
+            let users = Arc::new(Mutex::new(HashMap::new()));
+            let app_state = AppState {
+                clients,
+                users,
+                ..AppState::new()
+            };
+
+            let socket_addr: SocketAddr = format!("{}:{}", address, port).parse().unwrap();
+            let listener = tokio::net::TcpListener::bind(socket_addr).await.unwrap();
+
+            let app = Router::new()
+                .route("/room/{room}", get(handle_websocket).post(handle_post))
+                .route("/messages", get(handle_get))
+                .with_state(app_state);
+
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap();
+        });
+
+        // Give server time to start
+        sleep(Duration::from_millis(100)).await;
+
+        let client = reqwest::Client::new();
+
+        // Test multiple client connections via HTTP endpoints
+        for i in 0..3 {
+            let message = Message::new(format!("Client {} message", i));
+
+            let response = client
+                .post(format!("{}/room/1", server_url))
+                .json(&message)
+                .send()
+                .await;
+
+            assert!(response.is_ok());
+            assert!(response.unwrap().status().is_success());
+        }
+
+        // Verify messages were stored
+        let response = client
+            .get(format!("{}/messages", server_url))
+            .send()
+            .await
+            .unwrap();
+
+        assert!(response.status().is_success());
+        let content = response.text().await.unwrap();
+        assert!(content.contains("Client 0 message"));
+        assert!(content.contains("Client 1 message"));
+        assert!(content.contains("Client 2 message"));
+
+        // Stop server
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_rename_updates_user_and_rejects_blank_names() {
+        let state = test_state();
+        let addr = spawn_test_server(state.clone()).await;
+        let mut ws = connect_test_client(addr, "Alice").await;
+        expect_server_message(&mut ws, |m| matches!(m, ServerMessage::UserJoined { .. })).await;
+
+        send_client_message(
+            &mut ws,
+            &ClientMessage::Rename {
+                new_name: "   ".to_string(),
+            },
+        )
+        .await;
+        expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Error { .. })).await;
+
+        send_client_message(
+            &mut ws,
+            &ClientMessage::Rename {
+                new_name: "Bob".to_string(),
+            },
+        )
+        .await;
+        match expect_server_message(&mut ws, |m| matches!(m, ServerMessage::UserRenamed { .. }))
+            .await
+        {
+            ServerMessage::UserRenamed { old, new } => {
+                assert_eq!(old, "Alice");
+                assert_eq!(new, "Bob");
+            }
+            _ => unreachable!(),
+        }
+
+        let names: Vec<String> = state
+            .users
+            .lock()
+            .unwrap()
+            .values()
+            .map(|u| u.name.clone())
+            .collect();
+        assert_eq!(names, vec!["Bob".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_rename_is_checked_like_connecting() {
+        let state = AppState::with_config(ServerConfig {
+            admins: vec!["root".to_string()],
+            rate_limit_per_sec: 2,
+            ..ServerConfig::default()
+        });
+        state.bans.lock().unwrap().insert(
+            "mallory".to_string(),
+            Instant::now() + Duration::from_secs(60),
+        );
+        let addr = spawn_test_server(state.clone()).await;
+        let mut ws = connect_test_client(addr, "Alice").await;
+        expect_server_message(&mut ws, |m| matches!(m, ServerMessage::UserJoined { .. })).await;
+
+        let long_name = "x".repeat(MAX_NAME_LEN + 1);
+        for (new_name, expected) in [("Root", 403), ("Mallory", 403), (long_name.as_str(), 422)] {
+            send_client_message(
+                &mut ws,
+                &ClientMessage::Rename {
+                    new_name: new_name.to_string(),
+                },
+            )
+            .await;
+            match expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Error { .. })).await
+            {
+                ServerMessage::Error { code, .. } => assert_eq!(code, expected, "{}", new_name),
+                _ => unreachable!(),
+            }
+        }
+
+        // Control characters are stripped as from any other text
+        send_client_message(
+            &mut ws,
+            &ClientMessage::Rename {
+                new_name: "Bo\u{7}b".to_string(),
+            },
+        )
+        .await;
+        match expect_server_message(&mut ws, |m| matches!(m, ServerMessage::UserRenamed { .. }))
+            .await
+        {
+            ServerMessage::UserRenamed { new, .. } => assert_eq!(new, "Bob"),
+            _ => unreachable!(),
+        }
+
+        // Renames draw on the same allowance as messages
+        for new_name in ["Carol", "Dave"] {
+            send_client_message(
+                &mut ws,
+                &ClientMessage::Rename {
+                    new_name: new_name.to_string(),
+                },
+            )
+            .await;
+        }
+        match expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Error { .. })).await {
+            ServerMessage::Error { code, .. } => assert_eq!(code, 429),
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_names_get_a_suffix() {
+        let state = test_state();
+        let addr = spawn_test_server(state.clone()).await;
+        let mut first = connect_test_client(addr, "Alice").await;
+        expect_server_message(&mut first, |m| matches!(m, ServerMessage::Welcome { .. })).await;
+
+        // Names clash regardless of case; each newcomer is told what it got
+        let mut others = Vec::new();
+        for expected in ["alice_2", "ALICE_3"] {
+            let requested = expected.split('_').next().unwrap();
+            let mut ws = connect_test_client(addr, requested).await;
+            match expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Welcome { .. }))
+                .await
+            {
+                ServerMessage::Welcome { name, .. } => assert_eq!(name, expected),
+                _ => unreachable!(),
+            }
+            expect_server_message(
+                &mut first,
+                |m| matches!(m, ServerMessage::UserJoined { name } if name == expected),
+            )
+            .await;
+            others.push(ws);
+        }
+
+        // Renaming into a taken name is refused
+        send_client_message(
+            &mut first,
+            &ClientMessage::Rename {
+                new_name: "Alice_2".to_string(),
+            },
+        )
+        .await;
+        match expect_server_message(&mut first, |m| matches!(m, ServerMessage::Error { .. })).await
+        {
+            ServerMessage::Error { code, .. } => assert_eq!(code, 409),
+            _ => unreachable!(),
+        }
+
+        let mut names: Vec<String> = state
+            .users
+            .lock()
+            .unwrap()
+            .values()
+            .map(|u| u.name.clone())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["ALICE_3", "Alice", "alice_2"]);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_names_rejected_by_policy() {
+        let state = AppState::with_config(ServerConfig {
+            duplicate_names: DuplicateNamePolicy::Reject,
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let mut first = connect_test_client(addr, "Alice").await;
+        expect_server_message(&mut first, |m| matches!(m, ServerMessage::Welcome { .. })).await;
+
+        let mut second = connect_test_client(addr, "alice").await;
+        match expect_server_message(&mut second, |m| matches!(m, ServerMessage::Error { .. })).await
+        {
+            ServerMessage::Error { code, .. } => assert_eq!(code, 409),
+            _ => unreachable!(),
+        }
+        let frame = second.next().await;
+        assert!(matches!(
+            frame,
+            None | Some(Ok(WsMessage::Close(_))) | Some(Err(_))
+        ));
+        assert_eq!(state.users.lock().unwrap().len(), 1);
+
+        assert_eq!(
+            "reject".parse::<DuplicateNamePolicy>(),
+            Ok(DuplicateNamePolicy::Reject)
+        );
+        assert!("rename".parse::<DuplicateNamePolicy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_admin_purge_removes_only_target_messages() {
+        let state = AppState::with_config(ServerConfig {
+            admin_token: Some("secret".to_string()),
+            ..ServerConfig::default()
+        });
+        store_message(&state, DEFAULT_ROOM, Message::chat_message("Alice", "one"));
+        store_message(&state, DEFAULT_ROOM, Message::chat_message("Bob", "two"));
+        store_message(
+            &state,
+            DEFAULT_ROOM,
+            Message::chat_message("Alice", "three"),
+        );
+        state
+            .rooms
+            .lock()
+            .unwrap()
+            .get_mut(DEFAULT_ROOM)
+            .unwrap()
+            .pinned = vec![3, 2];
+        let addr = spawn_test_server(state.clone()).await;
+        let mut watcher = connect_test_client(addr, "Carol").await;
+        expect_server_message(&mut watcher, |m| {
+            matches!(m, ServerMessage::UserJoined { .. })
+        })
+        .await;
+        let client = reqwest::Client::new();
+        let purge_url = format!("http://{}/admin/purge", addr);
+
+        // Requests without the admin token are rejected
+        let response = client
+            .post(&purge_url)
+            .json(&serde_json::json!({ "name": "Alice" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(default_room_messages(&state).len(), 3);
+
+        let response = client
+            .post(&purge_url)
+            .bearer_auth("secret")
+            .json(&serde_json::json!({ "name": "Alice" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["removed"], 2);
+
+        // Clients hear of each message as if it were deleted on its own
+        expect_server_message(&mut watcher, |m| {
+            matches!(m, ServerMessage::MessageUnpinned { id: 3 })
+        })
+        .await;
+        for expected in [1, 3] {
+            expect_server_message(
+                &mut watcher,
+                |m| matches!(m, ServerMessage::MessageDeleted { id } if *id == expected),
+            )
+            .await;
+        }
+        expect_server_message(&mut watcher, |m| {
+            matches!(m, ServerMessage::MessagesPurged { count: 2, .. })
+        })
+        .await;
+
+        let messages = default_room_messages(&state);
+        let texts: Vec<&str> = messages.iter().map(|m| m.text.as_str()).collect();
+        assert_eq!(texts, vec![DELETED_PLACEHOLDER, "two", DELETED_PLACEHOLDER]);
+        assert!(messages[0].deleted && !messages[1].deleted && messages[2].deleted);
+        assert_eq!(state.rooms.lock().unwrap()[DEFAULT_ROOM].pinned, vec![2]);
+    }
+
+    #[tokio::test]
+    async fn test_direct_message_reaches_only_recipient() {
+        let addr = spawn_test_server(AppState::new()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+        let mut bob = connect_test_client(addr, "Bob").await;
+        let mut carol = connect_test_client(addr, "Carol").await;
+        expect_server_message(
+            &mut alice,
+            |m| matches!(m, ServerMessage::UserJoined { name } if name == "Carol"),
+        )
+        .await;
+
+        send_client_message(
+            &mut alice,
+            &ClientMessage::DirectMessage {
+                to: "Bob".to_string(),
+                text: "psst".to_string(),
+            },
+        )
+        .await;
+        send_client_message(
+            &mut alice,
+            &ClientMessage::Chat {
+                text: "hello all".to_string(),
+                client_msg_id: None,
+            },
+        )
+        .await;
+
+        let is_dm_or_chat = |m: &ServerMessage| {
+            matches!(
+                m,
+                ServerMessage::DirectMessage { .. } | ServerMessage::Chat { .. }
+            )
+        };
+        match expect_server_message(&mut bob, is_dm_or_chat).await {
+            ServerMessage::DirectMessage { from, to, text } => {
+                assert_eq!(from, "Alice");
+                assert_eq!(to, "Bob");
+                assert_eq!(text, "psst");
+            }
+            other => panic!("Expected DM, got {:?}", other),
+        }
+        assert!(matches!(
+            expect_server_message(&mut alice, is_dm_or_chat).await,
+            ServerMessage::DirectMessage { .. }
+        ));
+        // Carol's first message of either kind is the public chat, not the DM
+        assert!(matches!(
+            expect_server_message(&mut carol, is_dm_or_chat).await,
+            ServerMessage::Chat { .. }
+        ));
+
+        send_client_message(
+            &mut alice,
+            &ClientMessage::DirectMessage {
+                to: "Nobody".to_string(),
+                text: "hi".to_string(),
+            },
+        )
+        .await;
+        expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Error { .. })).await;
+    }
+
+    #[tokio::test]
+    async fn test_malformed_frame_gets_error_reply() {
+        let addr = spawn_test_server(AppState::new()).await;
+        let mut ws = connect_test_client(addr, "Alice").await;
+
+        let garbage = format!("not json {}", "x".repeat(200));
+        ws.send(WsMessage::Text(garbage.into())).await.unwrap();
+
+        match expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Error { .. })).await {
+            ServerMessage::Error { code, message } => {
+                assert_eq!(code, 400);
+                assert!(message.contains("not json"));
+                // 100 chars of input plus the prefix and ellipsis
+                assert!(message.chars().count() < 130);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frames_over_the_size_limit_end_the_connection() {
+        let state = AppState::with_config(ServerConfig {
+            max_message_len: 10,
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let mut ws = connect_test_client(addr, "Alice").await;
+        expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Welcome { .. })).await;
+
+        let oversized = ClientMessage::Chat {
+            text: "x".repeat(max_frame_size(&state.config)),
+            client_msg_id: None,
+        };
+        send_client_message(&mut ws, &oversized).await;
+        match expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Error { .. })).await {
+            ServerMessage::Error { code, .. } => assert_eq!(code, 413),
+            _ => unreachable!(),
+        }
+        // Then the server closes the connection
+        let closed = tokio::time::timeout(Duration::from_secs(2), async {
+            while let Some(Ok(frame)) = ws.next().await {
+                if frame.is_close() {
+                    return true;
+                }
+            }
+            false
+        })
+        .await
+        .unwrap();
+        assert!(closed);
+        assert!(default_room_messages(&state).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_binary_frame_gets_error_reply() {
+        let addr = spawn_test_server(AppState::new()).await;
+        let mut ws = connect_test_client(addr, "Alice").await;
+
+        ws.send(WsMessage::Binary(vec![0xff, 0x00, 0xfe].into()))
+            .await
+            .unwrap();
+
+        match expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Error { .. })).await {
+            ServerMessage::Error { code, .. } => assert_eq!(code, 415),
+            _ => unreachable!(),
+        }
+
+        // The connection stays usable afterwards
+        send_client_message(
+            &mut ws,
+            &ClientMessage::Chat {
+                text: "still here".to_string(),
+                client_msg_id: None,
+            },
+        )
+        .await;
+        expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Chat { .. })).await;
+    }
+
+    #[tokio::test]
+    async fn test_client_that_never_reads_stays_bounded() {
+        let state = AppState::new();
+        let (drop_tx, mut drop_rx) = outbound::channel(4, OverflowPolicy::DropOldest);
+        let (cut_tx, mut cut_rx) = outbound::channel(4, OverflowPolicy::Disconnect);
+        for (id, name, tx) in [("u1", "Alice", drop_tx), ("u2", "Bob", cut_tx)] {
+            state
+                .clients
+                .lock()
+                .unwrap()
+                .insert(id.to_string(), ClientHandle::new(tx));
+            state
+                .users
+                .lock()
+                .unwrap()
+                .insert(id.to_string(), User::new(name.to_string(), DEFAULT_ROOM));
+        }
+
+        // Neither client drains its queue while the room keeps talking
+        for i in 0..1000 {
+            broadcast_raw(&state, DEFAULT_ROOM, &Message::new(i.to_string()));
+        }
+
+        let mut kept = Vec::new();
+        while let Some(message) = drop_rx.try_recv() {
+            kept.push(message.text);
+        }
+        assert_eq!(kept, vec!["996", "997", "998", "999"]);
+        assert!(cut_rx.is_overflowed());
+        assert!(cut_rx.try_recv().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_client_that_stops_reading_is_disconnected_as_too_slow() {
+        let state = AppState::with_config(ServerConfig {
+            outbound_capacity: 8,
+            overflow_policy: OverflowPolicy::Disconnect,
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+        expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Welcome { .. })).await;
+        let mut bob = connect_test_client(addr, "Bob").await;
+        expect_server_message(&mut bob, |m| matches!(m, ServerMessage::Welcome { .. })).await;
+
+        // Both stop reading while far more than the socket buffers can hold
+        // is sent their way
+        let text = "x".repeat(64 * 1024);
+        for _ in 0..512 {
+            broadcast_raw(&state, DEFAULT_ROOM, &Message::new(text.clone()));
+            tokio::task::yield_now().await;
+        }
+
+        // Bob catches up in time to learn why he was dropped
+        match expect_server_message(&mut bob, |m| matches!(m, ServerMessage::Error { .. })).await {
+            ServerMessage::Error { code, message } => {
+                assert_eq!(code, 503);
+                assert_eq!(message, "Disconnected: too slow");
+            }
+            _ => unreachable!(),
+        }
+        let frame = bob.next().await;
+        assert!(matches!(
+            frame,
+            None | Some(Ok(WsMessage::Close(_))) | Some(Err(_))
+        ));
+
+        // Alice never reads again, and is dropped all the same
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !state.users.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("Stalled client was never disconnected");
+        drop(alice);
+    }
+
+    #[tokio::test]
+    async fn test_mentions_are_extracted_and_broadcast() {
+        let state = AppState::new();
+        for name in ["Ann", "Ann Lee", "Bob"] {
+            let user = User::new(name.to_string(), DEFAULT_ROOM);
+            state.users.lock().unwrap().insert(user.id.clone(), user);
+        }
+        assert_eq!(
+            extract_mentions(
+                &state,
+                "hey @ann lee and @BOB, not @bobby; @Ann too, @bob again"
+            ),
+            vec!["Ann Lee", "Bob", "Ann"]
+        );
+        assert!(extract_mentions(&state, "mail ann@example.com or Bob").is_empty());
+
+        let state = AppState::new();
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+        let mut bob = connect_test_client(addr, "Bob").await;
+        expect_server_message(&mut bob, |m| matches!(m, ServerMessage::Welcome { .. })).await;
+        send_client_message(
+            &mut alice,
+            &ClientMessage::Chat {
+                text: "@bob lunch?".to_string(),
+                client_msg_id: None,
+            },
+        )
+        .await;
+        match expect_server_message(&mut bob, |m| matches!(m, ServerMessage::Chat { .. })).await {
+            ServerMessage::Chat { mentions, .. } => assert_eq!(mentions, vec!["Bob"]),
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_with_no_clients_is_a_no_op() {
+        let state = AppState::new();
+
+        let delivered = broadcast_server_message(
+            &state,
+            DEFAULT_ROOM,
+            &ServerMessage::Chat {
+                text: "anyone there?".to_string(),
+                sender: None,
+                id: None,
+                reply_to: None,
+                quote: None,
+                mentions: Vec::new(),
+                timestamp: None,
+                signature: None,
+            },
+        )
+        .await;
+        assert_eq!(delivered, 0);
+
+        // Messages posted with nobody listening are still kept in history
+        let response = handle_post(
+            Path(DEFAULT_ROOM.to_string()),
+            State(state.clone()),
+            ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))),
+            HeaderMap::new(),
+            Json(PostRequest {
+                text: "hi".to_string(),
+                sender: None,
+            }),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(default_room_messages(&state).len(), 1);
+
+        let (tx, _rx) = outbound::channel(8, OverflowPolicy::Disconnect);
+        state
+            .clients
+            .lock()
+            .unwrap()
+            .insert("u1".to_string(), ClientHandle::new(tx));
+        state.users.lock().unwrap().insert(
+            "u1".to_string(),
+            User::new("Alice".to_string(), DEFAULT_ROOM),
+        );
+        let delivered = broadcast_server_message(
+            &state,
+            DEFAULT_ROOM,
+            &ServerMessage::Chat {
+                text: "hello".to_string(),
+                sender: None,
+                id: None,
+                reply_to: None,
+                quote: None,
+                mentions: Vec::new(),
+                timestamp: None,
+                signature: None,
+            },
+        )
+        .await;
+        assert_eq!(delivered, 1);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_messages_are_rejected() {
+        let state = AppState::new();
+        let addr = spawn_test_server(state.clone()).await;
+        let long_text = "世".repeat(5000);
+
+        let response = reqwest::Client::new()
+            .post(format!("http://{}/room/1", addr))
+            .json(&Message::new(long_text.clone()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let mut ws = connect_test_client(addr, "Alice").await;
+        send_client_message(
+            &mut ws,
+            &ClientMessage::Chat {
+                text: long_text,
+                client_msg_id: None,
+            },
+        )
+        .await;
+        match expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Error { .. })).await {
+            ServerMessage::Error { code, .. } => assert_eq!(code, 413),
+            _ => unreachable!(),
+        }
+        assert!(default_room_messages(&state).is_empty());
+
+        // 4096 characters is still allowed even though it's more bytes than that
+        send_client_message(
+            &mut ws,
+            &ClientMessage::Chat {
+                text: "世".repeat(4096),
+                client_msg_id: None,
+            },
+        )
+        .await;
+        expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Chat { .. })).await;
+    }
+
+    #[tokio::test]
+    async fn test_actions_are_broadcast_and_limited_like_chat() {
+        let state = AppState::with_config(ServerConfig {
+            rate_limit_per_sec: 2,
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+        let mut bob = connect_test_client(addr, "Bob").await;
+
+        send_client_message(
+            &mut alice,
+            &ClientMessage::Action {
+                text: "waves".to_string(),
+            },
+        )
+        .await;
+        match expect_server_message(&mut bob, |m| matches!(m, ServerMessage::Action { .. })).await {
+            ServerMessage::Action { name, text } => {
+                assert_eq!(name, "Alice");
+                assert_eq!(text, "waves");
+            }
+            _ => unreachable!(),
+        }
+        let texts: Vec<String> = default_room_messages(&state)
+            .into_iter()
+            .map(|m| m.display_text())
+            .collect();
+        assert_eq!(texts, vec!["* Alice waves"]);
+
+        send_client_message(
+            &mut alice,
+            &ClientMessage::Action {
+                text: "世".repeat(5000),
+            },
+        )
+        .await;
+        match expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Error { .. })).await
+        {
+            ServerMessage::Error { code, .. } => assert_eq!(code, 413),
+            _ => unreachable!(),
+        }
+
+        for _ in 0..3 {
+            send_client_message(
+                &mut alice,
+                &ClientMessage::Action {
+                    text: "dances".to_string(),
+                },
+            )
+            .await;
+        }
+        match expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Error { .. })).await
+        {
+            ServerMessage::Error { code, .. } => assert_eq!(code, 429),
+            _ => unreachable!(),
+        }
+        assert!(default_room_messages(&state).len() < 4);
+    }
+
+    #[tokio::test]
+    async fn test_ping_is_answered_with_its_nonce() {
+        let state = test_state();
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+        let mut bob = connect_test_client(addr, "Bob").await;
+        expect_server_message(&mut bob, |m| matches!(m, ServerMessage::Welcome { .. })).await;
+
+        send_client_message(&mut alice, &ClientMessage::Ping { nonce: 42 }).await;
+        expect_server_message(&mut alice, |m| {
+            matches!(m, ServerMessage::Pong { nonce: 42 })
+        })
+        .await;
+
+        // Only the sender hears the pong, and nothing is stored
+        send_client_message(
+            &mut bob,
+            &ClientMessage::Chat {
+                text: "after".to_string(),
+                client_msg_id: None,
+            },
+        )
+        .await;
+        expect_server_message(&mut bob, |m| {
+            assert!(!matches!(m, ServerMessage::Pong { .. }));
+            matches!(m, ServerMessage::Ack { .. })
+        })
+        .await;
+        let texts: Vec<String> = default_room_messages(&state)
+            .into_iter()
+            .map(|msg| msg.text)
+            .collect();
+        assert_eq!(texts, vec!["after"]);
+    }
+
+    #[tokio::test]
+    async fn test_status_changes_are_broadcast_and_idle_users_go_away() {
+        let state = AppState::with_config(ServerConfig {
+            // Idleness is checked on each keepalive tick
+            keepalive_interval: Duration::from_millis(300),
+            away_after: Duration::from_millis(100),
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+        let mut bob = connect_test_client(addr, "Bob").await;
+        expect_server_message(&mut bob, |m| matches!(m, ServerMessage::Welcome { .. })).await;
+        let status_of = |name: &str| {
+            let users = state.users.lock().unwrap();
+            users.values().find(|u| u.name == name).unwrap().status
+        };
+        assert_eq!(status_of("Bob"), UserStatus::Online);
+
+        send_client_message(
+            &mut bob,
+            &ClientMessage::SetStatus {
+                status: UserStatus::Busy,
+            },
+        )
+        .await;
+        expect_server_message(&mut alice, |m| {
+            matches!(m, ServerMessage::StatusChanged { name, status: UserStatus::Busy } if name == "Bob")
+        })
+        .await;
+        match expect_server_message(&mut alice, |m| matches!(m, ServerMessage::UserList(_))).await {
+            ServerMessage::UserList(list) => {
+                let bob_info = list.users.iter().find(|u| u.name == "Bob").unwrap();
+                assert_eq!(bob_info.status, UserStatus::Busy);
+            }
+            _ => unreachable!(),
+        }
+
+        // Saying nothing for a while marks Alice away, but leaves Bob busy
+        expect_server_message(&mut bob, |m| {
+            matches!(m, ServerMessage::StatusChanged { name, status: UserStatus::Away } if name == "Alice")
+        })
+        .await;
+        assert_eq!(status_of("Bob"), UserStatus::Busy);
+
+        // Her next message brings her back
+        send_client_message(
+            &mut alice,
+            &ClientMessage::Chat {
+                text: "sorry, was making tea".to_string(),
+                client_msg_id: None,
+            },
+        )
+        .await;
+        expect_server_message(&mut bob, |m| {
+            matches!(m, ServerMessage::StatusChanged { name, status: UserStatus::Online } if name == "Alice")
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_status_changes_are_rate_limited_and_repeats_are_quiet() {
+        let state = AppState::with_config(ServerConfig {
+            rate_limit_per_sec: 3,
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+        let mut bob = connect_test_client(addr, "Bob").await;
+        expect_server_message(&mut bob, |m| matches!(m, ServerMessage::Welcome { .. })).await;
+
+        for status in [
+            UserStatus::Busy,
+            UserStatus::Busy,
+            UserStatus::Away,
+            UserStatus::Online,
+        ] {
+            send_client_message(&mut bob, &ClientMessage::SetStatus { status }).await;
+        }
+        match expect_server_message(&mut bob, |m| matches!(m, ServerMessage::Error { .. })).await {
+            ServerMessage::Error { code, .. } => assert_eq!(code, 429),
+            _ => unreachable!(),
+        }
+
+        // The repeated status isn't announced again
+        let mut announced = Vec::new();
+        for _ in 0..2 {
+            match expect_server_message(&mut alice, |m| {
+                matches!(m, ServerMessage::StatusChanged { .. })
+            })
+            .await
+            {
+                ServerMessage::StatusChanged { status, .. } => announced.push(status),
+                _ => unreachable!(),
+            }
+        }
+        assert_eq!(announced, vec![UserStatus::Busy, UserStatus::Away]);
+        let users = state.users.lock().unwrap();
+        let bob_status = users.values().find(|u| u.name == "Bob").unwrap().status;
+        assert_eq!(bob_status, UserStatus::Away);
+    }
+
+    #[tokio::test]
+    async fn test_reactions_and_edits_are_rate_limited() {
+        let state = AppState::with_config(ServerConfig {
+            rate_limit_per_sec: 2,
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+        send_client_message(
+            &mut alice,
+            &ClientMessage::Chat {
+                text: "vote here".to_string(),
+                client_msg_id: None,
+            },
+        )
+        .await;
+        let id = match expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Ack { .. }))
+            .await
+        {
+            ServerMessage::Ack { id, .. } => id,
+            _ => unreachable!(),
+        };
+
+        // Toggling a reaction in a loop runs out of tokens like chatting does
+        let react = ClientMessage::React {
+            message_id: id,
+            emoji: "👍".to_string(),
+        };
+        for _ in 0..3 {
+            send_client_message(&mut alice, &react).await;
+        }
+        send_client_message(
+            &mut alice,
+            &ClientMessage::Edit {
+                id,
+                text: "vote here!".to_string(),
+            },
+        )
+        .await;
+        for _ in 0..3 {
+            match expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Error { .. }))
+                .await
+            {
+                ServerMessage::Error { code, .. } => assert_eq!(code, 429),
+                _ => unreachable!(),
+            }
+        }
+        let messages = default_room_messages(&state);
+        assert_eq!(messages[0].text, "vote here");
+        assert_eq!(messages[0].reactions["👍"], vec!["Alice"]);
+    }
+
+    #[tokio::test]
+    async fn test_rooms_can_be_created_and_listed() {
+        let state = AppState::with_config(ServerConfig {
+            admin_token: Some("secret".to_string()),
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let client = reqwest::Client::new();
+        let rooms_url = format!("http://{}/rooms", addr);
+
+        let response = client
+            .post(&rooms_url)
+            .json(&serde_json::json!({ "name": "random" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        for (request, expected) in [
+            (serde_json::json!({ "name": "random" }), StatusCode::CREATED),
+            (
+                serde_json::json!({ "name": "ops", "password": "hunter2" }),
+                StatusCode::CREATED,
+            ),
+            (
+                serde_json::json!({ "name": "random" }),
+                StatusCode::CONFLICT,
+            ),
+            (
+                serde_json::json!({ "name": DEFAULT_ROOM }),
+                StatusCode::CONFLICT,
+            ),
+            (serde_json::json!({ "name": "  " }), StatusCode::BAD_REQUEST),
+        ] {
+            let response = client
+                .post(&rooms_url)
+                .bearer_auth("secret")
+                .json(&request)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected, "creating {}", request);
+        }
+
+        let mut ws = connect_test_client_to_room(addr, "random", "Alice").await;
+        expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Welcome { .. })).await;
+        send_client_message(
+            &mut ws,
+            &ClientMessage::Chat {
+                text: "hi".to_string(),
+                client_msg_id: None,
+            },
+        )
+        .await;
+        expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Chat { .. })).await;
+
+        let rooms: Vec<RoomInfo> = client
+            .get(&rooms_url)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let summary: Vec<(&str, usize, usize, bool)> = rooms
+            .iter()
+            .map(|room| {
+                (
+                    room.id.as_str(),
+                    room.users,
+                    room.messages,
+                    room.password_protected,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (DEFAULT_ROOM, 0, 0, false),
+                ("ops", 0, 0, true),
+                ("random", 1, 1, false),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_idle_connection_is_warned_then_removed() {
+        let state = AppState::with_config(ServerConfig {
+            idle_timeout: Duration::from_millis(400),
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+
+        let mut ws = connect_test_client(addr, "Alice").await;
+        expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Welcome { .. })).await;
+        expect_server_message(&mut ws, |m| matches!(m, ServerMessage::IdleWarning { .. })).await;
+        expect_server_message(&mut ws, |m| {
+            matches!(m, ServerMessage::Error { code: 408, .. })
+        })
+        .await;
+
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while !state.users.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("idle user was not removed");
+    }
+
+    #[tokio::test]
+    async fn test_ephemeral_room_expires_and_notifies_members() {
+        let state = AppState::with_config(ServerConfig {
+            admin_token: Some("secret".to_string()),
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let client = reqwest::Client::new();
+        let create_url = format!("http://{}/rooms/ephemeral", addr);
+        let request = serde_json::json!({ "name": "standup", "lifetime_secs": 1 });
+
+        let response = client
+            .post(&create_url)
+            .bearer_auth("secret")
+            .json(&request)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = client
+            .post(&create_url)
+            .bearer_auth("secret")
+            .json(&request)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let mut ws = connect_test_client_to_room(addr, "standup", "Alice").await;
+        expect_server_message(&mut ws, |m| matches!(m, ServerMessage::UserJoined { .. })).await;
+        assert!(state.rooms.lock().unwrap().contains_key("standup"));
+
+        match expect_server_message(&mut ws, |m| matches!(m, ServerMessage::RoomClosed { .. }))
+            .await
+        {
+            ServerMessage::RoomClosed { room, .. } => assert_eq!(room, "standup"),
+            _ => unreachable!(),
+        }
+        assert!(!state.rooms.lock().unwrap().contains_key("standup"));
+        assert!(state.rooms.lock().unwrap().contains_key(DEFAULT_ROOM));
+
+        // The member is disconnected and removed once the room closes
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while let Some(Ok(frame)) = ws.next().await {
+                if matches!(frame, WsMessage::Close(_)) {
+                    break;
+                }
+            }
+        })
+        .await
+        .expect("Connection was not closed");
+        sleep(Duration::from_millis(50)).await;
+        assert!(state.users.lock().unwrap().is_empty());
+
+        let response = client
+            .post(format!("http://{}/room/standup", addr))
+            .json(&Message::new("late".to_string()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_expiry_spares_a_room_reopened_under_the_same_name() {
+        let state = AppState::with_config(ServerConfig {
+            admin_token: Some("secret".to_string()),
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let client = reqwest::Client::new();
+        let response = client
+            .post(format!("http://{}/rooms/ephemeral", addr))
+            .bearer_auth("secret")
+            .json(&serde_json::json!({ "name": "standup", "lifetime_secs": 1 }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        // The ephemeral room goes early and a lasting one takes its name
+        state.rooms.lock().unwrap().remove("standup");
+        let response = client
+            .post(format!("http://{}/rooms", addr))
+            .bearer_auth("secret")
+            .json(&serde_json::json!({ "name": "standup" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        sleep(Duration::from_millis(1200)).await;
+        assert!(state.rooms.lock().unwrap().contains_key("standup"));
+    }
+
+    #[tokio::test]
+    async fn test_admin_user_list_reports_message_count() {
+        let state = AppState::with_config(ServerConfig {
+            admin_token: Some("secret".to_string()),
+            record_ips: true,
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+        let _bob = connect_test_client(addr, "Bob").await;
+
+        for i in 0..3 {
+            send_client_message(
+                &mut alice,
+                &ClientMessage::Chat {
+                    text: format!("message {}", i),
+                    client_msg_id: None,
+                },
+            )
+            .await;
+        }
+        expect_server_message(
+            &mut alice,
+            |m| matches!(m, ServerMessage::Chat { text, .. } if text == "message 2"),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+        let users_url = format!("http://{}/admin/users", addr);
+        let response = client.get(&users_url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let list: AdminUserList = client
+            .get(&users_url)
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(list.count, 2);
+        let alice_info = list.users.iter().find(|u| u.name == "Alice").unwrap();
+        let bob_info = list.users.iter().find(|u| u.name == "Bob").unwrap();
+        assert_eq!(alice_info.message_count, 3);
+        assert_eq!(bob_info.message_count, 0);
+        assert_eq!(alice_info.room, DEFAULT_ROOM);
+        assert_eq!(alice_info.ip.as_deref(), Some("127.0.0.1"));
+        assert_eq!(alice_info.status, UserStatus::Online);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_senders_all_get_through() {
+        const CLIENTS: usize = 8;
+        const MESSAGES: usize = 25;
+        let state = AppState::with_config(ServerConfig {
+            rate_limit_per_sec: 1000,
+            outbound_capacity: 1024,
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let mut sockets = Vec::new();
+        for i in 0..CLIENTS {
+            let mut ws = connect_test_client(addr, &format!("User{}", i)).await;
+            expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Welcome { .. })).await;
+            sockets.push(ws);
+        }
+
+        // Everyone sends at once while receiving everyone else's messages
+        let tasks: Vec<_> = sockets
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut ws)| {
+                tokio::spawn(async move {
+                    for n in 0..MESSAGES {
+                        send_client_message(
+                            &mut ws,
+                            &ClientMessage::Chat {
+                                text: format!("{} from {}", n, i),
+                                client_msg_id: None,
+                            },
+                        )
+                        .await;
+                    }
+                    for _ in 0..CLIENTS * MESSAGES {
+                        expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Chat { .. }))
+                            .await;
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let ids: Vec<u64> = default_room_messages(&state)
+            .iter()
+            .filter_map(|msg| msg.id)
+            .collect();
+        assert_eq!(ids, (1..=(CLIENTS * MESSAGES) as u64).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_flooding_client_is_rate_limited() {
+        let state = AppState::with_config(ServerConfig {
+            rate_limit_per_sec: 5,
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+
+        for i in 0..10 {
+            send_client_message(
+                &mut alice,
+                &ClientMessage::Chat {
+                    text: format!("spam {}", i),
+                    client_msg_id: None,
+                },
+            )
+            .await;
+        }
+
+        let mut broadcast = 0;
+        let mut rejected = 0;
+        while broadcast + rejected < 10 {
+            match expect_server_message(&mut alice, |m| {
+                matches!(m, ServerMessage::Chat { .. } | ServerMessage::Error { .. })
+            })
+            .await
+            {
+                ServerMessage::Chat { .. } => broadcast += 1,
+                ServerMessage::Error { code, message } => {
+                    assert_eq!(code, 429);
+                    assert!(message.contains("Slow down"));
+                    rejected += 1;
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        assert_eq!(broadcast, 5);
+        assert_eq!(rejected, 5);
+        assert_eq!(default_room_messages(&state).len(), 5);
+        // The client is throttled, not disconnected
+        assert_eq!(state.users.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_admin_user_list_reports_client_version() {
+        let state = AppState::with_config(ServerConfig {
+            admin_token: Some("secret".to_string()),
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let mut current = connect_test_client(addr, "Current").await;
+
+        // A client predating version reporting only sends its name
+        let (mut legacy, _) = tokio_tungstenite::connect_async(format!("ws://{}/room/1", addr))
+            .await
+            .unwrap();
+        legacy
+            .send(WsMessage::Text(
+                r#"{"type":"Connect","name":"Legacy"}"#.into(),
+            ))
+            .await
+            .unwrap();
+        expect_server_message(
+            &mut current,
+            |m| matches!(m, ServerMessage::UserJoined { name } if name == "Legacy"),
+        )
+        .await;
+
+        let list: AdminUserList = reqwest::Client::new()
+            .get(format!("http://{}/admin/users", addr))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let current_info = list.users.iter().find(|u| u.name == "Current").unwrap();
+        assert_eq!(
+            current_info.client_version.as_deref(),
+            Some(env!("CARGO_PKG_VERSION"))
+        );
+        assert_eq!(
+            current_info.protocol_version,
+            Some(crate::shared::PROTOCOL_VERSION)
+        );
+        let legacy_info = list.users.iter().find(|u| u.name == "Legacy").unwrap();
+        assert!(legacy_info.client_version.is_none());
+        assert!(legacy_info.protocol_version.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_notifies_clients_and_flushes_history() {
+        let path =
+            std::env::temp_dir().join(format!("chat-shutdown-{}.jsonl", uuid::Uuid::new_v4()));
+        let policy = FlushPolicy {
+            max_pending: 100,
+            interval: Duration::from_secs(3600),
+        };
+        let state = AppState {
+            storage: Some(Arc::new(Mutex::new(MessageStore::new(&path, policy)))),
+            ..AppState::new()
+        };
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+        send_client_message(
+            &mut alice,
+            &ClientMessage::Chat {
+                text: "last words".to_string(),
+                client_msg_id: None,
+            },
+        )
+        .await;
+        expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Chat { .. })).await;
+
+        begin_shutdown(&state);
+        expect_server_message(&mut alice, |m| matches!(m, ServerMessage::ServerShutdown)).await;
+        finish_shutdown(&state).await;
+
+        assert!(state.clients.lock().unwrap().is_empty());
+        let stored = MessageStore::new(&path, policy).load().unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].text, "last words");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_json_history_supports_limit_and_before() {
+        let state = AppState::new();
+        for i in 0..5 {
+            store_message(
+                &state,
+                DEFAULT_ROOM,
+                Message::chat_message("Alice", &format!("msg {}", i)),
+            );
+        }
+        let addr = spawn_test_server(state).await;
+        let client = reqwest::Client::new();
+        let url = format!("http://{}/messages/json", addr);
+
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = response.json().await.unwrap();
+        let all = body.as_array().unwrap();
+        assert_eq!(all.len(), 5);
+        assert_eq!(all[0]["text"], "msg 0");
+        assert_eq!(all[0]["sender"], "Alice");
+
+        let response = client.get(format!("{}?limit=2", url)).send().await.unwrap();
+        assert_eq!(response.headers()[HISTORY_START_HEADER], "3");
+        let page: Vec<Message> = response.json().await.unwrap();
+        let texts: Vec<&str> = page.iter().map(|m| m.text.as_str()).collect();
+        assert_eq!(texts, vec!["msg 3", "msg 4"]);
+
+        // Scroll back from the start of the previous page
+        let page: Vec<Message> = client
+            .get(format!("{}?limit=2&before=3", url))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let texts: Vec<&str> = page.iter().map(|m| m.text.as_str()).collect();
+        assert_eq!(texts, vec!["msg 1", "msg 2"]);
+
+        // The plain-text endpoint is unchanged
+        let text = client
+            .get(format!("http://{}/messages", addr))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(text.lines().count(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_messages_can_be_prefixed_with_timestamps() {
+        let state = AppState::new();
+        store_message(&state, DEFAULT_ROOM, Message::chat_message("Alice", "hi"));
+        store_message(&state, DEFAULT_ROOM, Message::chat_message("Bob", "hey"));
+        state
+            .rooms
+            .lock()
+            .unwrap()
+            .get_mut(DEFAULT_ROOM)
+            .unwrap()
+            .messages[0]
+            .timestamp = Some(1_714_555_800);
+        let addr = spawn_test_server(state.clone()).await;
+        let client = reqwest::Client::new();
+
+        let text = client
+            .get(format!("http://{}/messages?timestamps=true", addr))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "2024-05-01T09:30:00Z Alice: hi");
+        // Messages are stamped when stored
+        let (stamp, rest) = lines[1].split_once(' ').unwrap();
+        assert_eq!(stamp.len(), "2024-05-01T09:30:00Z".len());
+        assert!(stamp.ends_with('Z'));
+        assert_eq!(rest, "Bob: hey");
+
+        // Without the parameter nothing changes
+        let text = client
+            .get(format!("http://{}/messages", addr))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(text, "Alice: hi\nBob: hey\n");
+
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(951_782_400), "2000-02-29T00:00:00Z");
+    }
+
+    #[tokio::test]
+    async fn test_room_users_endpoint_lists_room_members() {
+        let state = AppState::new();
+        for name in ["Alice", "Bob"] {
+            let user = User::new(name.to_string(), DEFAULT_ROOM);
+            state.users.lock().unwrap().insert(user.id.clone(), user);
+        }
+        let addr = spawn_test_server(state).await;
+        let client = reqwest::Client::new();
+
+        let response = client
+            .get(format!("http://{}/room/1/users", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let list: UserList = response.json().await.unwrap();
+        assert_eq!(list.count, 2);
+        let mut names: Vec<&str> = list.users.iter().map(|u| u.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["Alice", "Bob"]);
+        assert!(list.users.iter().all(|u| u.connected_secs < 60));
+
+        // Connection times count from when the user connected
+        let user = User::new("Carol".to_string(), DEFAULT_ROOM);
+        let later = user.connected_at + Duration::from_secs(120);
+        assert_eq!(SerializableUser::at(&user, later).connected_secs, 120);
+
+        let response = client
+            .get(format!("http://{}/room/nowhere/users", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_last_seen_is_tracked_and_persisted() {
+        let path =
+            std::env::temp_dir().join(format!("chat-lastseen-{}.jsonl", uuid::Uuid::new_v4()));
+        let state = AppState {
+            storage: Some(Arc::new(Mutex::new(MessageStore::new(
+                &path,
+                FlushPolicy::default(),
+            )))),
+            ..AppState::new()
+        };
+        let addr = spawn_test_server(state.clone()).await;
+        let last_seen = |name: &str| {
+            let url = format!("http://{}/users/{}/lastseen", addr, name);
+            async move { reqwest::get(url).await.unwrap() }
+        };
+
+        let mut alice = connect_test_client(addr, "Alice").await;
+        let response = last_seen("Alice").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let seen: LastSeen = response.json().await.unwrap();
+        assert!(seen.online);
+        assert_eq!(seen.last_seen, None);
+        assert_eq!(last_seen("Bob").await.status(), StatusCode::NOT_FOUND);
+
+        let before = unix_time();
+        send_client_message(
+            &mut alice,
+            &ClientMessage::Chat {
+                text: "hi".to_string(),
+                client_msg_id: None,
+            },
+        )
+        .await;
+        expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Ack { .. })).await;
+        let seen: LastSeen = last_seen("Alice").await.json().await.unwrap();
+        assert!(seen.online);
+        assert!(seen.last_seen.unwrap() >= before);
+
+        alice.close(None).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while !state.users.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let seen: LastSeen = last_seen("Alice").await.json().await.unwrap();
+        assert!(!seen.online);
+        assert!(seen.last_seen.unwrap() >= before);
+
+        // Written out on disconnect, so it survives a restart, by appending
+        // to a log rather than rewriting every saved time
+        let store = MessageStore::new(&path, FlushPolicy::default());
+        assert_eq!(
+            store.load_last_seen().unwrap().get("Alice").copied(),
+            seen.last_seen
+        );
+        assert!(!path.with_extension("lastseen.json").exists());
+        std::fs::remove_file(path.with_extension("lastseen.jsonl")).unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_last_seen_forgets_the_longest_unseen_names() {
+        let mut last_seen: HashMap<String, u64> =
+            (0..10).map(|i| (format!("user{}", i), 100 + i)).collect();
+        forget_oldest_seen(&mut last_seen, 10);
+        assert_eq!(last_seen.len(), 10);
+
+        last_seen.insert("newcomer".to_string(), 500);
+        forget_oldest_seen(&mut last_seen, 10);
+        let mut kept: Vec<&str> = last_seen.keys().map(String::as_str).collect();
+        kept.sort_unstable();
+        assert_eq!(
+            kept,
+            vec![
+                "newcomer", "user2", "user3", "user4", "user5", "user6", "user7", "user8", "user9"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connection_limits() {
+        let state = AppState::with_config(ServerConfig {
+            max_connections: 3,
+            max_connections_per_ip: 2,
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let url = format!("ws://{}/room/{}", addr, DEFAULT_ROOM);
+        let expect_rejected = |code: u16| {
+            let url = url.clone();
+            async move {
+                let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+                match expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Error { .. }))
+                    .await
+                {
+                    ServerMessage::Error { code: got, .. } => assert_eq!(got, code),
+                    other => panic!("Expected a connection limit error, got {:?}", other),
+                }
+                let frame = ws.next().await;
+                assert!(matches!(
+                    frame,
+                    None | Some(Ok(WsMessage::Close(_))) | Some(Err(_))
+                ));
+            }
+        };
+
+        // Every test connection comes from 127.0.0.1
+        let mut alice = connect_test_client(addr, "Alice").await;
+        let _bob = connect_test_client(addr, "Bob").await;
+        expect_server_message(
+            &mut alice,
+            |m| matches!(m, ServerMessage::UserJoined { name } if name == "Bob"),
+        )
+        .await;
+        expect_rejected(429).await;
+        assert_eq!(state.users.lock().unwrap().len(), 2);
+
+        // A closed connection frees its place
+        alice.close(None).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while state.connections.lock().unwrap().values().sum::<usize>() > 1 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let mut carol = connect_test_client(addr, "Carol").await;
+        expect_server_message(&mut carol, |m| matches!(m, ServerMessage::UserList(_))).await;
+
+        // The global limit holds whichever address connects
+        state
+            .connections
+            .lock()
+            .unwrap()
+            .insert(IpAddr::from([10, 0, 0, 1]), 1);
+        expect_rejected(503).await;
+    }
+
+    #[tokio::test]
+    async fn test_room_stream_sends_events() {
+        let state = AppState::new();
+        let addr = spawn_test_server(state.clone()).await;
+        assert_eq!(
+            reqwest::get(format!("http://{}/room/nope/stream", addr))
+                .await
+                .unwrap()
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+
+        let mut response = reqwest::get(format!("http://{}/room/{}/stream", addr, DEFAULT_ROOM))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["content-type"].to_str().unwrap(),
+            "text/event-stream"
+        );
+        assert_eq!(state.streams.lock().unwrap().len(), 1);
+
+        let mut alice = connect_test_client(addr, "Alice").await;
+        send_client_message(
+            &mut alice,
+            &ClientMessage::Chat {
+                text: "over sse".to_string(),
+                client_msg_id: None,
+            },
+        )
+        .await;
+
+        // Skip keep-alives and the join notices until the chat arrives
+        let mut buffer = String::new();
+        let text = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                let chunk = response.chunk().await.unwrap().unwrap();
+                buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+                while let Some(end) = buffer.find("\n\n") {
+                    let event: String = buffer.drain(..end + 2).collect();
+                    let Some(data) = event.lines().find_map(|l| l.strip_prefix("data: ")) else {
+                        continue;
+                    };
+                    if let ServerMessage::Chat { text, .. } = serde_json::from_str(data).unwrap() {
+                        return text;
+                    }
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(text, "over sse");
+
+        // A client that goes away is unsubscribed
+        drop(response);
+        tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                // Fan-out notices the closed connection on the next write
+                broadcast_server_message(&state, DEFAULT_ROOM, &ServerMessage::Pong { nonce: 0 })
+                    .await;
+                if state.streams.lock().unwrap().is_empty() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_admin_can_list_and_terminate_connections() {
+        let state = AppState::with_config(ServerConfig {
+            admin_token: Some("secret".to_string()),
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+        let mut bob = connect_test_client(addr, "Bob").await;
+        expect_server_message(
+            &mut alice,
+            |m| matches!(m, ServerMessage::UserJoined { name } if name == "Bob"),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+        let connections: Vec<ConnectionInfo> = client
+            .get(format!("http://{}/admin/connections", addr))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(connections.len(), 2);
+        let bob_id = &connections.iter().find(|c| c.name == "Bob").unwrap().id;
+
+        let terminate_url = format!("http://{}/admin/connections/{}", addr, bob_id);
+        let response = client.delete(&terminate_url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = client
+            .delete(&terminate_url)
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        expect_server_message(
+            &mut alice,
+            |m| matches!(m, ServerMessage::UserLeft { name } if name == "Bob"),
+        )
+        .await;
+        let remaining: Vec<String> = state
+            .users
+            .lock()
+            .unwrap()
+            .values()
+            .map(|u| u.name.clone())
+            .collect();
+        assert_eq!(remaining, vec!["Alice".to_string()]);
+        assert!(!state.clients.lock().unwrap().contains_key(bob_id));
+        let _ = bob.close(None).await;
+
+        let response = client
+            .delete(&terminate_url)
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_unresponsive_client_is_dropped_by_keepalive() {
+        let state = AppState::with_config(ServerConfig {
+            keepalive_interval: Duration::from_millis(100),
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+        // Ghost never reads, so it never answers pings
+        let _ghost = connect_test_client(addr, "Ghost").await;
+        expect_server_message(
+            &mut alice,
+            |m| matches!(m, ServerMessage::UserJoined { name } if name == "Ghost"),
+        )
+        .await;
+
+        expect_server_message(
+            &mut alice,
+            |m| matches!(m, ServerMessage::UserLeft { name } if name == "Ghost"),
+        )
+        .await;
+        let names: Vec<String> = state
+            .users
+            .lock()
+            .unwrap()
+            .values()
+            .map(|u| u.name.clone())
+            .collect();
+        // Alice kept answering pings while reading, so she stays connected
+        assert_eq!(names, vec!["Alice".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_hook_posts_as_the_given_user() {
+        let state = AppState::with_config(ServerConfig {
+            hook_tokens: HashMap::from([(DEFAULT_ROOM.to_string(), "h00k".to_string())]),
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let mut ws = connect_test_client(addr, "Alice").await;
+        expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Welcome { .. })).await;
+
+        let client = reqwest::Client::new();
+        let hook_url = format!("http://{}/hooks/{}", addr, DEFAULT_ROOM);
+        let body = serde_json::json!({ "username": "deploy-bot", "text": "v1.2 is live" });
+
+        let response = client.post(&hook_url).json(&body).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = client
+            .post(&hook_url)
+            .query(&[("token", "wrong")])
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        // Rooms without a hook token have no webhook at all
+        let response = client
+            .post(format!("http://{}/hooks/other", addr))
+            .query(&[("token", "h00k")])
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = client
+            .post(&hook_url)
+            .header(HOOK_TOKEN_HEADER, "h00k")
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        match expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Chat { .. })).await {
+            ServerMessage::Chat { text, sender, .. } => {
+                assert_eq!(text, "v1.2 is live");
+                assert_eq!(sender.as_deref(), Some("deploy-bot"));
+            }
+            _ => unreachable!(),
+        }
+        assert_eq!(
+            default_room_messages(&state)[0].sender.as_deref(),
+            Some("deploy-bot")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_announcement_reaches_every_room() {
+        let state = AppState::with_config(ServerConfig {
+            admin_token: Some("secret".to_string()),
+            ..ServerConfig::default()
+        });
+        state
+            .rooms
+            .lock()
+            .unwrap()
+            .insert("standup".to_string(), RoomState::default());
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+        let mut bob = connect_test_client_to_room(addr, "standup", "Bob").await;
+        expect_server_message(&mut bob, |m| matches!(m, ServerMessage::UserJoined { .. })).await;
+
+        let client = reqwest::Client::new();
+        let announce_url = format!("http://{}/admin/announce", addr);
+        let request = serde_json::json!({ "text": "Restarting in 5 minutes" });
+        let response = client
+            .post(&announce_url)
+            .json(&request)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = client
+            .post(&announce_url)
+            .bearer_auth("secret")
+            .json(&request)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        for ws in [&mut alice, &mut bob] {
+            expect_server_message(ws, |m| {
+                matches!(m, ServerMessage::Announcement { text } if text == "Restarting in 5 minutes")
+            })
+            .await;
+        }
+        assert_eq!(
+            default_room_messages(&state)[0].sender.as_deref(),
+            Some("SERVER")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_health_reports_uptime_rooms_and_users() {
+        let state = test_state();
+        let addr = spawn_test_server(state.clone()).await;
+        let mut ws = connect_test_client(addr, "Alice").await;
+        expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Welcome { .. })).await;
+
+        // `/health` is an alias of `/healthz`
+        for path in ["healthz", "health"] {
+            let response = reqwest::get(format!("http://{}/{}", addr, path))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let health: serde_json::Value = response.json().await.unwrap();
+            assert_eq!(health["status"], "ok");
+            assert_eq!(health["server_name"], DEFAULT_SERVER_NAME);
+            assert!(health["uptime_secs"].is_u64());
+            assert_eq!(health["rooms"], 1);
+            assert_eq!(health["connected_users"], 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_post_sets_message_fields_on_the_server() {
+        let state = AppState::with_config(ServerConfig {
+            admins: vec!["root".to_string()],
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let _alice = connect_test_client(addr, "Alice").await;
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while state.users.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let client = reqwest::Client::new();
+        let post = |body: serde_json::Value| {
+            client
+                .post(format!("http://{}/room/{}", addr, DEFAULT_ROOM))
+                .json(&body)
+                .send()
+        };
+
+        let before = unix_time();
+        let response = post(serde_json::json!({
+            "text": "hi @Alice",
+            "sender": " ci\u{1b}[31m ",
+            "id": 99,
+            "timestamp": 1,
+            "deleted": true,
+            "reactions": { "👍": ["Alice"] },
+            "reply_to": 1,
+            "mentions": ["Bob"],
+            "signature": "00",
+        }))
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let stored = default_room_messages(&state).pop().unwrap();
+        assert_eq!(stored.sender.as_deref(), Some("ci[31m"));
+        assert_eq!(stored.id, Some(1));
+        assert!(stored.timestamp.unwrap() >= before);
+        assert!(!stored.deleted);
+        assert!(stored.reactions.is_empty());
+        assert_eq!(stored.reply_to, None);
+        assert_eq!(stored.mentions, vec!["Alice".to_string()]);
+        assert_eq!(stored.signature, None);
+
+        // Nobody can post as the server, an admin or a connected user
+        for sender in ["SERVER", "Root", "alice"] {
+            let response = post(serde_json::json!({ "text": "hi", "sender": sender }))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", sender);
+        }
+        let response = post(serde_json::json!({ "text": "hi", "sender": "x".repeat(65) }))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(default_room_messages(&state).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_post_records_fanout_timing() {
+        let state = AppState::with_config(ServerConfig {
+            metrics_endpoint: true,
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        assert_eq!(state.metrics.fanout().count, 0);
+
+        let response = reqwest::Client::new()
+            .post(format!("http://{}/room/{}", addr, DEFAULT_ROOM))
+            .json(&Message::new("hello".to_string()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(state.metrics.fanout().count, 1);
+
+        let stats: ServerStats = reqwest::get(format!("http://{}/stats", addr))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(stats.fanout.count, 1);
+
+        let metrics = reqwest::get(format!("http://{}/metrics", addr))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(metrics.contains("chat_fanout_seconds_count 1"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint_counts_messages_and_users() {
+        // Off unless enabled
+        let addr = spawn_test_server(test_state()).await;
+        let response = reqwest::get(format!("http://{}/metrics", addr))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let state = AppState::with_config(ServerConfig {
+            metrics_endpoint: true,
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let scrape = || async move {
+            reqwest::get(format!("http://{}/metrics", addr))
+                .await
+                .unwrap()
+                .text()
+                .await
+                .unwrap()
+        };
+
+        let mut alice = connect_test_client(addr, "Alice").await;
+        let mut bob = connect_test_client(addr, "Bob").await;
+        expect_server_message(
+            &mut alice,
+            |m| matches!(m, ServerMessage::UserJoined { name } if name == "Bob"),
+        )
+        .await;
+        send_client_message(
+            &mut alice,
+            &ClientMessage::Chat {
+                text: "hello".to_string(),
+                client_msg_id: None,
+            },
+        )
+        .await;
+        expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Ack { .. })).await;
+
+        let text = scrape().await;
+        for name in [
+            "chat_messages_received_total",
+            "chat_messages_broadcast_total",
+            "chat_connected_users",
+            "chat_rooms",
+        ] {
+            assert!(text.contains(&format!("# TYPE {} ", name)), "{}", name);
+        }
+        assert!(text.contains("chat_messages_received_total 1\n"));
+        assert!(text.contains("chat_messages_broadcast_total 1\n"));
+        assert!(text.contains("chat_connected_users 2\n"));
+        assert!(text.contains("chat_rooms 1\n"));
+
+        // The gauge drops once the departed client is cleaned up
+        bob.close(None).await.unwrap();
+        expect_server_message(
+            &mut alice,
+            |m| matches!(m, ServerMessage::UserLeft { name } if name == "Bob"),
+        )
+        .await;
+        assert!(scrape().await.contains("chat_connected_users 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_version_endpoint_reports_server_name() {
+        let state = AppState::with_config(ServerConfig {
+            server_name: "hallway".to_string(),
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state).await;
+
+        let response = reqwest::get(format!("http://{}/version", addr))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let info: ServerInfo = response.json().await.unwrap();
+        assert_eq!(info.server_name, "hallway");
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.protocol_version, crate::shared::PROTOCOL_VERSION);
+
+        let health: HealthStatus = reqwest::get(format!("http://{}/healthz", addr))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(health.status, "ok");
+        assert_eq!(health.server_name, "hallway");
+
+        // New connections are greeted with the name before anything else
+        let mut ws = connect_test_client(addr, "Alice").await;
+        let first = ws.next().await.unwrap().unwrap();
+        match serde_json::from_str::<ServerMessage>(first.to_text().unwrap()).unwrap() {
+            ServerMessage::Welcome {
+                server_name, motd, ..
+            } => {
+                assert_eq!(server_name, "hallway");
+                assert!(motd.contains("hallway"));
+            }
+            other => panic!("Expected a welcome, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_configured_history_limit_drops_oldest() {
+        let state = AppState::with_config(ServerConfig {
+            max_messages: 3,
+            ..ServerConfig::default()
+        });
+        for i in 1..=5 {
+            store_message(
+                &state,
+                DEFAULT_ROOM,
+                Message::chat_message("Alice", &format!("message {}", i)),
+            );
+        }
+
+        let messages = default_room_messages(&state);
+        let ids: Vec<u64> = messages.iter().filter_map(|msg| msg.id).collect();
+        assert_eq!(ids, vec![3, 4, 5]);
+        assert_eq!(messages[0].text, "message 3");
+
+        // Zero keeps everything below the hard ceiling
+        let mut history: VecDeque<Message> = (0..5).map(|i| Message::new(i.to_string())).collect();
+        trim_history(&mut history, 0);
+        assert_eq!(history.len(), 5);
+        trim_history(&mut history, 2);
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].text, "3");
+    }
+
+    #[tokio::test]
+    async fn test_fetch_history_pages_backwards() {
+        let state = AppState::with_config(ServerConfig {
+            max_messages: 10,
+            ..ServerConfig::default()
+        });
+        for i in 1..=15 {
+            store_message(
+                &state,
+                DEFAULT_ROOM,
+                Message::chat_message("Bob", &format!("message {}", i)),
+            );
+        }
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+        // Skip the backlog sent on joining
+        expect_server_message(&mut alice, |m| matches!(m, ServerMessage::History { .. })).await;
+
+        let mut fetch = async |before, limit| {
+            send_client_message(&mut alice, &ClientMessage::FetchHistory { before, limit }).await;
+            match expect_server_message(&mut alice, |m| matches!(m, ServerMessage::History { .. }))
+                .await
+            {
+                ServerMessage::History { messages } => messages
+                    .iter()
+                    .filter_map(|msg| msg.id)
+                    .collect::<Vec<u64>>(),
+                _ => unreachable!(),
+            }
+        };
+        assert_eq!(fetch(None, 3).await, vec![13, 14, 15]);
+        assert_eq!(fetch(Some(13), 3).await, vec![10, 11, 12]);
+        // Only what the room still holds can be fetched
+        assert_eq!(fetch(Some(10), 10).await, vec![6, 7, 8, 9]);
+        assert_eq!(fetch(Some(6), 3).await, Vec::<u64>::new());
+        assert_eq!(fetch(None, 1000).await.len(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_trimmed_history_is_announced_and_marked() {
+        let state = AppState::with_config(ServerConfig {
+            max_messages: 3,
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let messages_url = format!("http://{}/messages", addr);
+        let mut alice = connect_test_client(addr, "Alice").await;
+        expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Welcome { .. })).await;
+
+        for i in 1..=3 {
+            store_message(
+                &state,
+                DEFAULT_ROOM,
+                Message::chat_message("Bob", &format!("message {}", i)),
+            );
+        }
+        let body = reqwest::get(&messages_url)
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(!body.contains(HISTORY_OMITTED_SENTINEL));
+
+        // Going past the cap is announced once, not for every dropped message
+        for i in 4..=6 {
+            store_message(
+                &state,
+                DEFAULT_ROOM,
+                Message::chat_message("Bob", &format!("message {}", i)),
+            );
+        }
+        match expect_server_message(&mut alice, |m| {
+            matches!(m, ServerMessage::HistoryTrimmed { .. })
+        })
+        .await
+        {
+            ServerMessage::HistoryTrimmed { dropped } => assert_eq!(dropped, 1),
+            _ => unreachable!(),
+        }
+        send_client_message(
+            &mut alice,
+            &ClientMessage::Chat {
+                text: "last".to_string(),
+                client_msg_id: None,
+            },
+        )
+        .await;
+        let next = expect_server_message(&mut alice, |m| {
+            matches!(
+                m,
+                ServerMessage::HistoryTrimmed { .. } | ServerMessage::Ack { .. }
+            )
+        })
+        .await;
+        assert!(matches!(next, ServerMessage::Ack { .. }));
+        assert_eq!(state.rooms.lock().unwrap()[DEFAULT_ROOM].dropped, 4);
+
+        let body = reqwest::get(&messages_url)
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                HISTORY_OMITTED_SENTINEL,
+                "Bob: message 5",
+                "Bob: message 6",
+                "Alice: last"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_history_stays_capped_under_sustained_load() {
+        let state = test_state();
+        let total = DEFAULT_MAX_MESSAGES * 50;
+        for i in 1..=total {
+            store_message(&state, DEFAULT_ROOM, Message::new(format!("message {}", i)));
+        }
+
+        let messages = default_room_messages(&state);
+        assert_eq!(messages.len(), DEFAULT_MAX_MESSAGES);
+        assert_eq!(
+            messages.first().unwrap().id,
+            Some((total - DEFAULT_MAX_MESSAGES + 1) as u64)
+        );
+        assert_eq!(messages.last().unwrap().id, Some(total as u64));
+    }
+
+    #[tokio::test]
+    async fn test_history_replay_reports_trimmed_gap() {
+        let state = test_state();
+        // Overflow the history so ids 1..=5 are trimmed
+        for i in 1..=DEFAULT_MAX_MESSAGES + 5 {
+            store_message(
+                &state,
+                DEFAULT_ROOM,
+                Message::chat_message("Alice", &format!("message {}", i)),
+            );
+        }
+        let addr = spawn_test_server(state).await;
+        let mut ws = connect_test_client(addr, "Bob").await;
+
+        send_client_message(&mut ws, &ClientMessage::History { from: 3, to: 8 }).await;
+
+        match expect_server_message(&mut ws, |m| {
+            matches!(
+                m,
+                ServerMessage::HistoryGap { .. } | ServerMessage::Chat { .. }
+            )
+        })
+        .await
+        {
+            ServerMessage::HistoryGap { from, to } => assert_eq!((from, to), (3, 5)),
+            other => panic!("Expected a history gap first, got {:?}", other),
+        }
+        for expected in 6..=8 {
+            match expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Chat { .. })).await
+            {
+                ServerMessage::Chat {
+                    text, sender, id, ..
+                } => {
+                    assert_eq!(id, Some(expected));
+                    assert_eq!(sender.as_deref(), Some("Alice"));
+                    assert_eq!(text, format!("message {}", expected));
+                }
+                other => panic!("Expected a replayed message, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auth_token_required_to_join_and_post() {
+        let state = AppState::with_config(ServerConfig {
+            auth_token: Some("letmein".to_string()),
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let url = format!("ws://{}/room/{}", addr, DEFAULT_ROOM);
+
+        let (mut accepted, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let connect =
+            ClientMessage::connect("Alice".to_string(), Some("letmein".to_string()), None);
+        send_client_message(&mut accepted, &connect).await;
+        expect_server_message(&mut accepted, |m| matches!(m, ServerMessage::UserList(_))).await;
+
+        let (mut rejected, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let connect =
+            ClientMessage::connect("Mallory".to_string(), Some("guess".to_string()), None);
+        send_client_message(&mut rejected, &connect).await;
+        match expect_server_message(&mut rejected, |m| matches!(m, ServerMessage::Error { .. }))
+            .await
+        {
+            ServerMessage::Error { code, .. } => assert_eq!(code, 401),
+            other => panic!("Expected an auth error, got {:?}", other),
+        }
+        let frame = rejected.next().await;
+        assert!(matches!(
+            frame,
+            None | Some(Ok(WsMessage::Close(_))) | Some(Err(_))
+        ));
+        let names: Vec<String> = state
+            .users
+            .lock()
+            .unwrap()
+            .values()
+            .map(|user| user.name.clone())
+            .collect();
+        assert_eq!(names, vec!["Alice".to_string()]);
+
+        let client = reqwest::Client::new();
+        let post_url = format!("http://{}/room/{}", addr, DEFAULT_ROOM);
+        let body = Message::new("hello".to_string());
+        let response = client.post(&post_url).json(&body).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = client
+            .post(&post_url)
+            .bearer_auth("letmein")
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(default_room_messages(&state).len(), 1);
+
+        // Reading the room takes the token too
+        for path in [
+            "/messages".to_string(),
+            "/messages/json".to_string(),
+            format!("/room/{}/users", DEFAULT_ROOM),
+            format!("/room/{}/pins", DEFAULT_ROOM),
+        ] {
+            let url = format!("http://{}{}", addr, path);
+            let response = client.get(&url).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{}", path);
+            let response = client
+                .get(&url)
+                .bearer_auth("letmein")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_room_password_required_to_join_and_post() {
+        let state = AppState::with_config(ServerConfig {
+            admin_token: Some("secret".to_string()),
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let client = reqwest::Client::new();
+        let response = client
+            .post(format!("http://{}/rooms/ephemeral", addr))
+            .bearer_auth("secret")
+            .json(&serde_json::json!({
+                "name": "vault",
+                "lifetime_secs": 60,
+                "password": "open sesame",
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(!format!("{:?}", state.rooms.lock().unwrap()["vault"]).contains("sesame"));
+
+        // Connecting straight to the room: missing and wrong passwords are
+        // turned away and the socket closed
+        let url = format!("ws://{}/room/vault", addr);
+        for (password, expected) in [(None, 401), (Some("guess"), 403)] {
+            let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+            let connect =
+                ClientMessage::connect("Mallory".to_string(), None, password.map(str::to_string));
+            send_client_message(&mut ws, &connect).await;
+            match expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Error { .. })).await
+            {
+                ServerMessage::Error { code, .. } => assert_eq!(code, expected),
+                other => panic!("Expected a password error, got {:?}", other),
+            }
+            let frame = ws.next().await;
+            assert!(matches!(
+                frame,
+                None | Some(Ok(WsMessage::Close(_))) | Some(Err(_))
+            ));
+        }
+        let (mut alice, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let connect =
+            ClientMessage::connect("Alice".to_string(), None, Some("open sesame".to_string()));
+        send_client_message(&mut alice, &connect).await;
+        expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Welcome { .. })).await;
+
+        // Switching rooms and watching are gated the same way
+        let mut bob = connect_test_client(addr, "Bob").await;
+        expect_server_message(&mut bob, |m| matches!(m, ServerMessage::Welcome { .. })).await;
+        let subscribe = ClientMessage::Subscribe {
+            room: "vault".to_string(),
+        };
+        send_client_message(&mut bob, &subscribe).await;
+        expect_server_message(&mut bob, |m| {
+            matches!(m, ServerMessage::Error { code: 403, .. })
+        })
+        .await;
+        let join = |password: Option<&str>| ClientMessage::JoinRoom {
+            room: "vault".to_string(),
+            password: password.map(str::to_string),
+        };
+        send_client_message(&mut bob, &join(Some("guess"))).await;
+        expect_server_message(&mut bob, |m| {
+            matches!(m, ServerMessage::Error { code: 403, .. })
+        })
+        .await;
+        send_client_message(&mut bob, &join(Some("open sesame"))).await;
+        expect_server_message(
+            &mut bob,
+            |m| matches!(m, ServerMessage::Welcome { room, .. } if room == "vault"),
+        )
+        .await;
+
+        let post_url = format!("http://{}/room/vault", addr);
+        let body = Message::new("hello".to_string());
+        let response = client.post(&post_url).json(&body).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = client
+            .post(&post_url)
+            .header("x-room-password", "open sesame")
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_default_room_password_guards_history() {
+        let state = AppState::with_config(ServerConfig {
+            room_password: Some("open sesame".to_string()),
+            ..ServerConfig::default()
+        });
+        store_message(
+            &state,
+            DEFAULT_ROOM,
+            Message::chat_message("Alice", "secret plans"),
+        );
+        let addr = spawn_test_server(state.clone()).await;
+        let client = reqwest::Client::new();
+
+        for path in ["messages", "messages/json"] {
+            let url = format!("http://{}/{}", addr, path);
+            let response = client.get(&url).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{}", path);
+            let response = client
+                .get(&url)
+                .header("x-room-password", "guess")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", path);
+            let response = client
+                .get(&url)
+                .header("x-room-password", "open sesame")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", path);
+            assert!(response.text().await.unwrap().contains("secret plans"));
+        }
+    }
+
+    #[test]
+    fn test_pin_cap_rejects_new_pins() {
+        let state = AppState::with_config(ServerConfig {
+            max_pins: 2,
+            pin_overflow: PinOverflowPolicy::Reject,
+            ..ServerConfig::default()
+        });
+        for i in 1..=3 {
+            store_message(&state, DEFAULT_ROOM, Message::new(format!("message {}", i)));
+        }
+
+        assert_eq!(
+            pin_message(&state, DEFAULT_ROOM, 1).ok(),
+            Some(PinOutcome::Pinned { evicted: vec![] })
+        );
+        assert_eq!(
+            pin_message(&state, DEFAULT_ROOM, 2).ok(),
+            Some(PinOutcome::Pinned { evicted: vec![] })
+        );
+        // Pinning again changes nothing, even at the cap
+        assert_eq!(
+            pin_message(&state, DEFAULT_ROOM, 1).ok(),
+            Some(PinOutcome::AlreadyPinned)
+        );
+        assert!(matches!(
+            pin_message(&state, DEFAULT_ROOM, 3),
+            Err(ServerMessage::Error { code: 409, .. })
+        ));
+        assert_eq!(state.rooms.lock().unwrap()[DEFAULT_ROOM].pinned, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_pin_cap_evicts_oldest_pin() {
+        let state = AppState::with_config(ServerConfig {
+            max_pins: 2,
+            pin_overflow: PinOverflowPolicy::EvictOldest,
+            ..ServerConfig::default()
+        });
+        for i in 1..=3 {
+            store_message(&state, DEFAULT_ROOM, Message::new(format!("message {}", i)));
+        }
+
+        for id in [2, 1] {
+            assert_eq!(
+                pin_message(&state, DEFAULT_ROOM, id).ok(),
+                Some(PinOutcome::Pinned { evicted: vec![] })
+            );
+        }
+        // Message 2 was pinned first, so it gives way
+        assert_eq!(
+            pin_message(&state, DEFAULT_ROOM, 3).ok(),
+            Some(PinOutcome::Pinned { evicted: vec![2] })
+        );
+
+        // The list comes back in pin order
+        let addr = spawn_test_server(state.clone()).await;
+        let pinned: Vec<Message> =
+            reqwest::get(format!("http://{}/room/{}/pins", addr, DEFAULT_ROOM))
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+        let texts: Vec<&str> = pinned.iter().map(|msg| msg.text.as_str()).collect();
+        assert_eq!(texts, vec!["message 1", "message 3"]);
+    }
+
+    #[tokio::test]
+    async fn test_moderator_can_pin_and_member_cannot() {
+        let state = AppState::with_config(ServerConfig {
+            auth_token: Some("letmein".to_string()),
+            moderator_token: Some("modpass".to_string()),
+            ..ServerConfig::default()
+        });
+        let id = store_message(&state, DEFAULT_ROOM, Message::new("read me".to_string()))
+            .and_then(|msg| msg.id)
+            .unwrap();
+        let addr = spawn_test_server(state.clone()).await;
+        let url = format!("ws://{}/room/{}", addr, DEFAULT_ROOM);
+
+        let (mut member, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let connect =
+            ClientMessage::connect("Mallory".to_string(), Some("letmein".to_string()), None);
+        send_client_message(&mut member, &connect).await;
+        match expect_server_message(&mut member, |m| matches!(m, ServerMessage::Welcome { .. }))
+            .await
+        {
+            ServerMessage::Welcome { role, .. } => assert_eq!(role, Role::Member),
+            other => panic!("Expected a welcome, got {:?}", other),
+        }
+
+        // The server refuses regardless of what the client believes its role is
+        send_client_message(&mut member, &ClientMessage::Pin { id }).await;
+        match expect_server_message(&mut member, |m| matches!(m, ServerMessage::Error { .. })).await
+        {
+            ServerMessage::Error { code, .. } => assert_eq!(code, 403),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+        assert!(state.rooms.lock().unwrap()[DEFAULT_ROOM].pinned.is_empty());
+
+        let (mut moderator, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let connect =
+            ClientMessage::connect("Alice".to_string(), Some("modpass".to_string()), None);
+        send_client_message(&mut moderator, &connect).await;
+        match expect_server_message(&mut moderator, |m| {
+            matches!(m, ServerMessage::Welcome { .. })
+        })
+        .await
+        {
+            ServerMessage::Welcome { role, .. } => assert_eq!(role, Role::Moderator),
+            other => panic!("Expected a welcome, got {:?}", other),
+        }
+
+        send_client_message(&mut moderator, &ClientMessage::Pin { id }).await;
+        expect_server_message(&mut member, |m| {
+            matches!(m, ServerMessage::MessagePinned { id: pinned, by } if *pinned == id && by == "Alice")
+        })
+        .await;
+        assert_eq!(state.rooms.lock().unwrap()[DEFAULT_ROOM].pinned, vec![id]);
+
+        send_client_message(&mut moderator, &ClientMessage::Pin { id: id + 100 }).await;
+        match expect_server_message(&mut moderator, |m| matches!(m, ServerMessage::Error { .. }))
+            .await
+        {
+            ServerMessage::Error { code, .. } => assert_eq!(code, 404),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_token_matches_only_the_whole_token() {
+        assert!(token_matches(Some("s3cret"), "s3cret"));
+        assert!(!token_matches(Some("s3cre"), "s3cret"));
+        assert!(!token_matches(Some("s3cret!"), "s3cret"));
+        assert!(!token_matches(Some(""), "s3cret"));
+        assert!(!token_matches(None, "s3cret"));
+    }
+
+    #[test]
+    fn test_authenticate_assigns_roles() {
+        let open = ServerConfig {
+            admins: vec!["root".to_string()],
+            admin_token: Some("secret".to_string()),
+            ..ServerConfig::default()
+        };
+        assert_eq!(authenticate(&open, None).ok(), Some(Role::Guest));
+        assert_eq!(authenticate(&open, Some("guess")).ok(), Some(Role::Guest));
+        assert_eq!(authenticate(&open, Some("secret")).ok(), Some(Role::Admin));
+        // A listed name alone is not a credential, whatever its case
+        assert!(check_reserved_name(&open, "root", Role::Guest).is_err());
+        assert!(check_reserved_name(&open, "ROOT", Role::Moderator).is_err());
+        assert!(check_reserved_name(&open, "root", Role::Admin).is_ok());
+        assert!(check_reserved_name(&open, "rooted", Role::Guest).is_ok());
+
+        let closed = ServerConfig {
+            auth_token: Some("letmein".to_string()),
+            moderator_token: Some("modpass".to_string()),
+            admin_token: Some("secret".to_string()),
+            ..ServerConfig::default()
+        };
+        assert!(authenticate(&closed, None).is_err());
+        assert!(authenticate(&closed, Some("guess")).is_err());
+        assert_eq!(
+            authenticate(&closed, Some("letmein")).ok(),
+            Some(Role::Member)
+        );
+        assert_eq!(
+            authenticate(&closed, Some("modpass")).ok(),
+            Some(Role::Moderator)
+        );
+        assert_eq!(
+            authenticate(&closed, Some("secret")).ok(),
+            Some(Role::Admin)
+        );
+
+        assert!(Role::Admin.can(Permission::Kick));
+        assert!(Role::Moderator.can(Permission::Pin));
+        assert!(!Role::Member.can(Permission::Pin));
+        assert!(!Role::Guest.can(Permission::Kick));
+    }
+
+    #[tokio::test]
+    async fn test_role_follows_assigned_name() {
+        let state = AppState::with_config(ServerConfig {
+            admins: vec!["root_2".to_string()],
+            admin_token: Some("secret".to_string()),
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let mut first = connect_test_client(addr, "root").await;
+        match expect_server_message(&mut first, |m| matches!(m, ServerMessage::Welcome { .. }))
+            .await
+        {
+            ServerMessage::Welcome { role, .. } => assert_eq!(role, Role::Guest),
+            other => panic!("Expected a welcome, got {:?}", other),
+        }
+
+        // Asking for "root" again would be answered with "root_2", which is
+        // an admin's name, so it is refused rather than handed over
+        let mut second = connect_test_client(addr, "root").await;
+        match expect_server_message(&mut second, |m| matches!(m, ServerMessage::Error { .. })).await
+        {
+            ServerMessage::Error { code, .. } => assert_eq!(code, 403),
+            other => panic!("Expected a reserved name error, got {:?}", other),
+        }
+        assert_eq!(state.users.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_admin_kick_disconnects_and_bans() {
+        let state = AppState::with_config(ServerConfig {
+            admins: vec!["Alice".to_string()],
+            admin_token: Some("secret".to_string()),
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let url = format!("ws://{}/room/{}", addr, DEFAULT_ROOM);
+        let (mut alice, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let connect = ClientMessage::connect("Alice".to_string(), Some("secret".to_string()), None);
+        send_client_message(&mut alice, &connect).await;
+        let mut mallory = connect_test_client(addr, "Mallory").await;
+        expect_server_message(
+            &mut alice,
+            |m| matches!(m, ServerMessage::UserJoined { name } if name == "Mallory"),
+        )
+        .await;
+
+        // Non-admins can't kick
+        send_client_message(
+            &mut mallory,
+            &ClientMessage::Kick {
+                target: "Alice".to_string(),
+            },
+        )
+        .await;
+        match expect_server_message(&mut mallory, |m| matches!(m, ServerMessage::Error { .. }))
+            .await
+        {
+            ServerMessage::Error { code, .. } => assert_eq!(code, 403),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+
+        // Names match whatever the case
+        send_client_message(
+            &mut alice,
+            &ClientMessage::Kick {
+                target: "MALLORY".to_string(),
+            },
+        )
+        .await;
+        expect_server_message(
+            &mut mallory,
+            |m| matches!(m, ServerMessage::Error { message, .. } if message.contains("kicked")),
+        )
+        .await;
+        expect_server_message(
+            &mut alice,
+            |m| matches!(m, ServerMessage::UserLeft { name } if name == "Mallory"),
+        )
+        .await;
+
+        // The name can't rejoin during the cooldown, in any case
+        let mut again = connect_test_client(addr, "mallory").await;
+        match expect_server_message(&mut again, |m| matches!(m, ServerMessage::Error { .. })).await
+        {
+            ServerMessage::Error { code, .. } => assert_eq!(code, 403),
+            other => panic!("Expected a ban error, got {:?}", other),
+        }
+        let names: Vec<String> = state
+            .users
+            .lock()
+            .unwrap()
+            .values()
+            .map(|user| user.name.clone())
+            .collect();
+        assert_eq!(names, vec!["Alice".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_kicks_need_a_higher_role_than_the_target() {
+        let state = AppState::with_config(ServerConfig {
+            moderator_token: Some("modpass".to_string()),
+            admin_token: Some("secret".to_string()),
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let url = format!("ws://{}/room/{}", addr, DEFAULT_ROOM);
+        let mut sockets = Vec::new();
+        for (name, token) in [("Boss", "secret"), ("Mod", "modpass"), ("Other", "modpass")] {
+            let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+            let connect = ClientMessage::connect(name.to_string(), Some(token.to_string()), None);
+            send_client_message(&mut ws, &connect).await;
+            expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Welcome { .. })).await;
+            sockets.push(ws);
+        }
+        let [boss, moderator, other] = &mut sockets[..] else {
+            unreachable!()
+        };
+
+        for target in ["Other", "boss"] {
+            send_client_message(
+                moderator,
+                &ClientMessage::Kick {
+                    target: target.to_string(),
+                },
+            )
+            .await;
+            match expect_server_message(moderator, |m| matches!(m, ServerMessage::Error { .. }))
+                .await
+            {
+                ServerMessage::Error { code, .. } => assert_eq!(code, 403, "{}", target),
+                _ => unreachable!(),
+            }
+        }
+        assert!(state.bans.lock().unwrap().is_empty());
+
+        send_client_message(
+            boss,
+            &ClientMessage::Kick {
+                target: "other".to_string(),
+            },
+        )
+        .await;
+        expect_server_message(
+            other,
+            |m| matches!(m, ServerMessage::Error { message, .. } if message.contains("kicked by Boss")),
+        )
+        .await;
+        assert!(state.bans.lock().unwrap().contains_key("other"));
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_then_restore_reproduces_rooms() {
+        let path =
+            std::env::temp_dir().join(format!("chat-snapshot-{}.json", uuid::Uuid::new_v4()));
+        let state = AppState::with_config(ServerConfig {
+            admin_token: Some("secret".to_string()),
+            snapshot_path: Some(path.clone()),
+            ..ServerConfig::default()
+        });
+        state
+            .rooms
+            .lock()
+            .unwrap()
+            .insert("standup".to_string(), RoomState::default());
+        store_message(&state, DEFAULT_ROOM, Message::chat_message("Alice", "one"));
+        store_message(&state, DEFAULT_ROOM, Message::chat_message("Bob", "two"));
+        store_message(&state, "standup", Message::chat_message("Carol", "done"));
+        pin_message(&state, DEFAULT_ROOM, 1).unwrap();
+        state
+            .last_seen
+            .lock()
+            .unwrap()
+            .insert("Dave".to_string(), 1_700_000_000);
+        let addr = spawn_test_server(state.clone()).await;
+        let client = reqwest::Client::new();
 
-        let app_state = AppState {
-            messages: messages.clone(),
-            clients: clients.clone(),
-            users: users.clone(),
+        let response = client
+            .post(format!("http://{}/admin/snapshot", addr))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let summary: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(summary["rooms"], 2);
+        assert_eq!(summary["messages"], 3);
+
+        // Diverge from the snapshot
+        store_message(
+            &state,
+            DEFAULT_ROOM,
+            Message::chat_message("Mallory", "spam"),
+        );
+        state.rooms.lock().unwrap().remove("standup");
+        state
+            .rooms
+            .lock()
+            .unwrap()
+            .get_mut(DEFAULT_ROOM)
+            .unwrap()
+            .pinned
+            .clear();
+        state.last_seen.lock().unwrap().clear();
+
+        let response = client
+            .post(format!("http://{}/admin/restore", addr))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let texts = |room: &str| -> Vec<String> {
+            state.rooms.lock().unwrap()[room]
+                .messages
+                .iter()
+                .map(|msg| msg.text.clone())
+                .collect()
         };
+        assert_eq!(texts(DEFAULT_ROOM), vec!["one", "two"]);
+        assert_eq!(texts("standup"), vec!["done"]);
+        assert_eq!(state.rooms.lock().unwrap()[DEFAULT_ROOM].pinned, vec![1]);
+        assert_eq!(state.last_seen.lock().unwrap()["Dave"], 1_700_000_000);
+        // The discarded message's ID isn't reused
+        assert_eq!(state.rooms.lock().unwrap()[DEFAULT_ROOM].last_id, 3);
+        let next = store_message(&state, DEFAULT_ROOM, Message::new("four".to_string()));
+        assert_eq!(next.unwrap().id, Some(4));
 
-        // Add a message
-        let test_message = Message {
-            text: "Test message".to_string(),
+        let response = client
+            .post(format!("http://{}/admin/restore", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_file_urls_come_from_configuration() {
+        assert_eq!(file_url(&AppState::new(), "abc"), "/files/abc");
+        let state = AppState::with_config(ServerConfig {
+            public_url: Some("https://chat.example.com/".to_string()),
+            ..ServerConfig::default()
+        });
+        assert_eq!(
+            file_url(&state, "abc"),
+            "https://chat.example.com/files/abc"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serves_https_with_the_configured_certificate() {
+        let dir = std::env::temp_dir().join(format!("chat-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&cert, certified.cert.pem()).unwrap();
+        std::fs::write(&key, certified.signing_key.serialize_pem()).unwrap();
+        let tls_state = |cert: PathBuf| {
+            AppState::with_config(ServerConfig {
+                tls_cert: Some(cert),
+                tls_key: Some(key.clone()),
+                ..ServerConfig::default()
+            })
         };
 
-        {
-            let mut messages_guard = app_state.messages.lock().unwrap();
-            messages_guard.push(test_message.clone());
-        }
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(tls_state(cert), listener, std::future::pending()));
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        let response = client
+            .get(format!("https://{}/healthz", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        // Plain HTTP isn't answered on the same port
+        assert!(
+            client
+                .get(format!("http://{}/healthz", addr))
+                .send()
+                .await
+                .is_err()
+        );
 
-        // Verify message was stored
-        assert_eq!(app_state.messages.lock().unwrap().len(), 1);
-        assert_eq!(app_state.messages.lock().unwrap()[0].text, "Test message");
+        // A certificate that can't be loaded stops the server from starting
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let missing = serve(
+            tls_state(dir.join("missing.pem")),
+            listener,
+            std::future::pending(),
+        )
+        .await;
+        assert!(matches!(missing, Err(ChatError::ConfigError(_))));
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
     #[tokio::test]
-    async fn test_user_management() {
-        let messages = Arc::new(Mutex::new(Vec::new()));
-        let clients = Arc::new(Mutex::new(Vec::new()));
-        let users = Arc::new(Mutex::new(HashMap::new()));
+    async fn test_uploads_are_announced_and_served() {
+        let dir = std::env::temp_dir().join(format!("chat-uploads-{}", uuid::Uuid::new_v4()));
+        let state = AppState::with_config(ServerConfig {
+            upload_dir: Some(dir.clone()),
+            max_upload_size: 16,
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let mut ws = connect_test_client(addr, "Alice").await;
+        expect_server_message(&mut ws, |msg| matches!(msg, ServerMessage::Welcome { .. })).await;
+        let client = reqwest::Client::new();
+        let upload = |content_type: &'static str, body: &'static str| {
+            let file = reqwest::multipart::Part::text(body)
+                .file_name("../notes.txt")
+                .mime_str(content_type)
+                .unwrap();
+            client
+                .post(format!("http://{}/room/{}/upload", addr, DEFAULT_ROOM))
+                .multipart(reqwest::multipart::Form::new().part("file", file))
+                .send()
+        };
 
-        let app_state = AppState {
-            messages: messages.clone(),
-            clients: clients.clone(),
-            users: users.clone(),
+        let response = upload("text/plain; charset=utf-8", "hello").await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body: serde_json::Value = response.json().await.unwrap();
+        let url = body["url"].as_str().unwrap().to_string();
+        // Relative to the server, whatever Host the uploader sent
+        assert!(url.starts_with("/files/"), "{}", url);
+
+        let announced = expect_server_message(&mut ws, |msg| {
+            matches!(msg, ServerMessage::Attachment { .. })
+        })
+        .await;
+        let ServerMessage::Attachment {
+            name,
+            url: announced_url,
+            content_type,
+            size,
+        } = announced
+        else {
+            unreachable!()
         };
+        assert_eq!(name, "notes.txt");
+        assert_eq!(announced_url, url);
+        assert_eq!(content_type, "text/plain");
+        assert_eq!(size, 5);
 
-        // Add a user
-        let user_id = uuid::Uuid::new_v4().to_string();
-        let user = User {
-            id: user_id.clone(),
-            name: "TestUser".to_string(),
-            connected_at: Instant::now(),
+        // Later joiners find it in the history
+        let stored = default_room_messages(&state);
+        assert_eq!(
+            stored.last().unwrap().text,
+            attachment_line("notes.txt", &url, "text/plain", 5)
+        );
+
+        let response = client
+            .get(format!("http://{}{}", addr, url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/plain");
+        assert_eq!(response.text().await.unwrap(), "hello");
+
+        // Types off the allowlist and files over the limit are refused
+        let response = upload("text/html", "<script>").await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let response = upload("text/plain", "far more than sixteen bytes")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // A form without a file part is malformed
+        let response = client
+            .post(format!("http://{}/room/{}/upload", addr, DEFAULT_ROOM))
+            .multipart(reqwest::multipart::Form::new().text("name", "notes.txt"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(default_room_messages(&state).len(), 1);
+
+        let response = client
+            .get(format!("http://{}/files/{}", addr, "0".repeat(32)))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_export_then_import_round_trips() {
+        let state = AppState::with_config(ServerConfig {
+            admin_token: Some("secret".to_string()),
+            ..ServerConfig::default()
+        });
+        store_message(&state, DEFAULT_ROOM, Message::chat_message("Alice", "one"));
+        store_message(&state, DEFAULT_ROOM, Message::action("Bob", "waves"));
+        store_message(
+            &state,
+            DEFAULT_ROOM,
+            Message::chat_message("Carol", "three"),
+        );
+        let addr = spawn_test_server(state.clone()).await;
+        let client = reqwest::Client::new();
+
+        let response = client
+            .get(format!("http://{}/admin/export", addr))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "application/x-ndjson");
+        let exported = response.text().await.unwrap();
+        assert_eq!(exported.lines().count(), 3);
+
+        // Clear the room, then bring the history back from the export
+        *state.rooms.lock().unwrap().get_mut(DEFAULT_ROOM).unwrap() = RoomState::default();
+        let import = || {
+            client
+                .post(format!("http://{}/admin/import", addr))
+                .bearer_auth("secret")
+                .body(exported.clone())
+                .send()
         };
+        let response = import().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let summary: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(summary["imported"], 3);
+        assert_eq!(summary["skipped"], 0);
+
+        let reexported =
+            storage::to_json_lines(&state.rooms.lock().unwrap()[DEFAULT_ROOM].messages).unwrap();
+        assert_eq!(reexported, exported);
+        assert_eq!(state.rooms.lock().unwrap()[DEFAULT_ROOM].last_id, 3);
+
+        // Importing the same export again changes nothing
+        let summary: serde_json::Value = import().await.unwrap().json().await.unwrap();
+        assert_eq!(summary["imported"], 0);
+        assert_eq!(summary["skipped"], 3);
+        assert_eq!(state.rooms.lock().unwrap()[DEFAULT_ROOM].messages.len(), 3);
+
+        // Damaged input is rejected without touching the room
+        let response = client
+            .post(format!("http://{}/admin/import", addr))
+            .bearer_auth("secret")
+            .body("{\"text\":\"ok\"}\nnot json")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(state.rooms.lock().unwrap()[DEFAULT_ROOM].messages.len(), 3);
+
+        let response = client
+            .get(format!("http://{}/admin/export?room=nowhere", addr))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = client
+            .get(format!("http://{}/admin/export", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_control_characters_are_stripped() {
+        let state = test_state();
+        let addr = spawn_test_server(state.clone()).await;
+        let mut ws = connect_test_client(addr, "Alice").await;
+
+        send_client_message(
+            &mut ws,
+            &ClientMessage::Chat {
+                text: "evil\x1B[2J\u{9b}31m\nline two".to_string(),
+                client_msg_id: None,
+            },
+        )
+        .await;
+        match expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Chat { .. })).await {
+            ServerMessage::Chat { text, .. } => assert_eq!(text, "evil[2J31m\nline two"),
+            other => panic!("Expected chat, got {:?}", other),
+        }
+
+        let response = reqwest::Client::new()
+            .post(format!("http://{}/room/{}", addr, DEFAULT_ROOM))
+            .json(&Message::new("evil\x1B[2J".to_string()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let stored: Vec<String> = default_room_messages(&state)
+            .into_iter()
+            .map(|msg| msg.text)
+            .collect();
+        assert_eq!(stored, vec!["evil[2J31m\nline two", "evil[2J"]);
+
+        // Operators can opt out
+        let permissive = AppState::with_config(ServerConfig {
+            allow_control_chars: true,
+            ..ServerConfig::default()
+        });
+        assert_eq!(sanitize_text(&permissive, "evil\x1B[2J"), "evil\x1B[2J");
+    }
+
+    #[tokio::test]
+    async fn test_blank_messages_are_rejected() {
+        let state = test_state();
+        let addr = spawn_test_server(state.clone()).await;
+        let mut ws = connect_test_client(addr, "Alice").await;
+
+        // Left empty once control characters are stripped too
+        for text in ["", " \n\t ", "\x1B"] {
+            send_client_message(
+                &mut ws,
+                &ClientMessage::Chat {
+                    text: text.to_string(),
+                    client_msg_id: None,
+                },
+            )
+            .await;
+            match expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Error { .. })).await
+            {
+                ServerMessage::Error { code, message } => {
+                    assert_eq!(code, 400);
+                    assert_eq!(message, "Message must not be empty");
+                }
+                other => panic!("Expected an error, got {:?}", other),
+            }
+        }
+
+        // Actions, direct messages and the old message format too
+        let legacy = serde_json::to_string(&Message::new(" ".to_string())).unwrap();
+        ws.send(WsMessage::Text(legacy.into())).await.unwrap();
+        send_client_message(
+            &mut ws,
+            &ClientMessage::Action {
+                text: "\t".to_string(),
+            },
+        )
+        .await;
+        send_client_message(
+            &mut ws,
+            &ClientMessage::DirectMessage {
+                to: "Alice".to_string(),
+                text: " ".to_string(),
+            },
+        )
+        .await;
+        for _ in 0..3 {
+            match expect_server_message(&mut ws, |m| {
+                matches!(
+                    m,
+                    ServerMessage::Error { .. }
+                        | ServerMessage::Action { .. }
+                        | ServerMessage::DirectMessage { .. }
+                )
+            })
+            .await
+            {
+                ServerMessage::Error { code, message } => {
+                    assert_eq!(code, 400);
+                    assert_eq!(message, "Message must not be empty");
+                }
+                other => panic!("Expected an error, got {:?}", other),
+            }
+        }
+        assert!(default_room_messages(&state).is_empty());
+
+        let response = reqwest::Client::new()
+            .post(format!("http://{}/room/{}", addr, DEFAULT_ROOM))
+            .json(&Message::new("   ".to_string()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(default_room_messages(&state).is_empty());
+
+        // Operators can opt out
+        let permissive = AppState::with_config(ServerConfig {
+            allow_empty: true,
+            ..ServerConfig::default()
+        });
+        assert!(!is_blank(&permissive, "  "));
+        assert!(is_blank(&state, "  "));
+        assert!(!is_blank(&state, " x "));
+    }
+
+    #[test]
+    fn test_text_is_validated_in_one_place() {
+        let state = AppState::with_config(ServerConfig {
+            max_message_len: 8,
+            blocklist: Some(Blocklist::parse("heck\n", BlocklistMode::Reject).unwrap()),
+            ..ServerConfig::default()
+        });
+        assert_eq!(validate_text(&state, "hi\x1b[2J"), Ok("hi[2J".to_string()));
+        // Control characters are stripped before the blank check
+        assert_eq!(validate_text(&state, " \x07 "), Err(TextRejection::Blank));
+        assert_eq!(
+            validate_text(&state, "far too long"),
+            Err(TextRejection::TooLong)
+        );
+        assert_eq!(
+            validate_text(&state, "oh heck"),
+            Err(TextRejection::Blocked)
+        );
+
+        let statuses: Vec<StatusCode> = [
+            TextRejection::Blank,
+            TextRejection::TooLong,
+            TextRejection::Blocked,
+        ]
+        .into_iter()
+        .map(TextRejection::status)
+        .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                StatusCode::BAD_REQUEST,
+                StatusCode::PAYLOAD_TOO_LARGE,
+                StatusCode::UNPROCESSABLE_ENTITY
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sender_gets_ack_with_increasing_ids() {
+        let state = test_state();
+        let addr = spawn_test_server(state.clone()).await;
+        let mut ws = connect_test_client(addr, "Alice").await;
+
+        for (expected, text) in [(1, "first"), (2, "second")] {
+            send_client_message(
+                &mut ws,
+                &ClientMessage::Chat {
+                    text: text.to_string(),
+                    client_msg_id: None,
+                },
+            )
+            .await;
+            match expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Chat { .. })).await
+            {
+                ServerMessage::Chat { id, .. } => assert_eq!(id, Some(expected)),
+                other => panic!("Expected chat, got {:?}", other),
+            }
+            match expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Ack { .. })).await {
+                ServerMessage::Ack { id, .. } => assert_eq!(id, expected),
+                other => panic!("Expected ack, got {:?}", other),
+            }
+        }
+
+        let history: Vec<serde_json::Value> =
+            reqwest::get(format!("http://{}/messages/json", addr))
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+        let ids: Vec<u64> = history.iter().map(|m| m["id"].as_u64().unwrap()).collect();
+        assert_eq!(ids, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_rest_rate_limit_returns_429_with_headers() {
+        let state = AppState::with_config(ServerConfig {
+            rate_limit_per_sec: 3,
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state).await;
+        let client = reqwest::Client::new();
+        let url = format!("http://{}/room/{}", addr, DEFAULT_ROOM);
+        let body = Message::new("hello".to_string());
+
+        for expected_remaining in ["2", "1", "0"] {
+            let response = client.post(&url).json(&body).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::CREATED);
+            assert_eq!(
+                response.headers()[RATE_LIMIT_REMAINING_HEADER],
+                expected_remaining
+            );
+        }
+
+        let response = client.post(&url).json(&body).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RATE_LIMIT_REMAINING_HEADER], "0");
+        let retry_after: u64 = response.headers()[RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(retry_after, 1);
+    }
+
+    #[tokio::test]
+    async fn test_replay_format_follows_client_protocol() {
+        let state = test_state();
+        store_message(
+            &state,
+            DEFAULT_ROOM,
+            Message::chat_message("Carol", "earlier"),
+        );
+        let addr = spawn_test_server(state).await;
+
+        // Clients reporting a protocol version get full message objects
+        let mut modern = connect_test_client(addr, "Alice").await;
+        let history =
+            expect_server_message(&mut modern, |m| matches!(m, ServerMessage::History { .. }))
+                .await;
+        match history {
+            ServerMessage::History { messages } => {
+                assert_eq!(messages.len(), 1);
+                assert_eq!(messages[0].text, "earlier");
+                assert_eq!(messages[0].id, Some(1));
+            }
+            other => panic!("Expected a history block, got {:?}", other),
+        }
+
+        // Legacy clients send no versions and get the bare text
+        let url = format!("ws://{}/room/{}", addr, DEFAULT_ROOM);
+        let (mut legacy, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        legacy
+            .send(WsMessage::Text(
+                r#"{"type":"Connect","name":"Old"}"#.to_string().into(),
+            ))
+            .await
+            .unwrap();
+        let history = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                if let WsMessage::Text(text) = legacy.next().await.unwrap().unwrap() {
+                    assert!(!text.contains("History"));
+                    if text.as_str() == "Carol: earlier" {
+                        break;
+                    }
+                }
+            }
+        })
+        .await;
+        assert!(history.is_ok(), "Expected the plain-text history");
+    }
+
+    #[tokio::test]
+    async fn test_join_backlog_is_capped() {
+        let state = AppState::with_config(ServerConfig {
+            join_backlog: 5,
+            ..ServerConfig::default()
+        });
+        for i in 1..=20 {
+            store_message(
+                &state,
+                DEFAULT_ROOM,
+                Message::chat_message("Carol", &format!("message {}", i)),
+            );
+        }
+        let addr = spawn_test_server(state.clone()).await;
 
+        let mut alice = connect_test_client(addr, "Alice").await;
+        match expect_server_message(&mut alice, |m| matches!(m, ServerMessage::History { .. }))
+            .await
         {
-            let mut users_guard = app_state.users.lock().unwrap();
-            users_guard.insert(user_id.clone(), user.clone());
+            ServerMessage::History { messages } => {
+                let ids: Vec<u64> = messages.iter().filter_map(|msg| msg.id).collect();
+                assert_eq!(ids, vec![16, 17, 18, 19, 20]);
+            }
+            _ => unreachable!(),
         }
 
-        // Verify user was added
-        assert_eq!(app_state.users.lock().unwrap().len(), 1);
-        assert!(app_state.users.lock().unwrap().contains_key(&user_id));
+        // Legacy clients get the same messages as text frames
+        let url = format!("ws://{}/room/{}", addr, DEFAULT_ROOM);
+        let (mut legacy, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        legacy
+            .send(WsMessage::Text(
+                r#"{"type":"Connect","name":"Old"}"#.to_string().into(),
+            ))
+            .await
+            .unwrap();
+        let mut replayed = Vec::new();
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while replayed
+                .last()
+                .is_none_or(|text| text != "Carol: message 20")
+            {
+                if let WsMessage::Text(text) = legacy.next().await.unwrap().unwrap()
+                    && text.starts_with("Carol: ")
+                {
+                    replayed.push(text.to_string());
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(replayed.len(), 5);
+        assert_eq!(replayed[0], "Carol: message 16");
+    }
+
+    #[tokio::test]
+    async fn test_welcome_greets_only_the_joining_client() {
+        let state = AppState::with_config(ServerConfig {
+            welcome: Some("Be nice".to_string()),
+            room_welcomes: HashMap::from([("standup".to_string(), "Keep it short".to_string())]),
+            ..ServerConfig::default()
+        });
+        store_message(
+            &state,
+            DEFAULT_ROOM,
+            Message::chat_message("Carol", "earlier"),
+        );
+        let addr = spawn_test_server(state.clone()).await;
+
+        // The greeting comes after the backlog
+        let mut alice = connect_test_client(addr, "Alice").await;
+        expect_server_message(&mut alice, |m| matches!(m, ServerMessage::History { .. })).await;
+        match expect_server_message(&mut alice, |m| matches!(m, ServerMessage::System { .. })).await
+        {
+            ServerMessage::System { text } => assert_eq!(text, "Be nice"),
+            _ => unreachable!(),
+        }
+
+        let mut bob = connect_test_client(addr, "Bob").await;
+        expect_server_message(&mut bob, |m| matches!(m, ServerMessage::System { .. })).await;
+
+        // Alice hears Bob join but not his greeting
+        let next = expect_server_message(&mut alice, |m| {
+            matches!(m, ServerMessage::System { .. })
+                || matches!(m, ServerMessage::UserJoined { name } if name == "Bob")
+        })
+        .await;
+        assert!(matches!(next, ServerMessage::UserJoined { name } if name == "Bob"));
+
+        // Greetings aren't stored, and rooms can have their own
+        assert_eq!(default_room_messages(&state).len(), 1);
+        match room_greeting(&state, "standup") {
+            Some(ServerMessage::System { text }) => assert_eq!(text, "Keep it short"),
+            other => panic!("unexpected greeting {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_topic_is_broadcast_listed_and_sent_to_joiners() {
+        let state = AppState::new();
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+
+        send_client_message(
+            &mut alice,
+            &ClientMessage::SetTopic {
+                text: " Release planning ".to_string(),
+            },
+        )
+        .await;
+        match expect_server_message(&mut alice, |m| {
+            matches!(m, ServerMessage::TopicChanged { .. })
+        })
+        .await
+        {
+            ServerMessage::TopicChanged { topic, by } => {
+                assert_eq!(topic.as_deref(), Some("Release planning"));
+                assert_eq!(by.as_deref(), Some("Alice"));
+            }
+            _ => unreachable!(),
+        }
+
+        let rooms: Vec<RoomInfo> = reqwest::get(format!("http://{}/rooms", addr))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(rooms[0].topic.as_deref(), Some("Release planning"));
+
+        // Joiners are told the topic without anyone changing it
+        let mut bob = connect_test_client(addr, "Bob").await;
+        match expect_server_message(&mut bob, |m| {
+            matches!(m, ServerMessage::TopicChanged { .. })
+        })
+        .await
+        {
+            ServerMessage::TopicChanged { topic, by } => {
+                assert_eq!(topic.as_deref(), Some("Release planning"));
+                assert_eq!(by, None);
+            }
+            _ => unreachable!(),
+        }
+
+        // Blank text clears it
+        send_client_message(
+            &mut bob,
+            &ClientMessage::SetTopic {
+                text: String::new(),
+            },
+        )
+        .await;
+        expect_server_message(&mut alice, |m| {
+            matches!(m, ServerMessage::TopicChanged { topic: None, by } if by.as_deref() == Some("Bob"))
+        })
+        .await;
+        assert_eq!(state.rooms.lock().unwrap()[DEFAULT_ROOM].topic, None);
+    }
+
+    #[tokio::test]
+    async fn test_topic_changes_are_filtered_and_rate_limited() {
+        let state = AppState::with_config(ServerConfig {
+            blocklist: Some(Blocklist::parse("heck\n", BlocklistMode::Reject).unwrap()),
+            rate_limit_per_sec: 1,
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+
+        send_client_message(
+            &mut alice,
+            &ClientMessage::SetTopic {
+                text: "what the heck".to_string(),
+            },
+        )
+        .await;
+        match expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Error { .. })).await
+        {
+            ServerMessage::Error { code, .. } => assert_eq!(code, 400),
+            _ => unreachable!(),
+        }
+        assert_eq!(state.rooms.lock().unwrap()[DEFAULT_ROOM].topic, None);
+
+        for text in ["Planning", "Planning again"] {
+            send_client_message(
+                &mut alice,
+                &ClientMessage::SetTopic {
+                    text: text.to_string(),
+                },
+            )
+            .await;
+        }
+        expect_server_message(&mut alice, |m| {
+            matches!(m, ServerMessage::TopicChanged { topic, .. } if topic.as_deref() == Some("Planning"))
+        })
+        .await;
+        match expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Error { .. })).await
+        {
+            ServerMessage::Error { code, .. } => assert_eq!(code, 429),
+            _ => unreachable!(),
+        }
+        assert_eq!(
+            state.rooms.lock().unwrap()[DEFAULT_ROOM].topic.as_deref(),
+            Some("Planning")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_topic_policy_can_reserve_topics_for_moderators() {
+        let state = AppState::with_config(ServerConfig {
+            topic_policy: TopicPolicy::Moderators,
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
 
-        // Remove user
+        send_client_message(
+            &mut alice,
+            &ClientMessage::SetTopic {
+                text: "Mine now".to_string(),
+            },
+        )
+        .await;
+        match expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Error { .. })).await
         {
-            let mut users_guard = app_state.users.lock().unwrap();
-            users_guard.remove(&user_id);
+            ServerMessage::Error { code, .. } => assert_eq!(code, 403),
+            _ => unreachable!(),
         }
+        assert_eq!(state.rooms.lock().unwrap()[DEFAULT_ROOM].topic, None);
+    }
 
-        // Verify user was removed
-        assert_eq!(app_state.users.lock().unwrap().len(), 0);
-        assert!(!app_state.users.lock().unwrap().contains_key(&user_id));
+    #[tokio::test]
+    async fn test_join_backlog_of_zero_replays_nothing() {
+        let state = AppState::with_config(ServerConfig {
+            join_backlog: 0,
+            ..ServerConfig::default()
+        });
+        store_message(&state, DEFAULT_ROOM, Message::chat_message("Carol", "hi"));
+        let addr = spawn_test_server(state).await;
+
+        let mut alice = connect_test_client(addr, "Alice").await;
+        let first = expect_server_message(&mut alice, |m| {
+            matches!(
+                m,
+                ServerMessage::History { .. } | ServerMessage::UserList(_)
+            )
+        })
+        .await;
+        assert!(matches!(first, ServerMessage::UserList(_)));
     }
 
     #[tokio::test]
-    async fn test_client_broadcast_simulation() {
-        let messages = Arc::new(Mutex::new(Vec::new()));
-        let clients = Arc::new(Mutex::new(Vec::new()));
-        let users = Arc::new(Mutex::new(HashMap::new()));
+    async fn test_join_room_sends_snapshot_and_leaves_previous() {
+        let state = test_state();
+        state
+            .rooms
+            .lock()
+            .unwrap()
+            .insert("standup".to_string(), RoomState::default());
+        store_message(
+            &state,
+            "standup",
+            Message::chat_message("Carol", "yesterday"),
+        );
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+        let mut bob = connect_test_client(addr, "Bob").await;
+        expect_server_message(
+            &mut alice,
+            |m| matches!(m, ServerMessage::UserJoined { name } if name == "Bob"),
+        )
+        .await;
 
-        let app_state = AppState {
-            messages: messages.clone(),
-            clients: clients.clone(),
-            users: users.clone(),
-        };
+        send_client_message(
+            &mut bob,
+            &ClientMessage::JoinRoom {
+                room: "standup".to_string(),
+                password: None,
+            },
+        )
+        .await;
+        expect_server_message(
+            &mut bob,
+            |m| matches!(m, ServerMessage::Welcome { room, .. } if room == "standup"),
+        )
+        .await;
+        // The room's history follows the welcome
+        expect_server_message(
+            &mut bob,
+            |m| matches!(m, ServerMessage::History { messages } if messages[0].text == "yesterday"),
+        )
+        .await;
 
-        // Create mock client channels
-        let (tx1, mut rx1) = tokio::sync::mpsc::unbounded_channel();
-        let (tx2, mut rx2) = tokio::sync::mpsc::unbounded_channel();
+        // Bob has left the first room
+        expect_server_message(
+            &mut alice,
+            |m| matches!(m, ServerMessage::UserLeft { name } if name == "Bob"),
+        )
+        .await;
+        assert_eq!(room_user_list(&state, DEFAULT_ROOM).count, 1);
+        assert_eq!(room_user_list(&state, "standup").count, 1);
 
-        // Add clients to state
-        {
-            let mut clients_guard = app_state.clients.lock().unwrap();
-            clients_guard.push(tx1);
-            clients_guard.push(tx2);
-        }
+        // Chat now goes to the new room only
+        send_client_message(
+            &mut bob,
+            &ClientMessage::Chat {
+                text: "morning".to_string(),
+                client_msg_id: None,
+            },
+        )
+        .await;
+        expect_server_message(&mut bob, |m| matches!(m, ServerMessage::Ack { .. })).await;
+        assert_eq!(state.rooms.lock().unwrap()["standup"].messages.len(), 2);
+        assert!(default_room_messages(&state).is_empty());
 
-        // Broadcast a message
-        let broadcast_message = Message {
-            text: "Broadcast test".to_string(),
-        };
+        send_client_message(
+            &mut bob,
+            &ClientMessage::LeaveRoom {
+                room: "standup".to_string(),
+            },
+        )
+        .await;
+        expect_server_message(
+            &mut alice,
+            |m| matches!(m, ServerMessage::UserJoined { name } if name == "Bob"),
+        )
+        .await;
+        assert_eq!(room_user_list(&state, DEFAULT_ROOM).count, 2);
+    }
 
-        {
-            let clients_guard = app_state.clients.lock().unwrap();
-            for client_tx in clients_guard.iter() {
-                let _ = client_tx.send(broadcast_message.clone());
+    #[tokio::test]
+    async fn test_replies_quote_their_parent_or_degrade() {
+        let state = AppState::with_config(ServerConfig {
+            max_messages: 2,
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+        expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Welcome { .. })).await;
+
+        send_client_message(
+            &mut alice,
+            &ClientMessage::Chat {
+                text: "lunch?".to_string(),
+                client_msg_id: None,
+            },
+        )
+        .await;
+        let parent =
+            match expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Ack { .. }))
+                .await
+            {
+                ServerMessage::Ack { id, .. } => id,
+                other => panic!("Expected ack, got {:?}", other),
+            };
+
+        let reply = |text: &str| ClientMessage::Reply {
+            to: parent,
+            text: text.to_string(),
+        };
+        let is_chat = |m: &ServerMessage| matches!(m, ServerMessage::Chat { .. });
+        send_client_message(&mut alice, &reply("sure")).await;
+        match expect_server_message(&mut alice, is_chat).await {
+            ServerMessage::Chat {
+                text,
+                reply_to,
+                quote,
+                ..
+            } => {
+                assert_eq!(text, "sure");
+                assert_eq!(reply_to, Some(parent));
+                assert_eq!(quote.as_deref(), Some("Alice: lunch?"));
             }
+            _ => unreachable!(),
         }
+        assert_eq!(default_room_messages(&state)[1].reply_to, Some(parent));
 
-        // Verify both clients received the message
-        let received1 = rx1.recv().await;
-        let received2 = rx2.recv().await;
+        // A message ID that was never handed out is rejected
+        send_client_message(
+            &mut alice,
+            &ClientMessage::Reply {
+                to: 99,
+                text: "what?".to_string(),
+            },
+        )
+        .await;
+        expect_server_message(&mut alice, |m| {
+            matches!(m, ServerMessage::Error { code: 404, .. })
+        })
+        .await;
 
-        assert!(received1.is_some());
-        assert!(received2.is_some());
-        assert_eq!(received1.unwrap().text, "Broadcast test");
-        assert_eq!(received2.unwrap().text, "Broadcast test");
+        // Once the parent is trimmed from the history, replies go out plain
+        send_client_message(
+            &mut alice,
+            &ClientMessage::Chat {
+                text: "anyone?".to_string(),
+                client_msg_id: None,
+            },
+        )
+        .await;
+        expect_server_message(&mut alice, is_chat).await;
+        send_client_message(&mut alice, &reply("still hungry")).await;
+        match expect_server_message(&mut alice, is_chat).await {
+            ServerMessage::Chat {
+                text,
+                reply_to,
+                quote,
+                ..
+            } => {
+                assert_eq!(text, "still hungry");
+                assert_eq!(reply_to, None);
+                assert_eq!(quote, None);
+            }
+            _ => unreachable!(),
+        }
+        assert_eq!(default_room_messages(&state)[1].reply_to, None);
     }
 
     #[tokio::test]
-    async fn test_message_formatting() {
-        let name = "Alice";
-        let line = "Hello everyone!";
+    async fn test_blocklist_rejects_or_masks_messages() {
+        for (mode, expected) in [
+            (BlocklistMode::Reject, None),
+            (BlocklistMode::Mask, Some("well **** that")),
+        ] {
+            let state = AppState::with_config(ServerConfig {
+                blocklist: Some(Blocklist::parse("heck\n", mode).unwrap()),
+                ..ServerConfig::default()
+            });
+            let addr = spawn_test_server(state.clone()).await;
+            let mut ws = connect_test_client(addr, "Alice").await;
+            expect_server_message(&mut ws, |m| matches!(m, ServerMessage::Welcome { .. })).await;
 
-        let mut whom = String::from(name);
-        whom.push_str(": ");
-        whom.push_str(line);
-
-        assert_eq!(whom, "Alice: Hello everyone!");
+            send_client_message(
+                &mut ws,
+                &ClientMessage::Chat {
+                    text: "well heck that".to_string(),
+                    client_msg_id: None,
+                },
+            )
+            .await;
+            let reply = expect_server_message(&mut ws, |m| {
+                matches!(m, ServerMessage::Chat { .. } | ServerMessage::Error { .. })
+            })
+            .await;
+            match (reply, expected) {
+                (ServerMessage::Error { code, .. }, None) => assert_eq!(code, 400),
+                (ServerMessage::Chat { text, .. }, Some(expected)) => assert_eq!(text, expected),
+                (other, _) => panic!("Unexpected reply in {:?} mode: {:?}", mode, other),
+            }
+            let stored: Vec<String> = default_room_messages(&state)
+                .into_iter()
+                .map(|msg| msg.text)
+                .collect();
+            assert_eq!(
+                stored,
+                expected.map(str::to_string).into_iter().collect::<Vec<_>>()
+            );
 
-        let message = Message { text: whom.clone() };
-        assert_eq!(message.text, "Alice: Hello everyone!");
+            // Words merely containing a blocked one pass untouched
+            send_client_message(
+                &mut ws,
+                &ClientMessage::Chat {
+                    text: "checking in".to_string(),
+                    client_msg_id: None,
+                },
+            )
+            .await;
+            expect_server_message(
+                &mut ws,
+                |m| matches!(m, ServerMessage::Chat { text, .. } if text == "checking in"),
+            )
+            .await;
+        }
     }
 
     #[tokio::test]
-    async fn test_server_url_construction() {
-        let address = "127.0.0.1";
-        let port = 8080;
-        let server_url = format!("http://{}:{}", address, port);
-
-        assert_eq!(server_url, "http://127.0.0.1:8080");
-
-        let messages_url = format!("{}/messages", server_url);
-        assert_eq!(messages_url, "http://127.0.0.1:8080/messages");
+    async fn test_stats_count_messages_as_they_flow() {
+        let state = test_state();
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+        expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Welcome { .. })).await;
 
-        let room_url = format!("{}/room/1", server_url);
-        assert_eq!(room_url, "http://127.0.0.1:8080/room/1");
+        let mut totals = Vec::new();
+        for text in ["one", "two"] {
+            send_client_message(&mut alice, &ClientMessage::Stats).await;
+            match expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Stats { .. }))
+                .await
+            {
+                ServerMessage::Stats {
+                    total_messages,
+                    connected_users,
+                    rooms,
+                    ..
+                } => {
+                    assert_eq!(connected_users, 1);
+                    assert_eq!(rooms, 1);
+                    totals.push(total_messages);
+                }
+                _ => unreachable!(),
+            }
+            send_client_message(
+                &mut alice,
+                &ClientMessage::Chat {
+                    text: text.to_string(),
+                    client_msg_id: None,
+                },
+            )
+            .await;
+            expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Ack { .. })).await;
+        }
+        assert_eq!(totals, vec![0, 1]);
     }
 
     #[tokio::test]
-    async fn test_user_id_generation() {
-        let user_id1 = uuid::Uuid::new_v4().to_string();
-        let user_id2 = uuid::Uuid::new_v4().to_string();
+    async fn test_resent_chat_with_same_key_is_posted_once() {
+        let state = test_state();
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+        expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Welcome { .. })).await;
 
-        // Verify UUIDs are unique
-        assert_ne!(user_id1, user_id2);
+        let keyed = ClientMessage::Chat {
+            text: "deploying now".to_string(),
+            client_msg_id: Some("4b1c0c5e-retry".to_string()),
+        };
+        let mut acks = Vec::new();
+        for _ in 0..2 {
+            send_client_message(&mut alice, &keyed).await;
+            match expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Ack { .. }))
+                .await
+            {
+                ServerMessage::Ack { id, .. } => acks.push(id),
+                _ => unreachable!(),
+            }
+        }
+        // Both sends are acknowledged with the ID of the one stored message
+        assert_eq!(acks[0], acks[1]);
 
-        // Verify UUID format (should contain hyphens)
-        assert!(user_id1.contains('-'));
-        assert!(user_id2.contains('-'));
+        // Messages without a key are never deduplicated
+        for _ in 0..2 {
+            send_client_message(
+                &mut alice,
+                &ClientMessage::Chat {
+                    text: "deploying now".to_string(),
+                    client_msg_id: None,
+                },
+            )
+            .await;
+            expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Ack { .. })).await;
+        }
+        assert_eq!(default_room_messages(&state).len(), 3);
+    }
 
-        // Verify user name generation
-        let name1 = format!("User_{}", user_id1.split('-').next().unwrap());
-        let name2 = format!("User_{}", user_id2.split('-').next().unwrap());
+    #[tokio::test]
+    async fn test_resent_chat_is_posted_once_across_reconnects() {
+        let state = test_state();
+        let addr = spawn_test_server(state.clone()).await;
+        let keyed = ClientMessage::Chat {
+            text: "deploying now".to_string(),
+            client_msg_id: Some("9e0f7a21-resend".to_string()),
+        };
+        let mut acks = Vec::new();
+        // The client resends after reconnecting, as it never saw the first ack
+        for _ in 0..2 {
+            let url = format!("ws://{}/room/{}", addr, DEFAULT_ROOM);
+            let (mut alice, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+            let connect = ClientMessage::connect("Alice".to_string(), None, None)
+                .with_identity(Some("alice-key".to_string()));
+            send_client_message(&mut alice, &connect).await;
+            send_client_message(&mut alice, &keyed).await;
+            match expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Ack { .. }))
+                .await
+            {
+                ServerMessage::Ack { id, client_msg_id } => {
+                    assert_eq!(client_msg_id.as_deref(), Some("9e0f7a21-resend"));
+                    acks.push(id);
+                }
+                _ => unreachable!(),
+            }
+        }
+        assert_eq!(acks[0], acks[1]);
+        assert_eq!(default_room_messages(&state).len(), 1);
+    }
 
-        assert!(name1.starts_with("User_"));
-        assert!(name2.starts_with("User_"));
-        assert_ne!(name1, name2);
+    #[test]
+    fn test_recent_client_ids_are_bounded() {
+        let now = Instant::now();
+        let mut recent = RecentClientIds::default();
+        for id in 0..=RECENT_CLIENT_IDS as u64 {
+            recent.insert(id.to_string(), id, now);
+        }
+        assert_eq!(recent.get("0", now), None);
+        assert_eq!(recent.get("1", now), Some(1));
+        assert_eq!(recent.ids.len(), RECENT_CLIENT_IDS);
     }
 
-    #[tokio::test]
-    async fn test_instant_timing() {
+    #[test]
+    fn test_recent_client_ids_expire() {
         let start = Instant::now();
-        sleep(Duration::from_millis(10)).await;
-        let duration = start.elapsed();
+        let mut recent = RecentClientIds::default();
+        recent.insert("old".to_string(), 1, start);
+        recent.insert("new".to_string(), 2, start + RECENT_CLIENT_ID_TTL / 2);
 
-        // Verify that some time has passed
-        assert!(duration.as_millis() >= 5);
-        assert!(duration.as_millis() <= 50); // Allow some margin for timing variations
+        let later = start + RECENT_CLIENT_ID_TTL;
+        assert_eq!(recent.get("old", later), None);
+        assert_eq!(recent.get("new", later), Some(2));
+        recent.expire(later + RECENT_CLIENT_ID_TTL);
+        assert!(recent.is_empty());
     }
 
     #[tokio::test]
-    async fn test_server_start_and_stop() {
-        let address = "127.0.0.1";
-        let port = 12346; // Use different port to avoid conflicts
-        let server_url = format!("http://{}:{}", address, port);
+    async fn test_edit_and_delete_only_own_messages() {
+        let state = test_state();
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+        let mut bob = connect_test_client(addr, "Bob").await;
 
-        // Start server in background
-        let server_handle = tokio::spawn(async move {
-            let messages = Arc::new(Mutex::new(Vec::new()));
-            let clients = Arc::new(Mutex::new(Vec::new()));
-            let users = Arc::new(Mutex::new(HashMap::new()));
-            let app_state = AppState {
-                messages,
-                clients,
-                users,
-            };
+        send_client_message(
+            &mut alice,
+            &ClientMessage::Chat {
+                text: "helo".to_string(),
+                client_msg_id: None,
+            },
+        )
+        .await;
+        let id = match expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Ack { .. }))
+            .await
+        {
+            ServerMessage::Ack { id, .. } => id,
+            other => panic!("Expected ack, got {:?}", other),
+        };
 
-            let socket_addr: SocketAddr = format!("{}:{}", address, port).parse().unwrap();
-            let listener = tokio::net::TcpListener::bind(socket_addr).await.unwrap();
+        // Someone else's message can't be edited or deleted
+        send_client_message(
+            &mut bob,
+            &ClientMessage::Edit {
+                id,
+                text: "pwned".to_string(),
+            },
+        )
+        .await;
+        match expect_server_message(&mut bob, |m| matches!(m, ServerMessage::Error { .. })).await {
+            ServerMessage::Error { code, .. } => assert_eq!(code, 403),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+        send_client_message(&mut bob, &ClientMessage::Delete { id }).await;
+        expect_server_message(&mut bob, |m| {
+            matches!(m, ServerMessage::Error { code: 403, .. })
+        })
+        .await;
+        assert_eq!(default_room_messages(&state)[0].text, "helo");
 
-            let app = Router::new()
-                .route("/room/1", get(handle_websocket))
-                .route("/room/1", post(handle_post))
-                .route("/messages", get(handle_get))
-                .with_state(app_state);
+        send_client_message(
+            &mut alice,
+            &ClientMessage::Edit {
+                id,
+                text: "hello".to_string(),
+            },
+        )
+        .await;
+        match expect_server_message(&mut bob, |m| {
+            matches!(m, ServerMessage::MessageEdited { .. })
+        })
+        .await
+        {
+            ServerMessage::MessageEdited {
+                id: edited,
+                text,
+                sender,
+            } => {
+                assert_eq!(edited, id);
+                assert_eq!(text, "hello");
+                assert_eq!(sender.as_deref(), Some("Alice"));
+            }
+            other => panic!("Expected an edit, got {:?}", other),
+        }
+        assert_eq!(default_room_messages(&state)[0].text, "hello");
 
-            axum::serve(listener, app).await.unwrap();
-        });
+        send_client_message(&mut alice, &ClientMessage::Delete { id }).await;
+        expect_server_message(
+            &mut bob,
+            |m| matches!(m, ServerMessage::MessageDeleted { id: deleted } if *deleted == id),
+        )
+        .await;
+        assert!(default_room_messages(&state)[0].deleted);
 
-        // Give server time to start
-        sleep(Duration::from_millis(100)).await;
+        let text = reqwest::get(format!("http://{}/messages", addr))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(text.is_empty());
+    }
 
-        // Test that server is responding
-        let client = reqwest::Client::new();
-        let response = client.get(&format!("{}/messages", server_url)).send().await;
+    #[tokio::test]
+    async fn test_purge_deletes_only_own_messages() {
+        let state = test_state();
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+        let mut bob = connect_test_client(addr, "Bob").await;
 
-        assert!(response.is_ok());
-        assert!(response.unwrap().status().is_success());
+        let posts = [
+            ("Alice", "one"),
+            ("Bob", "two"),
+            ("Alice", "three"),
+            ("Bob", "four"),
+            ("Alice", "five"),
+        ];
+        for (author, text) in posts {
+            let ws = if author == "Alice" {
+                &mut alice
+            } else {
+                &mut bob
+            };
+            send_client_message(
+                ws,
+                &ClientMessage::Chat {
+                    text: text.to_string(),
+                    client_msg_id: None,
+                },
+            )
+            .await;
+            expect_server_message(ws, |m| matches!(m, ServerMessage::Ack { .. })).await;
+        }
 
-        // Stop server by aborting the task
-        server_handle.abort();
+        send_client_message(&mut alice, &ClientMessage::PurgeMine).await;
+        for expected in [1, 3, 5] {
+            expect_server_message(
+                &mut bob,
+                |m| matches!(m, ServerMessage::MessageDeleted { id } if *id == expected),
+            )
+            .await;
+        }
+        expect_server_message(&mut alice, |m| {
+            matches!(m, ServerMessage::OwnMessagesPurged { count: 3 })
+        })
+        .await;
 
-        // Give server time to stop
-        sleep(Duration::from_millis(100)).await;
+        let messages = default_room_messages(&state);
+        let kept: Vec<_> = messages
+            .iter()
+            .filter(|m| !m.deleted)
+            .map(|m| m.text.as_str())
+            .collect();
+        assert_eq!(kept, ["two", "four"]);
+        assert!(
+            messages
+                .iter()
+                .filter(|m| m.deleted)
+                .all(|m| m.sender.as_deref() == Some("Alice") && m.text == DELETED_PLACEHOLDER)
+        );
+    }
 
-        // Test that server is no longer responding
-        let response = client.get(&format!("{}/messages", server_url)).send().await;
+    #[test]
+    fn test_message_changes_are_appended_to_history() {
+        let path =
+            std::env::temp_dir().join(format!("chat-changes-{}.jsonl", uuid::Uuid::new_v4()));
+        let policy = FlushPolicy {
+            max_pending: 1,
+            interval: Duration::from_secs(3600),
+        };
+        let state = AppState {
+            storage: Some(Arc::new(Mutex::new(MessageStore::new(&path, policy)))),
+            ..AppState::new()
+        };
+        for text in ["one", "two"] {
+            let message = Message {
+                author_id: Some("alice".to_string()),
+                ..Message::chat_message("Alice", text)
+            };
+            store_message(&state, DEFAULT_ROOM, message);
+        }
+        let lines = || std::fs::read_to_string(&path).unwrap().lines().count();
 
-        assert!(response.is_err());
+        // Each change adds a line rather than rewriting the file
+        modify_own_message(&state, DEFAULT_ROOM, 1, "alice", |msg| {
+            msg.text = "uno".to_string();
+        })
+        .unwrap();
+        assert_eq!(lines(), 3);
+        toggle_reaction(&state, DEFAULT_ROOM, 2, "👍", "Bob").unwrap();
+        assert_eq!(lines(), 4);
+
+        let stored = MessageStore::new(&path, policy).load().unwrap();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored[0].text, "uno");
+        assert_eq!(stored[1].reactions["👍"], vec!["Bob"]);
+
+        // Once changes outnumber the messages the file is compacted
+        assert_eq!(
+            purge_own_messages(&state, DEFAULT_ROOM, "alice").deleted,
+            vec![1, 2]
+        );
+        assert_eq!(lines(), 2);
+        let stored = MessageStore::new(&path, policy).load().unwrap();
+        assert!(stored.iter().all(|msg| msg.deleted));
+
+        std::fs::remove_file(&path).unwrap();
     }
 
     #[tokio::test]
-    async fn test_server_with_multiple_clients() {
-        let address = "127.0.0.1";
-        let port = 12347; // Use different port
-        let server_url = format!("http://{}:{}", address, port);
+    async fn test_authorship_follows_identity_across_restarts() {
+        let path =
+            std::env::temp_dir().join(format!("chat-authors-{}.jsonl", uuid::Uuid::new_v4()));
+        let policy = FlushPolicy {
+            max_pending: 1,
+            interval: Duration::from_secs(3600),
+        };
+        let connect_as = |addr: SocketAddr, identity: Option<&'static str>| async move {
+            let url = format!("ws://{}/room/{}", addr, DEFAULT_ROOM);
+            let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+            let connect = ClientMessage::connect("Alice".to_string(), None, None)
+                .with_identity(identity.map(str::to_string));
+            send_client_message(&mut ws, &connect).await;
+            ws
+        };
 
-        // Start server in background
-        let server_handle = tokio::spawn(async move {
-            let messages = Arc::new(Mutex::new(Vec::new()));
-            let clients = Arc::new(Mutex::new(Vec::new()));
-            let users = Arc::new(Mutex::new(HashMap::new()));
-            let app_state = AppState {
-                messages,
-                clients,
-                users,
-            };
+        let state = AppState {
+            storage: Some(Arc::new(Mutex::new(MessageStore::new(&path, policy)))),
+            ..AppState::new()
+        };
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_as(addr, Some("alice-key")).await;
+        send_client_message(
+            &mut alice,
+            &ClientMessage::Chat {
+                text: "mine".to_string(),
+                client_msg_id: None,
+            },
+        )
+        .await;
+        let id = match expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Ack { .. }))
+            .await
+        {
+            ServerMessage::Ack { id, .. } => id,
+            _ => unreachable!(),
+        };
+        drop(alice);
 
-            let socket_addr: SocketAddr = format!("{}:{}", address, port).parse().unwrap();
-            let listener = tokio::net::TcpListener::bind(socket_addr).await.unwrap();
+        // Only a hash of the identity is saved, and clients never see it
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(saved.contains(&author_key(Some("alice-key")).unwrap()));
+        assert!(!saved.contains("alice-key"));
+        let history = reqwest::get(format!("http://{}/messages/json", addr))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(!history.contains("author_id"));
 
-            let app = Router::new()
-                .route("/room/1", get(handle_websocket))
-                .route("/room/1", post(handle_post))
-                .route("/messages", get(handle_get))
-                .with_state(app_state);
+        // After a restart only the same identity may delete the message
+        let restarted = AppState::new();
+        let loaded = MessageStore::new(&path, policy).load().unwrap();
+        restarted
+            .rooms
+            .lock()
+            .unwrap()
+            .insert(DEFAULT_ROOM.to_string(), RoomState::with_history(loaded));
+        let addr = spawn_test_server(restarted.clone()).await;
+        for (identity, expected_error) in [(None, true), (Some("alice-key"), false)] {
+            let mut ws = connect_as(addr, identity).await;
+            send_client_message(&mut ws, &ClientMessage::Delete { id }).await;
+            match expect_server_message(&mut ws, |m| {
+                matches!(
+                    m,
+                    ServerMessage::Error { .. } | ServerMessage::MessageDeleted { .. }
+                )
+            })
+            .await
+            {
+                ServerMessage::Error { code, .. } if expected_error => assert_eq!(code, 403),
+                ServerMessage::MessageDeleted { id: deleted } if !expected_error => {
+                    assert_eq!(deleted, id)
+                }
+                other => panic!("Unexpected reply for {:?}: {:?}", identity, other),
+            }
+        }
 
-            axum::serve(listener, app).await.unwrap();
-        });
+        std::fs::remove_file(&path).unwrap();
+    }
 
-        // Give server time to start
-        sleep(Duration::from_millis(100)).await;
+    #[tokio::test]
+    async fn test_messages_are_signed_and_resigned_after_edits() {
+        let state = AppState::with_config(ServerConfig {
+            hmac_key: Some("s3cret".to_string()),
+            ..ServerConfig::default()
+        });
+        let addr = spawn_test_server(state.clone()).await;
+        let mut alice = connect_test_client(addr, "Alice").await;
+        let signer = MessageSigner::new("s3cret");
 
-        let client = reqwest::Client::new();
+        send_client_message(
+            &mut alice,
+            &ClientMessage::Chat {
+                text: "helo".to_string(),
+                client_msg_id: None,
+            },
+        )
+        .await;
+        match expect_server_message(&mut alice, |m| matches!(m, ServerMessage::Chat { .. })).await {
+            ServerMessage::Chat {
+                text,
+                sender,
+                timestamp,
+                signature,
+                ..
+            } => {
+                assert!(timestamp.is_some());
+                assert!(signer.verify(sender.as_deref(), &text, timestamp, signature.as_deref()));
+            }
+            other => panic!("Expected a chat message, got {:?}", other),
+        }
 
-        // Test multiple client connections via HTTP endpoints
-        for i in 0..3 {
-            let message = Message {
-                text: format!("Client {} message", i),
-            };
+        send_client_message(
+            &mut alice,
+            &ClientMessage::Edit {
+                id: 1,
+                text: "hello".to_string(),
+            },
+        )
+        .await;
+        expect_server_message(&mut alice, |m| {
+            matches!(m, ServerMessage::MessageEdited { .. })
+        })
+        .await;
+        let message = &default_room_messages(&state)[0];
+        assert_eq!(message.text, "hello");
+        assert!(signer.verify_message(message));
+        assert!(!MessageSigner::new("other").verify_message(message));
+    }
 
-            let response = client
-                .post(&format!("{}/room/1", server_url))
-                .json(&message)
-                .send()
-                .await;
+    #[tokio::test]
+    async fn test_reactions_toggle_and_ignore_trimmed_messages() {
+        let state = test_state();
+        for i in 0..DEFAULT_MAX_MESSAGES + 1 {
+            store_message(
+                &state,
+                DEFAULT_ROOM,
+                Message::chat_message("Alice", &format!("message {}", i)),
+            );
+        }
+        let newest = DEFAULT_MAX_MESSAGES as u64 + 1;
+        let addr = spawn_test_server(state.clone()).await;
+        let mut bob = connect_test_client(addr, "Bob").await;
+        let react = ClientMessage::React {
+            message_id: newest,
+            emoji: "👍".to_string(),
+        };
 
-            assert!(response.is_ok());
-            assert!(response.unwrap().status().is_success());
+        for expected in [true, false] {
+            send_client_message(&mut bob, &react).await;
+            match expect_server_message(&mut bob, |m| matches!(m, ServerMessage::Reaction { .. }))
+                .await
+            {
+                ServerMessage::Reaction {
+                    message_id,
+                    name,
+                    added,
+                    ..
+                } => {
+                    assert_eq!(message_id, newest);
+                    assert_eq!(name, "Bob");
+                    assert_eq!(added, expected);
+                }
+                other => panic!("Expected a reaction, got {:?}", other),
+            }
         }
+        send_client_message(&mut bob, &react).await;
+        expect_server_message(&mut bob, |m| matches!(m, ServerMessage::Reaction { .. })).await;
+        let reactions = default_room_messages(&state)
+            .last()
+            .unwrap()
+            .reactions
+            .clone();
+        assert_eq!(reactions["👍"], vec!["Bob".to_string()]);
 
-        // Verify messages were stored
-        let response = client
-            .get(&format!("{}/messages", server_url))
-            .send()
-            .await
+        // Message 1 has been trimmed from history
+        send_client_message(
+            &mut bob,
+            &ClientMessage::React {
+                message_id: 1,
+                emoji: "👍".to_string(),
+            },
+        )
+        .await;
+        expect_server_message(&mut bob, |m| {
+            matches!(m, ServerMessage::Error { code: 404, .. })
+        })
+        .await;
+
+        // Trimming messages with reactions is fine too
+        store_message(&state, DEFAULT_ROOM, Message::chat_message("Alice", "more"));
+        assert_eq!(default_room_messages(&state).len(), DEFAULT_MAX_MESSAGES);
+    }
+
+    #[test]
+    fn test_reactions_are_capped_per_message() {
+        let state = AppState::with_config(ServerConfig {
+            max_distinct_reactions: 2,
+            max_reactions: 3,
+            ..ServerConfig::default()
+        });
+        let id = store_message(&state, DEFAULT_ROOM, Message::new("react".to_string()))
+            .unwrap()
+            .id
             .unwrap();
+        let react =
+            |emoji: &str, name: &str| toggle_reaction(&state, DEFAULT_ROOM, id, emoji, name);
+        let rejected = |result: Result<bool, ServerMessage>| {
+            matches!(result, Err(ServerMessage::Error { code: 422, .. }))
+        };
 
-        assert!(response.status().is_success());
-        let content = response.text().await.unwrap();
-        assert!(content.contains("Client 0 message"));
-        assert!(content.contains("Client 1 message"));
-        assert!(content.contains("Client 2 message"));
+        // Within the caps
+        assert_eq!(react("👍", "Alice").ok(), Some(true));
+        assert_eq!(react("🎉", "Alice").ok(), Some(true));
+        // A third kind of emoji is one too many
+        assert!(rejected(react("🔥", "Bob")));
+        assert_eq!(react("👍", "Bob").ok(), Some(true));
+        // So is a fourth reaction of a kind already there
+        assert!(rejected(react("👍", "Carol")));
+        // Taking one back is always allowed and makes room again
+        assert_eq!(react("🎉", "Alice").ok(), Some(false));
+        assert_eq!(react("🔥", "Carol").ok(), Some(true));
 
-        // Stop server
-        server_handle.abort();
+        let reactions = default_room_messages(&state)[0].reactions.clone();
+        assert_eq!(reactions.len(), 2);
+        assert_eq!(reactions.values().map(Vec::len).sum::<usize>(), 3);
+        assert!(matches!(
+            toggle_reaction(&state, DEFAULT_ROOM, id + 1, "👍", "Alice"),
+            Err(ServerMessage::Error { code: 404, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_subscribed_rooms_deliver_tagged_events() {
+        let state = test_state();
+        for room in ["a", "b"] {
+            state
+                .rooms
+                .lock()
+                .unwrap()
+                .insert(room.to_string(), RoomState::default());
+        }
+        let addr = spawn_test_server(state.clone()).await;
+        let mut watcher = connect_test_client(addr, "Watcher").await;
+        expect_server_message(&mut watcher, |m| matches!(m, ServerMessage::UserList(_))).await;
+        for room in ["a", "b"] {
+            send_client_message(
+                &mut watcher,
+                &ClientMessage::Subscribe {
+                    room: room.to_string(),
+                },
+            )
+            .await;
+        }
+        // Subscriptions are processed in order, so once the own-room chat
+        // round-trips both are in place
+        send_client_message(
+            &mut watcher,
+            &ClientMessage::Chat {
+                text: "ready".to_string(),
+                client_msg_id: None,
+            },
+        )
+        .await;
+        expect_server_message(&mut watcher, |m| matches!(m, ServerMessage::Ack { .. })).await;
+
+        let mut alice = connect_test_client_to_room(addr, "a", "Alice").await;
+        let mut bob = connect_test_client_to_room(addr, "b", "Bob").await;
+        for (ws, text) in [(&mut alice, "from a"), (&mut bob, "from b")] {
+            send_client_message(
+                ws,
+                &ClientMessage::Chat {
+                    text: text.to_string(),
+                    client_msg_id: None,
+                },
+            )
+            .await;
+        }
+
+        let mut seen = Vec::new();
+        while seen.len() < 2 {
+            let msg = expect_server_message(&mut watcher, |m| {
+                matches!(m, ServerMessage::RoomEvent { event, .. }
+                    if matches!(**event, ServerMessage::Chat { .. }))
+            })
+            .await;
+            if let ServerMessage::RoomEvent { room, event } = msg
+                && let ServerMessage::Chat { text, .. } = *event
+            {
+                seen.push((room, text));
+            }
+        }
+        seen.sort();
+        assert_eq!(
+            seen,
+            vec![
+                ("a".to_string(), "from a".to_string()),
+                ("b".to_string(), "from b".to_string()),
+            ]
+        );
+        // Watching doesn't make the connection a member
+        assert_eq!(room_user_list(&state, "a").count, 1);
     }
 }
@@ -0,0 +1,266 @@
+use std::io;
+use std::time::Duration;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::{
+    Frame,
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style},
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+};
+use tokio::runtime::Handle;
+
+use crate::lock::LockExt;
+use crate::server::{AppState, broadcast_announcement};
+use crate::shared::{DEFAULT_ROOM, User};
+
+/// How often the console redraws when no key is pressed
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Runs the operator console until the operator quits with `q` or Ctrl+C.
+///
+/// This blocks on terminal input, so it should run on a blocking thread;
+/// `runtime` is used to send broadcasts from it.
+pub fn run(state: AppState, runtime: Handle) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let mut console = OperatorConsole::default();
+
+    let result = loop {
+        let snapshot = Snapshot::capture(&state);
+        console.clamp_selection(snapshot.users.len());
+        if let Err(e) = terminal.draw(|frame| console.render(frame, &snapshot)) {
+            break Err(e);
+        }
+
+        match event::poll(REFRESH_INTERVAL) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => break Err(e),
+        }
+        match event::read() {
+            Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                match console.handle_key(key) {
+                    Action::Broadcast(text) => {
+                        runtime.block_on(broadcast_announcement(&state, &text));
+                    }
+                    Action::Quit => break Ok(()),
+                    Action::None => {}
+                }
+            }
+            Ok(_) => {}
+            Err(e) => break Err(e),
+        }
+    };
+
+    ratatui::restore();
+    result
+}
+
+/// What the console loop should do after a key press.
+#[derive(Debug, PartialEq)]
+enum Action {
+    /// Send this text to every room as the server
+    Broadcast(String),
+    /// Stop the console and shut the server down
+    Quit,
+    /// Nothing beyond redrawing
+    None,
+}
+
+/// Server state copied out of the locks for one frame.
+struct Snapshot {
+    users: Vec<User>,
+    messages: Vec<String>,
+}
+
+impl Snapshot {
+    fn capture(state: &AppState) -> Self {
+        let mut users: Vec<User> = state.users.lock_or_recover().values().cloned().collect();
+        users.sort_by_key(|user| user.connected_at);
+        let messages = state
+            .rooms
+            .lock_or_recover()
+            .get(DEFAULT_ROOM)
+            .map(|room| room.messages.iter().map(|msg| msg.text.clone()).collect())
+            .unwrap_or_default();
+        Self { users, messages }
+    }
+}
+
+/// Interactive state of the operator console.
+#[derive(Default)]
+struct OperatorConsole {
+    /// Selected row of the user list
+    users: ListState,
+    /// Broadcast being typed, if the operator is in input mode
+    input: Option<String>,
+}
+
+impl OperatorConsole {
+    /// Handles a key press, returning what the console loop should do next.
+    ///
+    /// In normal mode `q` quits, arrows scroll the user list and `b` starts a
+    /// broadcast. While typing, Enter sends and Esc cancels.
+    fn handle_key(&mut self, key: KeyEvent) -> Action {
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            return Action::Quit;
+        }
+
+        if let Some(input) = &mut self.input {
+            match key.code {
+                KeyCode::Enter => {
+                    let text = input.trim().to_string();
+                    self.input = None;
+                    if !text.is_empty() {
+                        return Action::Broadcast(text);
+                    }
+                }
+                KeyCode::Esc => self.input = None,
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Char(c) => input.push(c),
+                _ => {}
+            }
+            return Action::None;
+        }
+
+        match key.code {
+            KeyCode::Char('q') => Action::Quit,
+            KeyCode::Char('b') => {
+                self.input = Some(String::new());
+                Action::None
+            }
+            KeyCode::Up => {
+                self.users.select_previous();
+                Action::None
+            }
+            KeyCode::Down => {
+                self.users.select_next();
+                Action::None
+            }
+            _ => Action::None,
+        }
+    }
+
+    /// Keeps the selection inside the user list as users come and go.
+    fn clamp_selection(&mut self, user_count: usize) {
+        match (self.users.selected(), user_count) {
+            (_, 0) => self.users.select(None),
+            (None, _) => self.users.select(Some(0)),
+            (Some(i), n) if i >= n => self.users.select(Some(n - 1)),
+            _ => {}
+        }
+    }
+
+    fn render(&mut self, frame: &mut Frame, snapshot: &Snapshot) {
+        let [main, input_area] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(3)]).areas(frame.area());
+        let [users_area, messages_area] =
+            Layout::horizontal([Constraint::Percentage(35), Constraint::Percentage(65)])
+                .areas(main);
+
+        let users: Vec<ListItem> = snapshot
+            .users
+            .iter()
+            .map(|user| {
+                ListItem::new(format!(
+                    "{} ({}s) #{}",
+                    user.name,
+                    user.connected_at.elapsed().as_secs(),
+                    user.room
+                ))
+            })
+            .collect();
+        let users = List::new(users)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(format!("Users ({})", snapshot.users.len())),
+            )
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(users, users_area, &mut self.users);
+
+        // Newest messages at the bottom, as many as fit
+        let height = messages_area.height.saturating_sub(2) as usize;
+        let start = snapshot.messages.len().saturating_sub(height);
+        let messages = Paragraph::new(snapshot.messages[start..].join("\n")).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!("Recent messages (room {})", DEFAULT_ROOM)),
+        );
+        frame.render_widget(messages, messages_area);
+
+        let input = match &self.input {
+            Some(text) => Paragraph::new(text.as_str()).block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title("Broadcast as SERVER (Enter to send, Esc to cancel)")
+                    .border_style(Style::default().fg(Color::Yellow)),
+            ),
+            None => Paragraph::new("b: broadcast  ↑/↓: select user  q: quit")
+                .block(Block::default().borders(Borders::ALL).title("Chat Server")),
+        };
+        frame.render_widget(input, input_area);
+        if let Some(text) = &self.input {
+            frame.set_cursor_position((
+                input_area.x + 1 + text.chars().count() as u16,
+                input_area.y + 1,
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    #[test]
+    fn test_console_broadcast_and_quit_keys() {
+        let mut console = OperatorConsole::default();
+
+        assert_eq!(console.handle_key(key(KeyCode::Char('b'))), Action::None);
+        // While typing, `q` is just text
+        for c in "quiet please".chars() {
+            assert_eq!(console.handle_key(key(KeyCode::Char(c))), Action::None);
+        }
+        assert_eq!(
+            console.handle_key(key(KeyCode::Enter)),
+            Action::Broadcast("quiet please".to_string())
+        );
+
+        // Cancelled broadcasts aren't sent
+        console.handle_key(key(KeyCode::Char('b')));
+        console.handle_key(key(KeyCode::Char('x')));
+        assert_eq!(console.handle_key(key(KeyCode::Esc)), Action::None);
+        assert!(console.input.is_none());
+
+        assert_eq!(console.handle_key(key(KeyCode::Char('q'))), Action::Quit);
+        assert_eq!(
+            console.handle_key(KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL)),
+            Action::Quit
+        );
+    }
+
+    #[test]
+    fn test_console_selection_follows_user_list() {
+        let mut console = OperatorConsole::default();
+
+        console.clamp_selection(3);
+        assert_eq!(console.users.selected(), Some(0));
+        console.handle_key(key(KeyCode::Down));
+        console.handle_key(key(KeyCode::Down));
+        assert_eq!(console.users.selected(), Some(2));
+
+        // A user left while the last row was selected
+        console.clamp_selection(2);
+        assert_eq!(console.users.selected(), Some(1));
+
+        console.clamp_selection(0);
+        assert_eq!(console.users.selected(), None);
+    }
+}
@@ -1,7 +1,10 @@
 use serde::{Deserialize, Serialize};
-use std::time::Instant;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
+use crate::rate_limit::TokenBucket;
+
 /// Custom error types for the chat application
 #[derive(Debug, Error)]
 #[allow(dead_code)]
@@ -16,15 +19,61 @@ pub enum ChatError {
     NetworkError(String),
     #[error("Invalid message format: {0}")]
     InvalidMessage(String),
+    #[error("Invalid configuration: {0}")]
+    ConfigError(String),
 }
 
 pub type ChatResult<T> = Result<T, ChatError>;
 
+/// Identifier of the room that always exists on every server
+pub const DEFAULT_ROOM: &str = "1";
+
+/// Version of the client-server protocol spoken by this build
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version the server accepts without warning
+pub const MIN_SUPPORTED_PROTOCOL_VERSION: u32 = 1;
+
+/// Name a server reports when `--server-name` isn't given
+pub const DEFAULT_SERVER_NAME: &str = "rust-chat";
+
 /// Represents a chat message sent between clients and server
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Message {
     /// The text content of the message
     pub text: String,
+    /// Name of the user who sent the message, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+    /// Server-assigned ID, increasing by one per message within its room
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    /// Key of the author, used to authorize edits, deletes and purges.
+    ///
+    /// Derived from the identity the client connected with, so it carries
+    /// over reconnects and restarts; the connection ID when it sent none.
+    /// Never sent to clients, though the history file keeps it.
+    #[serde(skip)]
+    pub author_id: Option<String>,
+    /// Whether the author deleted this message, leaving only a tombstone
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+    /// Names of the users who reacted, keyed by emoji
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub reactions: BTreeMap<String, Vec<String>>,
+    /// ID of the message this one replies to, in the same room
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<u64>,
+    /// When the server stored the message, in seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    /// Names of the users mentioned as `@name`, as worked out by the server
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mentions: Vec<String>,
+    /// Hex HMAC-SHA256 of the sender, text and timestamp, set when the
+    /// server has a signing key; see [`crate::signing::MessageSigner`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
 }
 
 /// Represents a list of users currently connected to the chat
@@ -48,6 +97,12 @@ pub struct UserList {
 pub struct SerializableUser {
     /// The user's display name
     pub name: String,
+    /// Seconds since the user connected
+    #[serde(default)]
+    pub connected_secs: u64,
+    /// Whether the user is around
+    #[serde(default)]
+    pub status: UserStatus,
 }
 
 /// Represents a user connected to the chat server
@@ -58,72 +113,748 @@ pub struct SerializableUser {
 #[derive(Debug, Clone)]
 pub struct User {
     /// Unique identifier for the user (UUID v4)
-    #[allow(dead_code)]
     pub id: String,
     /// The user's display name in the chat
     pub name: String,
     /// Timestamp when the user connected to the server
     pub connected_at: Instant,
+    /// Identifier of the room the user is currently in
+    pub room: String,
+    /// Other rooms this connection watches; their events arrive as `RoomEvent`
+    pub subscriptions: BTreeSet<String>,
+    /// Number of chat messages sent during this connection
+    pub message_count: usize,
+    /// Timestamp of the user's last chat message, or of connecting if none
+    pub last_active_at: Instant,
+    /// Remote address of the connection, recorded only when enabled on the server
+    pub ip: Option<String>,
+    /// Limits how fast this connection may send messages
+    pub rate_limiter: TokenBucket,
+    /// Crate version reported by the client on connect
+    pub client_version: Option<String>,
+    /// Protocol version reported by the client on connect
+    pub protocol_version: Option<u32>,
+    /// How room history is replayed to this connection
+    pub replay_format: ReplayFormat,
+    /// What this connection may do, decided when it authenticated
+    pub role: Role,
+    /// Whether the user is around, as set by them or by the idle timeout
+    pub status: UserStatus,
+    /// Whether `status` was set to away by the idle timeout, so sending a
+    /// message brings the user back
+    pub auto_away: bool,
+}
+
+/// What a connection is allowed to do, assigned when it authenticates.
+///
+/// Roles are ordered: each has every permission of the ones before it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Connected without credentials to a server that doesn't require any
+    #[default]
+    Guest,
+    /// Presented the server's shared auth token
+    Member,
+    /// Presented the moderator token
+    Moderator,
+    /// Presented the admin token, or is named in the server's admin list
+    Admin,
+}
+
+/// A privileged action gated by [`Role`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Disconnect another user and ban their name for a while
+    Kick,
+    /// Pin a message in a room
+    Pin,
+    /// Change a room's topic, on servers that reserve it for moderators
+    SetTopic,
+}
+
+impl Role {
+    /// Returns whether this role may perform `permission`.
+    pub fn can(self, permission: Permission) -> bool {
+        let required = match permission {
+            Permission::Kick | Permission::Pin | Permission::SetTopic => Role::Moderator,
+        };
+        self >= required
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Role::Guest => "guest",
+            Role::Member => "member",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        };
+        f.write_str(name)
+    }
+}
+
+/// How stored messages are replayed to a client when it joins a room.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplayFormat {
+    /// Each message's bare text, as understood by legacy clients
+    #[default]
+    Text,
+    /// A single `ServerMessage::History` block with every message's ID,
+    /// sender and reactions
+    Full,
+}
+
+impl ReplayFormat {
+    /// Picks the format for a connecting client: the one it asked for, or
+    /// full messages for clients that report a protocol version and plain
+    /// text for legacy clients that don't.
+    pub fn negotiate(requested: Option<Self>, protocol_version: Option<u32>) -> Self {
+        requested.unwrap_or(if protocol_version.is_some() {
+            Self::Full
+        } else {
+            Self::Text
+        })
+    }
+}
+
+/// Whether a user is around, shown next to their name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UserStatus {
+    /// Connected and around; every user starts out online
+    #[default]
+    Online,
+    /// Stepped away, or hasn't sent anything for a while
+    Away,
+    /// Around, but would rather not be disturbed
+    Busy,
+}
+
+impl std::fmt::Display for UserStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            UserStatus::Online => "online",
+            UserStatus::Away => "away",
+            UserStatus::Busy => "busy",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Detailed information about a connected user, only exposed to admins.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminUserInfo {
+    /// The user's display name
+    pub name: String,
+    /// Identifier of the room the user is in
+    pub room: String,
+    /// Seconds since the user connected
+    pub connected_secs: u64,
+    /// Number of chat messages sent during this connection
+    pub message_count: usize,
+    /// Remote address, if the server records it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    /// Crate version reported by the client, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_version: Option<String>,
+    /// Protocol version reported by the client, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol_version: Option<u32>,
+    /// Whether the user is around
+    pub status: UserStatus,
+}
+
+/// An active WebSocket connection, as listed by `/admin/connections`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    /// Connection (user) ID, used to terminate the connection
+    pub id: String,
+    /// Display name of the connected user
+    pub name: String,
+    /// Identifier of the room the connection is in
+    pub room: String,
+    /// Seconds since the connection was opened
+    pub connected_secs: u64,
+    /// Seconds since the user last sent a chat message (or connected)
+    pub idle_secs: u64,
+}
+
+/// Admin variant of [`UserList`] with per-connection metadata.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminUserList {
+    /// Connected users with their metadata
+    pub users: Vec<AdminUserInfo>,
+    /// Total number of connected users
+    pub count: usize,
+}
+
+/// Build and protocol information returned by `GET /version`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// Configured server name
+    pub server_name: String,
+    /// Crate version of the server build
+    pub version: String,
+    /// Protocol version spoken by the server
+    pub protocol_version: u32,
+}
+
+impl ServerInfo {
+    /// Describes this build running under `server_name`.
+    pub fn new(server_name: &str) -> Self {
+        Self {
+            server_name: server_name.to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol_version: PROTOCOL_VERSION,
+        }
+    }
+}
+
+/// Health report returned by `GET /healthz` (and its alias `GET /health`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthStatus {
+    /// Always `"ok"` while the server is accepting requests
+    pub status: String,
+    /// Configured server name
+    pub server_name: String,
+    /// Number of connected users
+    pub connected_users: usize,
+    /// Seconds since the server started
+    #[serde(default)]
+    pub uptime_secs: u64,
+    /// Number of open rooms
+    #[serde(default)]
+    pub rooms: usize,
+}
+
+/// When a user was last active, returned by `GET /users/{name}/lastseen`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LastSeen {
+    /// The user's display name
+    pub name: String,
+    /// Whether someone is connected under this name right now
+    pub online: bool,
+    /// When they last sent a message or disconnected, in seconds since the
+    /// Unix epoch; `None` if they're online but haven't done either yet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<u64>,
+}
+
+/// One entry of the room list returned by `GET /rooms`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomInfo {
+    /// Identifier of the room
+    pub id: String,
+    /// Number of users currently in the room
+    pub users: usize,
+    /// Number of messages in the room's history
+    pub messages: usize,
+    /// Whether a password is needed to join
+    pub password_protected: bool,
+    /// The room's topic, if it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
 }
 
 /// Message types for client-server communication
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ServerMessage {
-    /// Regular chat message
-    Chat { text: String },
+    /// Snapshot sent to a client right after it connects, before any history
+    Welcome {
+        server_name: String,
+        version: String,
+        room: String,
+        motd: String,
+        /// The name the connection was registered under, which differs from
+        /// the requested one when that was already taken
+        #[serde(default)]
+        name: String,
+        /// The role this connection was given when it authenticated
+        #[serde(default)]
+        role: Role,
+    },
+    /// Regular chat message, with its sender if known and its ID once it
+    /// has been stored
+    Chat {
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sender: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        /// ID of the message this one replies to
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<u64>,
+        /// Snippet of the replied-to message, when it's sent live
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        quote: Option<String>,
+        /// Users mentioned as `@name`, so clients needn't search the text
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        mentions: Vec<String>,
+        /// When the server stored the message, in seconds since the Unix epoch
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<u64>,
+        /// Signature of the stored message, as in [`Message::signature`]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<String>,
+    },
+    /// An IRC-style action, shown as `* name text`
+    Action { name: String, text: String },
+    /// A file was uploaded to the room and can be downloaded from `url`
+    Attachment {
+        name: String,
+        url: String,
+        content_type: String,
+        size: u64,
+    },
+    /// Some requested history is no longer retained and can't be replayed.
+    ///
+    /// Covers the message IDs `from..=to`.
+    HistoryGap { from: u64, to: u64 },
+    /// A page of stored messages, oldest first: the recent backlog sent on
+    /// joining a room with [`ReplayFormat::Full`], or the answer to
+    /// `FetchHistory`, which is empty when there's nothing older
+    History { messages: Vec<Message> },
+    /// The room's history reached its cap and `dropped` of the oldest
+    /// messages were discarded since the last such notice
+    HistoryTrimmed { dropped: usize },
+    /// Reply to a client's `Ping`, echoing its nonce
+    Pong { nonce: u64 },
+    /// Reply to a client's `Stats`
+    Stats {
+        uptime_secs: u64,
+        /// Chat messages posted since the server started
+        total_messages: u64,
+        connected_users: usize,
+        rooms: usize,
+    },
+    /// The connection has been idle for a while and will be closed in
+    /// `disconnect_in_secs` unless the client sends something
+    IdleWarning { disconnect_in_secs: u64 },
+    /// A stored message was changed by its author; `text` replaces it
+    MessageEdited {
+        id: u64,
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sender: Option<String>,
+    },
+    /// A stored message was deleted by its author
+    MessageDeleted { id: u64 },
+    /// A moderator pinned a stored message in the room
+    MessagePinned { id: u64, by: String },
+    /// A pinned message was unpinned to make room for a newer pin
+    MessageUnpinned { id: u64 },
+    /// The room's topic, sent when someone (`by`) changes it and to clients
+    /// joining the room; `None` means it has no topic
+    TopicChanged {
+        topic: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        by: Option<String>,
+    },
+    /// `name` added or removed the `emoji` reaction on a stored message
+    Reaction {
+        message_id: u64,
+        emoji: String,
+        name: String,
+        added: bool,
+    },
+    /// An event from a room the connection watches besides its own
+    RoomEvent {
+        room: String,
+        event: Box<ServerMessage>,
+    },
+    /// Confirms to the sender that their message was stored and broadcast
+    Ack {
+        id: u64,
+        /// Key the client gave the message, if it gave one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_msg_id: Option<String>,
+    },
     /// User list update
     UserList(UserList),
     /// User joined notification
     UserJoined { name: String },
     /// User left notification
     UserLeft { name: String },
+    /// User changed their display name
+    UserRenamed { old: String, new: String },
+    /// User went away, came back or became busy
+    StatusChanged { name: String, status: UserStatus },
+    /// Private message delivered only to the recipient and echoed to the sender
+    DirectMessage {
+        from: String,
+        to: String,
+        text: String,
+    },
+    /// The room was closed and the connection is about to be dropped
+    RoomClosed { room: String, reason: String },
+    /// The server is shutting down and the connection is about to be closed
+    ServerShutdown,
+    /// Notice from the server operator, sent to every room
+    Announcement { text: String },
+    /// Notice meant only for this connection, such as the greeting of the
+    /// room it just joined; it isn't part of the room's history
+    System { text: String },
+    /// Messages from a user were removed by a moderator; follows a
+    /// `MessageDeleted` for each of them
+    MessagesPurged { name: String, count: usize },
+    /// Reply to a `PurgeMine`, counting the messages that were deleted
+    OwnMessagesPurged { count: usize },
+    /// Error reply sent only to the client whose request failed.
+    ///
+    /// `code` follows HTTP semantics (400 malformed, 404 not found, ...).
+    Error { code: u16, message: String },
 }
 
 /// Message types for client-to-server communication
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ClientMessage {
-    /// Initial connection message with user name
-    Connect { name: String },
+    /// Initial connection message with user name.
+    ///
+    /// Versions are optional so older clients that don't send them can still connect.
+    Connect {
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_version: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        protocol_version: Option<u32>,
+        /// Shared secret required when the server is started with `--auth-token`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+        /// How to replay room history; see [`ReplayFormat::negotiate`]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        replay: Option<ReplayFormat>,
+        /// Password of the room being joined, if it has one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room_password: Option<String>,
+        /// Secret the client keeps between runs, so the messages it sends
+        /// stay its own to edit and delete after reconnecting
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        identity: Option<String>,
+    },
     /// Regular chat message
-    Chat { text: String },
+    Chat {
+        text: String,
+        /// Key the client picked for this message (e.g. a UUID); a resend
+        /// with the same key from the same author, even after reconnecting
+        /// with the same identity, is acknowledged again instead of being
+        /// posted twice
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_msg_id: Option<String>,
+    },
+    /// Chat message replying to message `to` in the current room
+    Reply { to: u64, text: String },
+    /// Describe something you're doing, e.g. `/me waves`
+    Action { text: String },
+    /// Request to change the user's display name
+    Rename { new_name: String },
+    /// Mark yourself online, away or busy
+    SetStatus { status: UserStatus },
+    /// Private message to a single user, addressed by name
+    DirectMessage { to: String, text: String },
+    /// Request to resend the room's messages with IDs in
+    /// `from..=to`, e.g. those missed while reconnecting
+    History { from: u64, to: u64 },
+    /// Request up to `limit` stored messages of the current room, newest
+    /// first from just before message `before` (or the latest message)
+    FetchHistory { before: Option<u64>, limit: usize },
+    /// Ask the server to echo `nonce` back in a `Pong`, to measure latency
+    Ping { nonce: u64 },
+    /// Ask the server for its uptime and load, answered with `Stats`
+    Stats,
+    /// Replace the text of one of your own messages in the current room
+    Edit { id: u64, text: String },
+    /// Delete one of your own messages in the current room
+    Delete { id: u64 },
+    /// Delete all of your own messages in the current room
+    PurgeMine,
+    /// Pin a message in the current room; needs [`Permission::Pin`]
+    Pin { id: u64 },
+    /// Toggle an emoji reaction on a message in the current room
+    React { message_id: u64, emoji: String },
+    /// Also receive events from another room, without leaving the current one
+    Subscribe { room: String },
+    /// Stop watching a room added with `Subscribe`
+    Unsubscribe { room: String },
+    /// Move this connection to another existing room
+    JoinRoom {
+        room: String,
+        /// Password of the room, if it has one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
+    },
+    /// Leave the current room, returning to the default room
+    LeaveRoom { room: String },
+    /// Request to remove a user from the server; only admins may kick
+    Kick { target: String },
+    /// Change the current room's topic; blank text clears it
+    SetTopic { text: String },
     /// Disconnect notification
     Disconnect,
 }
 
+impl SerializableUser {
+    /// Describes `user` as of `now`.
+    pub fn at(user: &User, now: Instant) -> Self {
+        SerializableUser {
+            name: user.name.clone(),
+            connected_secs: now.saturating_duration_since(user.connected_at).as_secs(),
+            status: user.status,
+        }
+    }
+}
+
 impl From<&User> for SerializableUser {
     fn from(user: &User) -> Self {
-        SerializableUser {
+        Self::at(user, Instant::now())
+    }
+}
+
+impl From<&User> for AdminUserInfo {
+    fn from(user: &User) -> Self {
+        AdminUserInfo {
+            name: user.name.clone(),
+            room: user.room.clone(),
+            connected_secs: user.connected_at.elapsed().as_secs(),
+            message_count: user.message_count,
+            ip: user.ip.clone(),
+            client_version: user.client_version.clone(),
+            protocol_version: user.protocol_version,
+            status: user.status,
+        }
+    }
+}
+
+impl ClientMessage {
+    /// Create a connect message announcing this build's client and protocol versions
+    #[cfg_attr(not(feature = "client"), allow(dead_code))]
+    pub fn connect(name: String, token: Option<String>, room_password: Option<String>) -> Self {
+        ClientMessage::Connect {
+            name,
+            client_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            protocol_version: Some(PROTOCOL_VERSION),
+            token,
+            replay: None,
+            room_password,
+            identity: None,
+        }
+    }
+
+    /// Sets the identity a connect message presents; other messages are
+    /// returned unchanged.
+    #[cfg_attr(not(feature = "client"), allow(dead_code))]
+    pub fn with_identity(mut self, key: Option<String>) -> Self {
+        if let ClientMessage::Connect { identity, .. } = &mut self {
+            *identity = key;
+        }
+        self
+    }
+}
+
+impl From<&User> for ConnectionInfo {
+    fn from(user: &User) -> Self {
+        ConnectionInfo {
+            id: user.id.clone(),
             name: user.name.clone(),
+            room: user.room.clone(),
+            connected_secs: user.connected_at.elapsed().as_secs(),
+            idle_secs: user.last_active_at.elapsed().as_secs(),
+        }
+    }
+}
+
+impl ServerMessage {
+    /// Create an error reply with the given code and message
+    pub fn error(code: u16, message: impl Into<String>) -> Self {
+        ServerMessage::Error {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Create a chat broadcast for a stored message, keeping its sender and ID
+    pub fn chat(message: &Message) -> Self {
+        ServerMessage::Chat {
+            text: message.text.clone(),
+            sender: message.sender.clone(),
+            id: message.id,
+            reply_to: message.reply_to,
+            quote: None,
+            mentions: message.mentions.clone(),
+            timestamp: message.timestamp,
+            signature: message.signature.clone(),
         }
     }
 }
 
+/// Makes up a name for a user who didn't give one, e.g. `User_1a2b3c4d`.
+pub fn default_user_name() -> String {
+    format!(
+        "User_{}",
+        uuid::Uuid::new_v4().simple().to_string().split_at(8).0
+    )
+}
+
+/// Formats an action for display as `* name text`.
+pub fn action_line(name: &str, text: &str) -> String {
+    format!("* {} {}", name, text)
+}
+
+/// Formats the line shown above a reply: the quoted snippet of the message
+/// it answers, or just its ID when the snippet isn't known.
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+pub fn reply_line(reply_to: u64, quote: Option<&str>) -> String {
+    match quote {
+        Some(quote) => format!("  > {}", quote),
+        None => format!("  > reply to #{}", reply_to),
+    }
+}
+
+/// Formats an attachment for display, e.g.
+/// `[file] cat.png (image/png, 2048 bytes) http://host/files/...`.
+pub fn attachment_line(name: &str, url: &str, content_type: &str, size: u64) -> String {
+    format!("[file] {} ({}, {} bytes) {}", name, content_type, size, url)
+}
+
+/// Describes a user's new status for display, e.g. `Alice is away`.
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+pub fn status_line(name: &str, status: UserStatus) -> String {
+    match status {
+        UserStatus::Online => format!("{} is back", name),
+        status => format!("{} is {}", name, status),
+    }
+}
+
+/// Returns the current time in seconds since the Unix epoch.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// Formats `secs` since the Unix epoch as an RFC 3339 UTC time, e.g.
+/// `2024-05-01T09:30:00Z`.
+pub fn rfc3339(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let time = secs % 86_400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Formats a chat line for display as `sender: text`, or just `text` when
+/// the sender isn't known.
+pub fn chat_line(sender: Option<&str>, text: &str) -> String {
+    match sender {
+        Some(sender) => format!("{}: {}", sender, text),
+        None => text.to_string(),
+    }
+}
+
 impl Message {
     /// Create a new message with the given text
-    #[allow(dead_code)]
     pub fn new(text: String) -> Self {
-        Self { text }
+        Self {
+            text,
+            ..Self::default()
+        }
     }
 
-    /// Create a formatted chat message with sender name
+    /// Create a chat message from `sender`; the name is kept out of the text
     pub fn chat_message(sender: &str, text: &str) -> Self {
         Self {
-            text: format!("{}: {}", sender, text),
+            text: text.to_string(),
+            sender: Some(sender.to_string()),
+            ..Self::default()
+        }
+    }
+
+    /// Create the history entry for an action by `name`.
+    ///
+    /// The whole line is the text, so history shows it the same way
+    /// whichever format it is replayed in.
+    pub fn action(name: &str, text: &str) -> Self {
+        Self::new(action_line(name, text))
+    }
+
+    /// Create the history entry for a file uploaded to a room, which is
+    /// likewise all text.
+    pub fn attachment(name: &str, url: &str, content_type: &str, size: u64) -> Self {
+        Self::new(attachment_line(name, url, content_type, size))
+    }
+
+    /// Returns the message as one line of plain text, as shown to clients
+    /// that don't understand `sender`.
+    pub fn display_text(&self) -> String {
+        chat_line(self.sender.as_deref(), &self.text)
+    }
+
+    /// Removes the `sender: ` prefix from the text of a message stored back
+    /// when the sender's name was part of the text.
+    pub fn strip_legacy_prefix(&mut self) {
+        if let Some(sender) = &self.sender
+            && let Some(text) = self
+                .text
+                .strip_prefix(sender.as_str())
+                .and_then(|rest| rest.strip_prefix(": "))
+        {
+            self.text = text.to_string();
         }
     }
 }
 
 impl User {
-    /// Create a new user with the given name and generated ID
-    pub fn new(name: String) -> Self {
+    /// Create a new user in `room` with the given name and generated ID
+    pub fn new(name: String, room: &str) -> Self {
+        let now = Instant::now();
         Self {
             id: uuid::Uuid::new_v4().to_string(),
             name,
-            connected_at: Instant::now(),
+            connected_at: now,
+            room: room.to_string(),
+            subscriptions: BTreeSet::new(),
+            message_count: 0,
+            last_active_at: now,
+            ip: None,
+            rate_limiter: TokenBucket::default(),
+            client_version: None,
+            protocol_version: None,
+            replay_format: ReplayFormat::default(),
+            role: Role::default(),
+            status: UserStatus::default(),
+            auto_away: false,
         }
     }
+
+    /// Records that the user sent a chat message
+    pub fn record_message(&mut self) {
+        self.message_count += 1;
+        self.last_active_at = Instant::now();
+    }
 }
 
 impl UserList {
@@ -136,3 +867,13 @@ impl UserList {
         }
     }
 }
+
+impl AdminUserList {
+    /// Create an admin user list from a collection of users
+    pub fn from_users(users: &[User]) -> Self {
+        Self {
+            users: users.iter().map(|u| u.into()).collect(),
+            count: users.len(),
+        }
+    }
+}
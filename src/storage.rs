@@ -0,0 +1,648 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::shared::{ChatError, ChatResult, Message};
+
+/// Controls how often buffered messages are written to disk.
+///
+/// Pending messages are flushed as soon as `max_pending` of them have
+/// accumulated, or once `interval` has elapsed since the last flush,
+/// whichever comes first.
+#[derive(Debug, Clone, Copy)]
+pub struct FlushPolicy {
+    /// Flush once this many messages are waiting to be written
+    pub max_pending: usize,
+    /// Flush at least this often while messages are waiting
+    pub interval: Duration,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        Self {
+            max_pending: 10,
+            interval: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Append-only JSON Lines store for chat history.
+///
+/// Messages are buffered in memory and appended to the file according to
+/// the configured [`FlushPolicy`]. Edits are appended too, as a later line
+/// for the same message ID, until [`MessageStore::rewrite`] compacts them.
+pub struct MessageStore {
+    path: PathBuf,
+    policy: FlushPolicy,
+    pending: Vec<Message>,
+    last_flush: Instant,
+    /// Changed messages appended since the history was last rewritten
+    updates_appended: usize,
+    /// Last-seen records appended since the times were last saved whole
+    last_seen_appended: usize,
+}
+
+/// A [`Message`] as written to disk. Unlike on the wire, the key of its
+/// author is kept, so authors can still edit and delete their messages
+/// after a restart.
+#[derive(Serialize)]
+struct StoredRef<'a> {
+    #[serde(flatten)]
+    message: &'a Message,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    author_id: Option<&'a str>,
+}
+
+impl<'a> From<&'a Message> for StoredRef<'a> {
+    fn from(message: &'a Message) -> Self {
+        Self {
+            message,
+            author_id: message.author_id.as_deref(),
+        }
+    }
+}
+
+/// A [`Message`] as read back from disk; see [`StoredRef`].
+#[derive(Deserialize)]
+struct Stored {
+    #[serde(flatten)]
+    message: Message,
+    #[serde(default)]
+    author_id: Option<String>,
+}
+
+impl From<Stored> for Message {
+    fn from(stored: Stored) -> Self {
+        Message {
+            author_id: stored.author_id,
+            ..stored.message
+        }
+    }
+}
+
+/// Serializes a list of messages in the on-disk format, for
+/// `#[serde(with)]`.
+mod stored_messages {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::{Stored, StoredRef};
+    use crate::shared::Message;
+
+    pub fn serialize<S: Serializer>(
+        messages: &[Message],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(messages.iter().map(StoredRef::from))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Message>, D::Error> {
+        let stored = Vec::<Stored>::deserialize(deserializer)?;
+        Ok(stored.into_iter().map(Message::from).collect())
+    }
+}
+
+/// One line of the log written by [`MessageStore::append_last_seen`].
+#[derive(Serialize, Deserialize)]
+struct LastSeenRecord {
+    name: String,
+    time: u64,
+}
+
+impl MessageStore {
+    /// Creates a store writing to `path` with the given flush policy.
+    pub fn new(path: impl Into<PathBuf>, policy: FlushPolicy) -> Self {
+        Self {
+            path: path.into(),
+            policy,
+            pending: Vec::new(),
+            last_flush: Instant::now(),
+            updates_appended: 0,
+            last_seen_appended: 0,
+        }
+    }
+
+    /// Returns the flush policy of this store.
+    pub fn policy(&self) -> FlushPolicy {
+        self.policy
+    }
+
+    /// Loads every message previously written to the store.
+    ///
+    /// A missing file is treated as an empty history. Lines that fail to
+    /// parse are skipped so a partially written tail doesn't block startup.
+    /// A message written again by [`MessageStore::update`] keeps its place
+    /// and takes its latest contents.
+    pub fn load(&self) -> ChatResult<Vec<Message>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let reader = BufReader::new(File::open(&self.path)?);
+        let mut messages = Vec::new();
+        let mut positions = HashMap::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<Stored>(&line).map(Message::from) {
+                Ok(mut message) => {
+                    message.strip_legacy_prefix();
+                    match message.id.and_then(|id| positions.get(&id).copied()) {
+                        Some(i) => messages[i] = message,
+                        None => {
+                            if let Some(id) = message.id {
+                                positions.insert(id, messages.len());
+                            }
+                            messages.push(message);
+                        }
+                    }
+                }
+                Err(e) => eprintln!("Skipping unreadable history line: {}", e),
+            }
+        }
+
+        Ok(messages)
+    }
+
+    /// Queues a message for writing, flushing if the pending count is reached.
+    pub fn append(&mut self, message: Message) -> ChatResult<()> {
+        self.pending.push(message);
+        if self.pending.len() >= self.policy.max_pending {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes pending messages if the flush interval has elapsed.
+    pub fn flush_if_due(&mut self) -> ChatResult<()> {
+        if !self.pending.is_empty() && self.last_flush.elapsed() >= self.policy.interval {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes all pending messages to disk.
+    pub fn flush(&mut self) -> ChatResult<()> {
+        if !self.pending.is_empty() {
+            append_lines(&self.path, &self.pending)?;
+            self.pending.clear();
+        }
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+
+    /// Records that `messages` changed since they were stored, e.g. edited,
+    /// deleted or reacted to, by appending their new contents after
+    /// anything pending.
+    ///
+    /// Returns how many changed messages have been appended since the
+    /// history was last rewritten, so the caller can decide when to compact
+    /// it with [`MessageStore::rewrite`].
+    pub fn update<'a>(
+        &mut self,
+        messages: impl IntoIterator<Item = &'a Message>,
+    ) -> ChatResult<usize> {
+        self.flush()?;
+        let messages: Vec<&Message> = messages.into_iter().collect();
+        append_lines(&self.path, messages.iter().copied())?;
+        self.updates_appended += messages.len();
+        Ok(self.updates_appended)
+    }
+
+    /// Replaces the stored history with `messages`, discarding anything pending.
+    ///
+    /// Used when history is edited in place (e.g. moderation) so removed
+    /// messages don't reappear on restart. The new file is written beside the
+    /// old one and renamed over it.
+    pub fn rewrite<'a>(
+        &mut self,
+        messages: impl IntoIterator<Item = &'a Message>,
+    ) -> ChatResult<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        if tmp_path.exists() {
+            std::fs::remove_file(&tmp_path)?;
+        }
+        append_lines(&tmp_path, messages)?;
+        std::fs::rename(&tmp_path, &self.path)?;
+
+        self.pending.clear();
+        self.last_flush = Instant::now();
+        self.updates_appended = 0;
+        Ok(())
+    }
+
+    /// Loads the room topics saved by [`MessageStore::save_topics`], keyed
+    /// by room. A missing file means no room has a topic.
+    pub fn load_topics(&self) -> ChatResult<BTreeMap<String, String>> {
+        self.load_sidecar("topics.json")
+    }
+
+    /// Replaces the saved room topics with `topics`.
+    pub fn save_topics(&self, topics: &BTreeMap<String, String>) -> ChatResult<()> {
+        self.save_sidecar("topics.json", topics)
+    }
+
+    /// Loads the last-seen times saved by [`MessageStore::save_last_seen`]
+    /// and [`MessageStore::append_last_seen`], in seconds since the Unix
+    /// epoch keyed by user name. A missing file means nobody has been seen.
+    ///
+    /// Unreadable log lines are skipped, like unreadable history lines.
+    pub fn load_last_seen(&self) -> ChatResult<BTreeMap<String, u64>> {
+        let mut last_seen: BTreeMap<String, u64> = self.load_sidecar("lastseen.json")?;
+        let log_path = self.path.with_extension("lastseen.jsonl");
+        if !log_path.exists() {
+            return Ok(last_seen);
+        }
+        for line in BufReader::new(File::open(log_path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<LastSeenRecord>(&line) {
+                Ok(record) => {
+                    let seen = last_seen.entry(record.name).or_default();
+                    *seen = (*seen).max(record.time);
+                }
+                Err(e) => eprintln!("Skipping unreadable last-seen line: {}", e),
+            }
+        }
+        Ok(last_seen)
+    }
+
+    /// Records that `name` was last seen at `time` without rewriting the
+    /// saved times, by appending to a log beside them.
+    ///
+    /// Returns how many records have been appended since the times were
+    /// last saved whole, so the caller can decide when to compact the log
+    /// with [`MessageStore::save_last_seen`].
+    pub fn append_last_seen(&mut self, name: &str, time: u64) -> ChatResult<usize> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path.with_extension("lastseen.jsonl"))?;
+        let mut writer = BufWriter::new(file);
+        let record = LastSeenRecord {
+            name: name.to_string(),
+            time,
+        };
+        serde_json::to_writer(&mut writer, &record)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+        self.last_seen_appended += 1;
+        Ok(self.last_seen_appended)
+    }
+
+    /// Replaces the saved last-seen times with `last_seen`, discarding the
+    /// log of appended records.
+    pub fn save_last_seen(&mut self, last_seen: &BTreeMap<String, u64>) -> ChatResult<()> {
+        self.save_sidecar("lastseen.json", last_seen)?;
+        let log_path = self.path.with_extension("lastseen.jsonl");
+        if log_path.exists() {
+            std::fs::remove_file(log_path)?;
+        }
+        self.last_seen_appended = 0;
+        Ok(())
+    }
+
+    /// Reads the JSON file with extension `extension` beside the history
+    /// file, or the default value if there isn't one.
+    fn load_sidecar<T: DeserializeOwned + Default>(&self, extension: &str) -> ChatResult<T> {
+        let path = self.path.with_extension(extension);
+        if !path.exists() {
+            return Ok(T::default());
+        }
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    /// Replaces the JSON file with extension `extension` beside the history
+    /// file with `value`.
+    ///
+    /// Like the history, the file is written beside the old one and renamed
+    /// over it.
+    fn save_sidecar<T: Serialize>(&self, extension: &str, value: &T) -> ChatResult<()> {
+        let path = self.path.with_extension(extension);
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, value)?;
+        writer.flush()?;
+        drop(writer);
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// Returns the number of messages waiting to be written.
+    #[allow(dead_code)]
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+fn append_lines<'a>(
+    path: &Path,
+    messages: impl IntoIterator<Item = &'a Message>,
+) -> ChatResult<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut writer = BufWriter::new(file);
+    for message in messages {
+        serde_json::to_writer(&mut writer, &StoredRef::from(message))?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Encodes `messages` as JSON Lines, one message per line, in the same
+/// format as the history file.
+pub fn to_json_lines<'a>(messages: impl IntoIterator<Item = &'a Message>) -> ChatResult<String> {
+    let mut out = String::new();
+    for message in messages {
+        out.push_str(&serde_json::to_string(&StoredRef::from(message))?);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+/// Parses messages written by [`to_json_lines`], skipping blank lines.
+///
+/// Unlike [`MessageStore::load`], an unreadable line is an error naming the
+/// line, so nothing is half-imported from a damaged file.
+pub fn from_json_lines(text: &str) -> ChatResult<Vec<Message>> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let mut message: Message = serde_json::from_str::<Stored>(line)
+                .map(Message::from)
+                .map_err(|e| ChatError::InvalidMessage(format!("line {}: {}", i + 1, e)))?;
+            message.strip_legacy_prefix();
+            Ok(message)
+        })
+        .collect()
+}
+
+/// History of one room as saved in a [`ServerSnapshot`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomSnapshot {
+    /// Retained messages, oldest first
+    #[serde(with = "stored_messages")]
+    pub messages: Vec<Message>,
+    /// ID of the newest message ever stored in the room
+    pub last_id: u64,
+    /// The room's topic, if it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    /// IDs of pinned messages, in the order they were pinned
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pinned: Vec<u64>,
+    /// Number of messages dropped from the front of the history by the cap
+    #[serde(default)]
+    pub dropped: usize,
+}
+
+/// Point-in-time copy of every room, written by `/admin/snapshot`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServerSnapshot {
+    /// Rooms keyed by room ID
+    pub rooms: BTreeMap<String, RoomSnapshot>,
+    /// When each user name was last seen, in seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub last_seen: BTreeMap<String, u64>,
+}
+
+impl ServerSnapshot {
+    /// Writes the snapshot to `path` as JSON.
+    ///
+    /// The file is written beside the target and renamed over it, so a
+    /// reader never sees a half-written snapshot.
+    pub fn save(&self, path: &Path) -> ChatResult<()> {
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        drop(writer);
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Reads a snapshot previously written by [`ServerSnapshot::save`].
+    pub fn load(path: &Path) -> ChatResult<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("chat-history-{}.jsonl", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_flush_after_max_pending_messages() {
+        let path = temp_path();
+        let policy = FlushPolicy {
+            max_pending: 3,
+            interval: Duration::from_secs(3600),
+        };
+        let mut store = MessageStore::new(&path, policy);
+
+        store.append(Message::new("one".to_string())).unwrap();
+        store.append(Message::new("two".to_string())).unwrap();
+        assert_eq!(store.pending_len(), 2);
+        assert!(store.load().unwrap().is_empty());
+
+        store.append(Message::new("three".to_string())).unwrap();
+        assert_eq!(store.pending_len(), 0);
+
+        let texts: Vec<String> = store.load().unwrap().into_iter().map(|m| m.text).collect();
+        assert_eq!(texts, vec!["one", "two", "three"]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_strips_sender_from_old_history() {
+        let path = temp_path();
+        std::fs::write(
+            &path,
+            concat!(
+                "{\"text\":\"Alice: hi: there\",\"sender\":\"Alice\"}\n",
+                "{\"text\":\"hello\",\"sender\":\"Bob\"}\n",
+                "{\"text\":\"Carol: anonymous\"}\n",
+            ),
+        )
+        .unwrap();
+
+        let store = MessageStore::new(&path, FlushPolicy::default());
+        let texts: Vec<String> = store.load().unwrap().into_iter().map(|m| m.text).collect();
+        assert_eq!(texts, vec!["hi: there", "hello", "Carol: anonymous"]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_json_lines_round_trip_and_report_bad_lines() {
+        let messages = vec![
+            Message {
+                id: Some(1),
+                ..Message::chat_message("Alice", "hi")
+            },
+            Message::new("legacy".to_string()),
+        ];
+        let text = to_json_lines(&messages).unwrap();
+        assert_eq!(text.lines().count(), 2);
+
+        let parsed = from_json_lines(&format!("{}\n\n", text)).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].id, Some(1));
+        assert_eq!(parsed[0].sender.as_deref(), Some("Alice"));
+        assert_eq!(parsed[1].text, "legacy");
+
+        let err = from_json_lines("{\"text\":\"ok\"}\nnot json").unwrap_err();
+        assert!(err.to_string().contains("line 2"));
+    }
+
+    #[test]
+    fn test_rewrite_replaces_file_contents() {
+        let path = temp_path();
+        let mut store = MessageStore::new(&path, FlushPolicy::default());
+        store.append(Message::new("old".to_string())).unwrap();
+        store.flush().unwrap();
+
+        store.rewrite(&[Message::new("new".to_string())]).unwrap();
+
+        let texts: Vec<String> = store.load().unwrap().into_iter().map(|m| m.text).collect();
+        assert_eq!(texts, vec!["new"]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_updates_are_appended_and_win_on_load() {
+        let path = temp_path();
+        let mut store = MessageStore::new(&path, FlushPolicy::default());
+        let first = Message {
+            id: Some(1),
+            ..Message::chat_message("Alice", "hi")
+        };
+        let second = Message {
+            id: Some(2),
+            ..Message::chat_message("Bob", "hello")
+        };
+        store.append(first.clone()).unwrap();
+        store.append(second).unwrap();
+
+        // The edit lands after the pending messages it refers to
+        let edited = Message {
+            text: "hi all".to_string(),
+            ..first
+        };
+        assert_eq!(store.update([&edited]).unwrap(), 1);
+        assert_eq!(store.pending_len(), 0);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 3);
+
+        let texts: Vec<String> = store.load().unwrap().into_iter().map(|m| m.text).collect();
+        assert_eq!(texts, vec!["hi all", "hello"]);
+
+        // Rewriting compacts the changes away
+        let loaded = store.load().unwrap();
+        store.rewrite(&loaded).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+        assert_eq!(store.update([&edited]).unwrap(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_topics_round_trip_beside_history() {
+        let path = temp_path();
+        let store = MessageStore::new(&path, FlushPolicy::default());
+        assert!(store.load_topics().unwrap().is_empty());
+
+        let topics = BTreeMap::from([("1".to_string(), "Release planning".to_string())]);
+        store.save_topics(&topics).unwrap();
+        assert_eq!(store.load_topics().unwrap(), topics);
+        // The history file itself is untouched
+        assert!(!path.exists());
+
+        std::fs::remove_file(path.with_extension("topics.json")).unwrap();
+    }
+
+    #[test]
+    fn test_last_seen_round_trips_beside_topics() {
+        let path = temp_path();
+        let mut store = MessageStore::new(&path, FlushPolicy::default());
+        assert!(store.load_last_seen().unwrap().is_empty());
+
+        let topics = BTreeMap::from([("1".to_string(), "Release planning".to_string())]);
+        store.save_topics(&topics).unwrap();
+        let last_seen = BTreeMap::from([("Alice".to_string(), 1_700_000_000)]);
+        store.save_last_seen(&last_seen).unwrap();
+        assert_eq!(store.load_last_seen().unwrap(), last_seen);
+        assert_eq!(store.load_topics().unwrap(), topics);
+
+        std::fs::remove_file(path.with_extension("topics.json")).unwrap();
+        std::fs::remove_file(path.with_extension("lastseen.json")).unwrap();
+    }
+
+    #[test]
+    fn test_last_seen_appends_until_saved_whole() {
+        let path = temp_path();
+        let mut store = MessageStore::new(&path, FlushPolicy::default());
+        let saved = BTreeMap::from([("Alice".to_string(), 100), ("Bob".to_string(), 100)]);
+        store.save_last_seen(&saved).unwrap();
+        let saved_path = path.with_extension("lastseen.json");
+        let saved_text = std::fs::read_to_string(&saved_path).unwrap();
+
+        assert_eq!(store.append_last_seen("Alice", 200).unwrap(), 1);
+        assert_eq!(store.append_last_seen("Carol", 150).unwrap(), 2);
+        // An older record doesn't wind a time back
+        assert_eq!(store.append_last_seen("Alice", 120).unwrap(), 3);
+        // Appending leaves the saved times alone
+        assert_eq!(std::fs::read_to_string(&saved_path).unwrap(), saved_text);
+        let expected = BTreeMap::from([
+            ("Alice".to_string(), 200),
+            ("Bob".to_string(), 100),
+            ("Carol".to_string(), 150),
+        ]);
+        assert_eq!(store.load_last_seen().unwrap(), expected);
+
+        // Saving whole folds the log away
+        store.save_last_seen(&expected).unwrap();
+        assert!(!path.with_extension("lastseen.jsonl").exists());
+        assert_eq!(store.load_last_seen().unwrap(), expected);
+        assert_eq!(store.append_last_seen("Bob", 300).unwrap(), 1);
+
+        std::fs::remove_file(saved_path).unwrap();
+        std::fs::remove_file(path.with_extension("lastseen.jsonl")).unwrap();
+    }
+
+    #[test]
+    fn test_flush_if_due_respects_interval() {
+        let path = temp_path();
+        let policy = FlushPolicy {
+            max_pending: 100,
+            interval: Duration::ZERO,
+        };
+        let mut store = MessageStore::new(&path, policy);
+
+        store.append(Message::new("hello".to_string())).unwrap();
+        assert_eq!(store.pending_len(), 1);
+
+        store.flush_if_due().unwrap();
+        assert_eq!(store.pending_len(), 0);
+        assert_eq!(store.load().unwrap().len(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
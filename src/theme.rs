@@ -0,0 +1,143 @@
+use std::str::FromStr;
+
+/// Colors the prompt uses for each kind of line.
+///
+/// Written as comma-separated `KIND=COLOR` pairs, e.g.
+/// `message=white,dm=bright-cyan`; kinds left out keep their default. Kinds
+/// are `message`, `error`, `system` and `dm`, and colors are the eight
+/// terminal colors, optionally prefixed with `bright-`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Theme {
+    /// Our own chat messages and those without a sender; everyone else's
+    /// get a color of their own from [`sender_color`]
+    pub message: term::color::Color,
+    /// Errors and the connection ending
+    pub error: term::color::Color,
+    /// Notices such as users joining, leaving or changing status
+    pub system: term::color::Color,
+    /// Direct messages
+    pub dm: term::color::Color,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            message: term::color::GREEN,
+            error: term::color::RED,
+            system: term::color::YELLOW,
+            dm: term::color::MAGENTA,
+        }
+    }
+}
+
+impl FromStr for Theme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut theme = Self::default();
+        for entry in s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (kind, color) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid theme entry '{}', expected KIND=COLOR", entry))?;
+            let color = parse_color(color.trim())?;
+            match kind.trim() {
+                "message" => theme.message = color,
+                "error" => theme.error = color,
+                "system" => theme.system = color,
+                "dm" => theme.dm = color,
+                kind => {
+                    return Err(format!(
+                        "Unknown theme kind '{}', expected message, error, system or dm",
+                        kind
+                    ));
+                }
+            }
+        }
+        Ok(theme)
+    }
+}
+
+/// Colors other people's chat lines are shown in. Leaves out the default
+/// colors of our own messages, errors, notices, DMs and actions, and black
+/// and white, which may be the background.
+const SENDER_COLORS: [term::color::Color; 6] = [
+    term::color::CYAN,
+    term::color::BLUE,
+    term::color::BRIGHT_GREEN,
+    term::color::BRIGHT_CYAN,
+    term::color::BRIGHT_BLUE,
+    term::color::BRIGHT_YELLOW,
+];
+
+/// Picks the color `sender`'s chat lines are shown in, the same one every
+/// time and on every machine.
+pub fn sender_color(sender: &str) -> term::color::Color {
+    // FNV-1a, since std's hasher may change between releases
+    let hash = sender.bytes().fold(0x811c_9dc5_u32, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    });
+    SENDER_COLORS[hash as usize % SENDER_COLORS.len()]
+}
+
+/// Parses a color name such as `red` or `bright-red`.
+fn parse_color(name: &str) -> Result<term::color::Color, String> {
+    let lower = name.to_lowercase();
+    let (bright, base) = match lower
+        .strip_prefix("bright-")
+        .or_else(|| lower.strip_prefix("bright_"))
+    {
+        Some(base) => (true, base),
+        None => (false, lower.as_str()),
+    };
+    let color = match base {
+        "black" => term::color::BLACK,
+        "red" => term::color::RED,
+        "green" => term::color::GREEN,
+        "yellow" => term::color::YELLOW,
+        "blue" => term::color::BLUE,
+        "magenta" => term::color::MAGENTA,
+        "cyan" => term::color::CYAN,
+        "white" => term::color::WHITE,
+        _ => return Err(format!("Unknown color '{}'", name)),
+    };
+    // The bright variants follow the eight normal colors
+    Ok(if bright { color + 8 } else { color })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_theme_overrides_only_given_kinds() {
+        let theme: Theme = "message=white, dm=Bright-Cyan".parse().unwrap();
+        assert_eq!(theme.message, term::color::WHITE);
+        assert_eq!(theme.dm, term::color::BRIGHT_CYAN);
+        assert_eq!(theme.error, Theme::default().error);
+        assert_eq!(theme.system, Theme::default().system);
+        assert_eq!("".parse::<Theme>().unwrap(), Theme::default());
+
+        assert!("message=purple".parse::<Theme>().is_err());
+        assert!("chat=red".parse::<Theme>().is_err());
+        assert!("red".parse::<Theme>().is_err());
+    }
+
+    #[test]
+    fn test_sender_colors_are_stable() {
+        assert_eq!(sender_color("Alice"), sender_color("Alice"));
+        // Pinned, so names keep their color from one release to the next
+        assert_eq!(sender_color("Alice"), term::color::BRIGHT_YELLOW);
+        assert_eq!(sender_color("Bob"), term::color::BRIGHT_BLUE);
+
+        let theme = Theme::default();
+        for name in ["Alice", "Bob", "Carol", "Dave", "", "名前"] {
+            let color = sender_color(name);
+            assert!(SENDER_COLORS.contains(&color));
+            assert!(![theme.message, theme.error, theme.system, theme.dm].contains(&color));
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use crate::client::{Incoming, topic_line};
+use crate::shared::{ServerMessage, action_line, chat_line, rfc3339, unix_time};
+
+/// Appends a record of the chat session to a file, one labeled line per
+/// message shown, e.g. `2024-05-01T09:30:00Z [chat] Alice: hi`.
+///
+/// Every line is flushed as it is written, so a crash loses nothing that
+/// was already on screen. A failed write is reported once and logging
+/// stops; the session itself carries on.
+pub struct Transcript {
+    file: Option<File>,
+}
+
+impl Transcript {
+    /// Opens `path` for appending, creating it if needed.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file: Some(file) })
+    }
+
+    /// Logs `event` if it is part of the conversation; rosters, acks and
+    /// the like are left out.
+    pub fn record(&mut self, event: &Incoming) {
+        let entry = match event {
+            Incoming::Server(server_msg) => transcript_entry(server_msg),
+            Incoming::Text(text) => Some(("chat", text.clone())),
+            Incoming::Status(status) => Some(("status", status.clone())),
+            Incoming::Closed(reason) => Some(("status", reason.clone())),
+            Incoming::Latency(_) => None,
+        };
+        if let Some((label, text)) = entry {
+            self.write(unix_time(), label, &text);
+        }
+    }
+
+    fn write(&mut self, timestamp: u64, label: &str, text: &str) {
+        if let Some(file) = &mut self.file
+            && let Err(e) = writeln!(file, "{} [{}] {}", rfc3339(timestamp), label, text)
+                .and_then(|_| file.flush())
+        {
+            eprintln!("Failed to write log file, no longer logging: {}", e);
+            self.file = None;
+        }
+    }
+}
+
+/// The label and text a server message is logged with, or `None` if it
+/// isn't logged.
+fn transcript_entry(server_msg: &ServerMessage) -> Option<(&'static str, String)> {
+    let entry = match server_msg {
+        ServerMessage::Chat { text, sender, .. } => ("chat", chat_line(sender.as_deref(), text)),
+        ServerMessage::Action { name, text } => ("action", action_line(name, text)),
+        ServerMessage::DirectMessage { from, to, text } => {
+            ("dm", format!("{} -> {}: {}", from, to, text))
+        }
+        ServerMessage::Attachment { name, url, .. } => ("file", format!("{} {}", name, url)),
+        ServerMessage::UserJoined { name } => ("join", format!("{} joined", name)),
+        ServerMessage::UserLeft { name } => ("leave", format!("{} left", name)),
+        ServerMessage::UserRenamed { old, new } => {
+            ("rename", format!("{} is now known as {}", old, new))
+        }
+        ServerMessage::TopicChanged { topic, by } => {
+            ("topic", topic_line(topic.as_deref(), by.as_deref()))
+        }
+        ServerMessage::Announcement { text } => ("announcement", text.clone()),
+        ServerMessage::System { text } => ("system", text.clone()),
+        ServerMessage::Error { code, message } => ("error", format!("{}: {}", code, message)),
+        ServerMessage::RoomEvent { room, event } => {
+            let (label, text) = transcript_entry(event)?;
+            return Some((label, format!("[{}] {}", room, text)));
+        }
+        _ => return None,
+    };
+    Some(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transcript_labels_messages() {
+        let path = std::env::temp_dir().join(format!("chat-log-{}.txt", uuid::Uuid::new_v4()));
+        let mut transcript = Transcript::open(&path).unwrap();
+
+        for server_msg in [
+            ServerMessage::UserJoined {
+                name: "Bob".to_string(),
+            },
+            ServerMessage::Chat {
+                text: "hi".to_string(),
+                sender: Some("Bob".to_string()),
+                id: Some(1),
+                reply_to: None,
+                quote: None,
+                mentions: Vec::new(),
+                timestamp: None,
+                signature: None,
+            },
+            ServerMessage::Ack {
+                id: 1,
+                client_msg_id: None,
+            },
+            ServerMessage::DirectMessage {
+                from: "Bob".to_string(),
+                to: "Alice".to_string(),
+                text: "psst".to_string(),
+            },
+        ] {
+            transcript.record(&Incoming::Server(server_msg));
+        }
+        transcript.write(1_714_555_800, "leave", "Bob left");
+
+        let logged = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = logged.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].ends_with("Z [join] Bob joined"));
+        assert!(lines[1].ends_with("Z [chat] Bob: hi"));
+        assert!(lines[2].ends_with("Z [dm] Bob -> Alice: psst"));
+        assert_eq!(lines[3], "2024-05-01T09:30:00Z [leave] Bob left");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
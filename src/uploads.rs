@@ -0,0 +1,125 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::shared::ChatResult;
+
+/// Largest file accepted by `POST /room/{room}/upload` by default, in bytes
+pub const DEFAULT_MAX_UPLOAD_SIZE: usize = 10 * 1024 * 1024;
+
+/// Content types accepted for upload unless the server is configured
+/// otherwise
+pub const DEFAULT_UPLOAD_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "application/pdf",
+    "text/plain",
+];
+
+/// Details of an uploaded file, kept next to it as `<id>.json`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadInfo {
+    /// File name given by the uploader
+    pub name: String,
+    /// Media type the file is served with
+    pub content_type: String,
+    /// Size of the file in bytes
+    pub size: u64,
+}
+
+/// Returns the media type of a `Content-Type` value, lowercased and without
+/// parameters, e.g. `text/plain` for `text/plain; charset=utf-8`.
+pub fn media_type(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Reduces a file name given by the uploader to its last path component
+/// without control characters, falling back to `upload` when nothing is left.
+pub fn clean_file_name(name: Option<&str>) -> String {
+    let name: String = name
+        .unwrap_or_default()
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect();
+    match name.trim() {
+        "" | "." | ".." => "upload".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Writes `data` and its `info` under `dir`, creating it if needed, and
+/// returns the ID the file is served under.
+pub fn save(dir: &Path, info: &UploadInfo, data: &[u8]) -> ChatResult<String> {
+    fs::create_dir_all(dir)?;
+    let id = uuid::Uuid::new_v4().simple().to_string();
+    fs::write(dir.join(&id), data)?;
+    fs::write(dir.join(format!("{}.json", id)), serde_json::to_vec(info)?)?;
+    Ok(id)
+}
+
+/// Reads the file saved under `id` in `dir`, or `None` if there's no such
+/// file.
+///
+/// IDs that aren't ones [`save`] could have returned are never looked up,
+/// so a request can't reach files outside `dir`.
+pub fn load(dir: &Path, id: &str) -> ChatResult<Option<(UploadInfo, Vec<u8>)>> {
+    if id.len() != 32 || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Ok(None);
+    }
+    let info = match fs::read(dir.join(format!("{}.json", id))) {
+        Ok(bytes) => serde_json::from_slice(&bytes)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(Some((info, fs::read(dir.join(id))?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_saved_files_load_back_by_id_only() {
+        let dir = std::env::temp_dir().join(format!("chat-uploads-{}", uuid::Uuid::new_v4()));
+        let info = UploadInfo {
+            name: "notes.txt".to_string(),
+            content_type: "text/plain".to_string(),
+            size: 5,
+        };
+        let id = save(&dir, &info, b"hello").unwrap();
+
+        assert_eq!(load(&dir, &id).unwrap(), Some((info, b"hello".to_vec())));
+        assert_eq!(load(&dir, &"0".repeat(32)).unwrap(), None);
+        // The metadata file and anything outside the directory are off limits
+        assert_eq!(load(&dir, &format!("{}.json", id)).unwrap(), None);
+        assert_eq!(load(&dir, "../../etc/passwd").unwrap(), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_names_and_types_are_cleaned() {
+        assert_eq!(clean_file_name(Some("../../etc/passwd")), "passwd");
+        assert_eq!(clean_file_name(Some("C:\\Users\\me\\cat.png")), "cat.png");
+        assert_eq!(
+            clean_file_name(Some("bad\x1b[2Jname.txt")),
+            "bad[2Jname.txt"
+        );
+        assert_eq!(clean_file_name(Some("..")), "upload");
+        assert_eq!(clean_file_name(None), "upload");
+
+        assert_eq!(media_type("Text/Plain; charset=utf-8"), "text/plain");
+        assert_eq!(media_type("image/png"), "image/png");
+    }
+}
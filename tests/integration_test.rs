@@ -1,5 +1,5 @@
-/// Integration tests for the chat application
-/// These tests focus on the shared types and basic functionality
+//! Integration tests for the chat application
+//! These tests focus on the shared types and basic functionality
 
 /// Test message serialization/deserialization
 #[tokio::test]
@@ -81,8 +81,8 @@ async fn test_server_message_formats() {
         "UserList": {
             "count": 2,
             "users": [
-                {"id": "user1", "name": "Alice"},
-                {"id": "user2", "name": "Bob"}
+                {"name": "Alice", "connected_secs": 5},
+                {"name": "Bob", "connected_secs": 120}
             ]
         }
     });
@@ -95,6 +95,8 @@ async fn test_server_message_formats() {
     assert!(parsed["UserList"].is_object());
     assert_eq!(parsed["UserList"]["count"], 2);
     assert_eq!(parsed["UserList"]["users"].as_array().unwrap().len(), 2);
+    assert_eq!(parsed["UserList"]["users"][1]["name"], "Bob");
+    assert_eq!(parsed["UserList"]["users"][1]["connected_secs"], 120);
 
     // Test user joined format
     let user_joined_msg = serde_json::json!({